@@ -0,0 +1,54 @@
+//! Parsing a 500-tunnel ngrok answer into a `Value` tree first, as
+//! discovery used to, against reading only what discovery needs straight
+//! into an `NgrokPage`. Run with `cargo bench --bench tunnel_parse`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use rebind::tunnel::{ngrok_tunnels, NgrokPage};
+use serde_json::{json, Value};
+
+const TUNNELS: usize = 500;
+const ROUNDS: u32 = 200;
+
+/// An agent answer shaped like ngrok's, metrics and all.
+fn payload() -> Vec<u8> {
+    let tunnels: Vec<Value> = (0..TUNNELS)
+        .map(|i| {
+            json!({
+                "name": format!("tunnel-{}", i),
+                "ID": format!("{:032x}", i),
+                "uri": format!("/api/tunnels/tunnel-{}", i),
+                "public_url": format!("https://t{}.ngrok.io", i),
+                "proto": "https",
+                "config": { "addr": format!("http://localhost:{}", 10000 + i), "inspect": true },
+                "metrics": {
+                    "conns": { "count": i, "gauge": 0, "rate1": 0.0, "rate5": 0.0, "rate15": 0.0, "p50": 0.0, "p90": 0.0, "p95": 0.0, "p99": 0.0 },
+                    "http": { "count": i * 3, "rate1": 0.1, "rate5": 0.2, "rate15": 0.3, "p50": 1.5e6, "p90": 2.5e6, "p95": 3.5e6, "p99": 4.5e6 },
+                },
+            })
+        })
+        .collect();
+    serde_json::to_vec(&json!({ "tunnels": tunnels, "uri": "/api/tunnels" })).unwrap()
+}
+
+fn time(label: &str, body: &[u8], parse: impl Fn(&[u8]) -> usize) -> Duration {
+    assert_eq!(parse(body), TUNNELS);
+    let started = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(parse(black_box(body)));
+    }
+    let each = started.elapsed() / ROUNDS;
+    println!("{:<12} {:>10.1?} per answer", label, each);
+    each
+}
+
+fn main() {
+    let body = payload();
+    println!("{} tunnels, {} bytes, {} rounds", TUNNELS, body.len(), ROUNDS);
+    let before = time("value tree", &body, |body| ngrok_tunnels("main", &serde_json::from_slice(body).unwrap()).len());
+    let after = time("ngrok page", &body, |body| {
+        serde_json::from_slice::<NgrokPage>(body).unwrap().tunnels("main", None).len()
+    });
+    println!("ngrok page takes {:.0}% of the value tree's time", after.as_secs_f64() / before.as_secs_f64() * 100.0);
+}
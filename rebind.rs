@@ -1,118 +1,2189 @@
-use std::{collections::HashMap, env, time::Duration};
+use std::io::IsTerminal;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{collections::HashMap, env, fs, sync::Arc};
 
-use dotenv::dotenv;
-use hyper::{body::to_bytes, Body, Client, Method, Request};
-use hyper::client::HttpConnector;
-use hyper_tls::HttpsConnector;
-use serde_json::Value;
+use log::{debug, error, info, warn};
+use rebind::config::{add_stable_urls, BotMode, Canary, Messages, SuccessPolicy, UrlRewrite, DropPending, env_or, DEFAULT_PRIORITY, filter_bots, rewrite_urls, BotTable, Platform};
+use rebind::ledger::new_uuid;
+use rebind::resolve::{is_on, parsed, resolve_config, value_of, Flags, ResolvedConfig, Setting, Source};
+use rebind::metrics::{self, Metrics};
+use rebind::notify::Notifier;
+use rebind::secrets;
+use rebind::selftest::{self, self_test, verify_secrets};
+use rebind::verify::{Expected, Verdict};
+use rebind::tunnel::{agent_provider, AgentState, TunnelProvider};
+use rebind::tokens::{token_shape, MalformedTokens, MissingTokens, TokenShape};
+use rebind::hooks::PostBindHook;
+use rebind::explain::explain;
+use rebind::{events, export, logger, state, trace};
+use rebind::telegram::{
+    already_bound, load_certificate, own_secret, set_webhook_payload, validate_secret, webhook_url, BindError, DEFAULT_API_BASE,
+};
+use rebind::{
+    audit, before_deadline, build_client, deadline, rebind, retry_failed, startup_delay, token_provider, tunnel_provider, unbind, verify_all, BotBinding, BotOutcome,
+    HealthCheck, HttpsClient, Ledger, Outcome, PulseSink, RebindConfig, RebindEvent, RebindReport, RetryPolicy, Targets, TokenProvider,
+    VerificationReport, Watcher,
+};
+use serde_json::json;
+use tokio::sync::oneshot;
 use tokio::time::sleep;
 
-static PORT_TO_NAME: &[(&str, &str)] = &[
-    ("9977", "gpt4o"),
-    ("9988", "mistral"),
-    ("9966", "deepseek"),
-];
-
-static NGROK_APIS: &[(&str, &str)] = &[
-    ("main", "http://localhost:4040/api/tunnels"),
-    ("alt", "http://localhost:4041/api/tunnels"),
-];
-
-async fn get_public_urls(client: &Client<HttpsConnector<HttpConnector>>) -> HashMap<String, String> {
-    let mut urls = HashMap::new();
-    for (label, api) in NGROK_APIS {
-        match api.parse::<hyper::Uri>() {
-            Ok(uri) => match client.get(uri).await {
-                Ok(mut res) if res.status().is_success() => {
-                    if let Ok(bytes) = to_bytes(res.body_mut()).await {
-                        if let Ok(v) = serde_json::from_slice::<Value>(&bytes) {
-                            if let Some(tunnels) = v.get("tunnels").and_then(|t| t.as_array()) {
-                                for t in tunnels {
-                                    if let (Some(public_url), Some(addr)) = (
-                                        t.get("public_url").and_then(|u| u.as_str()),
-                                        t.get("config").and_then(|c| c.get("addr")).and_then(|a| a.as_str()),
-                                    ) {
-                                        if let Some(port) = addr.split(':').last() {
-                                            if let Some((_, name)) = PORT_TO_NAME.iter().find(|(p, _)| *p == port) {
-                                                urls.insert((*name).to_string(), public_url.to_string());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Human,
+    Json,
+    /// One status-bar line for a one-shot run, e.g. `rebind: 3/3 ok @12:04`.
+    Oneline,
+    /// Exactly one versioned JSON object per one-shot run, for log shippers.
+    NdjsonEvent,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            "oneline" => Ok(Format::Oneline),
+            "ndjson-event" => Ok(Format::NdjsonEvent),
+            other => Err(format!("unknown format `{}` (expected human, json, oneline or ndjson-event)", other)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Options {
+    /// `--dry-run` or `REBIND_DRY_RUN`, resolved from [`Flags::dry_run`].
+    dry_run: bool,
+    /// With `--dry-run`, compare each bot's live webhook with the tunnel
+    /// and with what a real run would set.
+    diff: bool,
+    watch: bool,
+    /// With `--watch`, stop after a single poll and say through the exit
+    /// code whether it rebound anything.
+    once: bool,
+    /// With `--watch`, alert about a bot only once it has gone this long
+    /// without a success, not on each failed bind.
+    alert_after: Option<Duration>,
+    force: bool,
+    unbind: bool,
+    verify_only: bool,
+    config_check: bool,
+    /// Fail discovery when a tunnel forwards to a port no bot uses, rather
+    /// than warning (`--strict-unmapped` or `REBIND_STRICT_UNMAPPED`).
+    strict_unmapped: bool,
+    /// Bind, then prove delivery end to end. Rebinds for real.
+    self_test: bool,
+    /// Bind, then check the secret Telegram sends with a real update.
+    verify_secret: bool,
+    /// Rebind only the bots the state file says failed last time.
+    retry_failed: bool,
+    /// Give every selected Telegram bot a fresh secret, saved to
+    /// `REBIND_SECRETS_FILE`.
+    rotate_secret: bool,
+    /// Only check that the tunnel agents answer; never calls Telegram.
+    healthcheck: bool,
+    /// Print the resolved bot table and stop.
+    list: bool,
+    /// Print what the tunnel agents expose, mapped to bots, and stop.
+    discover: bool,
+    /// Print the bot table with each bot's live webhook as its
+    /// `stable_url`, as TOML.
+    export_config: bool,
+    /// Restricts binding, unbinding and dry runs to this bot. Discovery
+    /// still queries every tunnel agent.
+    bot: Option<String>,
+    /// `--config`, the bot table to read (`-` for stdin), `--profile`, the
+    /// `[profiles.NAME]` to lay over its top, and the other flags that
+    /// have an environment variable, which they win over. `--wait-lock` is
+    /// how long to wait for another rebind holding the state file's lock;
+    /// `None` gives up at once with `E_ALREADY_RUNNING`.
+    flags: Flags,
+    /// Log errors only and print results only when something failed.
+    quiet: bool,
+    /// Write every HTTP exchange of the run here as JSONL, redacted.
+    trace_file: Option<String>,
+    /// Bind (and verify) only this share of the selected bots, the first
+    /// ones by name.
+    canary: Option<Canary>,
+    /// Fail the run when a post-bind command fails.
+    strict_hooks: bool,
+    /// After the report, say for each bot where its token and URL came
+    /// from and why it was bound, skipped or failed.
+    explain: bool,
+    /// Move the bots one at a time onto the tunnels of the ngrok agent
+    /// with this label, verifying each before the next.
+    failover: Option<String>,
+    format: Format,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut opts = Options {
+            dry_run: false,
+            diff: false,
+            watch: false,
+            once: false,
+            alert_after: None,
+            export_config: false,
+            force: false,
+            unbind: false,
+            verify_only: false,
+            config_check: false,
+            strict_unmapped: false,
+            self_test: false,
+            verify_secret: false,
+            rotate_secret: false,
+            healthcheck: false,
+            list: false,
+            discover: false,
+            retry_failed: false,
+            bot: None,
+            flags: Flags::default(),
+            quiet: false,
+            trace_file: None,
+            canary: None,
+            strict_hooks: false,
+            explain: false,
+            failover: None,
+            format: Format::Human,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dry-run" => opts.flags.dry_run = true,
+                "--diff" => opts.diff = true,
+                "--watch" => opts.watch = true,
+                "--once" => opts.once = true,
+                "--force" => opts.force = true,
+                "--unbind" => opts.unbind = true,
+                "--verify-only" => opts.verify_only = true,
+                "--config-check" => opts.config_check = true,
+                "--strict-unmapped" => opts.flags.strict_unmapped = true,
+                "--self-test" => opts.self_test = true,
+                "--verify-secret" => opts.verify_secret = true,
+                "--rotate-secret" => opts.rotate_secret = true,
+                "--healthcheck" => opts.healthcheck = true,
+                "--list" => opts.list = true,
+                "--discover" => opts.discover = true,
+                "--export-config" => opts.export_config = true,
+                "--retry-failed" => opts.retry_failed = true,
+                "--strict-hooks" => opts.strict_hooks = true,
+                "--explain" => opts.explain = true,
+                "--quiet" => opts.quiet = true,
+                "--wait-lock" => opts.flags.wait_lock = Some(Duration::from_secs(DEFAULT_LOCK_WAIT_SECS)),
+                "--bot" => opts.bot = Some(args.next().ok_or("--bot needs a name")?),
+                "--profile" => opts.flags.profile = Some(args.next().ok_or("--profile needs a name")?),
+                "--config" => opts.flags.config = Some(args.next().ok_or("--config needs a path")?),
+                "--canary" => opts.canary = Some(args.next().ok_or("--canary needs a percentage or a count")?.parse()?),
+                "--trace-file" => opts.trace_file = Some(args.next().ok_or("--trace-file needs a path")?),
+                "--failover" => opts.failover = Some(args.next().ok_or("--failover needs an agent label")?),
+                "--format" => {
+                    opts.format = args.next().ok_or("--format needs a value")?.parse()?;
+                }
+                "--alert-after-secs" => {
+                    opts.alert_after = Some(alert_after(&args.next().ok_or("--alert-after-secs needs a number")?)?);
+                }
+                other => {
+                    if let Some(format) = other.strip_prefix("--format=") {
+                        opts.format = format.parse()?;
+                    } else if let Some(bot) = other.strip_prefix("--bot=") {
+                        opts.bot = Some(bot.to_string());
+                    } else if let Some(profile) = other.strip_prefix("--profile=") {
+                        opts.flags.profile = Some(profile.to_string());
+                    } else if let Some(path) = other.strip_prefix("--config=") {
+                        opts.flags.config = Some(path.to_string());
+                    } else if let Some(canary) = other.strip_prefix("--canary=") {
+                        opts.canary = Some(canary.parse()?);
+                    } else if let Some(label) = other.strip_prefix("--failover=") {
+                        opts.failover = Some(label.to_string());
+                    } else if let Some(path) = other.strip_prefix("--trace-file=") {
+                        opts.trace_file = Some(path.to_string());
+                    } else if let Some(secs) = other.strip_prefix("--alert-after-secs=") {
+                        opts.alert_after = Some(alert_after(secs)?);
+                    } else if let Some(secs) = other.strip_prefix("--wait-lock=") {
+                        let secs = secs.parse().map_err(|_| format!("--wait-lock needs a number of seconds, not `{}`", secs))?;
+                        opts.flags.wait_lock = Some(Duration::from_secs(secs));
+                    } else {
+                        return Err(format!("unknown argument `{}`", other));
                     }
                 }
-                Ok(res) => {
-                    eprintln!("[{}] request failed: {}", label, res.status());
+            }
+        }
+        opts.dry_run = opts.flags.dry_run().is_on();
+        opts.strict_unmapped = opts.flags.strict_unmapped().is_on();
+        if opts.diff && !opts.dry_run {
+            return Err("--diff only works with --dry-run".to_string());
+        }
+        if opts.once && !opts.watch {
+            return Err("--once only works with --watch".to_string());
+        }
+        if opts.alert_after.is_some() && !opts.watch {
+            return Err("--alert-after-secs only works with --watch".to_string());
+        }
+        let other_mode = opts.dry_run
+            || opts.watch
+            || opts.unbind
+            || opts.verify_only
+            || opts.config_check
+            || opts.self_test
+            || opts.verify_secret
+            || opts.rotate_secret
+            || opts.healthcheck
+            || opts.list
+            || opts.discover
+            || opts.failover.is_some()
+            || opts.export_config;
+        if opts.format == Format::Oneline && other_mode {
+            return Err("--format oneline only summarizes a rebind run".to_string());
+        }
+        if opts.format == Format::NdjsonEvent && other_mode {
+            return Err("--format ndjson-event only reports a rebind run".to_string());
+        }
+        if opts.canary.is_some() && ((other_mode && !opts.dry_run) || opts.retry_failed) {
+            return Err("--canary only works with a one-shot rebind or --dry-run".to_string());
+        }
+        if opts.failover.is_some() && (opts.force || opts.retry_failed) {
+            return Err("--failover doesn't combine with --force or --retry-failed".to_string());
+        }
+        if opts.strict_hooks && other_mode {
+            return Err("--strict-hooks only works with a one-shot rebind".to_string());
+        }
+        if opts.explain && (other_mode || opts.format != Format::Human) {
+            return Err("--explain only works with a one-shot rebind in --format human".to_string());
+        }
+        Ok(opts)
+    }
+}
+
+/// How long a bare `--wait-lock` waits for another rebind to finish.
+const DEFAULT_LOCK_WAIT_SECS: u64 = 60;
+
+/// The `--alert-after-secs` threshold.
+fn alert_after(secs: &str) -> Result<Duration, String> {
+    match secs.trim().parse::<u64>() {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("--alert-after-secs needs a positive number of seconds, not `{}`", secs)),
+    }
+}
+
+/// Exit codes. On failure the matching token is also printed alone as the
+/// last stderr line, so scripts can branch without parsing the log.
+mod exit {
+    pub struct Code(pub i32, pub &'static str);
+
+    /// Some bots failed while others were bound (or unbound), short of
+    /// what `REBIND_SUCCESS_POLICY` asks of a rebind run.
+    pub const E_PARTIAL: Code = Code(1, "E_PARTIAL");
+    /// Bad arguments, or `--bot`/`REBIND_ONLY`/`REBIND_EXCLUDE` select nothing.
+    pub const E_USAGE: Code = Code(2, "E_USAGE");
+    /// Unreadable bot table, tokens file, provider or metrics settings.
+    pub const E_CONFIG: Code = Code(3, "E_CONFIG");
+    /// `TG_SECRET` (or a bot's own secret) is invalid.
+    pub const E_NO_SECRET: Code = Code(4, "E_NO_SECRET");
+    /// A bot's token is missing (unless `REBIND_MISSING_TOKEN` says to
+    /// leave such bots out), or no bot has a usable one.
+    pub const E_NO_TOKEN: Code = Code(5, "E_NO_TOKEN");
+    /// No tunnel agent answered, or discovery was refused.
+    pub const E_NO_TUNNEL: Code = Code(6, "E_NO_TUNNEL");
+    /// Every bot that was attempted failed.
+    pub const E_ALL_BINDS_FAILED: Code = Code(7, "E_ALL_BINDS_FAILED");
+    /// `--config-check`, `--verify-only`, `--self-test` or `--verify-secret` found problems.
+    pub const E_CHECK_FAILED: Code = Code(8, "E_CHECK_FAILED");
+    /// `--watch --once --alert-after-secs` found a bot that has gone that
+    /// long without a success.
+    pub const E_OVERDUE: Code = Code(9, "E_OVERDUE");
+    /// `--healthcheck` got no tunnel data. 1, as Docker expects of an
+    /// unhealthy container.
+    pub const E_UNHEALTHY: Code = Code(1, "E_UNHEALTHY");
+    /// `--watch --once` rebound at least one bot, and none failed. Not a
+    /// failure; 0 means every binding was already right.
+    pub const CHANGED: Code = Code(10, "CHANGED");
+    /// Another rebind holds the state file's lock (and still did after
+    /// `--wait-lock`'s wait).
+    pub const E_ALREADY_RUNNING: Code = Code(11, "E_ALREADY_RUNNING");
+    /// `--strict-hooks`: every bind went as `REBIND_SUCCESS_POLICY` asks,
+    /// but a post-bind command failed.
+    pub const E_HOOK_FAILED: Code = Code(12, "E_HOOK_FAILED");
+    /// A second SIGINT/SIGTERM cut watch mode short.
+    pub const E_INTERRUPTED: Code = Code(130, "E_INTERRUPTED");
+}
+
+fn fail(code: exit::Code) -> ! {
+    eprintln!("{}", code.1);
+    std::process::exit(code.0)
+}
+
+/// `E_ALL_BINDS_FAILED` when nothing succeeded, `E_PARTIAL` otherwise.
+fn failed(succeeded: usize) -> exit::Code {
+    if succeeded == 0 {
+        exit::E_ALL_BINDS_FAILED
+    } else {
+        exit::E_PARTIAL
+    }
+}
+
+/// The exit code for a run or `--watch --once` poll whose `report` falls
+/// short of `policy`, if it does.
+fn shortfall(report: &RebindReport, policy: SuccessPolicy) -> Option<exit::Code> {
+    (!policy.met(report.succeeded(), report.failed())).then(|| failed(report.succeeded()))
+}
+
+/// `123 ms`, plus the watch-mode average when there is one.
+fn timing(bot: &str, duration: Duration, averages: &HashMap<String, Duration>) -> String {
+    match averages.get(bot) {
+        Some(avg) => format!("{} ms, avg {} ms", duration.as_millis(), avg.as_millis()),
+        None => format!("{} ms", duration.as_millis()),
+    }
+}
+
+const GREEN: &str = "32";
+const RED: &str = "31";
+const YELLOW: &str = "33";
+
+/// ANSI colors on a terminal, unless `NO_COLOR` is set.
+fn use_color() -> bool {
+    std::io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+}
+
+/// Set by `--quiet`.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Whether results that report nothing but success should go unprinted.
+fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Bots left out for want of a token under `REBIND_MISSING_TOKEN=warn`,
+/// for the summary to name.
+static MISSING_TOKENS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// The bot table's `[messages]`, once loaded.
+static MESSAGES: OnceLock<Messages> = OnceLock::new();
+
+/// The per-bot lines to log, the built-in ones until the table is loaded.
+fn messages() -> &'static Messages {
+    MESSAGES.get_or_init(Messages::default)
+}
+
+/// Prints `rows` under `headers` with every column but the last padded to
+/// its widest cell. Padding is measured on the plain text so color escapes
+/// don't throw off the alignment.
+fn print_table(headers: &[&str], rows: &[Vec<(String, Option<&str>)>], color: bool) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, (text, _)) in widths.iter_mut().zip(row) {
+            *width = (*width).max(text.chars().count());
+        }
+    }
+    let line = |cells: Vec<(&str, Option<&str>)>| {
+        let last = cells.len() - 1;
+        let mut out = String::new();
+        for (i, (text, code)) in cells.into_iter().enumerate() {
+            match code.filter(|_| color) {
+                Some(code) => out.push_str(&format!("\x1b[{}m{}\x1b[0m", code, text)),
+                None => out.push_str(text),
+            }
+            if i != last {
+                out.push_str(&" ".repeat(widths[i] - text.chars().count() + 2));
+            }
+        }
+        println!("{}", out.trim_end());
+    };
+    line(headers.iter().map(|h| (*h, None)).collect());
+    for row in rows {
+        line(row.iter().map(|(text, code)| (text.as_str(), *code)).collect());
+    }
+}
+
+/// One row per bot (bot, status, URL, latency, tunnel age); details that
+/// don't fit a cell, such as error messages, still go to the log.
+fn print_human(report: &RebindReport, averages: &HashMap<String, Duration>, policy: Option<SuccessPolicy>) {
+    let mut rows = Vec::new();
+    for BotOutcome { bot, outcome, duration } in &report.outcomes {
+        let took = timing(bot, *duration, averages);
+        let (status, code, url, took) = match outcome {
+            Outcome::Bound { webhook_url, verified } => {
+                info!("{}", Messages::render(&messages().success, bot, webhook_url, ""));
+                if let Err(problem) = verified {
+                    warn!("[⚠️] Verification failed for {}: {}", bot, problem);
+                }
+                ("bound", GREEN, webhook_url.as_str(), took)
+            }
+            Outcome::Unchanged { webhook_url } => ("unchanged", YELLOW, webhook_url.as_str(), took),
+            Outcome::Polling { removed } => {
+                if let Some(url) = removed {
+                    info!("[🔌] {}: deleted the webhook to {}; the bot polls now", bot, url);
                 }
+                ("polling", GREEN, "-", took)
+            }
+            Outcome::Failed(BindError::DeadlineExceeded) => ("deadline exceeded", RED, "-", took),
+            Outcome::Failed(err @ BindError::FloodWait(_)) => {
+                error!("{}", Messages::render(&messages().failure, bot, "", &err.to_string()));
+                ("flood wait", RED, "-", took)
+            }
+            Outcome::Failed(err) => {
+                error!("{}", Messages::render(&messages().failure, bot, "", &err.to_string()));
+                ("failed", RED, "-", took)
+            }
+            Outcome::Unhealthy(problem) => {
+                warn!("[🩺] {}: tunnel up but upstream unhealthy ({})", bot, problem);
+                ("unhealthy", YELLOW, "-", took)
+            }
+            Outcome::NoTunnel => {
+                info!("{}", Messages::render(&messages().skip, bot, "", ""));
+                ("no tunnel", YELLOW, "-", "-".to_string())
+            }
+        };
+        let age = report.tunnel_ages.get(bot).map_or_else(|| "-".to_string(), |age| state::format_age(*age));
+        rows.push(vec![
+            (bot.clone(), None),
+            (status.to_string(), Some(code)),
+            (url.to_string(), None),
+            (took, None),
+            (age, None),
+        ]);
+    }
+    if !rows.is_empty() {
+        print_table(&["BOT", "STATUS", "URL", "LATENCY", "TUNNEL AGE"], &rows, use_color());
+    }
+    info!("[📋] {}", summary(report, policy));
+    let late = report.outcomes.iter().filter(|o| matches!(o.outcome, Outcome::Failed(BindError::DeadlineExceeded))).count();
+    if late > 0 {
+        warn!("[⏳] Deadline reached; {} bots were cancelled before they finished", late);
+    }
+    let unauthorized = report.unauthorized();
+    if !unauthorized.is_empty() {
+        error!(
+            "[🔑] These bots have invalid tokens: {}; fix their credentials, not the network",
+            unauthorized.join(", ")
+        );
+    }
+}
+
+/// The setting called `name` when something other than its default set it.
+fn chosen<'a>(settings: &'a [Setting], name: &str) -> Option<&'a Setting> {
+    settings.iter().find(|s| s.name == name && s.source != Source::Default)
+}
+
+/// `TG_SECRET`, or whichever key of the bot table set it, with the secret.
+fn tg_secret(settings: &[Setting]) -> Option<(&str, &str)> {
+    let setting = chosen(settings, "tg_secret")?;
+    Some((setting.key.as_str(), setting.value.as_deref().filter(|s| !s.is_empty())?))
+}
+
+/// The request timeout `bot` gets and where it comes from: its own
+/// `timeout_secs`, the resolved `http_timeout_secs`, or the built-in default.
+fn effective_timeout(bot: &BotBinding, settings: &[Setting]) -> (Duration, String) {
+    if let Some(timeout) = bot.timeout {
+        return (timeout, "timeout_secs".to_string());
+    }
+    let source = chosen(settings, "http_timeout_secs").map_or_else(|| "default".to_string(), |s| s.key.clone());
+    (RetryPolicy::from_settings(settings).timeout, source)
+}
+
+/// Prints what a real run would send, without touching Telegram. Returns
+/// false when a polling bot, or one with a discovered tunnel, has no token.
+fn dry_run(bots: &[BotBinding], tokens: &dyn TokenProvider, urls: &HashMap<String, String>, settings: &[Setting]) -> bool {
+    let mut ok = true;
+    for bot in bots {
+        if bot.mode == BotMode::Poll {
+            match tokens.token(bot) {
+                Ok(_) => info!("[📝] Would make sure {} has no webhook, sending deleteWebhook if it has one", bot.name),
                 Err(err) => {
-                    eprintln!("[{}] \u{1f4a5} {}", label, err);
+                    error!("[❌] {}: {}", bot.name, err);
+                    ok = false;
+                }
+            }
+            continue;
+        }
+        let Some(url) = urls.get(&bot.name) else {
+            info!("[⚪] {}: no tunnel discovered for port {}", bot.name, bot.port);
+            continue;
+        };
+        if let Err(err) = tokens.token(bot) {
+            error!("[❌] {}: {}", bot.name, err);
+            ok = false;
+            continue;
+        }
+        let (timeout, timeout_source) = effective_timeout(bot, settings);
+        info!("[⏳] {}: requests time out after {}s ({})", bot.name, timeout.as_secs(), timeout_source);
+        match bot.platform {
+            Platform::Telegram => {
+                // The secret itself is never printed: the payload is built
+                // without it, and only says where it would come from.
+                let source = match own_secret(bot) {
+                    Some((source, _)) => Some(source),
+                    None => tg_secret(settings).map(|(key, _)| key.to_string()),
+                };
+                let mut payload = set_webhook_payload(bot, &webhook_url(bot, url), "");
+                if let Some(source) = source {
+                    payload["secret"] = match &bot.secret_query {
+                        None => json!(format!("<{}> as secret_token", source)),
+                        Some(param) => json!(format!("<{}> in the `{}` query parameter", source, param)),
+                    };
                 }
+                info!("[📝] Would POST setWebhook for {}: {}", bot.name, payload);
+            }
+            Platform::Discord => info!(
+                "[📝] Would PATCH application {} for {}: {}",
+                bot.application_id.as_deref().unwrap_or_default(),
+                bot.name,
+                json!({ "interactions_endpoint_url": webhook_url(bot, url) })
+            ),
+            Platform::Slack => info!(
+                "[📝] Would update the manifest of Slack app {} for {}: {}",
+                bot.application_id.as_deref().unwrap_or_default(),
+                bot.name,
+                json!({ "settings": { "event_subscriptions": { "request_url": webhook_url(bot, url) } } })
+            ),
+        }
+    }
+    ok
+}
+
+/// `--list`: every selected bot as the run would see it, after the bot
+/// table's profile, `REBIND_ONLY`/`REBIND_EXCLUDE` and `--bot` were applied,
+/// with where its token and secret come from, then the run-wide settings
+/// and the source each was taken from. Tokens show their last four
+/// characters at most, secrets nothing.
+fn list_bots(bots: &[BotBinding], tokens: &dyn TokenProvider, settings: &[Setting], format: Format) {
+    let tg_secret = tg_secret(settings);
+    let mut rows = Vec::new();
+    let mut entries = Vec::new();
+    for bot in bots {
+        let token_source = tokens.source(bot);
+        let token = match tokens.token(bot) {
+            Ok(token) if token.chars().count() >= 12 => {
+                format!("...{}", token.chars().skip(token.chars().count() - 4).collect::<String>())
+            }
+            Ok(_) => "set".to_string(),
+            Err(_) => "missing".to_string(),
+        };
+        let secret_source = match bot.platform {
+            Platform::Discord | Platform::Slack => None,
+            Platform::Telegram => match own_secret(bot) {
+                Some((source, _)) => Some(source),
+                None => tg_secret.map(|(key, _)| key.to_string()),
             },
-            Err(err) => eprintln!("Invalid URI {}: {}", api, err),
+        };
+        let mut options = Vec::new();
+        if bot.mode == BotMode::Poll {
+            options.push("mode=poll".to_string());
+        }
+        if bot.priority != DEFAULT_PRIORITY {
+            options.push(format!("priority={}", bot.priority));
+        }
+        if let Some(url) = &bot.stable_url {
+            options.push(format!("stable_url={}", url));
+        }
+        if let Some(id) = &bot.application_id {
+            options.push(format!("application_id={}", id));
+        }
+        match bot.drop_pending_updates {
+            DropPending::Never => {}
+            DropPending::Always => options.push("drop_pending_updates".to_string()),
+            DropPending::FirstOnly => options.push("drop_pending_updates=first-only".to_string()),
+        }
+        if let Some(kinds) = &bot.allowed_updates {
+            options.push(format!("allowed_updates={}", kinds.join("|")));
+        }
+        if let Some(n) = bot.max_connections {
+            options.push(format!("max_connections={}", n));
+        }
+        if let Some(ip) = &bot.ip_address {
+            options.push(format!("ip_address={}", ip));
+        }
+        if let Some(path) = &bot.health_path {
+            options.push(format!("health_path={}", path));
+        }
+        if let Some(param) = &bot.secret_query {
+            options.push(format!("secret_param={}", param));
+        }
+        let platform = match bot.platform {
+            Platform::Telegram => "telegram",
+            Platform::Discord => "discord",
+            Platform::Slack => "slack",
+        };
+        let (timeout, timeout_source) = effective_timeout(bot, settings);
+        rows.push(vec![
+            (bot.name.clone(), None),
+            (platform.to_string(), None),
+            (bot.port.to_string(), None),
+            (bot.webhook_path.clone(), None),
+            (format!("{} ({})", token_source, token), (token == "missing").then_some(RED)),
+            (secret_source.clone().unwrap_or_else(|| "-".to_string()), None),
+            (format!("{}s ({})", timeout.as_secs(), timeout_source), None),
+            (if options.is_empty() { "-".to_string() } else { options.join(", ") }, None),
+        ]);
+        entries.push(json!({
+            "bot": bot.name,
+            "platform": platform,
+            "port": bot.port,
+            "webhook_path": bot.webhook_path,
+            "token_source": token_source,
+            "token": token,
+            "secret_source": secret_source,
+            "timeout_secs": timeout.as_secs(),
+            "timeout_source": timeout_source,
+            "options": options,
+        }));
+    }
+    match format {
+        Format::Human | Format::Oneline | Format::NdjsonEvent => {
+            print_table(&["BOT", "PLATFORM", "PORT", "WEBHOOK PATH", "TOKEN", "SECRET", "TIMEOUT", "OPTIONS"], &rows, use_color());
+            let rows: Vec<_> = settings
+                .iter()
+                .map(|s| vec![(s.name.to_string(), None), (s.shown().to_string(), None), (s.origin(), None)])
+                .collect();
+            println!();
+            print_table(&["SETTING", "VALUE", "SOURCE"], &rows, use_color());
+        }
+        Format::Json => {
+            let settings: Vec<serde_json::Value> = settings
+                .iter()
+                .map(|s| json!({ "setting": s.name, "value": s.value.as_ref().map(|_| s.shown()), "source": s.source.as_str(), "key": s.key }))
+                .collect();
+            println!("{}", json!({ "bots": entries, "settings": settings }))
+        }
+    }
+}
+
+/// `--discover`: asks the tunnel agents once and prints each bot's public
+/// URL, with `rewrite` rules applied, and the tunnels on ports no bot uses.
+/// A bot's `stable_url` is not shown; this is about what the agents see.
+/// False when no agent could be reached.
+async fn discover_only(provider: &dyn TunnelProvider, bots: &[BotBinding], rewrites: &[UrlRewrite], format: Format) -> bool {
+    let mut urls = match provider.public_urls().await {
+        Ok(urls) => urls,
+        Err(err) => {
+            error!("[❌] {}", err);
+            return false;
+        }
+    };
+    rewrite_urls(rewrites, &mut urls);
+    let unmapped = provider.unmapped();
+    match format {
+        Format::Human | Format::Oneline | Format::NdjsonEvent => {
+            let mut rows = Vec::new();
+            for bot in bots {
+                let url = match urls.get(&bot.name) {
+                    Some(url) => (url.clone(), None),
+                    None => ("no tunnel".to_string(), Some(YELLOW)),
+                };
+                rows.push(vec![(bot.name.clone(), None), (bot.port.to_string(), None), url]);
+            }
+            for (port, url) in &unmapped {
+                rows.push(vec![("(unmapped)".to_string(), Some(YELLOW)), (port.to_string(), None), (url.clone(), None)]);
+            }
+            print_table(&["BOT", "PORT", "URL"], &rows, use_color());
+        }
+        Format::Json => {
+            let mapped: serde_json::Map<String, serde_json::Value> =
+                bots.iter().filter_map(|b| Some((b.name.clone(), json!(urls.get(&b.name)?)))).collect();
+            let unmapped: Vec<_> = unmapped.iter().map(|(port, url)| json!({ "port": port, "url": url })).collect();
+            let agents: Vec<_> =
+                provider.agents().iter().map(|a| json!({ "agent": a.agent, "status": a.state.as_str() })).collect();
+            println!("{}", json!({ "provider": provider.used(), "urls": mapped, "unmapped": unmapped, "agents": agents }));
         }
     }
-    urls
+    true
 }
 
-async fn bind_webhook(
-    client: &Client<HttpsConnector<HttpConnector>>,
-    bot_name: &str,
-    url: &str,
-    tg_secret: &str,
+/// `--dry-run --diff`: each bot's live webhook next to its discovered
+/// tunnel and the webhook a real run would set, read through
+/// `getWebhookInfo` without changing anything. With `force` nothing counts
+/// as unchanged, as in a real `--force` run.
+async fn print_diff(
+    targets: Targets<'_>,
+    bots: &[BotBinding],
+    urls: &HashMap<String, String>,
+    force: bool,
+    format: Format,
 ) {
-    let token_var = format!("BOT_TOKEN_{}", bot_name.to_ascii_uppercase());
-    let token = match env::var(&token_var) {
-        Ok(t) if !t.is_empty() => t,
-        _ => {
-            eprintln!("[❌] No token for {}", bot_name);
-            return;
+    let live = audit(targets, bots).await;
+    let mut rows = Vec::new();
+    let mut entries = Vec::new();
+    for (bot, (_, live)) in bots.iter().zip(live) {
+        let tunnel = urls.get(&bot.name);
+        let would_set = tunnel.map(|url| webhook_url(bot, url));
+        let (change, code) = match (&would_set, &live) {
+            (None, _) => ("would be left as-is", None),
+            (Some(expected), Ok(info)) if !force && already_bound(info, bot, expected) => ("no change", Some(GREEN)),
+            (Some(_), Ok(_)) => ("would rebind", Some(YELLOW)),
+            (Some(_), Err(_)) => ("would rebind (live webhook unknown)", Some(RED)),
+        };
+        if let Err(err) = &live {
+            warn!("[⚠️] {}: cannot read the live webhook: {}", bot.name, err);
+        }
+        let live_url = live.as_ref().ok().map(|info| info.url.clone()).filter(|url| !url.is_empty());
+        let cell = |value: Option<&String>| (value.cloned().unwrap_or_else(|| "-".to_string()), None);
+        rows.push(vec![
+            (bot.name.clone(), None),
+            cell(live_url.as_ref()),
+            cell(tunnel),
+            cell(would_set.as_ref()),
+            (change.to_string(), code),
+        ]);
+        let mut entry = json!({
+            "bot": bot.name,
+            "live": live_url,
+            "tunnel": tunnel,
+            "would_set": would_set,
+            "change": change,
+        });
+        if let Err(err) = &live {
+            entry["error"] = json!(err.to_string());
+        }
+        entries.push(entry);
+    }
+    match format {
+        Format::Human | Format::Oneline | Format::NdjsonEvent => print_table(&["BOT", "LIVE", "TUNNEL", "WOULD SET", "CHANGE"], &rows, use_color()),
+        Format::Json => println!("{}", json!({ "diff": entries })),
+    }
+}
+
+/// Telegram bots without a secret of their own, which fall back to `TG_SECRET`.
+fn need_tg_secret(bots: &[BotBinding]) -> bool {
+    bots.iter().any(|bot| bot.platform == Platform::Telegram && own_secret(bot).is_none())
+}
+
+/// Every Telegram bot's resolved secret that Telegram would reject, each
+/// checked on its own so one bad rotation doesn't hide another. Having no
+/// secret at all is fine.
+fn secret_problems(bots: &[BotBinding], settings: &[Setting]) -> Vec<String> {
+    let mut problems: Vec<String> = bots
+        .iter()
+        .filter(|bot| bot.platform == Platform::Telegram)
+        .filter_map(own_secret)
+        .filter_map(|(source, secret)| validate_secret(&source, &secret).err())
+        .collect();
+    if let Some((key, secret)) = tg_secret(settings).filter(|_| need_tg_secret(bots)) {
+        if let Err(err) = validate_secret(key, secret) {
+            problems.push(err);
+        }
+    }
+    problems
+}
+
+/// Telegram bots that will be bound without any secret.
+fn unsecured<'a>(bots: &'a [BotBinding], settings: &[Setting]) -> Vec<&'a str> {
+    if tg_secret(settings).is_some() {
+        return Vec::new();
+    }
+    bots.iter()
+        .filter(|bot| bot.platform == Platform::Telegram && own_secret(bot).is_none())
+        .map(|bot| bot.name.as_str())
+        .collect()
+}
+
+/// Every bot's token that is unset or empty, in table order.
+fn missing_env(bots: &[BotBinding], tokens: &dyn TokenProvider) -> Vec<String> {
+    let mut missing = Vec::new();
+    for bot in bots {
+        if let Err(BindError::MissingToken(var)) = tokens.token(bot) {
+            if !missing.contains(&var) {
+                missing.push(var);
+            }
+        }
+    }
+    missing
+}
+
+/// Checks every Telegram bot's token before anything is sent, so a
+/// truncated or transposed token names its bot here instead of coming back
+/// as a 401. Tokens of an unusual length are only warned about; malformed
+/// ones fail the run, or with `REBIND_MALFORMED_TOKENS=skip` leave their bot
+/// out (`warn` sends them anyway). Returns the bots to go on with.
+fn check_token_shapes(bots: Vec<BotBinding>, tokens: &dyn TokenProvider) -> Vec<BotBinding> {
+    let policy = MalformedTokens::from_env();
+    let mut malformed = Vec::new();
+    let mut kept = Vec::new();
+    for bot in bots {
+        let Ok(token) = tokens.token(&bot) else {
+            kept.push(bot);
+            continue;
+        };
+        match token_shape(&bot, &token) {
+            TokenShape::Ok => {}
+            TokenShape::Unusual(reason) => {
+                warn!("[⚠️] {}: the token from {} {}; sending it anyway", bot.name, tokens.source(&bot), reason)
+            }
+            TokenShape::Malformed(reason) => {
+                let problem = format!("{}: the token from {} {}", bot.name, tokens.source(&bot), reason);
+                match policy {
+                    MalformedTokens::Fail => {
+                        error!("[❌] {}", problem);
+                        malformed.push(bot.name.clone());
+                    }
+                    MalformedTokens::Skip => {
+                        warn!("[⚠️] {}; leaving {} out", problem, bot.name);
+                        continue;
+                    }
+                    MalformedTokens::Warn => warn!("[⚠️] {}; sending it anyway", problem),
+                }
+            }
+        }
+        kept.push(bot);
+    }
+    if !malformed.is_empty() {
+        error!(
+            "[❌] Not a bot token for {}; set REBIND_MALFORMED_TOKENS=skip to leave such bots out",
+            malformed.join(", ")
+        );
+        fail(exit::E_NO_TOKEN);
+    }
+    if kept.is_empty() {
+        error!("[❌] No bot has a usable token");
+        fail(exit::E_NO_TOKEN);
+    }
+    kept
+}
+
+fn describe_missing(missing: &[String]) -> String {
+    match missing {
+        [one] => format!("{} is", one),
+        many => format!("{} are", many.join(", ")),
+    }
+}
+
+/// Why `path` couldn't be written: an existing file must open for
+/// appending, otherwise the nearest existing ancestor must be a writable
+/// directory.
+fn unwritable(path: &Path) -> Option<String> {
+    if path.exists() {
+        return fs::OpenOptions::new().append(true).open(path).err().map(|e| e.to_string());
+    }
+    let dir = path
+        .ancestors()
+        .skip(1)
+        .map(|p| if p.as_os_str().is_empty() { Path::new(".") } else { p })
+        .find(|p| p.exists())?;
+    match fs::metadata(dir) {
+        Ok(meta) if !meta.is_dir() => Some(format!("{} is not a directory", dir.display())),
+        Ok(meta) if meta.permissions().readonly() => Some(format!("{} is read-only", dir.display())),
+        Ok(_) => None,
+        Err(err) => Some(err.to_string()),
+    }
+}
+
+/// `--config-check`: everything a run would trip over before its first
+/// network request, without making one.
+fn config_check(flags: &Flags, only: Option<&str>) -> Vec<String> {
+    let mut problems = Vec::new();
+    let (mut bots, agents, headers, settings) = resolve_config(flags)
+        .map(|resolved| (filter_bots(resolved.table.bots), resolved.table.agents, resolved.table.tunnel_headers, resolved.settings))
+        .unwrap_or_else(|err| {
+            problems.push(err.to_string());
+            (Vec::new(), Vec::new(), Vec::new(), Vec::new())
+        });
+    if let Some(name) = only {
+        if !bots.is_empty() && !bots.iter().any(|b| b.name == name) {
+            problems.push(format!("no bot named `{}` in the bot table", name));
+        }
+        bots.retain(|b| b.name == name);
+    }
+    match token_provider() {
+        Ok(tokens) => {
+            for bot in &bots {
+                match tokens.token(bot).map(|token| token_shape(bot, &token)) {
+                    Err(err) => problems.push(format!("{}: {}", bot.name, err)),
+                    Ok(TokenShape::Malformed(reason)) => {
+                        problems.push(format!("{}: the token from {} {}", bot.name, tokens.source(bot), reason))
+                    }
+                    Ok(_) => {}
+                }
+            }
+        }
+        Err(err) => problems.push(err),
+    }
+    problems.extend(secret_problems(&bots, &settings));
+    if let Err(err) = load_certificate() {
+        problems.push(err.to_string());
+    }
+    match build_client() {
+        Ok(client) => {
+            if let Err(err) = tunnel_provider(&client, &bots, &agents, &headers, false) {
+                problems.push(err);
+            }
+        }
+        Err(err) => problems.push(err),
+    }
+    if let Some(Err(err)) = metrics::metrics_addr() {
+        problems.push(err);
+    }
+    if let Err(err) = selftest::receiver_ip() {
+        problems.push(err);
+    }
+    let files = [
+        ("state file", state::state_path()),
+        ("ledger", Ledger::from_settings(&settings, "").map(|l| l.path)),
+        ("pulse file", PulseSink::from_env().map(|p| p.path)),
+    ];
+    for (label, path) in files.iter().filter_map(|(label, path)| Some((label, path.as_deref()?))) {
+        if let Some(problem) = unwritable(path) {
+            problems.push(format!("{} {} is not writable: {}", label, path.display(), problem));
+        }
+    }
+    problems
+}
+
+/// Loads the dotenv files listed in `REBIND_ENV_FILE` (colon-separated),
+/// later files overriding earlier ones and the real environment overriding
+/// both. A listed file that is missing or malformed is an error; without
+/// the variable `./.env` is loaded if it exists.
+fn load_env_files() -> Result<(), String> {
+    let Some(list) = env::var("REBIND_ENV_FILE").ok().filter(|l| !l.trim().is_empty()) else {
+        dotenv::dotenv().ok();
+        return Ok(());
+    };
+    // dotenv never replaces a variable that is already set, so loading the
+    // last file first lets it win.
+    for path in list.split(':').map(str::trim).filter(|p| !p.is_empty()).rev() {
+        dotenv::from_path(path).map_err(|err| match err {
+            dotenv::Error::Io(err) => format!("cannot read env file {} from REBIND_ENV_FILE: {}", path, err),
+            err => format!("env file {} from REBIND_ENV_FILE is invalid: {}", path, err),
+        })?;
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let env_files = load_env_files();
+    logger::init();
+    if let Err(err) = env_files {
+        error!("[❌] {}", err);
+        fail(exit::E_CONFIG);
+    }
+    let opts = match Options::parse(env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(err) => {
+            error!("[❌] {}", err);
+            fail(exit::E_USAGE);
+        }
+    };
+    if opts.quiet {
+        QUIET.store(true, Ordering::Relaxed);
+    }
+    if opts.quiet || opts.healthcheck {
+        logger::errors_only();
+    }
+    if let Some(path) = &opts.trace_file {
+        if let Err(err) = trace::install(Path::new(path)) {
+            error!("[❌] Cannot open the trace file {}: {}", path, err);
+            fail(exit::E_CONFIG);
+        }
+        info!("[📝] Tracing every HTTP exchange to {}", path);
+    }
+    if opts.config_check {
+        let problems = config_check(&opts.flags, opts.bot.as_deref());
+        match opts.format {
+            Format::Human | Format::Oneline | Format::NdjsonEvent if problems.is_empty() => info!("[✅] Configuration looks good"),
+            Format::Human | Format::Oneline | Format::NdjsonEvent => {
+                for problem in &problems {
+                    error!("[❌] {}", problem);
+                }
+            }
+            Format::Json if quiet() && problems.is_empty() => {}
+            Format::Json => println!("{}", json!({ "ok": problems.is_empty(), "problems": problems })),
+        }
+        if !problems.is_empty() {
+            fail(exit::E_CHECK_FAILED);
+        }
+        return;
+    }
+    let resolved = match resolve_config(&opts.flags) {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            error!("[❌] {}", err);
+            fail(exit::E_CONFIG);
         }
     };
+    let profile = resolved.value("profile").map(str::to_string);
+    let api_base = resolved.value("api_base").unwrap_or(DEFAULT_API_BASE).to_string();
+    let ResolvedConfig { table, settings } = resolved;
+    let BotTable { bots, rewrites, agents, tunnel_headers, messages, .. } = table;
+    let _ = MESSAGES.set(messages);
+    if opts.healthcheck {
+        if !healthcheck(&bots, &tunnel_headers, &settings, opts.format).await {
+            fail(exit::E_UNHEALTHY);
+        }
+        return;
+    }
+    if let Some(profile) = &profile {
+        info!("[📌] Using profile `{}`", profile);
+    }
+    if let Some(name) = &opts.bot {
+        if !bots.iter().any(|b| &b.name == name) {
+            let known: Vec<&str> = bots.iter().map(|b| b.name.as_str()).collect();
+            error!("[❌] no bot named `{}` in the bot table (known: {})", name, known.join(", "));
+            fail(exit::E_USAGE);
+        }
+    }
+    // Discovery sees the whole table so other bots' tunnels aren't reported
+    // as unmapped when `--bot` or the filters narrow the run.
+    let table = bots.clone();
+    let mut bots = filter_bots(bots);
+    if bots.is_empty() {
+        error!("[❌] REBIND_ONLY/REBIND_EXCLUDE leave no bots to work on");
+        fail(exit::E_USAGE);
+    }
+    if let Some(name) = &opts.bot {
+        if !bots.iter().any(|b| &b.name == name) {
+            error!("[❌] `{}` is left out by REBIND_ONLY/REBIND_EXCLUDE", name);
+            fail(exit::E_USAGE);
+        }
+        bots.retain(|b| &b.name == name);
+    }
+    if let Some(canary) = opts.canary {
+        let total = bots.len();
+        bots = canary.select(bots);
+        let names: Vec<&str> = bots.iter().map(|b| b.name.as_str()).collect();
+        if bots.len() < total {
+            info!("[🐤] Canary: {} of {} bots ({}); run without --canary to bind the rest", bots.len(), total, names.join(", "));
+        } else {
+            info!("[🐤] Canary covers all {} bots", total);
+        }
+    }
 
-    let webhook_url = format!("{}/webhook", url);
-    let endpoint = format!("https://api.telegram.org/bot{}/setWebhook", token);
-    let payload = serde_json::json!({
-        "url": webhook_url,
-        "secret_token": tg_secret,
+    if opts.discover {
+        let provider = match build_client()
+            .and_then(|client| tunnel_provider(&client, &table, &agents, &tunnel_headers, opts.strict_unmapped))
+        {
+            Ok(provider) => provider,
+            Err(err) => {
+                error!("[❌] {}", err);
+                fail(exit::E_CONFIG);
+            }
+        };
+        if !discover_only(provider.as_ref(), &bots, &rewrites, opts.format).await {
+            fail(exit::E_NO_TUNNEL);
+        }
+        return;
+    }
+
+    let tokens = match token_provider() {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            error!("[❌] {}", err);
+            fail(exit::E_CONFIG);
+        }
+    };
+    if let Some(path) = secrets::secrets_path() {
+        if let Err(err) = secrets::load(&path) {
+            error!("[❌] {}", err);
+            fail(exit::E_CONFIG);
+        }
+    } else if opts.rotate_secret {
+        error!("[❌] --rotate-secret needs REBIND_SECRETS_FILE to store the new secrets in");
+        fail(exit::E_USAGE);
+    }
+    if opts.list {
+        list_bots(&bots, tokens.as_ref(), &settings, opts.format);
+        return;
+    }
+    let missing = missing_env(&bots, tokens.as_ref());
+    let bots = match MissingTokens::from_env() {
+        _ if missing.is_empty() => bots,
+        MissingTokens::Error => {
+            error!("[❌] {} not set; see docs/ENVIRONMENT.md for setup", describe_missing(&missing));
+            fail(exit::E_NO_TOKEN);
+        }
+        policy => {
+            let (kept, left_out): (Vec<BotBinding>, Vec<BotBinding>) =
+                bots.into_iter().partition(|bot| !matches!(tokens.token(bot), Err(BindError::MissingToken(_))));
+            let names: Vec<String> = left_out.into_iter().map(|bot| bot.name).collect();
+            if policy == MissingTokens::Warn {
+                warn!("[⚠️] {} not set; leaving {} out", describe_missing(&missing), names.join(", "));
+                let _ = MISSING_TOKENS.set(names);
+            } else {
+                info!("[⚪] No token for {}; leaving it out", names.join(", "));
+            }
+            kept
+        }
+    };
+    let bots = check_token_shapes(bots, tokens.as_ref());
+
+    let client = match build_client() {
+        Ok(client) => client,
+        Err(err) => {
+            error!("[❌] {}", err);
+            fail(exit::E_CONFIG);
+        }
+    };
+    let run_id = new_uuid();
+    let ledger = Ledger::from_settings(&settings, &run_id);
+    let discord_api_base = value_of(&settings, "discord_api_base").unwrap_or_default().to_string();
+    let slack_api_base = value_of(&settings, "slack_api_base").unwrap_or_default().to_string();
+    let tg_secret = tg_secret(&settings).map(|(_, secret)| secret.to_string()).unwrap_or_default();
+    let retry = RetryPolicy::from_settings(&settings);
+    // The modes that stop before the RebindConfig is built reach the bots
+    // through these, the same targets as RebindConfig::targets.
+    let targets = || Targets {
+        client: &client,
+        retry,
+        telegram_api_base: &api_base,
+        discord_api_base: &discord_api_base,
+        slack_api_base: &slack_api_base,
+        secret: &tg_secret,
+        tokens: tokens.as_ref(),
+    };
+    // Everything past here but a dry run or --verify-only binds or unbinds.
+    // The lock is held until the process exits.
+    let _lock = match state::state_path() {
+        Some(path) if !opts.dry_run && !opts.verify_only && !opts.export_config => match state::lock(&path, opts.flags.wait_lock).await {
+            Ok(lock) => Some(lock),
+            Err(err @ state::LockError::Held(_)) => {
+                error!("[❌] {}", err);
+                fail(exit::E_ALREADY_RUNNING);
+            }
+            Err(err) => {
+                error!("[❌] {}", err);
+                fail(exit::E_CONFIG);
+            }
+        },
+        _ => None,
+    };
+    if opts.unbind || opts.verify_only || opts.rotate_secret || opts.export_config {
+        let targets = targets();
+        if opts.export_config {
+            let live = audit(targets, &bots).await;
+            for (bot, result) in &live {
+                if let Err(err) = result {
+                    warn!("[⚠️] {}: cannot read the live webhook: {}", bot, err);
+                }
+            }
+            print!("{}", export::export_table(&bots, &live));
+        } else if opts.rotate_secret {
+            let telegram: Vec<BotBinding> = bots.into_iter().filter(|b| b.platform == Platform::Telegram).collect();
+            if telegram.is_empty() {
+                error!("[❌] --rotate-secret found no Telegram bots; Discord signs its own requests");
+                fail(exit::E_USAGE);
+            }
+            match rotate_all(targets, &telegram, opts.format).await {
+                Err(()) => fail(exit::E_CONFIG),
+                Ok(rotated) if rotated < telegram.len() => fail(failed(rotated)),
+                Ok(_) => {}
+            }
+        } else if opts.unbind {
+            let unbound = unbind_all(targets, &bots, ledger.as_ref(), opts.format).await;
+            if unbound < bots.len() {
+                fail(failed(unbound));
+            }
+        } else {
+            let tunnels = match tunnel_provider(&client, &table, &agents, &tunnel_headers, false) {
+                Ok(provider) => match provider.public_urls().await {
+                    Ok(mut urls) => {
+                        rewrite_urls(&rewrites, &mut urls);
+                        add_stable_urls(&bots, &mut urls);
+                        Some(urls.into_values().collect())
+                    }
+                    Err(err) => {
+                        warn!("[⚠️] Skipping the orphaned-webhook check: {}", err);
+                        None
+                    }
+                },
+                Err(err) => {
+                    warn!("[⚠️] Skipping the orphaned-webhook check: {}", err);
+                    None
+                }
+            };
+            if !verify_only(targets, &bots, tunnels, &run_id, opts.format).await {
+                fail(exit::E_CHECK_FAILED);
+            }
+        }
+        return;
+    }
+
+    let problems = secret_problems(&bots, &settings);
+    for problem in &problems {
+        error!("[❌] {}", problem);
+    }
+    if !problems.is_empty() {
+        fail(exit::E_NO_SECRET);
+    }
+    let unsecured = unsecured(&bots, &settings);
+    if !unsecured.is_empty() {
+        warn!(
+            "[⚠️] No TG_SECRET or secret of their own for {}; Telegram will deliver their updates without one",
+            unsecured.join(", ")
+        );
+    }
+    let provider = match &opts.failover {
+        Some(label) => agent_provider(&client, &table, &agents, &tunnel_headers, label),
+        None => tunnel_provider(&client, &table, &agents, &tunnel_headers, opts.strict_unmapped),
+    };
+    let provider = match provider {
+        Ok(provider) => provider,
+        Err(err) => {
+            error!("[❌] {}", err);
+            fail(exit::E_CONFIG);
+        }
+    };
+
+    if opts.dry_run {
+        info!("[🔄] Dry run: discovering {} tunnels without touching Telegram...", provider.name());
+    } else if opts.format == Format::Human && opts.retry_failed {
+        info!("[🔄] Retrying the bots that failed last time...");
+    } else if let (Format::Human, Some(label)) = (opts.format, &opts.failover) {
+        info!("[🔀] Failing over to the tunnels of ngrok agent `{}`, one bot at a time...", label);
+    } else if opts.format == Format::Human && !opts.self_test && !opts.verify_secret {
+        info!("[🔄] Rebinding all Telegram webhooks via {}...", provider.name());
+    }
+    let delay = startup_delay(&settings);
+    debug!("Waiting {} ms before discovery", delay.as_millis());
+    sleep(delay).await;
+
+    if opts.dry_run {
+        let mut urls = match provider.public_urls().await {
+            Ok(mut urls) => {
+                rewrite_urls(&rewrites, &mut urls);
+                urls
+            }
+            Err(err) if bots.iter().any(|b| b.stable_url.is_some()) => {
+                warn!("[⚠️] {}; showing only the bots with a stable_url", err);
+                HashMap::new()
+            }
+            Err(err) => {
+                error!("[❌] {}", err);
+                fail(exit::E_NO_TUNNEL);
+            }
+        };
+        add_stable_urls(&bots, &mut urls);
+        let tokens_ok = dry_run(&bots, tokens.as_ref(), &urls, &settings);
+        if opts.diff {
+            print_diff(targets(), &bots, &urls, opts.force, opts.format).await;
+        }
+        if !tokens_ok {
+            fail(exit::E_NO_TOKEN);
+        }
+        return;
+    }
+
+    let (events, progress) = log_progress(&client);
+    let notify = Notifier::from_settings(&settings, &client);
+    let post_bind = match PostBindHook::from_settings(&settings) {
+        Ok(hook) => hook,
+        Err(err) => {
+            error!("[❌] {}", err);
+            fail(exit::E_CONFIG);
+        }
+    };
+    let mut config = RebindConfig {
+        client,
+        run_id,
+        api_base,
+        discord_api_base,
+        slack_api_base,
+        provider,
+        tokens,
+        secret: tg_secret,
+        bots,
+        rewrites,
+        retry,
+        concurrency: parsed(&settings, "concurrency").unwrap_or(8),
+        force: opts.force,
+        state_file: state::state_path(),
+        track_secrets: is_on(&settings, "track_secrets"),
+        healthcheck: HealthCheck::from_settings(&settings),
+        ledger,
+        pulses: PulseSink::from_env(),
+        notify,
+        post_bind,
+        events: Some(events),
+        // A watch keeps running; the deadline only bounds a one-shot run.
+        deadline: if opts.watch { None } else { deadline(&settings) },
+        cancel: None,
+        alert_after: opts.alert_after,
+        recent_bind: Some(Duration::from_secs(parsed(&settings, "recent_bind_secs").unwrap_or(0))).filter(|window| !window.is_zero()),
+    };
+    if config.state_file.is_none() && config.bots.iter().any(|bot| bot.drop_pending_updates == DropPending::FirstOnly) {
+        warn!("[⚠️] REBIND_DROP_PENDING=first-only can't tell a first bind without a state file; every bind will drop pending updates");
+    }
+    if opts.self_test || opts.verify_secret {
+        let receiver = match selftest::receiver_ip() {
+            Ok(ip) => ip,
+            Err(err) => {
+                error!("[❌] {}", err);
+                fail(exit::E_CONFIG);
+            }
+        };
+        let passed = if opts.self_test {
+            self_test_all(&config, receiver, opts.format).await
+        } else {
+            verify_secret_all(&config, receiver, opts.format).await
+        };
+        if !passed {
+            fail(exit::E_CHECK_FAILED);
+        }
+        return;
+    }
+    if opts.watch {
+        let metrics = match metrics::metrics_addr() {
+            None => None,
+            Some(_) if opts.once => None,
+            Some(Err(err)) => {
+                error!("[❌] {}", err);
+                fail(exit::E_CONFIG);
+            }
+            Some(Ok(addr)) => {
+                let metrics = Arc::new(Metrics::default());
+                if let Err(err) = metrics::serve(addr, metrics.clone()) {
+                    error!("[❌] cannot serve metrics on {}: {}", addr, err);
+                    fail(exit::E_CONFIG);
+                }
+                info!("[📈] Serving metrics on http://{}/metrics", addr);
+                Some(metrics)
+            }
+        };
+        watch(config, opts.format, metrics, opts.once, &settings).await;
+        return;
+    }
+
+    if let Some(label) = &opts.failover {
+        let code = failover(&mut config, label, opts.format).await;
+        drop(config.events.take());
+        let _ = tokio::time::timeout(Duration::from_secs(15), progress).await;
+        if let Some(code) = code {
+            fail(code);
+        }
+        return;
+    }
+
+    let previous = config.state_file.as_deref().map(state::load).unwrap_or_default();
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+    let result = if opts.retry_failed {
+        if config.state_file.is_none() {
+            error!("[❌] --retry-failed reads the failures from the state file, but REBIND_STATE_FILE is empty");
+            fail(exit::E_USAGE);
+        }
+        match retry_failed(&config).await {
+            Some(report) => Ok(report),
+            None => {
+                info!("[✅] No failed bots recorded by the last run; nothing to retry");
+                if opts.format == Format::NdjsonEvent {
+                    let mut event = run_event(Ok(&RebindReport::default()));
+                    event["ok"] = json!(true);
+                    println!("{}", event);
+                }
+                return;
+            }
+        }
+    } else {
+        rebind(&config).await
+    };
+    // The run is over; let the progress task finish (and export the run's
+    // trace) before anything below can exit the process.
+    drop(config.events.take());
+    let _ = tokio::time::timeout(Duration::from_secs(15), progress).await;
+    let report = match result {
+        Ok(report) => report,
+        Err(err) => {
+            error!("[❌] {}", err);
+            if opts.format == Format::NdjsonEvent {
+                println!("{}", run_event(Err(&err.to_string())));
+            }
+            fail(exit::E_NO_TUNNEL);
+        }
+    };
+    let expected = Expected::from_report(&report, webhook_urls(&config.bots, &previous.bindings), started);
+    // Bots left without a tunnel are checked too, for webhooks still
+    // pointing at a tunnel that is gone.
+    let checked = |bot: &BotBinding| {
+        report.outcomes.iter().any(|o| o.bot == bot.name && !matches!(o.outcome, Outcome::Failed(_)))
+    };
+    let verified: Vec<BotBinding> = config.bots.iter().filter(|b| checked(b)).cloned().collect();
+    let verification =
+        before_deadline(config.deadline, verify_all(config.targets(), &verified, &expected, pending_warn())).await;
+    match &verification {
+        Some(verification) => alert_backlog(config.pulses.as_ref(), &config.run_id, verification),
+        None => warn!("[⏳] Deadline reached before the webhooks could be verified"),
+    }
+    let policy = SuccessPolicy::from_settings(&settings);
+    print_report(&report, opts.format, &HashMap::new(), verification.as_ref(), Some(policy));
+    if opts.explain {
+        print!("{}", explain(&config, &settings, &previous, &report, verification.as_ref()));
+    }
+    if let Some(code) = shortfall(&report, policy) {
+        fail(code);
+    }
+    if opts.strict_hooks && !report.hook_failures.is_empty() {
+        let bots: Vec<&str> = report.hook_failures.iter().map(|(bot, _)| bot.as_str()).collect();
+        error!("[❌] Post-bind command failed for {}", bots.join(", "));
+        fail(exit::E_HOOK_FAILED);
+    }
+}
+
+/// Logs each step of a run at debug level as it happens; the summary and
+/// table come from the finished report. With the `otel` feature and
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` set the steps are also exported as a trace.
+/// The task ends once every sender is dropped.
+fn log_progress(client: &HttpsClient) -> (events::EventSender, tokio::task::JoinHandle<()>) {
+    let (sender, mut progress) = events::channel();
+    #[cfg(feature = "otel")]
+    let mut tracer = rebind::otel::Tracer::from_env(client);
+    #[cfg(not(feature = "otel"))]
+    let _ = client;
+    let task = tokio::spawn(async move {
+        while let Some(event) = progress.recv().await {
+            #[cfg(feature = "otel")]
+            if let Some(tracer) = &mut tracer {
+                tracer.record(&event).await;
+            }
+            match event {
+                RebindEvent::DiscoveryStarted => debug!("[🔍] Discovering tunnels"),
+                RebindEvent::TunnelFound { name, url } => debug!("[🔍] {}: tunnel {}", name, url),
+                RebindEvent::DiscoveryFinished { error: Some(err), .. } => debug!("[🔍] Discovery failed: {}", err),
+                RebindEvent::DiscoveryFinished { tunnels, .. } => debug!("[🔍] Discovered {} tunnels", tunnels),
+                RebindEvent::BindStarted { name } => debug!("[🔄] {}: binding", name),
+                RebindEvent::BindResult { name, outcome, duration, .. } => {
+                    debug!("[📋] {}: {} after {} ms", name, outcome, duration.as_millis())
+                }
+                RebindEvent::RunComplete { .. } => {}
+            }
+        }
+        #[cfg(feature = "otel")]
+        if let Some(tracer) = &mut tracer {
+            tracer.finish().await;
+        }
     });
+    (sender, task)
+}
 
-    let req = Request::builder()
-        .method(Method::POST)
-        .uri(endpoint)
-        .header("Content-Type", "application/json")
-        .body(Body::from(serde_json::to_vec(&payload).unwrap()))
-        .unwrap();
+/// `--healthcheck`: whether the tunnel agents answer with tunnel data
+/// within the resolved `healthcheck_timeout_secs` (2 unless set), for a
+/// container liveness probe. Telegram is never called, and only failures
+/// are logged.
+async fn healthcheck(bots: &[BotBinding], headers: &[(String, String)], settings: &[Setting], format: Format) -> bool {
+    let timeout = Duration::from_secs(chosen(settings, "healthcheck_timeout_secs").and_then(|s| s.value.as_deref()?.parse().ok()).unwrap_or(2));
+    let result = match build_client().and_then(|client| tunnel_provider(&client, bots, &[], headers, false)) {
+        Ok(provider) => match tokio::time::timeout(timeout, provider.public_urls()).await {
+            Ok(result) => result.map_err(|err| err.to_string()),
+            Err(_) => Err(format!("no tunnel data within {}s", timeout.as_secs())),
+        },
+        Err(err) => Err(err),
+    };
+    match (&result, format) {
+        (Err(err), Format::Human | Format::Oneline | Format::NdjsonEvent) => error!("[❌] {}", err),
+        (_, Format::Human | Format::Oneline | Format::NdjsonEvent) => {}
+        (Ok(_), Format::Json) if quiet() => {}
+        (Ok(urls), Format::Json) => println!("{}", json!({ "healthy": true, "tunnels": urls.len() })),
+        (Err(err), Format::Json) => println!("{}", json!({ "healthy": false, "error": err })),
+    }
+    result.is_ok()
+}
 
-    match client.request(req).await {
-        Ok(mut res) if res.status().is_success() => {
-            println!("[✅] Bound {} to {}", bot_name, webhook_url);
+/// `--failover LABEL`: moves the selected webhook bots, one at a time in
+/// table order, onto the tunnels of the ngrok agent `label`, which
+/// `config.provider` queries. Each bot is rebound and its live webhook
+/// verified before the next is touched, so a bot always points at one
+/// agent or the other. A bot the agent has no healthy tunnel for stays
+/// where it is and is reported, as do bots with a `stable_url`; the
+/// first bind that fails or doesn't verify stops the cutover, leaving the
+/// rest untouched. Returns the exit code for a cutover that didn't move
+/// every bot: `E_NO_TUNNEL` when the agent couldn't be reached,
+/// `E_PARTIAL`/`E_ALL_BINDS_FAILED` otherwise.
+async fn failover(config: &mut RebindConfig, label: &str, format: Format) -> Option<exit::Code> {
+    let bots = std::mem::take(&mut config.bots);
+    let (mut moved, mut already, mut left) = (Vec::new(), Vec::new(), Vec::new());
+    let (mut stopped, mut unreachable) = (None, false);
+    for bot in bots.iter().filter(|b| b.mode == BotMode::Webhook) {
+        if bot.stable_url.is_some() {
+            left.push((bot.name.clone(), "has a stable_url, not a tunnel".to_string()));
+            continue;
         }
-        Ok(mut res) => {
-            let text = to_bytes(res.body_mut()).await.ok().map(|b| String::from_utf8_lossy(&b).into_owned()).unwrap_or_default();
-            eprintln!("[❌] Failed {}: {}", bot_name, text);
+        config.bots = vec![bot.clone()];
+        let report = match rebind(config).await {
+            Ok(report) => report,
+            Err(err) => {
+                stopped = Some((bot.name.clone(), err.to_string()));
+                unreachable = true;
+                break;
+            }
+        };
+        let Some(BotOutcome { outcome, .. }) = report.outcomes.into_iter().next() else { continue };
+        match outcome {
+            Outcome::Bound { webhook_url, verified: Ok(_) } => {
+                info!("[🔀] {}: moved to {}", bot.name, webhook_url);
+                moved.push((bot.name.clone(), webhook_url));
+            }
+            Outcome::Bound { verified: Err(problem), .. } => {
+                stopped = Some((bot.name.clone(), format!("bound, but {}", problem)));
+                break;
+            }
+            Outcome::Unchanged { webhook_url } => {
+                info!("[🔀] {}: already on {}", bot.name, webhook_url);
+                already.push((bot.name.clone(), webhook_url));
+            }
+            Outcome::NoTunnel => {
+                let reason = format!("agent `{}` has no tunnel for port {}", label, bot.port);
+                warn!("[⚠️] {}: {}; left where it is", bot.name, reason);
+                left.push((bot.name.clone(), reason));
+            }
+            Outcome::Unhealthy(problem) => {
+                let reason = format!("the tunnel on agent `{}` is up but upstream unhealthy ({})", label, problem);
+                warn!("[⚠️] {}: {}; left where it is", bot.name, reason);
+                left.push((bot.name.clone(), reason));
+            }
+            Outcome::Failed(err) => {
+                stopped = Some((bot.name.clone(), err.to_string()));
+                break;
+            }
+            Outcome::Polling { .. } => {}
         }
+    }
+    config.bots = bots;
+    let rest: Vec<&str> = match &stopped {
+        Some((at, _)) => config
+            .bots
+            .iter()
+            .filter(|b| b.mode == BotMode::Webhook)
+            .map(|b| b.name.as_str())
+            .skip_while(|name| name != at)
+            .filter(|name| !left.iter().any(|(bot, _)| bot == name))
+            .collect(),
+        None => Vec::new(),
+    };
+    match format {
+        Format::Human | Format::Oneline | Format::NdjsonEvent => {
+            if let Some((bot, err)) = &stopped {
+                error!("[❌] {}: {}; stopping the failover", bot, err);
+                if !rest.is_empty() {
+                    warn!("[⚠️] Not moved: {}", rest.join(", "));
+                }
+            }
+            info!(
+                "[📋] Failover to `{}`: {} moved, {} already there, {} left behind{}",
+                label,
+                moved.len(),
+                already.len(),
+                left.len(),
+                if stopped.is_some() { ", stopped early" } else { "" }
+            );
+        }
+        Format::Json => {
+            let urls = |list: &[(String, String)]| list.iter().map(|(bot, url)| json!({ "bot": bot, "url": url })).collect::<Vec<_>>();
+            let reasons = |list: &[(String, String)]| list.iter().map(|(bot, why)| json!({ "bot": bot, "reason": why })).collect::<Vec<_>>();
+            println!(
+                "{}",
+                json!({ "failover": {
+                    "agent": label,
+                    "moved": urls(&moved),
+                    "already": urls(&already),
+                    "left": reasons(&left),
+                    "stopped": stopped.as_ref().map(|(bot, err)| json!({ "bot": bot, "error": err })),
+                    "not_moved": rest,
+                }})
+            );
+        }
+    }
+    match &stopped {
+        _ if unreachable => Some(exit::E_NO_TUNNEL),
+        Some(_) => Some(failed(moved.len() + already.len())),
+        None if !left.is_empty() => Some(exit::E_PARTIAL),
+        None => None,
+    }
+}
+
+/// `--self-test`: binds every selected Telegram bot and checks each one
+/// receives a probe through its tunnel. Returns false if any bot failed.
+async fn self_test_all(config: &RebindConfig, receiver: IpAddr, format: Format) -> bool {
+    if format == Format::Human {
+        info!("[🩺] Self-testing webhook delivery...");
+    }
+    match self_test(config, receiver).await {
+        Ok(results) => print_checks("Self-test", "self_test", &results, format),
         Err(err) => {
-            eprintln!("[{}] \u{1f4a5} {}", bot_name, err);
+            error!("[❌] {}", err);
+            false
         }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    dotenv().ok();
-    let tg_secret = env::var("TG_SECRET").expect("TG_SECRET not set");
+/// `--verify-secret`: binds every selected Telegram bot and checks the
+/// secret Telegram delivers its next update with. Returns false if any bot
+/// failed.
+async fn verify_secret_all(config: &RebindConfig, receiver: IpAddr, format: Format) -> bool {
+    if format == Format::Human {
+        info!("[🩺] Checking the secret Telegram delivers updates with...");
+    }
+    match verify_secrets(config, receiver).await {
+        Ok(results) => print_checks("Secret check", "verify_secret", &results, format),
+        Err(err) => {
+            error!("[❌] {}", err);
+            false
+        }
+    }
+}
 
-    println!("[🔄] Rebinding all Telegram webhooks...");
-    sleep(Duration::from_secs(2)).await;
+/// Per-bot pass/fail lines, or one JSON object under `key`. Returns
+/// whether every bot passed.
+fn print_checks(what: &str, key: &str, results: &[(String, Result<(), String>)], format: Format) -> bool {
+    let passed = results.iter().all(|(_, r)| r.is_ok());
+    match format {
+        Format::Human | Format::Oneline | Format::NdjsonEvent => {
+            for (bot, result) in results {
+                match result {
+                    Ok(()) => info!("[✅] {} passed for {}", what, bot),
+                    Err(problem) => error!("[❌] {} failed for {}: {}", what, bot, problem),
+                }
+            }
+        }
+        Format::Json if quiet() && passed => {}
+        Format::Json => {
+            let rows: Vec<_> = results
+                .iter()
+                .map(|(bot, result)| match result {
+                    Ok(()) => json!({ "bot": bot, "ok": true }),
+                    Err(problem) => json!({ "bot": bot, "ok": false, "error": problem }),
+                })
+                .collect();
+            println!("{}", json!({ key: rows }));
+        }
+    }
+    passed
+}
 
-    let https = HttpsConnector::new();
-    let client: Client<_, Body> = Client::builder().build(https);
+/// `--rotate-secret`: re-sends each bot's live webhook URL with a fresh
+/// secret and prints the new secrets once. Only bots whose rotation was
+/// confirmed by `getWebhookInfo` are saved to `REBIND_SECRETS_FILE`; the
+/// rest keep their old entry. Returns how many bots were rotated, or `Err`
+/// when the file couldn't be written.
+async fn rotate_all(targets: Targets<'_>, bots: &[BotBinding], format: Format) -> Result<usize, ()> {
+    let Some(path) = secrets::secrets_path() else { return Err(()) };
+    if format == Format::Human {
+        info!("[🔄] Rotating webhook secrets...");
+    }
+    let len = secrets::secret_length();
+    let mut results = Vec::with_capacity(bots.len());
+    for bot in bots {
+        results.push((bot.name.clone(), secrets::rotate(targets, bot, len).await));
+    }
+    let rotated: Vec<(&String, &String)> =
+        results.iter().filter_map(|(bot, r)| r.as_ref().ok().map(|secret| (bot, secret))).collect();
+
+    let mut saved = true;
+    if !rotated.is_empty() {
+        let mut stored = secrets::load(&path).unwrap_or_default();
+        stored.extend(rotated.iter().map(|(bot, secret)| ((*bot).clone(), (*secret).clone())));
+        if let Err(err) = secrets::save(&path, &stored) {
+            error!("[❌] Cannot write {}: {}; Telegram already uses the secrets below, store them by hand", path.display(), err);
+            saved = false;
+        }
+    }
+    // With --quiet the secrets only need printing when the file lacks them.
+    let silent = quiet() && saved && rotated.len() == results.len();
+    match format {
+        Format::Human | Format::Oneline | Format::NdjsonEvent => {
+            for (bot, result) in &results {
+                if let Err(err) = result {
+                    error!("[❌] Failed to rotate {}: {}", bot, err);
+                }
+            }
+            if !rotated.is_empty() && !silent {
+                let rows: Vec<_> =
+                    rotated.iter().map(|(bot, secret)| vec![((*bot).clone(), None), ((*secret).clone(), None)]).collect();
+                print_table(&["BOT", "SECRET"], &rows, false);
+                info!(
+                    "[📝] Rotated {} of {} secrets{}; update each bot server to expect its new secret",
+                    rotated.len(),
+                    results.len(),
+                    if saved { format!(" into {}", path.display()) } else { String::new() }
+                );
+            }
+        }
+        Format::Json if silent => {}
+        Format::Json => {
+            let (mut done, mut failed) = (Vec::new(), Vec::new());
+            for (bot, result) in &results {
+                match result {
+                    Ok(secret) => done.push(json!({ "bot": bot, "secret": secret })),
+                    Err(err) => failed.push(json!({ "bot": bot, "error": err })),
+                }
+            }
+            println!("{}", json!({ "rotated": done, "failed": failed, "saved": saved }));
+        }
+    }
+    if saved {
+        Ok(rotated.len())
+    } else {
+        Err(())
+    }
+}
+
+/// `--unbind`: deletes every selected bot's webhook. Returns how many bots
+/// were unbound.
+async fn unbind_all(targets: Targets<'_>, bots: &[BotBinding], ledger: Option<&Ledger>, format: Format) -> usize {
+    if format == Format::Human {
+        info!("[🔄] Removing Telegram webhooks...");
+    }
+    let results = unbind(targets, bots).await;
+    // Forget unbound bots so a later watch session rebinds them even if
+    // their tunnel URL hasn't moved.
+    let state_file = state::state_path();
+    let mut saved = state_file.as_deref().map(state::load).unwrap_or_default();
+    if let Some(ledger) = ledger {
+        ledger.record_unbind(&results, &saved.bindings);
+    }
+    let mut forgot = false;
+    for (bot, _) in results.iter().filter(|(_, r)| r.is_ok()) {
+        forgot |= saved.forget(bot);
+    }
+    if let Some(path) = state_file.filter(|_| forgot) {
+        if let Err(err) = state::save(&path, &saved) {
+            warn!("[⚠️] Cannot write state file {}: {}", path.display(), err);
+        }
+    }
+    match format {
+        Format::Human | Format::Oneline | Format::NdjsonEvent => {
+            for (bot, result) in &results {
+                match result {
+                    Ok(()) => info!("[🧹] Unbound {}", bot),
+                    Err(err) => error!("[❌] Failed {}: {}", bot, err),
+                }
+            }
+        }
+        Format::Json if quiet() && results.iter().all(|(_, r)| r.is_ok()) => {}
+        Format::Json => {
+            let (mut unbound, mut failed) = (Vec::new(), Vec::new());
+            for (bot, result) in &results {
+                match result {
+                    Ok(()) => unbound.push(json!({ "bot": bot })),
+                    Err(err) => failed.push(json!({ "bot": bot, "error": err.to_string() })),
+                }
+            }
+            println!("{}", json!({ "unbound": unbound, "failed": failed }));
+        }
+    }
+    results.iter().filter(|(_, r)| r.is_ok()).count()
+}
 
-    let urls = get_public_urls(&client).await;
-    for (name, url) in urls {
-        bind_webhook(&client, &name, &url, &tg_secret).await;
+/// Queued updates above which a bot is flagged as having a backlog
+/// (`REBIND_PENDING_WARN`, or the older `REBIND_PENDING_ALERT`; default 100).
+fn pending_warn() -> u64 {
+    env_or("REBIND_PENDING_WARN", env_or("REBIND_PENDING_ALERT", 100u64))
+}
+
+/// Raises a `rebind_alert` for the bots `verification` found backlogged.
+fn alert_backlog(pulses: Option<&PulseSink>, run_id: &str, verification: &VerificationReport) {
+    let backlogged = verification.backlogged();
+    if let Some(pulses) = pulses.filter(|_| !backlogged.is_empty()) {
+        pulses.backlog(run_id, &backlogged, pending_warn());
+    }
+}
+
+/// Each bot's webhook URL for the public URLs in `bindings`; empty for a
+/// polling bot.
+fn webhook_urls(bots: &[BotBinding], bindings: &HashMap<String, String>) -> HashMap<String, String> {
+    bots.iter()
+        .filter_map(|b| match b.mode {
+            BotMode::Poll => Some((b.name.clone(), String::new())),
+            BotMode::Webhook => Some((b.name.clone(), webhook_url(b, bindings.get(&b.name)?))),
+        })
+        .collect()
+}
+
+/// `--verify-only`: compares every selected bot's live webhook with the one
+/// last saved in the state file, without binding, and flags webhooks under
+/// none of the discovered `tunnels`. Backlogs raise a `rebind_alert` pulse.
+/// Returns false if any bot is flagged or failed.
+async fn verify_only(
+    targets: Targets<'_>,
+    bots: &[BotBinding],
+    tunnels: Option<Vec<String>>,
+    run_id: &str,
+    format: Format,
+) -> bool {
+    let saved = state::state_path().as_deref().map(state::load).unwrap_or_default();
+    let expected = Expected { set: webhook_urls(bots, &saved.bindings), tunnels, ..Expected::default() };
+    let report = verify_all(targets, bots, &expected, pending_warn()).await;
+    alert_backlog(PulseSink::from_env().as_ref(), run_id, &report);
+    if quiet() && report.ok() {
+        return true;
+    }
+    match format {
+        Format::Human | Format::Oneline | Format::NdjsonEvent => {
+            let rows: Vec<_> = report
+                .bots
+                .iter()
+                .map(|b| {
+                    let (status, code) = match b.verdict {
+                        Verdict::Ok if b.backlog.is_some() => ("backlog", YELLOW),
+                        Verdict::Ok if b.flagged() => ("flagged", YELLOW),
+                        Verdict::Ok => ("ok", GREEN),
+                        Verdict::NotYetUpdated => (b.verdict.as_str(), YELLOW),
+                        Verdict::Mismatch | Verdict::Failed => (b.verdict.as_str(), RED),
+                    };
+                    let (pending, url) = match &b.live {
+                        Ok(info) if !info.url.is_empty() => (info.pending_update_count.to_string(), info.url.clone()),
+                        Ok(info) => (info.pending_update_count.to_string(), "-".to_string()),
+                        Err(_) => ("-".to_string(), "-".to_string()),
+                    };
+                    vec![(b.bot.clone(), None), (status.to_string(), Some(code)), (pending, None), (url, None)]
+                })
+                .collect();
+            print_table(&["BOT", "STATUS", "PENDING", "URL"], &rows, use_color());
+            log_verification(&report);
+        }
+        Format::Json => println!("{}", json!({ "webhooks": report.to_json() })),
     }
+    report.ok()
 }
 
+/// Logs why each flagged bot was flagged.
+fn log_verification(report: &VerificationReport) {
+    for b in &report.bots {
+        let expected = b.expected.as_deref().unwrap_or("-");
+        match (&b.live, b.verdict) {
+            (Err(err), _) => error!("[❌] Failed {}: {}", b.bot, err),
+            (Ok(info), Verdict::NotYetUpdated) => warn!(
+                "[⏳] {}: Telegram still reports {:?} instead of {:?}; it usually catches up within seconds",
+                b.bot, info.url, expected
+            ),
+            (Ok(info), Verdict::Mismatch) if expected.is_empty() => {
+                error!("[❌] {}: Telegram reports {:?}, but the bot should be polling", b.bot, info.url)
+            }
+            (Ok(info), Verdict::Mismatch) => {
+                error!("[❌] {}: Telegram reports {:?}, but {:?} was set", b.bot, info.url, expected)
+            }
+            _ => {}
+        }
+        for problem in &b.problems {
+            warn!("[⚠️] {}: {}", b.bot, problem);
+        }
+    }
+}
+
+/// `averages` are watch mode's rolling per-bot durations; JSON entries
+/// carry them as `avg_duration_ms`. With `--quiet` a report without
+/// failures isn't printed at all.
+fn print_report(
+    report: &RebindReport,
+    format: Format,
+    averages: &HashMap<String, Duration>,
+    verification: Option<&VerificationReport>,
+    policy: Option<SuccessPolicy>,
+) {
+    if quiet() && report.failed() == 0 && format != Format::NdjsonEvent {
+        return;
+    }
+    match format {
+        Format::Human => {
+            print_human(report, averages, policy);
+            if let Some(verification) = verification.filter(|v| !v.bots.is_empty()) {
+                log_verification(verification);
+                info!(
+                    "[🔍] Verified {} webhooks: {} ok, {} not yet updated, {} mismatched, {} unreachable",
+                    verification.bots.len(),
+                    verification.count(Verdict::Ok),
+                    verification.count(Verdict::NotYetUpdated),
+                    verification.count(Verdict::Mismatch),
+                    verification.count(Verdict::Failed),
+                );
+            }
+        }
+        Format::Json => {
+            let mut value = report.to_json();
+            for list in value.as_object_mut().into_iter().flat_map(|o| o.values_mut()) {
+                for entry in list.as_array_mut().into_iter().flatten() {
+                    if let Some(avg) = entry["bot"].as_str().and_then(|bot| averages.get(bot)) {
+                        entry["avg_duration_ms"] = json!(avg.as_millis() as u64);
+                    }
+                }
+            }
+            if let Some(verification) = verification {
+                value["verification"] = verification.to_json();
+            }
+            if let Some(names) = MISSING_TOKENS.get() {
+                value["missing_tokens"] = json!(names);
+            }
+            println!("{}", value);
+        }
+        Format::Oneline => {
+            info!("[📋] {}", summary(report, policy));
+            println!("{}", oneline(report));
+        }
+        Format::NdjsonEvent => {
+            info!("[📋] {}", summary(report, policy));
+            let mut event = run_event(Ok(report));
+            event["ok"] = json!(policy.unwrap_or_default().met(report.succeeded(), report.failed()));
+            if let Some(verification) = verification {
+                event["verification"] = verification.to_json();
+            }
+            println!("{}", event);
+        }
+    }
+}
+
+/// Version of the `--format ndjson-event` object. Fields may be added
+/// within a version; renaming or removing one bumps it.
+const EVENT_SCHEMA_VERSION: u32 = 1;
+
+/// The `--format ndjson-event` object for a finished run, or for one whose
+/// discovery failed: `schema_version`, `ts`, `host`, `ok`, `error`, one
+/// `results` entry per bot (`bot`, `outcome`, `url`, `error`,
+/// `duration_ms`, `tunnel_age_secs`) and the report's `summary`.
+fn run_event(result: Result<&RebindReport, &str>) -> serde_json::Value {
+    let mut event = json!({
+        "schema_version": EVENT_SCHEMA_VERSION,
+        "event": "rebind_run",
+        "ts": logger::timestamp(),
+        "host": hostname(),
+        "ok": false,
+        "error": null,
+        "results": [],
+        "summary": null,
+    });
+    let report = match result {
+        Ok(report) => report,
+        Err(err) => {
+            event["error"] = json!(err);
+            return event;
+        }
+    };
+    let results: Vec<_> = report
+        .outcomes
+        .iter()
+        .map(|BotOutcome { bot, outcome, duration }| {
+            let (url, error) = match outcome {
+                Outcome::Bound { webhook_url, .. } | Outcome::Unchanged { webhook_url } => (Some(webhook_url.clone()), None),
+                Outcome::Polling { .. } | Outcome::NoTunnel => (None, None),
+                Outcome::Unhealthy(problem) => (None, Some(problem.clone())),
+                Outcome::Failed(err) => (None, Some(err.to_string())),
+            };
+            json!({
+                "bot": bot,
+                "outcome": outcome.label(),
+                "url": url,
+                "error": error,
+                "duration_ms": duration.as_millis() as u64,
+                "tunnel_age_secs": report.tunnel_ages.get(bot).map(|age| age.as_secs()),
+            })
+        })
+        .collect();
+    event["results"] = json!(results);
+    event["summary"] = report.to_json()["summary"].take();
+    if let Some(names) = MISSING_TOKENS.get() {
+        event["missing_tokens"] = json!(names);
+    }
+    event
+}
+
+/// `HOSTNAME`, or else `/etc/hostname`; `unknown` when neither says.
+fn hostname() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// `Rebind complete: 3 bound, 0 unchanged, 0 failed, 1 skipped in 842ms
+/// (discovery 120ms)`, naming the agents that didn't answer when discovery
+/// was partial; runs that didn't discover leave out the last part. A
+/// `policy` other than `all` says whether the run met it.
+fn summary(report: &RebindReport, policy: Option<SuccessPolicy>) -> String {
+    let mut line = format!(
+        "Rebind complete: {} bound, {} unchanged, {} failed, {} skipped in {}ms",
+        report.bound(),
+        report.unchanged(),
+        report.failed(),
+        report.skipped(),
+        report.elapsed.as_millis()
+    );
+    if report.polling() > 0 {
+        line = line.replacen(" unchanged,", &format!(" unchanged, {} polling,", report.polling()), 1);
+    }
+    if !report.discovery.is_zero() {
+        let failed: Vec<String> = report
+            .agents
+            .iter()
+            .filter(|a| a.state != AgentState::Ok)
+            .map(|a| format!("{} {}", a.agent, a.state.as_str()))
+            .collect();
+        if failed.is_empty() {
+            line.push_str(&format!(" (discovery {}ms)", report.discovery.as_millis()));
+        } else {
+            line.push_str(&format!(" (discovery {}ms, partial: {})", report.discovery.as_millis(), failed.join(", ")));
+        }
+    }
+    if let Some(policy) = policy.filter(|p| *p != SuccessPolicy::All) {
+        let met = if policy.met(report.succeeded(), report.failed()) { "met" } else { "not met" };
+        line.push_str(&format!("; success policy {} {}", policy, met));
+    }
+    if let Some(names) = MISSING_TOKENS.get() {
+        line.push_str(&format!("; no token, left out: {}", names.join(", ")));
+    }
+    line
+}
+
+/// `rebind: 3/3 ok @12:04`, naming the failed (or else skipped) bots
+/// instead of `ok`. The time is UTC, like the log timestamps.
+fn oneline(report: &RebindReport) -> String {
+    let names = |pred: fn(&Outcome) -> bool| -> Vec<&str> {
+        report.outcomes.iter().filter(|o| pred(&o.outcome)).map(|o| o.bot.as_str()).collect()
+    };
+    let failed = names(|o| matches!(o, Outcome::Failed(_)));
+    let skipped = names(|o| matches!(o, Outcome::NoTunnel | Outcome::Unhealthy(_)));
+    let status = match (failed.is_empty(), skipped.is_empty()) {
+        (false, _) => format!("FAIL({})", failed.join(",")),
+        (true, false) => format!("SKIP({})", skipped.join(",")),
+        (true, true) => "ok".to_string(),
+    };
+    let now = logger::timestamp();
+    let time = now.get(11..16).unwrap_or("--:--");
+    let ok = report.bound() + report.unchanged() + report.polling();
+    format!("rebind: {}/{} {} @{}", ok, report.outcomes.len(), status, time)
+}
+
+/// Resolves on SIGINT or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+                return;
+            }
+            Err(err) => warn!("[⚠️] Cannot listen for SIGTERM: {}", err),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// `Changes: gpt4o bound (new tunnel), mistral failed (previously failed);
+/// unchanged: deepseek` for a watch poll's report, `bots` being every bot
+/// of the table.
+fn changelog(report: &RebindReport, bots: &[String]) -> String {
+    let changes: Vec<String> =
+        report.changes().iter().map(|(bot, outcome, reason)| format!("{} {} ({})", bot, outcome, reason)).collect();
+    let unchanged: Vec<&str> = bots
+        .iter()
+        .map(String::as_str)
+        .filter(|bot| report.outcomes.iter().all(|o| o.bot != *bot || matches!(o.outcome, Outcome::Unchanged { .. })))
+        .collect();
+    match (changes.is_empty(), unchanged.is_empty()) {
+        (true, _) => "No changes".to_string(),
+        (false, true) => format!("Changes: {}", changes.join(", ")),
+        (false, false) => format!("Changes: {}; unchanged: {}", changes.join(", "), unchanged.join(", ")),
+    }
+}
+
+/// Binds every bot right away, then polls every `watch_interval` seconds
+/// of `settings` (default 30) until SIGINT or SIGTERM, feeding `metrics` when
+/// they are served. A poll in progress is allowed to finish, so state is
+/// saved and no bot is left half-bound; a second signal exits immediately.
+///
+/// A poll that tried any bot is summed up in one line: each bot with what
+/// happened and why it was tried, then the bots left as they were.
+/// Quiet polls are coalesced: after the first poll and after each change, a
+/// single "bots stable" line is logged every `watch_heartbeat_secs`
+/// (default 300; 0 only logs it at start and once discovery recovers).
+/// Changes, warnings and errors are logged as they happen.
+///
+/// With `once` the first poll is the only one, and the process exits with
+/// [`exit::CHANGED`] if it rebound a bot, `E_NO_TUNNEL` if discovery
+/// failed, `E_PARTIAL`/`E_ALL_BINDS_FAILED` if a bot failed and the
+/// `success_policy` isn't met (as a one-shot run judges it), and 0 otherwise. Under
+/// `--alert-after-secs` a failure only counts once a bot is overdue, which
+/// exits with `E_OVERDUE`.
+async fn watch(config: RebindConfig, format: Format, metrics: Option<Arc<Metrics>>, once: bool, settings: &[Setting]) {
+    let interval = Duration::from_secs(parsed(settings, "watch_interval").unwrap_or(30u64).max(1));
+    let heartbeat = Duration::from_secs(parsed(settings, "watch_heartbeat_secs").unwrap_or(300));
+    let policy = SuccessPolicy::from_settings(settings);
+    let (stop_tx, mut stop_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = stop_tx.send(());
+        shutdown_signal().await;
+        error!("[❌] Second signal received, exiting without finishing");
+        fail(exit::E_INTERRUPTED);
+    });
+
+    let bots = config.bots.len();
+    let names: Vec<String> = config.bots.iter().map(|b| b.name.clone()).collect();
+    let alerting = config.alert_after.is_some();
+    let mut watcher = Watcher::new(config);
+    let (mut polls, mut bound, mut failed) = (0usize, 0usize, 0usize);
+    let mut stable_since = logger::timestamp();
+    // `None` makes the next quiet poll report right away.
+    let mut last_heartbeat: Option<Instant> = None;
+    loop {
+        let result = watcher.poll().await;
+        polls += 1;
+        if let Some(metrics) = &metrics {
+            metrics.record(&result);
+        }
+        if let Ok(report) = &result {
+            bound += report.bound();
+            failed += report.failed();
+        }
+        let overdue = match &result {
+            Ok(report) => !report.overdue.is_empty(),
+            Err(_) => !watcher.overdue().is_empty(),
+        };
+        let code = match &result {
+            _ if overdue => Some(exit::E_OVERDUE),
+            // Short of a bot being overdue, failures don't count.
+            Err(_) if alerting => None,
+            Err(_) => Some(exit::E_NO_TUNNEL),
+            Ok(report) if report.failed() > 0 && !alerting => shortfall(report, policy).or((report.bound() > 0).then_some(exit::CHANGED)),
+            Ok(report) if report.bound() > 0 => Some(exit::CHANGED),
+            Ok(_) => None,
+        };
+        match result {
+            Err(err) => {
+                error!("[❌] {}", err);
+                stable_since = logger::timestamp();
+                last_heartbeat = None;
+            }
+            Ok(report) if report.outcomes.is_empty() => {
+                let due = last_heartbeat.is_none_or(|at| !heartbeat.is_zero() && at.elapsed() >= heartbeat);
+                if due {
+                    last_heartbeat = Some(Instant::now());
+                    match format {
+                        Format::Human | Format::Oneline | Format::NdjsonEvent => info!("[💤] No changes; {} bots stable since {}", bots, stable_since),
+                        Format::Json if quiet() => {}
+                        Format::Json => println!("{}", json!({ "stable": { "bots": bots, "since": stable_since } })),
+                    }
+                } else if format == Format::Human {
+                    debug!("[💤] No changes");
+                }
+            }
+            Ok(report) => {
+                stable_since = logger::timestamp();
+                last_heartbeat = Some(Instant::now());
+                if format == Format::Human {
+                    info!("[🧾] {}", changelog(&report, &names));
+                }
+                print_report(&report, format, &watcher.average_durations(), None, None);
+            }
+        }
+        if once {
+            if let Some(code) = code {
+                fail(code);
+            }
+            return;
+        }
+        tokio::select! {
+            biased;
+            _ = &mut stop_rx => break,
+            _ = sleep(interval) => {}
+        }
+    }
+    match format {
+        Format::Human | Format::Oneline | Format::NdjsonEvent => info!("[👋] Watch stopped after {} polls: {} bound, {} failed", polls, bound, failed),
+        Format::Json if quiet() && failed == 0 => {}
+        Format::Json => println!("{}", json!({ "stopped": { "polls": polls, "bound": bound, "failed": failed } })),
+    }
+}
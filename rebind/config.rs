@@ -0,0 +1,1553 @@
+//! The bot table: which local port belongs to which Telegram bot, and the
+//! per-bot `setWebhook` options.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{IsTerminal, Read};
+use std::sync::{mpsc, OnceLock};
+use std::time::Duration;
+use std::{env, fmt, fs, io};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::regex::Regex;
+use crate::resolve::{file_text, file_value_problem, parsed, spec_for_key, value_of, Setting, SPECS};
+use crate::toml;
+
+/// Built-in bot table, used only when no `bots.toml` is present.
+pub static PORT_TO_NAME: &[(u16, &str)] = &[
+    (9977, "gpt4o"),
+    (9988, "mistral"),
+    (9966, "deepseek"),
+];
+pub const DEFAULT_CONFIG_PATH: &str = "bots.toml";
+/// Config path meaning "read the bot table from stdin".
+pub const STDIN_CONFIG: &str = "-";
+pub const DEFAULT_WEBHOOK_PATH: &str = "/webhook";
+/// Where a bot's `priority` sits without one, in the middle of 0–100.
+pub const DEFAULT_PRIORITY: i64 = 50;
+pub const DEFAULT_SECRET_PARAM: &str = "token";
+
+/// Where a bot's webhook is registered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    #[default]
+    Telegram,
+    /// The application's interactions endpoint; needs `application_id`.
+    Discord,
+    /// The app's Event Subscriptions request URL; needs `application_id`
+    /// (the app ID) and an app configuration token.
+    Slack,
+}
+
+/// What a run leaves the bot with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BotMode {
+    /// A webhook on the bot's tunnel.
+    #[default]
+    Webhook,
+    /// No webhook at all, so the bot can call `getUpdates`; Telegram only.
+    Poll,
+}
+
+/// When `setWebhook` asks Telegram to drop the updates queued for a bot,
+/// from the run-wide `drop_pending` setting or the bot's own
+/// `drop_pending_updates`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DropPending {
+    #[default]
+    Never,
+    Always,
+    /// Only when the state file has no record of the bot ever being bound,
+    /// so a fresh deploy starts clean but a tunnel change loses nothing.
+    FirstOnly,
+}
+
+impl From<bool> for DropPending {
+    fn from(drop: bool) -> Self {
+        if drop {
+            DropPending::Always
+        } else {
+            DropPending::Never
+        }
+    }
+}
+
+impl std::str::FromStr for DropPending {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" => Ok(DropPending::Always),
+            "0" | "false" | "no" => Ok(DropPending::Never),
+            "first-only" => Ok(DropPending::FirstOnly),
+            other => Err(format!("unknown setting `{}` (expected true, false or first-only)", other)),
+        }
+    }
+}
+
+impl DropPending {
+    /// The setting as written: `false`, `true` or `first-only`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropPending::Never => "false",
+            DropPending::Always => "true",
+            DropPending::FirstOnly => "first-only",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BotBinding {
+    pub port: u16,
+    pub name: String,
+    pub platform: Platform,
+    /// Discord application whose interactions endpoint is updated, or the
+    /// Slack app whose request URL is.
+    pub application_id: Option<String>,
+    pub token_env: Option<String>,
+    pub drop_pending_updates: DropPending,
+    pub allowed_updates: Option<Vec<String>>,
+    /// Telegram's cap on simultaneous webhook connections, 1–100.
+    pub max_connections: Option<u32>,
+    /// Sent as-is; Telegram validates it.
+    pub ip_address: Option<String>,
+    /// Path probed by the pre-bind health check instead of the global one.
+    pub health_path: Option<String>,
+    /// Path appended to the public URL, with `{name}` already substituted
+    /// and unusual characters percent-encoded.
+    pub webhook_path: String,
+    /// Query parameter carrying `TG_SECRET` in the webhook URL, for
+    /// frameworks that don't read the `secret_token` header. `None` sends
+    /// the header.
+    pub secret_query: Option<String>,
+    /// Env var holding this bot's own secret instead of `TG_SECRET_<NAME>`.
+    pub secret_env: Option<String>,
+    /// Secret written straight into the bot table; wins over any env var.
+    pub secret: Option<String>,
+    /// 0–100; a higher priority's requests go out first when a run is
+    /// held back by the concurrency or rate limit.
+    pub priority: i64,
+    /// Public URL that never changes, e.g. an ngrok reserved domain. It
+    /// stands in for discovery, so the bot is only rebound when its live
+    /// webhook doesn't match.
+    pub stable_url: Option<String>,
+    /// Per-request timeout for this bot's API calls (`timeout_secs`), for
+    /// bots behind slower routes; `None` keeps `REBIND_HTTP_TIMEOUT_SECS`.
+    pub timeout: Option<Duration>,
+    /// `mode = "poll"` deletes the webhook instead of setting one.
+    pub mode: BotMode,
+}
+
+impl BotBinding {
+    /// Whether the bot's webhook URL comes from tunnel discovery: not when
+    /// it has a `stable_url` or is polling.
+    pub fn needs_tunnel(&self) -> bool {
+        self.mode == BotMode::Webhook && self.stable_url.is_none()
+    }
+
+    /// This bot as bound now, with [`DropPending::FirstOnly`] settled by
+    /// whether the state file records it as `ever_bound`.
+    pub fn settle_drop_pending(&self, ever_bound: bool) -> Cow<'_, BotBinding> {
+        match self.drop_pending_updates {
+            DropPending::FirstOnly => {
+                Cow::Owned(BotBinding { drop_pending_updates: DropPending::from(!ever_bound), ..self.clone() })
+            }
+            _ => Cow::Borrowed(self),
+        }
+    }
+
+    /// Env var holding this bot's token: `token_env`, or `BOT_TOKEN_<NAME>`.
+    pub fn token_var(&self) -> String {
+        match &self.token_env {
+            Some(var) => var.clone(),
+            None => format!("BOT_TOKEN_{}", self.name.to_ascii_uppercase()),
+        }
+    }
+
+    /// Env var holding this bot's secret: `secret_env`, or `TG_SECRET_<NAME>`.
+    pub fn secret_var(&self) -> String {
+        match &self.secret_env {
+            Some(var) => var.clone(),
+            None => format!("TG_SECRET_{}", self.name.to_ascii_uppercase()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(String, String),
+    Invalid(String, Vec<String>),
+    /// The requested profile, and the profiles the file does define.
+    UnknownProfile(String, String, Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "cannot read {}: {}", path, err),
+            ConfigError::Parse(path, err) => write!(f, "cannot parse {}: {}", path, err),
+            ConfigError::Invalid(path, problems) => {
+                write!(f, "invalid bot table in {}:", path)?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+            ConfigError::UnknownProfile(path, name, available) if available.is_empty() => {
+                write!(f, "no profile `{}` in {}, which defines no profiles", name, path)
+            }
+            ConfigError::UnknownProfile(path, name, available) => {
+                write!(f, "no profile `{}` in {} (available: {})", name, path, available.join(", "))
+            }
+        }
+    }
+}
+
+/// The bot table and the settings loaded alongside it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BotTable {
+    pub bots: Vec<BotBinding>,
+    /// `[[rewrite]]` entries from the selected profile, or else from the
+    /// top level.
+    pub rewrites: Vec<UrlRewrite>,
+    /// `[[agent]]` entries, likewise.
+    pub agents: Vec<AgentPorts>,
+    /// `[tunnel.headers]`, likewise: name and value of each header to send
+    /// with every tunnel discovery request (never to Telegram), e.g. for an
+    /// auth proxy in front of the ngrok inspector.
+    pub tunnel_headers: Vec<(String, String)>,
+    /// `[messages]`, likewise, over the built-in lines.
+    pub messages: Messages,
+}
+
+/// The key of one of [`crate::resolve::SPECS`] in the bot table's top
+/// level and in the selected profile.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileLayer {
+    pub top: Option<String>,
+    pub profile: Option<String>,
+}
+
+/// An `[[agent]]` entry: the ports the ngrok agent labelled `label` (in
+/// `NGROK_API_URLS`) should have tunnels for, so discovery can warn when
+/// one of them is missing instead of the bot silently going unbound.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentPorts {
+    pub label: String,
+    pub expected_ports: Vec<u16>,
+}
+
+/// `[messages]`: the per-bot log lines for a bind that succeeded, failed
+/// or was skipped, with `{name}`, `{url}` and `{error}` filled in, e.g.
+/// plain ASCII for a log pipeline that chokes on emoji.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Messages {
+    pub success: String,
+    pub failure: String,
+    pub skip: String,
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Messages {
+            success: "[✅] {name}: bound to {url}".to_string(),
+            failure: "[❌] Failed {name}: {error}".to_string(),
+            skip: "[⚪] {name}: no tunnel discovered".to_string(),
+        }
+    }
+}
+
+impl Messages {
+    /// `template` with its placeholders filled in.
+    pub fn render(template: &str, name: &str, url: &str, error: &str) -> String {
+        template.replace("{name}", name).replace("{url}", url).replace("{error}", error)
+    }
+}
+
+/// The `{word}` placeholders of `template` that [`Messages::render`]
+/// doesn't know.
+fn unknown_placeholders(template: &str) -> Vec<&str> {
+    let mut unknown = Vec::new();
+    let mut rest = template;
+    while let Some((_, after)) = rest.split_once('{') {
+        match after.split_once('}') {
+            Some((word, tail)) if !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+                if !MESSAGE_PLACEHOLDERS.contains(&word) {
+                    unknown.push(word);
+                }
+                rest = tail;
+            }
+            _ => rest = after,
+        }
+    }
+    unknown
+}
+
+/// A `[[rewrite]]` entry: discovered public URLs matching `pattern` have
+/// their first match replaced by `replace`, e.g. to bind a stable CNAME in
+/// front of an ephemeral tunnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlRewrite {
+    pub pattern: Regex,
+    pub replace: String,
+}
+
+/// Puts each webhook bot's `stable_url` into `urls`, over any tunnel
+/// discovered for it.
+pub fn add_stable_urls(bots: &[BotBinding], urls: &mut HashMap<String, String>) {
+    for bot in bots.iter().filter(|bot| bot.mode == BotMode::Webhook) {
+        if let Some(url) = &bot.stable_url {
+            urls.insert(bot.name.clone(), url.clone());
+        }
+    }
+}
+
+/// `url` run through `rewrites` in order.
+pub fn rewrite_url(rewrites: &[UrlRewrite], url: &str) -> String {
+    rewrites.iter().fold(url.to_string(), |url, rw| rw.pattern.replace(&url, &rw.replace).unwrap_or(url))
+}
+
+/// Runs every bot's URL in `urls` through `rewrites` in order, logging each
+/// one that changed.
+pub fn rewrite_urls(rewrites: &[UrlRewrite], urls: &mut HashMap<String, String>) {
+    if rewrites.is_empty() {
+        return;
+    }
+    let mut names: Vec<&String> = urls.keys().collect();
+    names.sort();
+    let mut rewritten = Vec::new();
+    for name in names {
+        let before = &urls[name];
+        let after = rewrite_url(rewrites, before);
+        if &after != before {
+            log::info!("[🔀] {}: rewrote {} to {}", name, before, after);
+            rewritten.push((name.clone(), after));
+        }
+    }
+    urls.extend(rewritten);
+}
+
+#[derive(Deserialize)]
+struct BotsFile {
+    #[serde(default)]
+    bot: Vec<RawBot>,
+    #[serde(default)]
+    rewrite: Vec<RawRewrite>,
+    #[serde(default)]
+    agent: Vec<RawAgent>,
+    #[serde(default)]
+    tunnel: RawTunnel,
+    #[serde(default)]
+    messages: RawMessages,
+}
+
+#[derive(Deserialize, Default)]
+struct RawMessages {
+    success: Option<String>,
+    failure: Option<String>,
+    skip: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct RawTunnel {
+    #[serde(default)]
+    headers: serde_json::Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct RawAgent {
+    label: String,
+    #[serde(default)]
+    expected_ports: Vec<u16>,
+}
+
+#[derive(Deserialize)]
+struct RawRewrite {
+    pattern: String,
+    replace: String,
+}
+
+/// Keep in sync with [`BOT_FIELDS`].
+#[derive(Deserialize)]
+struct RawBot {
+    port: Option<Value>,
+    name: Option<String>,
+    #[serde(default)]
+    platform: Platform,
+    application_id: Option<String>,
+    token_env: Option<String>,
+    drop_pending_updates: Option<bool>,
+    allowed_updates: Option<Vec<String>>,
+    max_connections: Option<i64>,
+    ip_address: Option<String>,
+    health_path: Option<String>,
+    webhook_path: Option<String>,
+    secret_mode: Option<String>,
+    secret_param: Option<String>,
+    secret_env: Option<String>,
+    secret: Option<String>,
+    priority: Option<i64>,
+    stable_url: Option<String>,
+    timeout_secs: Option<i64>,
+    #[serde(default)]
+    mode: BotMode,
+}
+
+/// What a bot-table value has to look like.
+#[derive(Debug, Clone, Copy)]
+enum Shape {
+    Str,
+    Bool,
+    Int,
+    /// An integer, or a string holding one.
+    Port,
+    StrList,
+    OneOf(&'static [&'static str]),
+}
+
+impl Shape {
+    fn accepts(self, value: &Value) -> bool {
+        match self {
+            Shape::Str => value.is_string(),
+            Shape::Bool => value.is_boolean(),
+            Shape::Int => value.is_i64(),
+            Shape::Port => value.is_i64() || value.is_string(),
+            Shape::StrList => value.as_array().is_some_and(|items| items.iter().all(Value::is_string)),
+            Shape::OneOf(options) => value.as_str().is_some_and(|v| options.contains(&v)),
+        }
+    }
+
+    fn describe(self) -> String {
+        match self {
+            Shape::Str => "a string".to_string(),
+            Shape::Bool => "true or false".to_string(),
+            Shape::Int => "an integer".to_string(),
+            Shape::Port => "a port number".to_string(),
+            Shape::StrList => "a list of strings".to_string(),
+            Shape::OneOf(options) => {
+                let quoted: Vec<String> = options.iter().map(|o| format!("\"{}\"", o)).collect();
+                format!("one of {}", quoted.join(", "))
+            }
+        }
+    }
+}
+
+/// Every key a `[[bot]]` table may hold; keep in sync with [`RawBot`].
+static BOT_FIELDS: &[(&str, Shape)] = &[
+    ("port", Shape::Port),
+    ("name", Shape::Str),
+    ("platform", Shape::OneOf(&["telegram", "discord", "slack"])),
+    ("application_id", Shape::Str),
+    ("token_env", Shape::Str),
+    ("drop_pending_updates", Shape::Bool),
+    ("allowed_updates", Shape::StrList),
+    ("max_connections", Shape::Int),
+    ("ip_address", Shape::Str),
+    ("health_path", Shape::Str),
+    ("webhook_path", Shape::Str),
+    ("secret_mode", Shape::OneOf(&["header", "query"])),
+    ("secret_param", Shape::Str),
+    ("secret_env", Shape::Str),
+    ("secret", Shape::Str),
+    ("priority", Shape::Int),
+    ("stable_url", Shape::Str),
+    ("timeout_secs", Shape::Int),
+    ("mode", Shape::OneOf(&["webhook", "poll"])),
+];
+
+fn kind_of(value: &Value) -> String {
+    match value {
+        Value::Null => "nothing".to_string(),
+        Value::Bool(_) => "a boolean".to_string(),
+        Value::Number(n) if n.is_f64() => "a float".to_string(),
+        Value::Number(_) => "an integer".to_string(),
+        Value::String(s) => format!("\"{}\"", s),
+        Value::Array(_) => "a list".to_string(),
+        Value::Object(_) => "a table".to_string(),
+    }
+}
+
+/// Levenshtein distance, for "did you mean" hints.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let next = (diagonal + usize::from(ca != *cb)).min(row[j] + 1).min(row[j + 1] + 1);
+            diagonal = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate `word` was most likely meant to be, if any is close.
+fn closest<'a>(word: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .into_iter()
+        .map(|c| (edit_distance(word, c), c))
+        .filter(|(d, c)| *d <= 2 || (word.len() >= 4 && (c.starts_with(word) || word.starts_with(c))))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+/// Keys allowed at the top level and, apart from `profiles`, inside each
+/// `[profiles.NAME]` table, besides the key of every run-wide setting in
+/// [`crate::resolve::SPECS`].
+static TOP_LEVEL_KEYS: &[&str] = &["bot", "rewrite", "agent", "tunnel", "messages", "profiles"];
+static PROFILE_KEYS: &[&str] = &["bot", "rewrite", "agent", "tunnel", "messages"];
+static REWRITE_KEYS: &[&str] = &["pattern", "replace"];
+static AGENT_KEYS: &[&str] = &["label", "expected_ports"];
+static TUNNEL_KEYS: &[&str] = &["headers"];
+static MESSAGE_KEYS: &[&str] = &["success", "failure", "skip"];
+static MESSAGE_PLACEHOLDERS: &[&str] = &["name", "url", "error"];
+
+/// `message`, prefixed with the line `path` was found on.
+fn located(lines: &HashMap<String, usize>, path: &str, message: String) -> (usize, String) {
+    match lines.get(path) {
+        Some(line) => (*line, format!("line {}: {}", line, message)),
+        None => (0, message),
+    }
+}
+
+fn hint(word: &str, candidates: &[&'static str]) -> String {
+    match closest(word, candidates.iter().copied()) {
+        Some(close) => format!(", did you mean `{}`?", close),
+        None => String::new(),
+    }
+}
+
+/// Checks the parsed file's keys and value types before serde sees it, so
+/// a typo names the field, the bot and the line instead of failing
+/// opaquely or being silently ignored. Every profile is checked, not just
+/// the selected one.
+fn shape_problems(value: &Value, lines: &HashMap<String, usize>) -> Vec<String> {
+    let mut problems = Vec::new();
+    let Some(root) = value.as_object() else { return Vec::new() };
+    let known = |keys: &[&'static str]| -> Vec<&'static str> { keys.iter().copied().chain(SPECS.iter().map(|spec| spec.key)).collect() };
+    let (top_keys, profile_keys) = (known(TOP_LEVEL_KEYS), known(PROFILE_KEYS));
+    for key in root.keys().filter(|k| !top_keys.contains(&k.as_str())) {
+        problems.push(located(lines, key, format!("unknown top-level key `{}`{}", key, hint(key, &top_keys))));
+    }
+    table_problems(&mut problems, lines, root, "");
+    match root.get("profiles") {
+        None => {}
+        Some(Value::Object(profiles)) => {
+            for (name, profile) in profiles {
+                let base = format!("profiles.{}", name);
+                let Some(profile) = profile.as_object() else {
+                    let message = format!("profile `{}` must be a table, written `[{}]`", name, base);
+                    problems.push(located(lines, &base, message));
+                    continue;
+                };
+                for key in profile.keys().filter(|k| !profile_keys.contains(&k.as_str())) {
+                    let message = format!("unknown key `{}` in profile `{}`{}", key, name, hint(key, &profile_keys));
+                    problems.push(located(lines, &format!("{}.{}", base, key), message));
+                }
+                table_problems(&mut problems, lines, profile, &format!("{}.", base));
+            }
+        }
+        Some(_) => {
+            problems.push(located(lines, "profiles", "`profiles` must be a table of `[profiles.NAME]` tables".to_string()))
+        }
+    }
+    // Keys come back sorted by name; report them in file order.
+    problems.sort_by_key(|(line, _)| *line);
+    problems.into_iter().map(|(_, message)| message).collect()
+}
+
+/// The run-wide settings, `[[rewrite]]`, `[[agent]]`, `[tunnel]`, `[messages]` and `[[bot]]` entries of the top level
+/// (`prefix` empty) or of one profile (`prefix` `profiles.NAME.`).
+fn table_problems(
+    problems: &mut Vec<(usize, String)>,
+    lines: &HashMap<String, usize>,
+    table: &serde_json::Map<String, Value>,
+    prefix: &str,
+) {
+    for (key, value) in table {
+        let path = format!("{}{}", prefix, key);
+        if let Some(message) = spec_for_key(key).and_then(|spec| file_value_problem(spec, &path, value)) {
+            problems.push(located(lines, &path, message));
+        }
+    }
+    match table.get("rewrite") {
+        Some(Value::Array(rewrites)) if rewrites.iter().all(Value::is_object) => {
+            for (idx, rewrite) in rewrites.iter().enumerate() {
+                let at = format!("{}rewrite[{}]", prefix, idx);
+                let rewrite = rewrite.as_object().unwrap();
+                for (key, value) in rewrite {
+                    let path = format!("{}.{}", at, key);
+                    if !REWRITE_KEYS.contains(&key.as_str()) {
+                        let message = format!("unknown field `{}` at {}{}", key, at, hint(key, REWRITE_KEYS));
+                        problems.push(located(lines, &path, message));
+                    } else if !value.is_string() {
+                        let message = format!("`{}` at {} must be a string, not {}", key, at, kind_of(value));
+                        problems.push(located(lines, &path, message));
+                    }
+                }
+                for key in REWRITE_KEYS.iter().filter(|k| !rewrite.contains_key(**k)) {
+                    problems.push(located(lines, &at, format!("{}: missing `{}`", at, key)));
+                }
+            }
+        }
+        None => {}
+        Some(_) => {
+            let path = format!("{}rewrite", prefix);
+            problems.push(located(lines, &path, format!("`{}` must be an array of tables, written `[[{}]]`", path, path)));
+        }
+    }
+    match table.get("agent") {
+        Some(Value::Array(agents)) if agents.iter().all(Value::is_object) => {
+            for (idx, agent) in agents.iter().enumerate() {
+                let at = format!("{}agent[{}]", prefix, idx);
+                let agent = agent.as_object().unwrap();
+                for (key, value) in agent {
+                    let path = format!("{}.{}", at, key);
+                    let ports = || value.as_array().is_some_and(|ports| ports.iter().all(|p| p.as_u64().is_some_and(|p| (1..=65535).contains(&p))));
+                    let message = match key.as_str() {
+                        "label" if !value.is_string() => format!("`label` at {} must be a string, not {}", at, kind_of(value)),
+                        "expected_ports" if !ports() => format!("`expected_ports` at {} must be a list of port numbers", at),
+                        "label" | "expected_ports" => continue,
+                        _ => format!("unknown field `{}` at {}{}", key, at, hint(key, AGENT_KEYS)),
+                    };
+                    problems.push(located(lines, &path, message));
+                }
+                if !agent.contains_key("label") {
+                    problems.push(located(lines, &at, format!("{}: missing `label`", at)));
+                }
+            }
+        }
+        None => {}
+        Some(_) => {
+            let path = format!("{}agent", prefix);
+            problems.push(located(lines, &path, format!("`{}` must be an array of tables, written `[[{}]]`", path, path)));
+        }
+    }
+    match table.get("tunnel") {
+        Some(Value::Object(tunnel)) => {
+            let at = format!("{}tunnel", prefix);
+            for key in tunnel.keys().filter(|k| !TUNNEL_KEYS.contains(&k.as_str())) {
+                let message = format!("unknown field `{}` in [{}]{}", key, at, hint(key, TUNNEL_KEYS));
+                problems.push(located(lines, &format!("{}.{}", at, key), message));
+            }
+            match tunnel.get("headers") {
+                Some(Value::Object(headers)) => {
+                    for (name, value) in headers.iter().filter(|(_, v)| !v.is_string()) {
+                        let path = format!("{}.headers.{}", at, name);
+                        problems.push(located(lines, &path, format!("header `{}` must be a string, not {}", name, kind_of(value))));
+                    }
+                }
+                None => {}
+                Some(_) => {
+                    let path = format!("{}.headers", at);
+                    problems.push(located(lines, &path, format!("`{}` must be a table, written `[{}]`", path, path)));
+                }
+            }
+        }
+        None => {}
+        Some(_) => {
+            let path = format!("{}tunnel", prefix);
+            problems.push(located(lines, &path, format!("`{}` must be a table, written `[{}]`", path, path)));
+        }
+    }
+    match table.get("messages") {
+        Some(Value::Object(messages)) => {
+            let at = format!("{}messages", prefix);
+            for (key, value) in messages {
+                let path = format!("{}.{}", at, key);
+                let message = match value.as_str() {
+                    _ if !MESSAGE_KEYS.contains(&key.as_str()) => {
+                        format!("unknown field `{}` in [{}]{}", key, at, hint(key, MESSAGE_KEYS))
+                    }
+                    None => format!("`{}` must be a string, not {}", path, kind_of(value)),
+                    Some(template) => match unknown_placeholders(template).first() {
+                        Some(word) => {
+                            format!("`{}` uses unknown placeholder `{{{}}}` (known: {{name}}, {{url}}, {{error}})", path, word)
+                        }
+                        None => continue,
+                    },
+                };
+                problems.push(located(lines, &path, message));
+            }
+        }
+        None => {}
+        Some(_) => {
+            let path = format!("{}messages", prefix);
+            problems.push(located(lines, &path, format!("`{}` must be a table, written `[{}]`", path, path)));
+        }
+    }
+    let bots = match table.get("bot") {
+        Some(Value::Array(bots)) if bots.iter().all(Value::is_object) => bots.as_slice(),
+        None => &[],
+        Some(_) => {
+            let path = format!("{}bot", prefix);
+            problems.push(located(lines, &path, format!("`{}` must be an array of tables, written `[[{}]]`", path, path)));
+            &[]
+        }
+    };
+    let names: Vec<&'static str> = BOT_FIELDS.iter().map(|(name, _)| *name).collect();
+    for (idx, bot) in bots.iter().enumerate() {
+        let at = format!("{}bot[{}]", prefix, idx);
+        for (key, value) in bot.as_object().into_iter().flatten() {
+            let path = format!("{}.{}", at, key);
+            match BOT_FIELDS.iter().find(|(name, _)| name == key) {
+                None => problems.push(located(
+                    lines,
+                    &path,
+                    format!("unknown field `{}` at {}{}", key, at, hint(key, &names)),
+                )),
+                Some((_, shape)) if !shape.accepts(value) => {
+                    let mut message =
+                        format!("`{}` at {} must be {}, not {}", key, at, shape.describe(), kind_of(value));
+                    if let (Shape::OneOf(options), Some(v)) = (shape, value.as_str()) {
+                        message.push_str(&hint(v, options));
+                    }
+                    problems.push(located(lines, &path, message));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+/// The key of each of [`SPECS`] in the top level of `value` and in its
+/// `[profiles.NAME]` for `profile`, as [`file_text`] gives it.
+pub(crate) fn file_layers(value: &Value, profile: Option<&str>) -> HashMap<&'static str, FileLayer> {
+    let selected = profile.and_then(|name| value.get("profiles")?.get(name));
+    let get = |table: Option<&Value>, key: &str| file_text(table?.get(key)?);
+    SPECS
+        .iter()
+        .map(|spec| (spec.key, FileLayer { top: get(Some(value), spec.key), profile: get(selected, spec.key) }))
+        .collect()
+}
+
+/// Lays `profile` over the top level: its run-wide settings win, and
+/// each of its
+/// bots is merged field by field over the shared bot of the same name, or
+/// appended when there is none. Without a profile the top level is used
+/// as is.
+fn select_profile(value: Value, path: &str, profile: Option<&str>) -> Result<Value, ConfigError> {
+    let Value::Object(mut root) = value else { return Ok(value) };
+    let profiles = root.remove("profiles");
+    let Some(name) = profile else { return Ok(Value::Object(root)) };
+    let mut profiles = match profiles {
+        Some(Value::Object(profiles)) => profiles,
+        _ => serde_json::Map::new(),
+    };
+    let Some(Value::Object(selected)) = profiles.remove(name) else {
+        let mut available: Vec<String> = profiles.keys().cloned().collect();
+        available.sort();
+        return Err(ConfigError::UnknownProfile(path.to_string(), name.to_string(), available));
+    };
+    for (key, value) in selected {
+        match (key.as_str(), value) {
+            ("bot", Value::Array(overrides)) => {
+                let shared = root.entry("bot").or_insert_with(|| Value::Array(Vec::new()));
+                let Value::Array(shared) = shared else { continue };
+                for over in overrides {
+                    let existing = shared.iter_mut().find(|b| b.get("name").is_some() && b.get("name") == over.get("name"));
+                    match (existing, over) {
+                        (Some(Value::Object(base)), Value::Object(fields)) => base.extend(fields),
+                        (_, over) => shared.push(over),
+                    }
+                }
+            }
+            (_, value) => {
+                root.insert(key, value);
+            }
+        }
+    }
+    Ok(Value::Object(root))
+}
+
+/// Update types known to the Bot API; anything else in `allowed_updates` is
+/// passed through with a warning in case Telegram added it recently.
+pub static KNOWN_UPDATE_TYPES: &[&str] = &[
+    "message",
+    "edited_message",
+    "channel_post",
+    "edited_channel_post",
+    "business_connection",
+    "business_message",
+    "edited_business_message",
+    "deleted_business_messages",
+    "message_reaction",
+    "message_reaction_count",
+    "inline_query",
+    "chosen_inline_result",
+    "callback_query",
+    "shipping_query",
+    "pre_checkout_query",
+    "purchased_paid_media",
+    "poll",
+    "poll_answer",
+    "my_chat_member",
+    "chat_member",
+    "chat_join_request",
+    "chat_boost",
+    "removed_chat_boost",
+];
+
+pub fn default_bots(drop_pending: impl Into<DropPending>) -> Vec<BotBinding> {
+    let drop_pending = drop_pending.into();
+    PORT_TO_NAME
+        .iter()
+        .map(|(port, name)| BotBinding {
+            port: *port,
+            name: (*name).to_string(),
+            platform: Platform::Telegram,
+            application_id: None,
+            token_env: None,
+            drop_pending_updates: drop_pending,
+            allowed_updates: None,
+            max_connections: None,
+            ip_address: None,
+            health_path: None,
+            webhook_path: DEFAULT_WEBHOOK_PATH.to_string(),
+            secret_query: None,
+            secret_env: None,
+            secret: None,
+            priority: DEFAULT_PRIORITY,
+            stable_url: None,
+            timeout: None,
+            mode: BotMode::Webhook,
+        })
+        .collect()
+}
+
+/// [`default_bots`] with their webhook paths under `base`.
+fn default_bots_under(drop_pending: DropPending, base: Option<&str>) -> Vec<BotBinding> {
+    let mut bots = default_bots(drop_pending);
+    if let Some(base) = base {
+        for bot in &mut bots {
+            bot.webhook_path = join_webhook_path(&resolve_webhook_path(base, &bot.name), &bot.webhook_path);
+        }
+    }
+    bots
+}
+
+/// Expands `{name}` in a `webhook_path` template and percent-encodes
+/// everything outside `A-Z a-z 0-9 - . _ ~ /`.
+pub fn resolve_webhook_path(template: &str, name: &str) -> String {
+    let mut out = String::new();
+    for byte in template.replace("{name}", name).bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => out.push(byte as char),
+            other => out.push_str(&format!("%{:02X}", other)),
+        }
+    }
+    out
+}
+
+/// `path` under the `webhook_base_path` prefix `base`, with a leading `/`
+/// and runs of `/` collapsed, so `telegram/` and `/telegram` both give
+/// `/telegram/webhook` for `/webhook`. Like `webhook_path`, the prefix may
+/// use `{name}` and is percent-encoded by [`resolve_webhook_path`] first.
+pub fn join_webhook_path(base: &str, path: &str) -> String {
+    let mut out = String::new();
+    for c in format!("/{}/{}", base, path).chars() {
+        if c != '/' || !out.ends_with('/') {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Reads the bot table from `REBIND_CONFIG` (default `bots.toml`), falling
+/// back to [`PORT_TO_NAME`] when the file does not exist, with every
+/// run-wide setting resolved by [`crate::resolve::resolve_config`].
+/// Uses the profile named by `REBIND_PROFILE`, if any.
+pub fn load_bots() -> Result<Vec<BotBinding>, ConfigError> {
+    crate::resolve::resolve_config(&Default::default()).map(|resolved| resolved.table.bots)
+}
+
+/// How errors in the bot table at `path` name it.
+pub(crate) fn source_name(path: &str) -> &str {
+    if path == STDIN_CONFIG {
+        "<stdin>"
+    } else {
+        path
+    }
+}
+
+/// The bot table at `path`, parsed and shape-checked, or `None` when the
+/// file does not exist. A path of `-` ([`STDIN_CONFIG`]) reads the table
+/// from stdin, where a missing table is an error rather than a reason to
+/// use the built-in one.
+pub(crate) fn read_table(path: &str) -> Result<Option<Value>, ConfigError> {
+    if path == STDIN_CONFIG {
+        let src = read_stdin().map_err(|err| ConfigError::Io("<stdin>".to_string(), err))?;
+        return parse_value("<stdin>", &src).map(Some);
+    }
+    match fs::read_to_string(path) {
+        Ok(src) => parse_value(path, &src).map(Some),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(ConfigError::Io(path.to_string(), err)),
+    }
+}
+
+/// All of stdin, read once per process. Gives up after
+/// `REBIND_STDIN_TIMEOUT_SECS` (default 5) so a run nothing was piped into
+/// doesn't hang, and at once when stdin is a terminal.
+fn read_stdin() -> io::Result<String> {
+    static STDIN: OnceLock<Result<String, (io::ErrorKind, String)>> = OnceLock::new();
+    let read = STDIN.get_or_init(|| {
+        if io::stdin().is_terminal() {
+            return Err((io::ErrorKind::InvalidInput, "stdin is a terminal; pipe the bot table in".to_string()));
+        }
+        let timeout = Duration::from_secs(env_or("REBIND_STDIN_TIMEOUT_SECS", 5u64));
+        let (done, read) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut src = String::new();
+            let _ = done.send(io::stdin().read_to_string(&mut src).map(|_| src));
+        });
+        match read.recv_timeout(timeout) {
+            Ok(Ok(src)) => Ok(src),
+            Ok(Err(err)) => Err((err.kind(), err.to_string())),
+            Err(_) => Err((io::ErrorKind::TimedOut, format!("nothing arrived within {}s", timeout.as_secs()))),
+        }
+    });
+    read.clone().map_err(|(kind, message)| io::Error::new(kind, message))
+}
+
+/// Applies `REBIND_ONLY` (comma-separated allowlist) and then
+/// `REBIND_EXCLUDE` (denylist) to the bot table, logging every bot left
+/// out and why. Meant to run before any network activity.
+pub fn filter_bots(bots: Vec<BotBinding>) -> Vec<BotBinding> {
+    let list = |key: &str| -> Option<Vec<String>> {
+        let names: Vec<String> = env::var(key)
+            .ok()?
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+        (!names.is_empty()).then_some(names)
+    };
+    filter_bots_by(bots, list("REBIND_ONLY").as_deref(), &list("REBIND_EXCLUDE").unwrap_or_default())
+}
+
+fn filter_bots_by(bots: Vec<BotBinding>, only: Option<&[String]>, exclude: &[String]) -> Vec<BotBinding> {
+    let listed = only.into_iter().flatten().map(|name| ("REBIND_ONLY", name));
+    for (key, name) in listed.chain(exclude.iter().map(|name| ("REBIND_EXCLUDE", name))) {
+        if !bots.iter().any(|b| &b.name == name) {
+            log::warn!("[⚠️] {} names `{}`, which is not in the bot table", key, name);
+        }
+    }
+    bots.into_iter()
+        .filter(|bot| {
+            if only.is_some_and(|only| !only.contains(&bot.name)) {
+                log::info!("[🚫] {}: left out, not listed in REBIND_ONLY", bot.name);
+                return false;
+            }
+            if exclude.contains(&bot.name) {
+                log::info!("[🚫] {}: left out, listed in REBIND_EXCLUDE", bot.name);
+                return false;
+            }
+            true
+        })
+        .collect()
+}
+
+/// How many bots a `--canary` run binds: a percentage of them (`30%`) or a
+/// count (`2`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Canary {
+    Percent(u8),
+    Count(usize),
+}
+
+impl std::str::FromStr for Canary {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(pct) => match pct.trim().parse::<u8>() {
+                Ok(pct @ 1..=100) => Ok(Canary::Percent(pct)),
+                _ => Err(format!("canary percentage `{}` is not between 1% and 100%", s)),
+            },
+            None => match s.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(Canary::Count(n)),
+                _ => Err(format!("canary `{}` is neither a percentage (30%) nor a bot count (2)", s)),
+            },
+        }
+    }
+}
+
+impl Canary {
+    /// The canary's share of `bots`: the first ones by name, at least one,
+    /// so the same table always yields the same set and a bigger canary
+    /// only adds to it. Kept in table order.
+    pub fn select(self, bots: Vec<BotBinding>) -> Vec<BotBinding> {
+        let size = match self {
+            Canary::Percent(pct) => (bots.len() * usize::from(pct)).div_ceil(100).max(1),
+            Canary::Count(n) => n,
+        };
+        let mut names: Vec<&str> = bots.iter().map(|b| b.name.as_str()).collect();
+        names.sort_unstable();
+        names.truncate(size);
+        let chosen: Vec<String> = names.into_iter().map(str::to_string).collect();
+        bots.into_iter().filter(|bot| chosen.contains(&bot.name)).collect()
+    }
+}
+
+/// What a one-shot run must achieve to exit 0, from `REBIND_SUCCESS_POLICY`:
+/// every bot bound (`all`, the default), at least one (`any`), or at least
+/// N (`quorum:N`). Bound, unchanged and polling bots count as successes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SuccessPolicy {
+    #[default]
+    All,
+    Any,
+    Quorum(usize),
+}
+
+impl std::str::FromStr for SuccessPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "all" => Ok(SuccessPolicy::All),
+            "any" => Ok(SuccessPolicy::Any),
+            other => match other.strip_prefix("quorum:").map(|n| n.trim().parse::<usize>()) {
+                Some(Ok(n)) if n > 0 => Ok(SuccessPolicy::Quorum(n)),
+                _ => Err(format!("unknown success policy `{}` (expected all, any or quorum:N)", other)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for SuccessPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SuccessPolicy::All => write!(f, "all"),
+            SuccessPolicy::Any => write!(f, "any"),
+            SuccessPolicy::Quorum(n) => write!(f, "quorum:{}", n),
+        }
+    }
+}
+
+impl SuccessPolicy {
+    pub fn from_settings(settings: &[Setting]) -> Self {
+        parsed(settings, "success_policy").unwrap_or(SuccessPolicy::All)
+    }
+
+    /// Whether a run with `succeeded` successes and `failed` failures meets
+    /// the policy. A run that attempted nothing only meets `all` and `any`.
+    pub fn met(self, succeeded: usize, failed: usize) -> bool {
+        match self {
+            SuccessPolicy::All => failed == 0,
+            SuccessPolicy::Any => succeeded > 0 || failed == 0,
+            SuccessPolicy::Quorum(n) => succeeded >= n,
+        }
+    }
+}
+
+pub fn parse_bots(path: &str, src: &str, drop_pending: impl Into<DropPending>) -> Result<Vec<BotBinding>, ConfigError> {
+    parse_table(path, src, drop_pending, None).map(|table| table.bots)
+}
+
+/// Parses a bot table written as TOML, or as the equivalent JSON object
+/// when `src` starts with `{`. Only the table itself is consulted: the
+/// selected profile's `webhook_base_path` and `drop_pending_updates`, then
+/// the top level's, then `drop_pending`; the environment is not.
+pub fn parse_table(path: &str, src: &str, drop_pending: impl Into<DropPending>, profile: Option<&str>) -> Result<BotTable, ConfigError> {
+    let value = parse_value(path, src)?;
+    let settings = crate::resolve::table_settings(path, &file_layers(&value, profile), profile, |_| None, drop_pending.into());
+    build_table(path, Some(value), profile, &settings)
+}
+
+/// `src` parsed as TOML or JSON, with [`shape_problems`] reported.
+fn parse_value(path: &str, src: &str) -> Result<Value, ConfigError> {
+    let parsed = if src.trim_start().starts_with('{') {
+        serde_json::from_str(src).map(|value| (value, HashMap::new())).map_err(|e| e.to_string())
+    } else {
+        toml::parse_with_lines(src).map_err(|e| e.to_string())
+    };
+    let (value, lines) = parsed.map_err(|e| ConfigError::Parse(path.to_string(), e))?;
+    let problems = shape_problems(&value, &lines);
+    if !problems.is_empty() {
+        return Err(ConfigError::Invalid(path.to_string(), problems));
+    }
+    Ok(value)
+}
+
+/// The bot table in `value`, with `profile` laid over it and the resolved
+/// `drop_pending` and `webhook_base_path` of `settings` applied to its
+/// bots. Without a `value` (no file) it is the built-in table, which has
+/// no profiles to select.
+pub(crate) fn build_table(path: &str, value: Option<Value>, profile: Option<&str>, settings: &[Setting]) -> Result<BotTable, ConfigError> {
+    let drop_pending = value_of(settings, "drop_pending").and_then(|v| v.parse().ok()).unwrap_or_default();
+    let base_path = value_of(settings, "webhook_base_path");
+    let Some(value) = value else {
+        if let Some(name) = profile {
+            return Err(ConfigError::UnknownProfile(path.to_string(), name.to_string(), Vec::new()));
+        }
+        return Ok(BotTable {
+            bots: default_bots_under(drop_pending, base_path),
+            rewrites: Vec::new(),
+            agents: Vec::new(),
+            tunnel_headers: Vec::new(),
+            messages: Messages::default(),
+        });
+    };
+    let value = select_profile(value, path, profile)?;
+    let file: BotsFile =
+        serde_json::from_value(value).map_err(|e| ConfigError::Parse(path.to_string(), e.to_string()))?;
+
+    let mut problems = Vec::new();
+    let mut rewrites = Vec::new();
+    for (idx, raw) in file.rewrite.into_iter().enumerate() {
+        match Regex::new(&raw.pattern).and_then(|re| re.check_replacement(&raw.replace).map(|_| re)) {
+            Ok(pattern) => rewrites.push(UrlRewrite { pattern, replace: raw.replace }),
+            Err(err) => problems.push(format!("rewrite[{}]: pattern `{}`: {}", idx, raw.pattern, err)),
+        }
+    }
+    let mut agents: Vec<AgentPorts> = Vec::new();
+    for (idx, raw) in file.agent.into_iter().enumerate() {
+        let label = raw.label.trim().to_string();
+        if label.is_empty() {
+            problems.push(format!("agent[{}]: `label` is empty", idx));
+        } else if agents.iter().any(|a| a.label == label) {
+            problems.push(format!("agent `{}` is declared more than once", label));
+        } else {
+            agents.push(AgentPorts { label, expected_ports: raw.expected_ports });
+        }
+    }
+    let mut tunnel_headers = Vec::new();
+    for (name, value) in file.tunnel.headers {
+        let value = value.as_str().unwrap_or_default().to_string();
+        if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+            problems.push(format!("tunnel.headers: `{}` is not a valid header name", name));
+        } else if hyper::header::HeaderValue::from_str(&value).is_err() {
+            problems.push(format!("tunnel.headers: the value of `{}` is not a valid header value", name));
+        } else {
+            tunnel_headers.push((name, value));
+        }
+    }
+    let defaults = Messages::default();
+    let messages = Messages {
+        success: file.messages.success.unwrap_or(defaults.success),
+        failure: file.messages.failure.unwrap_or(defaults.failure),
+        skip: file.messages.skip.unwrap_or(defaults.skip),
+    };
+    let mut bots: Vec<BotBinding> = Vec::new();
+    for (idx, raw) in file.bot.into_iter().enumerate() {
+        let label = match &raw.name {
+            Some(name) => format!("bot[{}] ({})", idx, name),
+            None => format!("bot[{}]", idx),
+        };
+        let port = match &raw.port {
+            Some(Value::Number(n)) => n.as_u64().and_then(|p| u16::try_from(p).ok()),
+            Some(Value::String(s)) => s.trim().parse::<u16>().ok(),
+            _ => None,
+        };
+        let port = match (port, &raw.port) {
+            (Some(0), _) | (None, Some(_)) => {
+                problems.push(format!("{}: port {} is not a valid port number", label, raw.port.as_ref().unwrap()));
+                continue;
+            }
+            (None, None) => {
+                problems.push(format!("{}: missing `port`", label));
+                continue;
+            }
+            (Some(p), _) => p,
+        };
+        let name = match raw.name {
+            Some(name) if !name.trim().is_empty() => name,
+            _ => {
+                problems.push(format!("{}: missing `name`", label));
+                continue;
+            }
+        };
+        if bots.iter().any(|b| b.name == name) {
+            problems.push(format!("bot name `{}` is declared more than once", name));
+            continue;
+        }
+        let max_connections = match raw.max_connections {
+            Some(n @ 1..=100) => Some(n as u32),
+            Some(n) => {
+                problems.push(format!("{}: max_connections {} is outside 1..=100", label, n));
+                continue;
+            }
+            None => None,
+        };
+        let timeout = match raw.timeout_secs {
+            Some(n @ 1..=600) => Some(Duration::from_secs(n as u64)),
+            Some(n) => {
+                problems.push(format!("{}: timeout_secs {} is outside 1..=600", label, n));
+                continue;
+            }
+            None => None,
+        };
+        let priority = match raw.priority {
+            Some(n @ 0..=100) => n,
+            Some(n) => {
+                problems.push(format!("{}: priority {} is outside 0..=100", label, n));
+                continue;
+            }
+            None => DEFAULT_PRIORITY,
+        };
+        if raw.platform == Platform::Discord && raw.application_id.as_deref().is_none_or(str::is_empty) {
+            problems.push(format!("{}: discord bots need `application_id`", label));
+            continue;
+        }
+        if raw.platform == Platform::Slack && raw.application_id.as_deref().is_none_or(str::is_empty) {
+            problems.push(format!("{}: slack bots need `application_id` (the app ID)", label));
+            continue;
+        }
+        if raw.mode == BotMode::Poll && raw.platform != Platform::Telegram {
+            problems.push(format!("{}: mode = \"poll\" only applies to telegram bots", label));
+            continue;
+        }
+        if let Some(path) = raw.health_path.as_deref().filter(|p| !p.starts_with('/')) {
+            problems.push(format!("{}: health_path `{}` must start with `/`", label, path));
+            continue;
+        }
+        let webhook_path = match raw.webhook_path.as_deref() {
+            None => DEFAULT_WEBHOOK_PATH.to_string(),
+            Some(path) if path.starts_with('/') => resolve_webhook_path(path, &name),
+            Some(path) => {
+                problems.push(format!("{}: webhook_path `{}` must start with `/`", label, path));
+                continue;
+            }
+        };
+        let webhook_path = match base_path {
+            Some(base) => join_webhook_path(&resolve_webhook_path(base, &name), &webhook_path),
+            None => webhook_path,
+        };
+        // Bots may share a port when one server routes them by path, but
+        // two bots on the same URL would overwrite each other's webhook.
+        if let Some(other) = bots.iter().find(|b| b.port == port && b.webhook_path == webhook_path) {
+            problems.push(format!(
+                "port {} is declared by both `{}` and `{}` with webhook_path `{}`; give each its own webhook_path",
+                port, other.name, name, webhook_path
+            ));
+            continue;
+        }
+        let secret_query = match (raw.secret_mode.as_deref(), raw.secret_param) {
+            (None | Some("header"), None) => None,
+            (Some("query"), param) => Some(param.unwrap_or_else(|| DEFAULT_SECRET_PARAM.to_string())),
+            (None | Some("header"), Some(_)) => {
+                problems.push(format!("{}: secret_param needs secret_mode = \"query\"", label));
+                continue;
+            }
+            (Some(other), _) => {
+                problems.push(format!("{}: unknown secret_mode `{}` (expected header or query)", label, other));
+                continue;
+            }
+        };
+        let stable_url = raw.stable_url.map(|url| url.trim().trim_end_matches('/').to_string());
+        if let Some(url) = stable_url.as_deref().filter(|url| !url.starts_with("https://")) {
+            problems.push(format!("{}: stable_url `{}` must be an https:// URL", label, url));
+            continue;
+        }
+        if raw.secret.is_some() && raw.secret_env.is_some() {
+            problems.push(format!("{}: set either secret or secret_env, not both", label));
+            continue;
+        }
+        for kind in raw.allowed_updates.iter().flatten() {
+            if !KNOWN_UPDATE_TYPES.contains(&kind.as_str()) {
+                log::warn!("[⚠️] {}: unrecognized allowed_updates entry `{}`", label, kind);
+            }
+        }
+        bots.push(BotBinding {
+            port,
+            name,
+            platform: raw.platform,
+            application_id: raw.application_id,
+            token_env: raw.token_env,
+            drop_pending_updates: raw.drop_pending_updates.map_or(drop_pending, DropPending::from),
+            allowed_updates: raw.allowed_updates,
+            max_connections,
+            ip_address: raw.ip_address,
+            health_path: raw.health_path,
+            webhook_path,
+            secret_query,
+            secret_env: raw.secret_env,
+            secret: raw.secret,
+            priority,
+            stable_url,
+            timeout,
+            mode: raw.mode,
+        });
+    }
+
+    if problems.is_empty() {
+        Ok(BotTable { bots, rewrites, agents, tunnel_headers, messages })
+    } else {
+        Err(ConfigError::Invalid(path.to_string(), problems))
+    }
+}
+
+/// Parses `key` from the environment, warning and using `default` when the
+/// value is malformed.
+pub fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(raw) if !raw.trim().is_empty() => match raw.trim().parse() {
+            Ok(value) => value,
+            Err(_) => {
+                log::warn!("[⚠️] Ignoring invalid {}={:?}", key, raw);
+                default
+            }
+        },
+        _ => default,
+    }
+}
+
+/// True when `key` is set to `1`, `true` or `yes`.
+pub fn env_flag(key: &str) -> bool {
+    matches!(
+        env::var(key).map(|v| v.trim().to_ascii_lowercase()).as_deref(),
+        Ok("1") | Ok("true") | Ok("yes")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_path_template() {
+        assert_eq!(resolve_webhook_path("/tg/{name}/hook", "gpt4o"), "/tg/gpt4o/hook");
+        assert_eq!(resolve_webhook_path("/hook/{name}", "my bot?"), "/hook/my%20bot%3F");
+
+        let src = "[[bot]]\nport = 9977\nname = \"gpt4o\"\nwebhook_path = \"/tg/{name}/hook\"\n\n[[bot]]\nport = 9988\nname = \"mistral\"\n";
+        let bots = parse_bots("bots.toml", src, false).unwrap();
+        assert_eq!(bots[0].webhook_path, "/tg/gpt4o/hook");
+        assert_eq!(bots[1].webhook_path, DEFAULT_WEBHOOK_PATH);
+
+        let err = parse_bots("bots.toml", "[[bot]]\nport = 1\nname = \"a\"\nwebhook_path = \"hook\"\n", false).unwrap_err();
+        assert!(err.to_string().contains("must start with `/`"), "{}", err);
+    }
+
+    #[test]
+    fn base_path_prefixes_every_webhook_path() {
+        assert_eq!(join_webhook_path("telegram/", "/webhook"), "/telegram/webhook");
+        assert_eq!(join_webhook_path("//telegram", "//gpt4o//webhook"), "/telegram/gpt4o/webhook");
+        assert_eq!(join_webhook_path("", "/webhook"), "/webhook");
+
+        let src = "webhook_base_path = \"/telegram/\"\n\n[[bot]]\nport = 9977\nname = \"gpt4o\"\nwebhook_path = \"/{name}/webhook\"\n\n\
+                   [[bot]]\nport = 9988\nname = \"mistral\"\n\n[profiles.v2]\nwebhook_base_path = \"/tg/v2\"\n";
+        let paths = |profile| -> Vec<String> {
+            parse_table("bots.toml", src, false, profile).unwrap().bots.into_iter().map(|b| b.webhook_path).collect()
+        };
+        assert_eq!(paths(None), ["/telegram/gpt4o/webhook", "/telegram/webhook"]);
+        assert_eq!(paths(Some("v2")), ["/tg/v2/gpt4o/webhook", "/tg/v2/webhook"]);
+    }
+
+    #[test]
+    fn typos_name_the_field_and_line() {
+        let src = "[[bot]]\nport = 9977\nname = \"gpt4o\"\n\n[[bot]]\nport = 9988\nname = \"mistral\"\n\
+                   platform = \"discrod\"\nallowed_update = [\"message\"]\ndrop_pending_updates = \"yes\"\n";
+        let problems = match parse_bots("bots.toml", src, false) {
+            Err(ConfigError::Invalid(_, problems)) => problems,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            problems,
+            [
+                "line 8: `platform` at bot[1] must be one of \"telegram\", \"discord\", \"slack\", not \"discrod\", did you mean `discord`?",
+                "line 9: unknown field `allowed_update` at bot[1], did you mean `allowed_updates`?",
+                "line 10: `drop_pending_updates` at bot[1] must be true or false, not \"yes\"",
+            ]
+        );
+
+        let err = parse_bots("bots.toml", "[bots]\nport = 1\n", false).unwrap_err();
+        assert!(err.to_string().contains("line 1: unknown top-level key `bots`, did you mean `bot`?"), "{}", err);
+        let err = parse_bots("bots.toml", "[[bot]]\nport = 1\nname = \"a\"\npriority = 101\n", false).unwrap_err();
+        assert!(err.to_string().contains("bot[0] (a): priority 101 is outside 0..=100"), "{}", err);
+        let err = parse_bots("bots.toml", "[[bot]]\nport = 1\nname = \"a\"\nstable_url = \"http://a.example\"\n", false).unwrap_err();
+        assert!(err.to_string().contains("bot[0] (a): stable_url `http://a.example` must be an https:// URL"), "{}", err);
+    }
+
+    #[test]
+    fn per_bot_timeouts_override_the_global_one() {
+        let src = "[[bot]]\nport = 1\nname = \"slow\"\ntimeout_secs = 30\n\n[[bot]]\nport = 2\nname = \"fast\"\n";
+        let bots = parse_bots("bots.toml", src, false).unwrap();
+        let global = crate::RetryPolicy { timeout: Duration::from_secs(10), ..crate::RetryPolicy::from_settings(&[]) };
+        assert_eq!(global.for_bot(&bots[0]).timeout, Duration::from_secs(30));
+        assert_eq!(global.for_bot(&bots[1]).timeout, Duration::from_secs(10));
+
+        let err = parse_bots("bots.toml", "[[bot]]\nport = 1\nname = \"a\"\ntimeout_secs = 0\n", false).unwrap_err();
+        assert!(err.to_string().contains("bot[0] (a): timeout_secs 0 is outside 1..=600"), "{}", err);
+    }
+
+    #[test]
+    fn message_templates_only_take_known_placeholders() {
+        let src = "[messages]\nsuccess = \"OK {name} -> {url}\"\n\n[[bot]]\nport = 1\nname = \"a\"\n";
+        let table = parse_table("bots.toml", src, false, None).unwrap();
+        assert_eq!(Messages::render(&table.messages.success, "a", "https://a.ngrok.io/webhook", ""), "OK a -> https://a.ngrok.io/webhook");
+        assert_eq!(table.messages.failure, Messages::default().failure);
+
+        let err = parse_table("bots.toml", "[messages]\nfailure = \"FAIL {name}: {err}\"\n", false, None).unwrap_err();
+        assert!(err.to_string().contains("line 2: `messages.failure` uses unknown placeholder `{err}` (known: {name}, {url}, {error})"), "{}", err);
+        let err = parse_table("bots.toml", "[messages]\nskipped = \"SKIP {name}\"\n", false, None).unwrap_err();
+        assert!(err.to_string().contains("unknown field `skipped` in [messages], did you mean `skip`?"), "{}", err);
+    }
+
+    #[test]
+    fn canaries_take_the_first_bots_by_name() {
+        let names = |bots: Vec<BotBinding>| bots.into_iter().map(|b| b.name).collect::<Vec<_>>();
+        // deepseek, gpt4o, mistral by name; table order is gpt4o, mistral, deepseek.
+        assert_eq!(names(Canary::Percent(30).select(default_bots(false))), ["deepseek"]);
+        assert_eq!(names(Canary::Percent(50).select(default_bots(false))), ["gpt4o", "deepseek"]);
+        assert_eq!(names(Canary::Count(2).select(default_bots(false))), ["gpt4o", "deepseek"]);
+        assert_eq!(names(Canary::Percent(100).select(default_bots(false))).len(), 3);
+        assert_eq!("30%".parse(), Ok(Canary::Percent(30)));
+        assert_eq!("2".parse(), Ok(Canary::Count(2)));
+        assert!("0%".parse::<Canary>().is_err() && "0".parse::<Canary>().is_err() && "half".parse::<Canary>().is_err());
+    }
+
+    #[test]
+    fn success_policies_count_what_they_need() {
+        assert!(SuccessPolicy::All.met(3, 0) && !SuccessPolicy::All.met(2, 1));
+        assert!(SuccessPolicy::Any.met(1, 2) && !SuccessPolicy::Any.met(0, 3) && SuccessPolicy::Any.met(0, 0));
+        assert!(SuccessPolicy::Quorum(2).met(2, 1) && !SuccessPolicy::Quorum(2).met(1, 0));
+        assert_eq!("quorum:2".parse(), Ok(SuccessPolicy::Quorum(2)));
+        assert_eq!(SuccessPolicy::Quorum(2).to_string(), "quorum:2");
+        assert!("quorum:0".parse::<SuccessPolicy>().is_err() && "most".parse::<SuccessPolicy>().is_err());
+    }
+
+    #[test]
+    fn only_telegram_bots_can_poll() {
+        let bots = parse_bots("bots.toml", "[[bot]]\nport = 1\nname = \"dev\"\nmode = \"poll\"\n", false).unwrap();
+        assert_eq!(bots[0].mode, BotMode::Poll);
+        assert!(!bots[0].needs_tunnel());
+
+        let src = "[[bot]]\nport = 1\nname = \"a\"\nplatform = \"discord\"\napplication_id = \"1\"\nmode = \"poll\"\n";
+        let err = parse_bots("bots.toml", src, false).unwrap_err();
+        assert!(err.to_string().contains("bot[0] (a): mode = \"poll\" only applies to telegram bots"), "{}", err);
+    }
+
+    #[test]
+    fn profiles_merge_over_the_shared_table() {
+        let src = "api_base = \"https://tg.example\"\n\n[[bot]]\nport = 9977\nname = \"gpt4o\"\n\n\
+                   [profiles.staging]\napi_base = \"http://127.0.0.1:8081/\"\n\n\
+                   [[profiles.staging.bot]]\nname = \"gpt4o\"\nport = 19977\n\n\
+                   [[profiles.staging.bot]]\nport = 19988\nname = \"mistral\"\n\n[profiles.prod]\n";
+        let shared = parse_table("bots.toml", src, false, None).unwrap();
+        assert_eq!(shared.bots.len(), 1);
+
+        let staging = parse_table("bots.toml", src, false, Some("staging")).unwrap();
+        let ports: Vec<_> = staging.bots.iter().map(|b| (b.name.as_str(), b.port)).collect();
+        assert_eq!(ports, [("gpt4o", 19977), ("mistral", 19988)]);
+        let layers = file_layers(&parse_value("bots.toml", src).unwrap(), Some("staging"));
+        assert_eq!(layers["api_base"], FileLayer { top: Some("https://tg.example".to_string()), profile: Some("http://127.0.0.1:8081/".to_string()) });
+        assert_eq!(parse_table("bots.toml", src, false, Some("prod")).unwrap(), shared);
+
+        let err = parse_table("bots.toml", src, false, Some("dev")).unwrap_err();
+        assert_eq!(err.to_string(), "no profile `dev` in bots.toml (available: prod, staging)");
+        let err = parse_bots("bots.toml", "[profiles.staging]\napi_bsae = \"x\"\n", false).unwrap_err();
+        assert!(err.to_string().contains("line 2: unknown key `api_bsae` in profile `staging`, did you mean `api_base`?"), "{}", err);
+        let err = parse_bots("bots.toml", "drop_pending_updates = \"sometimes\"\n", false).unwrap_err();
+        assert!(err.to_string().contains("line 1: `drop_pending_updates` must be true, false or \"first-only\""), "{}", err);
+    }
+
+    #[test]
+    fn json_tables_parse_like_toml() {
+        let toml = parse_table("bots.toml", "api_base = \"https://tg.example\"\n\n[[bot]]\nport = 9977\nname = \"gpt4o\"\n", false, None);
+        let json = parse_table("<stdin>", r#"{"api_base": "https://tg.example", "bot": [{"port": 9977, "name": "gpt4o"}]}"#, false, None);
+        assert_eq!(json.unwrap(), toml.unwrap());
+
+        let err = parse_bots("<stdin>", r#"{"bot": [{"port": 9977, "nmae": "gpt4o"}]}"#, false).unwrap_err();
+        assert!(err.to_string().contains("unknown field `nmae` at bot[0], did you mean `name`?"), "{}", err);
+        let err = parse_bots("<stdin>", "{\"bot\": [", false).unwrap_err();
+        assert!(err.to_string().starts_with("cannot parse <stdin>: EOF while parsing a list"), "{}", err);
+    }
+
+    #[test]
+    fn agents_list_the_ports_they_should_serve() {
+        let src = "[[agent]]\nlabel = \"main\"\nexpected_ports = [9977, 9988]\n\n[[agent]]\nlabel = \"alt\"\n";
+        let table = parse_table("bots.toml", src, false, None).unwrap();
+        assert_eq!(
+            table.agents,
+            [
+                AgentPorts { label: "main".to_string(), expected_ports: vec![9977, 9988] },
+                AgentPorts { label: "alt".to_string(), expected_ports: Vec::new() },
+            ]
+        );
+        let err = parse_bots("bots.toml", "[[agent]]\nlabel = \"main\"\nexpected_ports = [0]\nexpected_port = 1\n", false).unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("line 3: `expected_ports` at agent[0] must be a list of port numbers"), "{}", err);
+        assert!(err.contains("line 4: unknown field `expected_port` at agent[0], did you mean `expected_ports`?"), "{}", err);
+    }
+
+    #[test]
+    fn tunnel_headers_are_checked_and_kept() {
+        let src = "[tunnel.headers]\nAuthorization = \"Bearer abc\"\nX-Api-Key = \"k\"\n";
+        let table = parse_table("bots.toml", src, false, None).unwrap();
+        assert_eq!(
+            table.tunnel_headers,
+            [("Authorization".to_string(), "Bearer abc".to_string()), ("X-Api-Key".to_string(), "k".to_string())]
+        );
+        let err = parse_bots("bots.toml", "[tunnel]\nheader = 1\n\n[tunnel.headers]\nX-Retries = 3\n", false).unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("line 2: unknown field `header` in [tunnel], did you mean `headers`?"), "{}", err);
+        assert!(err.contains("line 5: header `X-Retries` must be a string, not an integer"), "{}", err);
+        let err = parse_bots("bots.toml", "[tunnel.headers]\n\"Bad Name\" = \"x\"\n", false).unwrap_err();
+        assert!(err.to_string().contains("tunnel.headers: `Bad Name` is not a valid header name"), "{}", err);
+    }
+
+    #[test]
+    fn rewrites_apply_in_order() {
+        let src = "[[rewrite]]\npattern = '^https://[a-z0-9]+\\.ngrok\\.io$'\nreplace = \"https://bots.example.com\"\n\n\
+                   [[rewrite]]\npattern = '^https://bots\\.(.*)$'\nreplace = \"https://edge.$1\"\n";
+        let table = parse_table("bots.toml", src, false, None).unwrap();
+        let mut urls = HashMap::from([
+            ("gpt4o".to_string(), "https://abc123.ngrok.io".to_string()),
+            ("mistral".to_string(), "https://other.example".to_string()),
+        ]);
+        rewrite_urls(&table.rewrites, &mut urls);
+        assert_eq!(urls["gpt4o"], "https://edge.example.com");
+        assert_eq!(urls["mistral"], "https://other.example");
+
+        let err = parse_bots("bots.toml", "[[rewrite]]\npattern = \"(a\"\nreplace = \"$2\"\n", false).unwrap_err();
+        assert!(err.to_string().contains("rewrite[0]: pattern `(a`: unclosed `(` at offset 0"), "{}", err);
+        let err = parse_bots("bots.toml", "[[rewrite]]\npattern = \"a\"\n", false).unwrap_err();
+        assert!(err.to_string().contains("line 1: rewrite[0]: missing `replace`"), "{}", err);
+    }
+
+    #[test]
+    fn allowlist_then_denylist() {
+        let names = |bots: Vec<BotBinding>| bots.into_iter().map(|b| b.name).collect::<Vec<_>>();
+        let list = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let only = list(&["gpt4o", "deepseek", "mixtral"]);
+        assert_eq!(names(filter_bots_by(default_bots(false), Some(&only), &[])), ["gpt4o", "deepseek"]);
+        assert_eq!(names(filter_bots_by(default_bots(false), Some(&only), &list(&["deepseek"]))), ["gpt4o"]);
+        assert_eq!(names(filter_bots_by(default_bots(false), None, &list(&["gpt4o"]))), ["mistral", "deepseek"]);
+    }
+
+    #[test]
+    fn bots_may_share_a_port_on_different_paths() {
+        let shared = "[[bot]]\nport = 9977\nname = \"a\"\nwebhook_path = \"/{name}\"\n\n\
+                      [[bot]]\nport = 9977\nname = \"b\"\nwebhook_path = \"/{name}\"\n";
+        let bots = parse_bots("bots.toml", shared, false).unwrap();
+        assert_eq!((bots[0].port, bots[1].port), (9977, 9977));
+
+        let clash = "[[bot]]\nport = 9977\nname = \"a\"\n\n[[bot]]\nport = 9977\nname = \"b\"\n";
+        let err = parse_bots("bots.toml", clash, false).unwrap_err();
+        assert!(err.to_string().contains("give each its own webhook_path"), "{}", err);
+    }
+
+    #[test]
+    fn first_only_drops_pending_updates_until_the_bot_is_on_record() {
+        assert_eq!("First-Only".parse(), Ok(DropPending::FirstOnly));
+        assert_eq!("yes".parse(), Ok(DropPending::Always));
+        assert!("sometimes".parse::<DropPending>().is_err());
+
+        let src = "[[bot]]\nport = 9977\nname = \"a\"\n\n[[bot]]\nport = 9988\nname = \"b\"\ndrop_pending_updates = false\n";
+        let bots = parse_bots("bots.toml", src, DropPending::FirstOnly).unwrap();
+        assert_eq!(bots[1].drop_pending_updates, DropPending::Never);
+        assert_eq!(bots[0].settle_drop_pending(false).drop_pending_updates, DropPending::Always);
+        assert_eq!(bots[0].settle_drop_pending(true).drop_pending_updates, DropPending::Never);
+        assert!(matches!(bots[1].settle_drop_pending(false), Cow::Borrowed(_)));
+    }
+}
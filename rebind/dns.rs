@@ -0,0 +1,98 @@
+//! Resolver cache shared by every connection of a client, so concurrent
+//! requests to `api.telegram.org` share one lookup instead of each hitting
+//! a flaky resolver on its own.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::service::Service;
+
+use crate::config::env_or;
+
+/// How long a lookup is reused by default; watch mode picks up DNS changes
+/// after at most this long.
+pub const DEFAULT_DNS_TTL_SECS: u64 = 300;
+
+type Slot = Arc<tokio::sync::Mutex<Option<(Vec<SocketAddr>, Instant)>>>;
+
+#[derive(Debug, Clone)]
+pub struct CachingResolver {
+    inner: GaiResolver,
+    /// Zero disables caching.
+    ttl: Duration,
+    slots: Arc<Mutex<HashMap<String, Slot>>>,
+}
+
+impl CachingResolver {
+    pub fn new(ttl: Duration) -> Self {
+        CachingResolver { inner: GaiResolver::new(), ttl, slots: Arc::default() }
+    }
+
+    /// `REBIND_DNS_TTL_SECS` (default 300; 0 resolves every connection).
+    pub fn from_env() -> Self {
+        CachingResolver::new(Duration::from_secs(env_or("REBIND_DNS_TTL_SECS", DEFAULT_DNS_TTL_SECS)))
+    }
+
+    /// Localhost is answered from `/etc/hosts` anyway, so tunnel agent
+    /// lookups skip the cache.
+    fn caches(&self, host: &str) -> bool {
+        !self.ttl.is_zero() && host != "localhost" && !host.ends_with(".localhost")
+    }
+}
+
+impl Service<Name> for CachingResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let mut inner = self.inner.clone();
+        if !self.caches(name.as_str()) {
+            return Box::pin(async move { Ok(inner.call(name).await?.collect::<Vec<_>>().into_iter()) });
+        }
+        let slot = self.slots.lock().unwrap().entry(name.as_str().to_ascii_lowercase()).or_default().clone();
+        let ttl = self.ttl;
+        Box::pin(async move {
+            // Holding the slot while resolving makes concurrent callers for
+            // the same host wait for this lookup rather than start their own.
+            let mut cached = slot.lock().await;
+            if let Some((addrs, resolved)) = cached.as_ref().filter(|(_, at)| at.elapsed() < ttl) {
+                log::debug!("resolved {} from cache ({:?} old)", name, resolved.elapsed());
+                return Ok(addrs.clone().into_iter());
+            }
+            let addrs: Vec<SocketAddr> = inner.call(name).await?.collect();
+            *cached = Some((addrs.clone(), Instant::now()));
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn lookups_are_reused_within_the_ttl() {
+        let mut resolver = CachingResolver::new(Duration::from_secs(60));
+        assert!(!resolver.caches("localhost"));
+        let name = Name::from_str("api.example.invalid").unwrap();
+        let slot: Slot = Arc::default();
+        let addr: SocketAddr = "192.0.2.7:0".parse().unwrap();
+        *slot.lock().await = Some((vec![addr], Instant::now()));
+        resolver.slots.lock().unwrap().insert(name.as_str().to_string(), slot);
+        // Resolving the `.invalid` name for real would fail.
+        assert_eq!(resolver.call(name).await.unwrap().collect::<Vec<_>>(), vec![addr]);
+    }
+}
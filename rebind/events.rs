@@ -0,0 +1,123 @@
+//! Progress of a run as it happens, for embedding the rebinder in a
+//! supervisor. Set [`crate::RebindConfig::events`] to receive them; sending
+//! never blocks the run, and a dropped receiver is ignored.
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::{Outcome, RebindReport};
+
+pub type EventSender = mpsc::UnboundedSender<RebindEvent>;
+pub type EventReceiver = mpsc::UnboundedReceiver<RebindEvent>;
+
+/// A channel to hand to [`crate::RebindConfig::events`].
+pub fn channel() -> (EventSender, EventReceiver) {
+    mpsc::unbounded_channel()
+}
+
+/// Counts of a finished run, as in [`RebindReport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunSummary {
+    pub bound: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+impl From<&RebindReport> for RunSummary {
+    fn from(report: &RebindReport) -> Self {
+        RunSummary {
+            bound: report.bound(),
+            unchanged: report.unchanged(),
+            failed: report.failed(),
+            skipped: report.skipped(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RebindEvent {
+    /// The tunnel provider is being queried.
+    DiscoveryStarted,
+    /// A bot's public URL was discovered.
+    TunnelFound { name: String, url: String },
+    /// Discovery is over: how many bots got a tunnel, or why it failed.
+    DiscoveryFinished { tunnels: usize, error: Option<String> },
+    BindStarted { name: String },
+    /// `outcome` is the report's status for the bot (`bound`, `unchanged`,
+    /// `polling`, `failed`, `unhealthy` or `no tunnel`), `detail` the error
+    /// or webhook URL that goes with it and `status` the HTTP status of a
+    /// failed call.
+    BindResult { name: String, outcome: &'static str, detail: Option<String>, status: Option<u16>, duration: Duration },
+    RunComplete { summary: RunSummary },
+}
+
+impl RebindEvent {
+    pub(crate) fn bind_result(name: &str, outcome: &Outcome, duration: Duration) -> Self {
+        let detail = match outcome {
+            Outcome::Bound { webhook_url, .. } | Outcome::Unchanged { webhook_url } => Some(webhook_url.clone()),
+            Outcome::Polling { removed } => removed.clone(),
+            Outcome::NoTunnel => None,
+            Outcome::Unhealthy(problem) => Some(problem.clone()),
+            Outcome::Failed(err) => Some(err.to_string()),
+        };
+        let status = match outcome {
+            Outcome::Failed(err) => err.http_status(),
+            _ => None,
+        };
+        RebindEvent::BindResult { name: name.to_string(), outcome: outcome.label(), detail, status, duration }
+    }
+
+    /// `{"event": "bind_result", ...}`, one object per event.
+    pub fn to_json(&self) -> Value {
+        match self {
+            RebindEvent::DiscoveryStarted => json!({ "event": "discovery_started" }),
+            RebindEvent::TunnelFound { name, url } => json!({ "event": "tunnel_found", "bot": name, "url": url }),
+            RebindEvent::DiscoveryFinished { tunnels, error } => {
+                json!({ "event": "discovery_finished", "tunnels": tunnels, "error": error })
+            }
+            RebindEvent::BindStarted { name } => json!({ "event": "bind_started", "bot": name }),
+            RebindEvent::BindResult { name, outcome, detail, status, duration } => json!({
+                "event": "bind_result",
+                "bot": name,
+                "outcome": outcome,
+                "detail": detail,
+                "status": status,
+                "duration_ms": duration.as_millis() as u64,
+            }),
+            RebindEvent::RunComplete { summary } => json!({
+                "event": "run_complete",
+                "bound": summary.bound,
+                "unchanged": summary.unchanged,
+                "failed": summary.failed,
+                "skipped": summary.skipped,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BindError;
+
+    #[test]
+    fn bind_results_carry_the_report_status() {
+        let failed = RebindEvent::bind_result("gpt4o", &Outcome::Failed(BindError::Timeout), Duration::from_millis(12));
+        assert_eq!(
+            failed.to_json(),
+            json!({
+                "event": "bind_result",
+                "bot": "gpt4o",
+                "outcome": "failed",
+                "detail": "request timed out",
+                "status": null,
+                "duration_ms": 12,
+            })
+        );
+        let skipped = RebindEvent::bind_result("mistral", &Outcome::NoTunnel, Duration::ZERO);
+        assert!(matches!(skipped, RebindEvent::BindResult { outcome: "no tunnel", detail: None, .. }));
+    }
+}
@@ -0,0 +1,236 @@
+//! `--explain`: why a run did what it did to each bot, for when a rebind
+//! surprises someone. For every bot it says where the token came from,
+//! which agent's tunnel the URL was taken from and what normalizing and
+//! `[[rewrite]]` rules made of it, whether the webhook was checked after
+//! the bind, and the decision with the reason for it. The run-wide
+//! settings follow, each with the source that won. Meant for a person to
+//! read; `--format json` is for scripts.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::config::{rewrite_url, BotMode, UrlRewrite};
+use crate::resolve::{describe, Setting};
+use crate::state::{format_age, unix_now, State};
+use crate::tunnel::{normalize_public_url, tunnel_port, AgentStatus};
+use crate::verify::VerificationReport;
+use crate::{BotBinding, Outcome, RebindConfig, RebindReport};
+
+/// What the report alone can't tell about a bot.
+#[derive(Debug, Default)]
+struct Facts {
+    /// Where the token was looked up, e.g. `BOT_TOKEN_GPT4O (env)`.
+    token: String,
+    /// Bound with `--force`.
+    forced: bool,
+    /// Its tracked secret differs from the one in the state file.
+    rotated: bool,
+    /// How long ago the state file says it was bound with the same URL,
+    /// secret and options, within `REBIND_RECENT_BIND_SECS`.
+    recent: Option<Duration>,
+}
+
+/// The explanation for every bot of `config`, in table order, then
+/// `settings`. `previous` is the state file as it was before the run.
+pub fn explain(
+    config: &RebindConfig,
+    settings: &[Setting],
+    previous: &State,
+    report: &RebindReport,
+    verification: Option<&VerificationReport>,
+) -> String {
+    let secrets = config.secret_fingerprints();
+    let now = unix_now();
+    let mut out = String::new();
+    for bot in &config.bots {
+        let url = report.tunnels.get(&bot.name);
+        let facts = Facts {
+            token: format!("{} ({})", config.tokens.source(bot), config.tokens.name()),
+            forced: config.force,
+            rotated: crate::secret_rotated(bot, &secrets, previous),
+            recent: config.recent_bind.zip(url.and_then(|url| config.bind_key(bot, url))).and_then(|(window, key)| {
+                previous.recently_bound(&bot.name, &key, window, now)
+            }),
+        };
+        explain_bot(&mut out, bot, &facts, previous, report, &config.rewrites, verification);
+    }
+    if !settings.is_empty() {
+        let _ = write!(out, "settings\n{}", describe(settings));
+    }
+    out
+}
+
+fn explain_bot(
+    out: &mut String,
+    bot: &BotBinding,
+    facts: &Facts,
+    previous: &State,
+    report: &RebindReport,
+    rewrites: &[UrlRewrite],
+    verification: Option<&VerificationReport>,
+) {
+    let outcome = report.outcomes.iter().find(|o| o.bot == bot.name).map(|o| &o.outcome);
+    let url = report.tunnels.get(&bot.name);
+    let agent = url.and_then(|url| source(bot, url, &report.agents, rewrites));
+    let _ = writeln!(out, "{}", bot.name);
+    let _ = writeln!(out, "  token     {}", facts.token);
+
+    let found = match (url, &agent) {
+        _ if bot.mode == BotMode::Poll => "none; the bot polls".to_string(),
+        (Some(url), _) if bot.stable_url.as_ref() == Some(url) => format!("{}, the bot's stable_url", url),
+        (Some(url), Some(agent)) => format!("{}, {}", url, agent.how),
+        (Some(url), None) => format!("{}, from {}", url, report.provider.as_deref().unwrap_or("the tunnel provider")),
+        (None, _) => format!("none; no tunnel forwards to port {}", bot.port),
+    };
+    let _ = writeln!(out, "  url       {}", found);
+
+    let mut checked = match outcome {
+        Some(Outcome::Bound { verified: Ok(info), .. }) => format!("yes, getWebhookInfo reports {}", info.url),
+        Some(Outcome::Bound { verified: Err(problem), .. }) => format!("yes, and it found a problem: {}", problem),
+        Some(Outcome::Unchanged { .. }) if facts.recent.is_some() && !facts.forced && !facts.rotated => {
+            "no; the state file vouched for it".to_string()
+        }
+        Some(Outcome::Unchanged { .. } | Outcome::Polling { .. }) => "the live webhook was looked up first".to_string(),
+        _ => "no".to_string(),
+    };
+    if let Some(after) = verification.and_then(|v| v.bots.iter().find(|b| b.bot == bot.name)) {
+        let _ = write!(checked, "; after the run: {}", after.verdict.as_str());
+        if !after.problems.is_empty() {
+            let _ = write!(checked, " ({})", after.problems.join("; "));
+        }
+    }
+    let _ = writeln!(out, "  verified  {}", checked);
+
+    let before = previous.bindings.get(&bot.name);
+    let decision = match outcome {
+        None => "not tried this run".to_string(),
+        Some(Outcome::Bound { verified, .. }) => {
+            let why = match (before, url) {
+                _ if facts.forced => "--force".to_string(),
+                _ if facts.rotated => "secret changed".to_string(),
+                (None, _) => "no binding on record".to_string(),
+                (Some(before), Some(url)) if before != url => match &agent {
+                    Some(agent) => format!("new tunnel on agent `{}` (was {})", agent.label, before),
+                    None => format!("new tunnel (was {})", before),
+                },
+                _ => "live webhook didn't match".to_string(),
+            };
+            match verified {
+                Ok(_) => format!("{} → rebound", why),
+                Err(_) => format!("{} → rebound, but not to what was asked", why),
+            }
+        }
+        Some(Outcome::Unchanged { .. }) => match facts.recent {
+            Some(age) if !facts.forced && !facts.rotated => format!("recently bound elsewhere ({} ago) → skipped", format_age(age)),
+            _ if before.is_some() && before == url => "URL unchanged → skipped".to_string(),
+            _ => "live webhook already matches → skipped".to_string(),
+        },
+        Some(Outcome::Polling { removed: Some(removed) }) => format!("mode = \"poll\" → deleted the webhook to {}", removed),
+        Some(Outcome::Polling { removed: None }) => "mode = \"poll\", no webhook set → left alone".to_string(),
+        Some(Outcome::Failed(err)) => format!("bind failed → {}", err),
+        Some(Outcome::NoTunnel) => "no tunnel → skipped".to_string(),
+        Some(Outcome::Unhealthy(problem)) => format!("upstream unhealthy ({}) → skipped", problem),
+    };
+    let _ = writeln!(out, "  decision  {}", decision);
+}
+
+/// The agent whose tunnel became `url`, and how.
+struct Source {
+    label: String,
+    how: String,
+}
+
+/// The first tunnel of `agents` on `bot`'s port that, normalized and
+/// rewritten, comes out as `url`.
+fn source(bot: &BotBinding, url: &str, agents: &[AgentStatus], rewrites: &[UrlRewrite]) -> Option<Source> {
+    agents.iter().find_map(|agent| {
+        agent.tunnels.iter().filter(|t| tunnel_port(&t.addr) == Some(bot.port) && t.is_https()).find_map(|tunnel| {
+            let normalized = normalize_public_url(&tunnel.public_url).ok()?;
+            (rewrite_url(rewrites, &normalized) == url).then(|| {
+                let mut how = format!("from agent `{}` ({} → {})", agent.agent, tunnel.public_url, tunnel.addr);
+                if normalized != tunnel.public_url {
+                    let _ = write!(how, ", normalized to {}", normalized);
+                }
+                if normalized != url {
+                    how.push_str(", then rewritten");
+                }
+                Source { label: agent.agent.clone(), how }
+            })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::default_bots;
+    use crate::tunnel::{AgentState, Tunnel};
+    use crate::{BindError, BotOutcome, WebhookInfo};
+
+    #[test]
+    fn each_bot_gets_its_source_and_a_reasoned_decision() {
+        let bots = default_bots(false);
+        let (gpt4o, mistral, deepseek) = (&bots[0], &bots[1], &bots[2]);
+        let outcome = |bot: &BotBinding, outcome| BotOutcome { bot: bot.name.clone(), outcome, duration: Duration::ZERO };
+        let report = RebindReport {
+            outcomes: vec![
+                outcome(
+                    gpt4o,
+                    Outcome::Bound {
+                        webhook_url: "https://b.ngrok.io/webhook".to_string(),
+                        verified: Ok(WebhookInfo {
+                            url: "https://b.ngrok.io/webhook".to_string(),
+                            pending_update_count: 0,
+                            last_error_message: None,
+                            last_error_date: None,
+                            allowed_updates: None,
+                        }),
+                    },
+                ),
+                outcome(mistral, Outcome::Unchanged { webhook_url: "https://c.ngrok.io/webhook".to_string() }),
+                outcome(deepseek, Outcome::Failed(BindError::Timeout)),
+            ],
+            tunnels: [(gpt4o, "https://b.ngrok.io"), (mistral, "https://c.ngrok.io"), (deepseek, "https://d.ngrok.io")]
+                .map(|(bot, url)| (bot.name.clone(), url.to_string()))
+                .into(),
+            agents: vec![AgentStatus {
+                agent: "alt".to_string(),
+                state: AgentState::Ok,
+                tunnels: vec![Tunnel {
+                    public_url: "https://B.ngrok.io/".to_string(),
+                    addr: format!("http://localhost:{}", gpt4o.port),
+                    agent: "alt".to_string(),
+                    proto: Some("https".to_string()),
+                }],
+            }],
+            provider: Some("ngrok".to_string()),
+            ..RebindReport::default()
+        };
+        let previous = State {
+            bindings: [(gpt4o, "https://a.ngrok.io"), (mistral, "https://c.ngrok.io")]
+                .map(|(bot, url)| (bot.name.clone(), url.to_string()))
+                .into(),
+            ..State::default()
+        };
+        let mut out = String::new();
+        for bot in &bots {
+            explain_bot(&mut out, bot, &Facts::default(), &previous, &report, &[], None);
+        }
+        for line in [
+            "  url       https://b.ngrok.io, from agent `alt` (https://B.ngrok.io/ → http://localhost:9977), normalized to https://b.ngrok.io\n",
+            "  verified  yes, getWebhookInfo reports https://b.ngrok.io/webhook\n",
+            "  decision  new tunnel on agent `alt` (was https://a.ngrok.io) → rebound\n",
+            "  url       https://c.ngrok.io, from ngrok\n",
+            "  decision  URL unchanged → skipped\n",
+            "  decision  bind failed → request timed out\n",
+        ] {
+            assert!(out.contains(line), "missing {:?} in\n{}", line, out);
+        }
+
+        let recent = Facts { recent: Some(Duration::from_secs(90)), ..Facts::default() };
+        let mut out = String::new();
+        explain_bot(&mut out, mistral, &recent, &previous, &report, &[], None);
+        assert!(out.contains("  verified  no; the state file vouched for it\n"), "{}", out);
+        assert!(out.contains("  decision  recently bound elsewhere (1m ago) → skipped\n"), "{}", out);
+    }
+}
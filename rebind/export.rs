@@ -0,0 +1,152 @@
+//! `--export-config`: writes the bot table back out as TOML, with each
+//! bot's live webhook (from `getWebhookInfo`) pinned as its `stable_url`,
+//! for moving a hand-configured setup onto a bot table. Tokens are never
+//! written, and query strings are dropped since they may carry the secret.
+//! What Telegram couldn't tell us is left as a comment on the bot.
+
+use std::fmt::Write;
+
+use crate::config::{BotBinding, BotMode, DropPending, Platform, DEFAULT_PRIORITY, DEFAULT_WEBHOOK_PATH};
+use crate::{BindError, WebhookInfo};
+
+/// `value` as a TOML basic string; JSON's escapes are all valid there.
+fn quote(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+/// Splits a live webhook URL into the public URL to pin and the path to
+/// keep: the bot's own `webhook_path` when the URL ends with it, otherwise
+/// whatever path the URL has. `None` for a URL without a host.
+fn split_live(bot: &BotBinding, live: &str) -> Option<(String, String)> {
+    let url = live.split(['?', '#']).next().unwrap_or_default();
+    if let Some(base) = url.strip_suffix(&bot.webhook_path).filter(|base| base.contains("://")) {
+        return Some((base.to_string(), bot.webhook_path.clone()));
+    }
+    let (scheme, rest) = url.split_once("://")?;
+    let (host, path) = rest.split_once('/').map_or((rest, String::new()), |(host, path)| (host, format!("/{}", path)));
+    (!host.is_empty()).then(|| (format!("{}://{}", scheme, host), path))
+}
+
+/// The bot table for `bots`, with `live` holding each one's answer to
+/// `getWebhookInfo` in the same order.
+pub fn export_table(bots: &[BotBinding], live: &[(String, Result<WebhookInfo, BindError>)]) -> String {
+    let mut out = String::from(
+        "# Exported by `rebind --export-config`. Each bot's live webhook is pinned as\n\
+         # its stable_url; drop stable_url to go back to tunnel discovery. Tokens are\n\
+         # never written, and Telegram doesn't report secrets.\n",
+    );
+    for (bot, (_, info)) in bots.iter().zip(live) {
+        let mut notes = Vec::new();
+        let mut path = bot.webhook_path.clone();
+        let mut stable_url = None;
+        let mut allowed_updates = bot.allowed_updates.clone();
+        match info {
+            // A polling bot's live webhook is what the next run deletes.
+            _ if bot.mode == BotMode::Poll => {}
+            Ok(info) if info.url.is_empty() => notes.push("stable_url: no webhook is set".to_string()),
+            Ok(info) => {
+                match split_live(bot, &info.url) {
+                    Some((url, live_path)) => {
+                        if live_path != path {
+                            notes.push(format!("webhook_path: taken from the live webhook, not `{}`", path));
+                            path = live_path;
+                        }
+                        stable_url = Some(url);
+                    }
+                    None => notes.push("stable_url: the live webhook is not a URL".to_string()),
+                }
+                if info.url.contains('?') && bot.secret_query.is_none() {
+                    notes.push("the live webhook has a query string, which was left out".to_string());
+                }
+                if bot.platform == Platform::Telegram {
+                    allowed_updates = info.allowed_updates.clone().or(allowed_updates);
+                }
+            }
+            Err(err) => notes.push(format!("stable_url: getWebhookInfo failed: {}", err)),
+        }
+        if bot.platform == Platform::Telegram && bot.secret.is_none() {
+            notes.push(format!("secret: not recoverable from Telegram; bound with {} or TG_SECRET", bot.secret_var()));
+        }
+
+        let _ = write!(out, "\n[[bot]]\nport = {}\nname = {}\n", bot.port, quote(&bot.name));
+        for note in &notes {
+            let _ = writeln!(out, "# {}", note.replace('\n', " "));
+        }
+        match bot.platform {
+            Platform::Telegram => {}
+            Platform::Discord => out.push_str("platform = \"discord\"\n"),
+            Platform::Slack => out.push_str("platform = \"slack\"\n"),
+        }
+        if bot.mode == BotMode::Poll {
+            out.push_str("mode = \"poll\"\n");
+        }
+        let strings = [
+            ("stable_url", stable_url.as_deref()),
+            ("application_id", bot.application_id.as_deref()),
+            ("token_env", bot.token_env.as_deref()),
+            ("webhook_path", Some(path.as_str()).filter(|p| *p != DEFAULT_WEBHOOK_PATH)),
+            ("health_path", bot.health_path.as_deref()),
+            ("ip_address", bot.ip_address.as_deref()),
+            ("secret_mode", bot.secret_query.as_ref().map(|_| "query")),
+            ("secret_param", bot.secret_query.as_deref()),
+            ("secret_env", bot.secret_env.as_deref()),
+            ("secret", bot.secret.as_deref()),
+        ];
+        for (key, value) in strings.iter().filter_map(|(key, value)| Some((key, (*value)?))) {
+            let _ = writeln!(out, "{} = {}", key, quote(value));
+        }
+        if bot.drop_pending_updates == DropPending::Always {
+            out.push_str("drop_pending_updates = true\n");
+        }
+        if let Some(kinds) = &allowed_updates {
+            let kinds: Vec<String> = kinds.iter().map(|k| quote(k)).collect();
+            let _ = writeln!(out, "allowed_updates = [{}]", kinds.join(", "));
+        }
+        if let Some(n) = bot.max_connections {
+            let _ = writeln!(out, "max_connections = {}", n);
+        }
+        if bot.priority != DEFAULT_PRIORITY {
+            let _ = writeln!(out, "priority = {}", bot.priority);
+        }
+        if let Some(timeout) = bot.timeout {
+            let _ = writeln!(out, "timeout_secs = {}", timeout.as_secs());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{default_bots, parse_table};
+
+    fn live(url: &str) -> Result<WebhookInfo, BindError> {
+        Ok(WebhookInfo {
+            url: url.to_string(),
+            pending_update_count: 0,
+            last_error_message: None,
+            last_error_date: None,
+            allowed_updates: None,
+        })
+    }
+
+    #[test]
+    fn live_webhooks_become_stable_urls_that_parse_back() {
+        let bots = default_bots(false);
+        let answers = vec![
+            ("gpt4o".to_string(), live("https://a.ngrok.io/webhook")),
+            ("mistral".to_string(), live("https://b.ngrok.io/tg/hook?token=s3cret")),
+            ("deepseek".to_string(), Err(BindError::Timeout)),
+        ];
+        let text = export_table(&bots, &answers);
+        assert!(!text.contains("s3cret"), "{}", text);
+        assert!(text.contains("# stable_url: getWebhookInfo failed: request timed out\n"), "{}", text);
+
+        let table = parse_table("export.toml", &text, false, None).unwrap();
+        let urls: Vec<_> = table.bots.iter().map(|b| (b.stable_url.as_deref(), b.webhook_path.as_str())).collect();
+        assert_eq!(
+            urls,
+            [(Some("https://a.ngrok.io"), "/webhook"), (Some("https://b.ngrok.io"), "/tg/hook"), (None, "/webhook")]
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Post-bind command: a local program run after each successful bind, for
+//! whatever a chatops script or counter needs to know. The template is
+//! split into arguments before `{name}` and `{url}` are substituted, and
+//! the program is started directly rather than through a shell, so a URL
+//! can't smuggle in extra commands.
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::process::Command;
+
+use crate::resolve::{parsed, value_of, Setting};
+
+#[derive(Debug, Clone)]
+pub struct PostBindHook {
+    /// Program and arguments, still holding their placeholders.
+    argv: Vec<String>,
+    timeout: Duration,
+}
+
+impl PostBindHook {
+    /// The resolved `post_bind_command`, killed after
+    /// `post_bind_timeout_secs` (default 10). `None` when no command is
+    /// set; an error when it can't be split into arguments.
+    pub fn from_settings(settings: &[Setting]) -> Result<Option<Self>, String> {
+        let Some(template) = value_of(settings, "post_bind_command") else {
+            return Ok(None);
+        };
+        let timeout = Duration::from_secs(parsed(settings, "post_bind_timeout_secs").unwrap_or(10));
+        PostBindHook::parse(template, timeout).map(Some).map_err(|err| format!("post_bind_command: {}", err))
+    }
+
+    /// Splits `template` on whitespace; single or double quotes keep
+    /// whitespace inside one argument, and a backslash escapes the next
+    /// character outside single quotes.
+    pub fn parse(template: &str, timeout: Duration) -> Result<Self, String> {
+        let mut argv = Vec::new();
+        let mut word: Option<String> = None;
+        let mut quote = None;
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+                (_, '\\') => {
+                    let escaped = chars.next().ok_or("ends with a lone backslash")?;
+                    word.get_or_insert_with(String::new).push(escaped);
+                }
+                (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+                (None, '\'' | '"') => {
+                    quote = Some(c);
+                    word.get_or_insert_with(String::new);
+                }
+                (None, c) if c.is_whitespace() => argv.extend(word.take()),
+                (None, c) => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        if quote.is_some() {
+            return Err("has an unclosed quote".to_string());
+        }
+        argv.extend(word);
+        if argv.is_empty() {
+            return Err("names no program".to_string());
+        }
+        Ok(PostBindHook { argv, timeout })
+    }
+
+    /// The program and arguments for `name` bound to `url`.
+    pub fn command(&self, name: &str, url: &str) -> Vec<String> {
+        self.argv.iter().map(|arg| arg.replace("{name}", name).replace("{url}", url)).collect()
+    }
+
+    /// Runs the command for `name` bound to `url`; an error says how it
+    /// failed, ran over its timeout, or exited with something other than 0.
+    pub async fn run(&self, name: &str, url: &str) -> Result<(), String> {
+        let argv = self.command(name, url);
+        let mut child = Command::new(&argv[0])
+            .args(&argv[1..])
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| format!("cannot start {}: {}", argv[0], err))?;
+        match tokio::time::timeout(self.timeout, child.wait()).await {
+            Ok(Ok(status)) if status.success() => Ok(()),
+            Ok(Ok(status)) => Err(format!("{} exited with {}", argv[0], status)),
+            Ok(Err(err)) => Err(format!("{} could not be waited on: {}", argv[0], err)),
+            Err(_) => Err(format!("{} still running after {:?}; killed", argv[0], self.timeout)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_fill_arguments_without_a_shell() {
+        let hook = PostBindHook::parse(r#"notify --bot {name} 'bound to {url}' "a b"\ c"#, Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            hook.command("gpt4o", "https://a.ngrok.io/webhook; rm -rf /"),
+            ["notify", "--bot", "gpt4o", "bound to https://a.ngrok.io/webhook; rm -rf /", "a b c"]
+        );
+        assert!(PostBindHook::parse("notify 'open", Duration::from_secs(1)).is_err());
+        assert!(PostBindHook::parse("  ", Duration::from_secs(1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn exit_status_and_timeout_are_reported() {
+        let ok = PostBindHook::parse("true {url}", Duration::from_secs(5)).unwrap();
+        assert_eq!(ok.run("gpt4o", "https://a.ngrok.io").await, Ok(()));
+        let failing = PostBindHook::parse("false", Duration::from_secs(5)).unwrap();
+        assert!(failing.run("gpt4o", "").await.unwrap_err().contains("exited with"));
+        let slow = PostBindHook::parse("sleep 5", Duration::from_millis(50)).unwrap();
+        assert!(slow.run("gpt4o", "").await.unwrap_err().contains("killed"));
+    }
+}
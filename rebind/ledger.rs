@@ -0,0 +1,339 @@
+//! Append-only audit trail of rebind actions, one JSON line per bot and
+//! action, hash-chained like every other SentientOS audit log so
+//! `verify_audits --strict` accepts it: each line is
+//! `{"timestamp", "data", "prev_hash", "rolling_hash"}`, where
+//! `rolling_hash` is the SHA-256 of the timestamp, `json.dumps(data,
+//! sort_keys=True)` and `prev_hash`, and `prev_hash` the previous line's
+//! `rolling_hash` (64 zeros for the first). With `REBIND_SIGNING_KEY` set
+//! each line also carries a `signature`, the hex HMAC-SHA256 of its
+//! `rolling_hash` under that key.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::logger::timestamp;
+use crate::resolve::{value_of, Setting};
+use crate::sha256::{hmac_sha256_hex, sha256_hex};
+use crate::{BindError, Outcome, RebindReport};
+
+pub const DEFAULT_LEDGER_PATH: &str = "/glow/rebind/ledger.jsonl";
+/// `prev_hash` of the first entry of a chain.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Where entries go and which run they belong to. One `run_id` covers a
+/// whole process, so every iteration of a watch session shares it.
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    pub path: PathBuf,
+    pub run_id: String,
+    /// Signs each entry's `rolling_hash`; `None` leaves entries unsigned.
+    pub signing_key: Option<String>,
+}
+
+impl Ledger {
+    /// The resolved `ledger_path` (default [`DEFAULT_LEDGER_PATH`]),
+    /// signed with `signing_key` when set. An empty `ledger_path` turns the
+    /// ledger off.
+    pub fn from_settings(settings: &[Setting], run_id: &str) -> Option<Self> {
+        let path = value_of(settings, "ledger_path").unwrap_or(DEFAULT_LEDGER_PATH);
+        if path.is_empty() {
+            return None;
+        }
+        let signing_key = value_of(settings, "signing_key").map(str::to_string);
+        Some(Ledger { path: PathBuf::from(path), run_id: run_id.to_string(), signing_key })
+    }
+
+    /// One entry per outcome in `report`. `old` holds the previously bound
+    /// public URLs, `urls` the ones just discovered.
+    pub fn record_report(
+        &self,
+        provider: &str,
+        report: &RebindReport,
+        old: &HashMap<String, String>,
+        urls: &HashMap<String, String>,
+    ) {
+        let entries = report.outcomes.iter().map(|o| {
+            let (result, detail) = match &o.outcome {
+                Outcome::Bound { verified: Ok(_), .. } => ("bound", None),
+                Outcome::Bound { verified: Err(problem), .. } => ("bound", Some(problem.clone())),
+                Outcome::Unchanged { .. } => ("unchanged", None),
+                Outcome::Polling { removed } => ("polling", removed.as_ref().map(|url| format!("deleted {}", url))),
+                Outcome::NoTunnel => ("skipped", Some("no tunnel".to_string())),
+                Outcome::Unhealthy(problem) => ("skipped", Some(problem.clone())),
+                Outcome::Failed(err) => ("failed", Some(err.to_string())),
+            };
+            let mut entry = self.entry("bind", &o.bot, old.get(&o.bot), urls.get(&o.bot), result, detail);
+            entry["provider"] = json!(provider);
+            entry
+        });
+        self.append(entries.collect());
+    }
+
+    /// One entry per bot passed to [`crate::unbind`].
+    pub fn record_unbind(&self, results: &[(String, Result<(), BindError>)], old: &HashMap<String, String>) {
+        let entries = results.iter().map(|(bot, result)| {
+            let (outcome, detail) = match result {
+                Ok(()) => ("unbound", None),
+                Err(err) => ("failed", Some(err.to_string())),
+            };
+            self.entry("unbind", bot, old.get(bot), None, outcome, detail)
+        });
+        self.append(entries.collect());
+    }
+
+    fn entry(
+        &self,
+        action: &str,
+        bot: &str,
+        old_url: Option<&String>,
+        new_url: Option<&String>,
+        result: &str,
+        detail: Option<String>,
+    ) -> Value {
+        let mut entry = json!({
+            "event": "rebind",
+            "run_id": self.run_id,
+            "action": action,
+            "bot": bot,
+            "old_url": old_url,
+            "new_url": new_url,
+            "result": result,
+        });
+        if let Some(detail) = detail {
+            entry["detail"] = json!(detail);
+        }
+        entry
+    }
+
+    fn append(&self, entries: Vec<Value>) {
+        if entries.is_empty() {
+            return;
+        }
+        if let Err(err) = append_chained(&self.path, entries, self.signing_key.as_deref()) {
+            log::warn!("[⚠️] Cannot write ledger {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// The `rolling_hash` of an entry, as `audit_chain._hash_entry` computes it.
+pub fn rolling_hash(timestamp: &str, data: &Value, prev_hash: &str) -> String {
+    sha256_hex(format!("{}{}{}", timestamp, python_json(data), prev_hash).as_bytes())
+}
+
+/// `value` as Python's `json.dumps(value, sort_keys=True)` writes it:
+/// sorted keys, `", "` and `": "` separators and non-ASCII escaped.
+pub fn python_json(value: &Value) -> String {
+    let mut out = String::new();
+    write_python_json(&mut out, value);
+    out
+}
+
+fn write_python_json(out: &mut String, value: &Value) {
+    match value {
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_python_json(out, item);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_python_str(out, key);
+                out.push_str(": ");
+                write_python_json(out, &map[key]);
+            }
+            out.push('}');
+        }
+        Value::String(s) => write_python_str(out, s),
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+fn write_python_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            ' '..='~' => out.push(c),
+            _ => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    out.push_str(&format!("\\u{:04x}", unit));
+                }
+            }
+        }
+    }
+    out.push('"');
+}
+
+/// Appends each of `entries` (the `data` of a line) to the chain in `path`,
+/// holding an exclusive lock on the file so two processes can't fork it.
+fn append_chained(path: &Path, entries: Vec<Value>, signing_key: Option<&str>) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+    file.lock()?;
+    let mut prev = match last_line(&mut file)? {
+        None => GENESIS_HASH.to_string(),
+        Some(line) => match serde_json::from_str::<Value>(&line).ok().and_then(|v| v["rolling_hash"].as_str().map(str::to_string)) {
+            Some(hash) => hash,
+            None => {
+                log::warn!("[⚠️] Ledger {} ends with an unchained entry; starting a new hash chain", path.display());
+                GENESIS_HASH.to_string()
+            }
+        },
+    };
+    let mut lines = String::new();
+    for data in entries {
+        let timestamp = timestamp();
+        let hash = rolling_hash(&timestamp, &data, &prev);
+        let mut line = json!({ "timestamp": timestamp, "data": data, "prev_hash": prev, "rolling_hash": hash });
+        if let Some(key) = signing_key {
+            line["signature"] = json!(hmac_sha256_hex(key.as_bytes(), hash.as_bytes()));
+        }
+        lines.push_str(&line.to_string());
+        lines.push('\n');
+        prev = hash;
+    }
+    file.write_all(lines.as_bytes())
+}
+
+/// The last non-empty line of `file`, read backwards from the end so a long
+/// ledger isn't read whole.
+fn last_line(file: &mut File) -> io::Result<Option<String>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut window = 4096u64;
+    loop {
+        let start = len.saturating_sub(window);
+        file.seek(SeekFrom::Start(start))?;
+        let mut tail = Vec::new();
+        Read::take(&mut *file, len - start).read_to_end(&mut tail)?;
+        let text = String::from_utf8_lossy(&tail);
+        let trimmed = text.trim_end();
+        match trimmed.rfind('\n') {
+            Some(at) => return Ok(Some(trimmed[at + 1..].to_string())),
+            None if start == 0 => return Ok(Some(trimmed.to_string()).filter(|l| !l.is_empty())),
+            None => window *= 4,
+        }
+    }
+}
+
+/// Appends `entries` to `path` as JSON lines in a single write, creating
+/// the parent directory if needed.
+pub(crate) fn append_jsonl(path: &Path, entries: &[Value]) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&entry.to_string());
+        lines.push('\n');
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(lines.as_bytes())
+}
+
+/// 16 bytes from `/dev/urandom`, falling back to the clock and pid where
+/// that isn't available.
+pub(crate) fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    let read = fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes));
+    if read.is_err() {
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+        bytes = (nanos ^ (u128::from(std::process::id()) << 64)).to_le_bytes();
+    }
+    bytes
+}
+
+/// A random (version 4) UUID.
+pub fn new_uuid() -> String {
+    let mut bytes = random_bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuids_are_v4() {
+        let id = new_uuid();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!("89ab".contains(&id[19..20]), "{}", id);
+        assert_ne!(id, new_uuid());
+    }
+
+    #[test]
+    fn appends_one_line_per_unbind_and_creates_the_directory() {
+        let dir = std::env::temp_dir().join(format!("rebind-ledger-{}", std::process::id()));
+        let ledger = Ledger { path: dir.join("nested").join("ledger.jsonl"), run_id: new_uuid(), signing_key: None };
+        let results = vec![("gpt4o".to_string(), Ok(())), ("mistral".to_string(), Err(BindError::Timeout))];
+        ledger.record_unbind(&results, &HashMap::new());
+        ledger.record_unbind(&results[..1], &HashMap::new());
+
+        let lines: Vec<Value> = fs::read_to_string(&ledger.path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["data"]["result"], "unbound");
+        assert_eq!(lines[1]["data"]["result"], "failed");
+        assert_eq!(lines[1]["data"]["detail"], "request timed out");
+        assert!(lines.iter().all(|l| l["data"]["run_id"] == json!(ledger.run_id)));
+    }
+
+    #[test]
+    fn entries_chain_across_appends_like_audit_chain() {
+        // json.dumps({"b": "é\n", "a": [1, None, True]}, sort_keys=True)
+        let data = json!({ "b": "é\n", "a": [1, null, true] });
+        assert_eq!(python_json(&data), r#"{"a": [1, null, true], "b": "\u00e9\n"}"#);
+        assert_eq!(python_json(&json!("🔑")), r#""\ud83d\udd11""#);
+
+        let dir = std::env::temp_dir().join(format!("rebind-chain-{}", std::process::id()));
+        let key = "ledger-key";
+        let ledger = Ledger { path: dir.join("ledger.jsonl"), run_id: new_uuid(), signing_key: Some(key.to_string()) };
+        let results = vec![("gpt4o".to_string(), Ok(())), ("mistral".to_string(), Err(BindError::Timeout))];
+        ledger.record_unbind(&results, &HashMap::new());
+        ledger.record_unbind(&results[..1], &HashMap::new());
+
+        let lines: Vec<Value> =
+            fs::read_to_string(&ledger.path).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        fs::remove_dir_all(&dir).unwrap();
+        let mut prev = GENESIS_HASH.to_string();
+        for line in &lines {
+            assert_eq!(line["prev_hash"], json!(prev));
+            let hash = rolling_hash(line["timestamp"].as_str().unwrap(), &line["data"], &prev);
+            assert_eq!(line["rolling_hash"], json!(hash));
+            assert_eq!(line["signature"], json!(hmac_sha256_hex(key.as_bytes(), hash.as_bytes())));
+            prev = hash;
+        }
+        assert_eq!(lines.len(), 3);
+    }
+}
@@ -0,0 +1,875 @@
+//! Rebinds Telegram webhooks to whatever public URLs the local tunnel agent
+//! is currently handing out.
+//!
+//! The `rebind` binary is a thin wrapper around [`rebind()`]; other binaries
+//! and tests can build a [`RebindConfig`] themselves and call it directly.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use futures_util::stream::{self, StreamExt};
+use hyper::{Body, Client, Method, StatusCode};
+#[cfg(feature = "tls-native")]
+use hyper_tls::HttpsConnector;
+use serde_json::{json, Value};
+
+use resolve::{is_on, parsed, value_of, Setting};
+
+pub mod config;
+pub mod dns;
+pub mod events;
+pub mod explain;
+pub mod export;
+pub mod hooks;
+pub mod ledger;
+pub mod logger;
+pub mod metrics;
+pub mod notify;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod proxy;
+pub mod pulse;
+pub mod ratelimit;
+pub mod regex;
+pub mod resolve;
+pub mod secrets;
+pub mod selftest;
+pub mod sha256;
+pub mod state;
+pub mod target;
+pub mod telegram;
+#[cfg(feature = "tls-native")]
+pub mod tls;
+pub mod tokens;
+pub mod toml;
+pub mod trace;
+pub mod tunnel;
+pub mod verify;
+pub mod watch;
+
+pub use config::{load_bots, BotBinding, ConfigError};
+pub use events::{RebindEvent, RunSummary};
+pub use ledger::Ledger;
+pub use proxy::{Proxy, ProxyConnector};
+pub use pulse::PulseSink;
+pub use state::State;
+pub use target::{Targets, WebhookTarget};
+pub use telegram::{
+    bind_webhook, delete_webhook, verify_webhook, BindError, ConnectionError, RetryPolicy, TelegramApiError, WebhookInfo,
+};
+pub use tokens::{token_provider, TokenProvider};
+pub use tokio_util::sync::CancellationToken;
+pub use tunnel::{tunnel_provider, DiscoveryError, TunnelProvider};
+pub use verify::{verify_all, VerificationReport};
+pub use watch::Watcher;
+
+#[cfg(feature = "tls-native")]
+type Connector = HttpsConnector<ProxyConnector>;
+/// Without a TLS backend, `https://` requests fail instead of going out in
+/// the clear.
+#[cfg(not(feature = "tls-native"))]
+type Connector = ProxyConnector;
+
+pub type HttpsClient = Client<Connector>;
+
+/// Idle keep-alive connections are held this long so a watcher polling every
+/// 30s reuses its sockets to Telegram and the tunnel agents.
+pub const DEFAULT_POOL_IDLE_TIMEOUT_SECS: u64 = 300;
+/// One warm connection per request in flight at the default
+/// `REBIND_CONCURRENCY`.
+pub const POOL_MAX_IDLE_PER_HOST: usize = 8;
+
+/// The one client a process should use for every request, across bots and
+/// watch iterations. The idle timeout is `REBIND_POOL_IDLE_TIMEOUT_SECS`;
+/// external requests honour `HTTPS_PROXY`/`ALL_PROXY` and `NO_PROXY`, and
+/// with the `tls-native` feature, TLS is set up by `tls::connector_from_env`,
+/// whose error is returned. Callers only ever see [`HttpsClient`].
+pub fn build_client() -> Result<HttpsClient, String> {
+    let idle = config::env_or("REBIND_POOL_IDLE_TIMEOUT_SECS", DEFAULT_POOL_IDLE_TIMEOUT_SECS);
+    let proxy = Proxy::from_env();
+    if let Some(proxy) = &proxy {
+        log::debug!("Sending external requests through proxy {}", proxy.uri);
+    }
+    #[cfg(feature = "tls-native")]
+    let connector = HttpsConnector::from((ProxyConnector::new(proxy), tls::connector_from_env()?.into()));
+    #[cfg(not(feature = "tls-native"))]
+    let connector = {
+        log::debug!("Built without a TLS backend; only http:// endpoints can be reached");
+        ProxyConnector::new(proxy).http_only()
+    };
+    Ok(Client::builder()
+        .pool_idle_timeout(Duration::from_secs(idle))
+        .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+        .build(connector))
+}
+
+/// A client without a proxy, extra root certificates or pool settings, for
+/// tests and tools that shouldn't read the environment's.
+pub fn direct_client() -> HttpsClient {
+    #[cfg(feature = "tls-native")]
+    let connector = HttpsConnector::new_with_connector(ProxyConnector::new(None));
+    #[cfg(not(feature = "tls-native"))]
+    let connector = ProxyConnector::new(None).http_only();
+    Client::builder().build(connector)
+}
+
+/// Everything a single rebind run needs.
+pub struct RebindConfig {
+    pub client: HttpsClient,
+    /// Shared by every ledger entry and pulse of this process.
+    pub run_id: String,
+    /// Bot API root, normally `https://api.telegram.org`.
+    pub api_base: String,
+    /// Discord API root, normally `https://discord.com/api/v10`.
+    pub discord_api_base: String,
+    /// Slack Web API root, normally `https://slack.com/api`.
+    pub slack_api_base: String,
+    pub provider: Box<dyn TunnelProvider>,
+    pub tokens: Box<dyn TokenProvider>,
+    /// `TG_SECRET`, empty for none; bots with their own secret override it.
+    pub secret: String,
+    pub bots: Vec<BotBinding>,
+    /// Applied to every discovered public URL before it is bound.
+    pub rewrites: Vec<config::UrlRewrite>,
+    pub retry: RetryPolicy,
+    /// Maximum number of a run's API requests in flight at the same time.
+    pub concurrency: usize,
+    /// Call `setWebhook` even when the live webhook already matches.
+    pub force: bool,
+    /// Where last-known-good bindings are kept; `None` disables it.
+    pub state_file: Option<PathBuf>,
+    /// Keep each Telegram bot's secret fingerprint in the state file and
+    /// rebind when it changes, even if the URL didn't (`REBIND_TRACK_SECRETS`).
+    pub track_secrets: bool,
+    /// Probe each public URL before binding it; `None` skips the probe.
+    pub healthcheck: Option<HealthCheck>,
+    /// Audit trail of every bind attempt; `None` disables it.
+    pub ledger: Option<Ledger>,
+    /// Where `rebind_complete`/`rebind_alert` pulses go; `None` disables them.
+    pub pulses: Option<PulseSink>,
+    /// Gets each run's report once the run is over; `None` disables it.
+    pub notify: Option<notify::Notifier>,
+    /// Run after each successful bind; `None` runs nothing.
+    pub post_bind: Option<hooks::PostBindHook>,
+    /// Receives a [`RebindEvent`] for each step of every run as it happens.
+    pub events: Option<events::EventSender>,
+    /// When the run must be over. Discovery still running then fails, and
+    /// bots not bound by then are cancelled and reported as failed with
+    /// [`BindError::DeadlineExceeded`].
+    pub deadline: Option<tokio::time::Instant>,
+    /// Cancelling it stops the run like a deadline does: discovery still
+    /// running fails, and requests in flight are dropped and their bots
+    /// reported as failed with [`BindError::Cancelled`].
+    pub cancel: Option<CancellationToken>,
+    /// Raise a `rebind_alert` only for bots that have gone this long without
+    /// a success (see [`watch::Watcher::overdue`]) rather than for every
+    /// failed bind; `None` alerts on each failure.
+    pub alert_after: Option<Duration>,
+    /// Leave a bot alone when the state file says it was bound this
+    /// recently, by this host or another sharing the file, to the same URL
+    /// with the same secret and options (`REBIND_RECENT_BIND_SECS`); `None`
+    /// always checks the live webhook.
+    pub recent_bind: Option<Duration>,
+}
+
+/// The resolved `deadline_secs` from now, if set; 0 means no deadline.
+pub fn deadline(settings: &[Setting]) -> Option<tokio::time::Instant> {
+    let secs: u64 = parsed(settings, "deadline_secs").unwrap_or(0);
+    (secs > 0).then(|| tokio::time::Instant::now() + Duration::from_secs(secs))
+}
+
+/// How long to wait before the first discovery: the resolved
+/// `startup_delay_ms` (default 2000) plus a random part of
+/// `startup_jitter_ms` (default 0), so hosts started on a shared schedule
+/// spread their calls to ngrok and Telegram.
+pub fn startup_delay(settings: &[Setting]) -> Duration {
+    let delay: u64 = parsed(settings, "startup_delay_ms").unwrap_or(2000);
+    let jitter = match parsed::<u64>(settings, "startup_jitter_ms").unwrap_or(0) {
+        0 => 0,
+        max => u64::from_le_bytes(ledger::random_bytes()[..8].try_into().unwrap()) % (max + 1),
+    };
+    Duration::from_millis(delay.saturating_add(jitter))
+}
+
+/// Runs `work` to completion, or until `deadline` passes (`None` when it
+/// did).
+pub async fn before_deadline<F: std::future::Future>(deadline: Option<tokio::time::Instant>, work: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, work).await.ok(),
+        None => Some(work.await),
+    }
+}
+
+/// Runs `work` to completion, or until `cancel` is cancelled (`None` when
+/// it was).
+pub async fn unless_cancelled<F: std::future::Future>(cancel: Option<&CancellationToken>, work: F) -> Option<F::Output> {
+    match cancel {
+        Some(cancel) => cancel.run_until_cancelled(work).await,
+        None => Some(work.await),
+    }
+}
+
+/// Pre-bind probe: `GET {public_url}{path}` must answer within `timeout`
+/// with anything but a gateway error, which is what ngrok and cloudflared
+/// return when the local service isn't listening.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    /// Overrides the bot's webhook path; a bot's own `health_path` wins.
+    pub path: Option<String>,
+    pub timeout: Duration,
+}
+
+impl HealthCheck {
+    /// `Some` when the resolved `healthcheck` is on. The path defaults to
+    /// the bot's webhook path (`healthcheck_path`, overridable per bot with
+    /// `health_path`) and the timeout to 3s (`healthcheck_timeout_secs`).
+    pub fn from_settings(settings: &[Setting]) -> Option<Self> {
+        if !is_on(settings, "healthcheck") {
+            return None;
+        }
+        Some(HealthCheck {
+            path: value_of(settings, "healthcheck_path").map(str::to_string),
+            timeout: Duration::from_secs(parsed(settings, "healthcheck_timeout_secs").unwrap_or(3)),
+        })
+    }
+
+    /// Why the upstream behind `public_url` looks dead, if it does.
+    async fn problem(&self, client: &HttpsClient, bot: &BotBinding, public_url: &str) -> Option<String> {
+        let path = bot.health_path.as_deref().or(self.path.as_deref()).unwrap_or(&bot.webhook_path);
+        let url = format!("{}{}", public_url.trim_end_matches('/'), path);
+        let req = match telegram::request_builder(Method::GET).uri(&url).body(Body::empty()) {
+            Ok(req) => req,
+            Err(err) => return Some(format!("invalid health check URL {}: {}", url, err)),
+        };
+        match telegram::fetch(client, req, self.timeout).await {
+            Ok((parts, _)) => match parts.status {
+                StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => {
+                    Some(format!("GET {} returned {}", url, parts.status))
+                }
+                _ => None,
+            },
+            Err(err) => Some(format!("GET {} failed: {}", url, err)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    /// `verified` holds what `getWebhookInfo` reported afterwards, or why the
+    /// live webhook doesn't match what was set.
+    Bound { webhook_url: String, verified: Result<WebhookInfo, String> },
+    /// The live webhook already pointed at `webhook_url`; nothing was sent.
+    Unchanged { webhook_url: String },
+    /// A `mode = "poll"` bot left without a webhook; `removed` is the one
+    /// deleted to get there, `None` when there was none.
+    Polling { removed: Option<String> },
+    NoTunnel,
+    /// The tunnel is up but the health check says nothing answers behind it.
+    Unhealthy(String),
+    Failed(BindError),
+}
+
+impl Outcome {
+    /// `bound`, `unchanged`, `polling`, `no tunnel`, `unhealthy` or `failed`.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Outcome::Bound { .. } => "bound",
+            Outcome::Unchanged { .. } => "unchanged",
+            Outcome::Polling { .. } => "polling",
+            Outcome::NoTunnel => "no tunnel",
+            Outcome::Unhealthy(_) => "unhealthy",
+            Outcome::Failed(_) => "failed",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BotOutcome {
+    pub bot: String,
+    pub outcome: Outcome,
+    /// Time spent on this bot's health check and API calls; discovery,
+    /// which every bot shares, is not included. Zero for [`Outcome::NoTunnel`].
+    pub duration: Duration,
+}
+
+/// Per-bot outcomes of a run, in bot-table order whatever order the
+/// concurrent binds finished in, so two runs' reports diff cleanly.
+#[derive(Debug, Default)]
+pub struct RebindReport {
+    pub outcomes: Vec<BotOutcome>,
+    /// Public URL discovered for each bot of the run that has a tunnel.
+    pub tunnels: HashMap<String, String>,
+    /// How long each bot's tunnel has been up, as far as the state file
+    /// knows; bots on a tunnel never seen before are missing.
+    pub tunnel_ages: HashMap<String, Duration>,
+    /// How long tunnel discovery took; zero when the run didn't discover.
+    pub discovery: Duration,
+    /// The tunnel provider the URLs came from, which with a
+    /// [`tunnel::FallbackProvider`] is the first that found any; `None`
+    /// when the run didn't discover.
+    pub provider: Option<String>,
+    /// How each agent answered discovery, in query order; empty when the
+    /// run didn't discover or the provider doesn't say.
+    pub agents: Vec<tunnel::AgentStatus>,
+    /// How long the whole run took, discovery included.
+    pub elapsed: Duration,
+    /// The run was cancelled through [`RebindConfig::cancel`]; bots it cut
+    /// short are failed with [`BindError::Cancelled`].
+    pub cancelled: bool,
+    /// Bots that have gone [`RebindConfig::alert_after`] without a success,
+    /// as of this run; always empty without it.
+    pub overdue: Vec<String>,
+    /// Bots whose [`RebindConfig::post_bind`] command failed, with why. The
+    /// binds themselves still count as successes.
+    pub hook_failures: Vec<(String, String)>,
+    /// Why each bot of a [`watch::Watcher`] poll was tried (`startup`,
+    /// `forced`, `new tunnel`, `previously failed`, ...); empty outside
+    /// watch mode.
+    pub reasons: HashMap<String, String>,
+}
+
+impl RebindReport {
+    pub fn bound(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Bound { .. }))
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Failed(_)))
+    }
+
+    pub fn unchanged(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Unchanged { .. }))
+    }
+
+    pub fn polling(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Polling { .. }))
+    }
+
+    /// Bots left working: bound, already bound or polling, as
+    /// [`config::SuccessPolicy`] counts them.
+    pub fn succeeded(&self) -> usize {
+        self.bound() + self.unchanged() + self.polling()
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::NoTunnel | Outcome::Unhealthy(_)))
+    }
+
+    /// Some agent didn't answer usefully, so tunnels it serves may be
+    /// missing; bots left without one are among the skipped.
+    pub fn discovery_partial(&self) -> bool {
+        self.agents.iter().any(|a| a.state != tunnel::AgentState::Ok)
+    }
+
+    /// Bots whose token the API rejected, in table order.
+    pub fn unauthorized(&self) -> Vec<&str> {
+        self.outcomes
+            .iter()
+            .filter(|o| matches!(o.outcome, Outcome::Failed(BindError::Unauthorized(_))))
+            .map(|o| o.bot.as_str())
+            .collect()
+    }
+
+    /// `{ "bound": [...], "unchanged": [...], "failed": [...], "skipped": [...] }`,
+    /// one entry per bot with its `tunnel_age_secs` when known, plus the
+    /// names of bots with `invalid_tokens`. Failed bots that never got an
+    /// HTTP answer carry an `error_kind` (`dns`, `connection_refused`, `tls`
+    /// or `other`), and one Telegram told to wait longer than
+    /// `REBIND_MAX_FLOOD_WAIT_SECS` its `flood_wait_secs`. `summary` holds
+    /// the counts and the run's `total_ms` and `discovery_ms`; `cancelled`
+    /// is set when the run was cut short.
+    /// `agents` lists each agent's `status` (`ok`, `unreachable` or
+    /// `parse-error`), and `discovery_partial` is set when any wasn't ok.
+    /// A watch poll adds `changes`, each bot it tried with its outcome and
+    /// why it was tried.
+    pub fn to_json(&self) -> Value {
+        let (mut bound, mut unchanged, mut polling, mut failed, mut skipped) =
+            (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for BotOutcome { bot, outcome, duration } in &self.outcomes {
+            let (list, mut entry) = match outcome {
+                Outcome::Bound { webhook_url, verified } => {
+                    let mut entry = json!({ "bot": bot, "url": webhook_url });
+                    match verified {
+                        Ok(info) => entry["pending_update_count"] = json!(info.pending_update_count),
+                        Err(problem) => entry["verification_error"] = json!(problem),
+                    }
+                    (&mut bound, entry)
+                }
+                Outcome::Unchanged { webhook_url } => (&mut unchanged, json!({ "bot": bot, "url": webhook_url })),
+                Outcome::Polling { removed } => (&mut polling, json!({ "bot": bot, "removed": removed })),
+                Outcome::Failed(err) => {
+                    let mut entry = json!({ "bot": bot, "error": err.to_string() });
+                    if let Some(kind) = err.connection_error() {
+                        entry["error_kind"] = json!(kind.as_str());
+                    }
+                    if let BindError::FloodWait(wait) = err {
+                        entry["flood_wait_secs"] = json!(wait.as_secs());
+                    }
+                    (&mut failed, entry)
+                }
+                Outcome::NoTunnel => (&mut skipped, json!({ "bot": bot, "reason": "no tunnel" })),
+                Outcome::Unhealthy(problem) => (
+                    &mut skipped,
+                    json!({ "bot": bot, "reason": "tunnel up but upstream unhealthy", "detail": problem }),
+                ),
+            };
+            entry["duration_ms"] = json!(duration.as_millis() as u64);
+            if let Some(age) = self.tunnel_ages.get(bot) {
+                entry["tunnel_age_secs"] = json!(age.as_secs());
+            }
+            list.push(entry);
+        }
+        let agents: Vec<_> = self.agents.iter().map(|a| json!({ "agent": a.agent, "status": a.state.as_str() })).collect();
+        let hook_failures: Vec<_> = self.hook_failures.iter().map(|(bot, err)| json!({ "bot": bot, "error": err })).collect();
+        let mut out = json!({
+            "bound": bound,
+            "unchanged": unchanged,
+            "polling": polling,
+            "failed": failed,
+            "skipped": skipped,
+            "invalid_tokens": self.unauthorized(),
+            "cancelled": self.cancelled,
+            "provider": self.provider,
+            "agents": agents,
+            "discovery_partial": self.discovery_partial(),
+            "overdue": self.overdue,
+            "hook_failures": hook_failures,
+            "summary": {
+                "bound": self.bound(),
+                "unchanged": self.unchanged(),
+                "polling": self.polling(),
+                "failed": self.failed(),
+                "skipped": self.skipped(),
+                "total_ms": self.elapsed.as_millis() as u64,
+                "discovery_ms": self.discovery.as_millis() as u64,
+            },
+        });
+        if !self.reasons.is_empty() {
+            out["changes"] = self.changes().iter().map(|(bot, outcome, reason)| json!({ "bot": bot, "outcome": outcome, "reason": reason })).collect();
+        }
+        out
+    }
+
+    /// Each bot a watch poll tried, in table order, with the label of its
+    /// outcome and why it was tried.
+    pub fn changes(&self) -> Vec<(&str, &'static str, &str)> {
+        self.outcomes
+            .iter()
+            .filter_map(|o| Some((o.bot.as_str(), o.outcome.label(), self.reasons.get(&o.bot)?.as_str())))
+            .collect()
+    }
+
+    fn count(&self, pred: impl Fn(&Outcome) -> bool) -> usize {
+        self.outcomes.iter().filter(|o| pred(&o.outcome)).count()
+    }
+}
+
+/// Discovers tunnels through `config.provider` and binds every bot that has
+/// one, with at most `config.concurrency` requests in flight. Bots whose live webhook
+/// already matches are left alone unless `config.force` is set. Fails
+/// without touching Telegram if no tunnel agent could be reached.
+/// Successful bindings, and when each tunnel was first seen, are merged
+/// into `config.state_file`.
+/// Cancelling `config.cancel` during discovery fails the run with
+/// [`DiscoveryError::Cancelled`]; once binding has begun it returns the
+/// report so far, every bot still in flight failed with
+/// [`BindError::Cancelled`] and recorded as such, so `--retry-failed`
+/// picks them up. A cancelled run sends no notification.
+pub async fn rebind(config: &RebindConfig) -> Result<RebindReport, DiscoveryError> {
+    let started = Instant::now();
+    let urls = discover(config).await?;
+    let discovery = started.elapsed();
+    let mut saved = config.state_file.as_deref().map(state::load).unwrap_or_default();
+    note_kept_bindings(&urls, &saved.bindings);
+    let now = state::unix_now();
+    let observed = saved.observe(&urls, now);
+    let secrets = config.secret_fingerprints();
+    let mut report = bind_all(config, config.bots.iter(), &urls, &secrets, &saved).await;
+    (report.discovery, report.elapsed) = (discovery, started.elapsed());
+    report.provider = provider_used(config);
+    if report.provider.is_some() {
+        report.agents = config.provider.agents();
+    }
+    finish(config, &mut report, &mut saved, &urls, &secrets, now, observed).await;
+    Ok(report)
+}
+
+/// Rebinds only the bots whose last bind failed, to the URL they failed
+/// on, without running discovery. `None` when the state file records no
+/// failures among `config.bots`.
+pub async fn retry_failed(config: &RebindConfig) -> Option<RebindReport> {
+    let started = Instant::now();
+    let mut saved = config.state_file.as_deref().map(state::load).unwrap_or_default();
+    let urls: HashMap<String, String> = saved
+        .failed
+        .iter()
+        .filter(|(name, _)| config.bots.iter().any(|b| &b.name == *name))
+        .map(|(name, url)| (name.clone(), url.clone()))
+        .collect();
+    if urls.is_empty() {
+        return None;
+    }
+    let secrets = config.secret_fingerprints();
+    let bots = config.bots.iter().filter(|b| urls.contains_key(&b.name));
+    let mut report = bind_all(config, bots, &urls, &secrets, &saved).await;
+    report.elapsed = started.elapsed();
+    finish(config, &mut report, &mut saved, &urls, &secrets, state::unix_now(), false).await;
+    Some(report)
+}
+
+/// What every run does once its binds are in, for [`rebind`] and
+/// [`retry_failed`] alike: tunnel ages on the report, the ledger, pulses,
+/// the notification unless cancelled, the state file and the final event.
+/// `changed` says `saved` already differs from the file on disk.
+async fn finish(
+    config: &RebindConfig,
+    report: &mut RebindReport,
+    saved: &mut State,
+    urls: &HashMap<String, String>,
+    secrets: &HashMap<String, String>,
+    now: i64,
+    changed: bool,
+) {
+    report.tunnel_ages = saved.tunnel_ages(&report.tunnels, now);
+    record_ledger(config, report, &saved.bindings, urls);
+    emit_pulses(config, report, urls);
+    if !report.cancelled {
+        notify(config, report).await;
+    }
+    let noted = saved.note_recent(report, &config.bind_keys(urls), now);
+    if saved.record(report, urls, secrets) | saved.note_successes(urls, now) | noted | changed {
+        save_state(config, saved);
+    }
+    config.emit(RebindEvent::RunComplete { summary: RunSummary::from(&*report) });
+}
+
+/// Discovery came back empty: say that the saved bindings stay in place
+/// rather than letting it look like everything was unbound.
+fn note_kept_bindings(urls: &HashMap<String, String>, saved: &HashMap<String, String>) {
+    if urls.is_empty() && !saved.is_empty() {
+        log::warn!("[📌] No tunnels discovered; keeping the previous bindings for {} bots", saved.len());
+    }
+}
+
+/// Runs discovery, raising a `rebind_alert` when no agent answers (unless
+/// [`RebindConfig::alert_after`] leaves alerting to overdue bots). The
+/// provider may know the whole bot table; only `config.bots` are kept, with
+/// `config.rewrites` applied. Bots with a `stable_url` get it instead; when
+/// every bot has one no agent is asked, and when some do, a failed
+/// discovery still leaves them to bind.
+async fn discover(config: &RebindConfig) -> Result<HashMap<String, String>, DiscoveryError> {
+    config.emit(RebindEvent::DiscoveryStarted);
+    let mut result = if !config.bots.iter().any(BotBinding::needs_tunnel) {
+        Ok(HashMap::new())
+    } else {
+        unless_cancelled(config.cancel.as_ref(), before_deadline(config.deadline, config.provider.public_urls()))
+            .await
+            .unwrap_or(Some(Err(DiscoveryError::Cancelled)))
+            .unwrap_or(Err(DiscoveryError::DeadlineExceeded))
+    };
+    if let Ok(urls) = &mut result {
+        urls.retain(|name, _| config.bots.iter().any(|b| &b.name == name && b.mode == config::BotMode::Webhook));
+        config::rewrite_urls(&config.rewrites, urls);
+    }
+    if let (Err(err), Some(pulses), None) = (&result, &config.pulses, config.alert_after) {
+        pulses.discovery_failed(&config.run_id, err);
+    }
+    if !config.bots.iter().all(BotBinding::needs_tunnel) {
+        if let Err(err) = &result {
+            log::warn!("[⚠️] {}; going on with the bots that don't need a tunnel", err);
+            result = Ok(HashMap::new());
+        }
+    }
+    if let Ok(urls) = &mut result {
+        config::add_stable_urls(&config.bots, urls);
+        for bot in &config.bots {
+            if let Some(url) = urls.get(&bot.name) {
+                config.emit(RebindEvent::TunnelFound { name: bot.name.clone(), url: url.clone() });
+            }
+        }
+    }
+    config.emit(match &result {
+        Ok(urls) => RebindEvent::DiscoveryFinished { tunnels: urls.len(), error: None },
+        Err(err) => RebindEvent::DiscoveryFinished { tunnels: 0, error: Some(err.to_string()) },
+    });
+    result
+}
+
+/// The provider [`discover`] got its URLs from, `None` when every bot has
+/// a `stable_url` and no provider was asked.
+fn provider_used(config: &RebindConfig) -> Option<String> {
+    config.bots.iter().any(BotBinding::needs_tunnel).then(|| config.provider.used().to_string())
+}
+
+fn emit_pulses(config: &RebindConfig, report: &RebindReport, urls: &HashMap<String, String>) {
+    if let Some(pulses) = &config.pulses {
+        pulses.report(&config.run_id, report, urls.len(), config.alert_after.is_none());
+    }
+}
+
+async fn notify(config: &RebindConfig, report: &RebindReport) {
+    if let Some(notifier) = &config.notify {
+        notifier.send(&config.run_id, report).await;
+    }
+}
+
+fn record_ledger(
+    config: &RebindConfig,
+    report: &RebindReport,
+    old: &HashMap<String, String>,
+    urls: &HashMap<String, String>,
+) {
+    if let Some(ledger) = &config.ledger {
+        ledger.record_report(config.provider.used(), report, old, urls);
+    }
+}
+
+fn save_state(config: &RebindConfig, saved: &State) {
+    if let Some(path) = &config.state_file {
+        if let Err(err) = state::save(path, saved) {
+            log::warn!("[⚠️] Cannot write state file {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Binds each of `bots` to its entry in `urls`; the report lists them in
+/// bot-table order. A bot whose entry in `secrets` differs from the one in
+/// `saved` is rebound even when its live webhook already matches.
+async fn bind_all<'a>(
+    config: &RebindConfig,
+    bots: impl Iterator<Item = &'a BotBinding>,
+    urls: &HashMap<String, String>,
+    secrets: &HashMap<String, String>,
+    saved: &State,
+) -> RebindReport {
+    // Every bot starts at once, highest priority first; `config.concurrency`
+    // caps the requests in flight instead, so a bot backing off between
+    // retries holds no slot, and queued requests go by priority.
+    let mut bots: Vec<&BotBinding> = bots.collect();
+    bots.sort_by_key(|bot| std::cmp::Reverse(bot.priority));
+    let binds = stream::iter(bots)
+        .map(|bot| ratelimit::with_priority(bot.priority, async move {
+            let started = Instant::now();
+            let rotated = secret_rotated(bot, secrets, saved);
+            if rotated && saved.secrets.contains_key(&bot.name) {
+                log::info!("[🔄] {}: secret changed since the last bind; rebinding", bot.name);
+            }
+            let outcome = match urls.get(&bot.name) {
+                _ if bot.mode == config::BotMode::Poll => {
+                    config.emit(RebindEvent::BindStarted { name: bot.name.clone() });
+                    let unbind = before_deadline(config.deadline, ensure_polling(config, bot));
+                    unless_cancelled(config.cancel.as_ref(), unbind)
+                        .await
+                        .unwrap_or(Some(Err(BindError::Cancelled)))
+                        .unwrap_or(Err(BindError::DeadlineExceeded))
+                        .unwrap_or_else(Outcome::Failed)
+                }
+                Some(url) if !rotated && recently_bound(config, bot, url, saved) => {
+                    Outcome::Unchanged { webhook_url: telegram::webhook_url(bot, url) }
+                }
+                Some(url) => {
+                    config.emit(RebindEvent::BindStarted { name: bot.name.clone() });
+                    let first = !saved.ever_bound(&bot.name);
+                    if first && bot.drop_pending_updates == config::DropPending::FirstOnly {
+                        log::info!("[🧹] {}: no bind on record; dropping pending updates", bot.name);
+                    }
+                    let bot = &*bot.settle_drop_pending(!first);
+                    let bind = before_deadline(config.deadline, bind_and_verify(config, bot, url, rotated));
+                    unless_cancelled(config.cancel.as_ref(), bind)
+                        .await
+                        .unwrap_or(Some(Err(BindError::Cancelled)))
+                        .unwrap_or(Err(BindError::DeadlineExceeded))
+                        .unwrap_or_else(Outcome::Failed)
+                }
+                None => Outcome::NoTunnel,
+            };
+            let duration = started.elapsed();
+            config.emit(RebindEvent::bind_result(&bot.name, &outcome, duration));
+            let hook = match (&config.post_bind, &outcome) {
+                (Some(hook), Outcome::Bound { webhook_url, .. }) => run_post_bind(hook, &bot.name, webhook_url).await,
+                _ => None,
+            };
+            (bot.name.clone(), (outcome, duration, hook))
+        }))
+        .buffer_unordered(usize::MAX)
+        .collect();
+    let mut results: HashMap<String, (Outcome, Duration, Option<String>)> =
+        ratelimit::with_request_slots(config.concurrency, binds).await;
+
+    let mut hook_failures = Vec::new();
+    let outcomes = config
+        .bots
+        .iter()
+        .filter_map(|bot| {
+            let (outcome, duration, hook) = results.remove(&bot.name)?;
+            hook_failures.extend(hook.map(|err| (bot.name.clone(), err)));
+            Some(BotOutcome { bot: bot.name.clone(), outcome, duration })
+        })
+        .collect();
+    let tunnels = urls.iter().filter(|(name, _)| config.bots.iter().any(|b| &b.name == *name));
+    RebindReport {
+        outcomes,
+        tunnels: tunnels.map(|(name, url)| (name.clone(), url.clone())).collect(),
+        cancelled: config.cancel.as_ref().is_some_and(|cancel| cancel.is_cancelled()),
+        hook_failures,
+        ..RebindReport::default()
+    }
+}
+
+/// Runs `hook` for `bot` newly bound to `webhook_url`, logging how it
+/// went; the error when it failed.
+async fn run_post_bind(hook: &hooks::PostBindHook, bot: &str, webhook_url: &str) -> Option<String> {
+    match hook.run(bot, webhook_url).await {
+        Ok(()) => {
+            log::info!("[🪝] {}: post-bind command succeeded", bot);
+            None
+        }
+        Err(err) => {
+            log::warn!("[🪝] {}: post-bind command failed: {}", bot, err);
+            Some(err)
+        }
+    }
+}
+
+/// Whether the state file says `bot` was bound to `url` with its current
+/// secret and options within `config.recent_bind`, logging so if it was.
+/// Never with `config.force`.
+fn recently_bound(config: &RebindConfig, bot: &BotBinding, url: &str, saved: &State) -> bool {
+    if config.force {
+        return false;
+    }
+    let (Some(window), Some(key)) = (config.recent_bind, config.bind_key(bot, url)) else { return false };
+    let Some(age) = saved.recently_bound(&bot.name, &key, window, state::unix_now()) else { return false };
+    log::info!(
+        "[🗂️] {}: recently bound elsewhere ({} ago) to the same URL, secret and options; skipping",
+        bot.name,
+        state::format_age(age)
+    );
+    true
+}
+
+/// Whether `bot`'s secret changed since it was last bound. Untracked bots
+/// never count as rotated.
+fn secret_rotated(bot: &BotBinding, secrets: &HashMap<String, String>, saved: &State) -> bool {
+    secrets.get(&bot.name).is_some_and(|secret| saved.secrets.get(&bot.name) != Some(secret))
+}
+
+impl RebindConfig {
+    fn emit(&self, event: RebindEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    /// Fingerprints of the secret each Telegram bot will be bound with, when
+    /// `track_secrets` is on.
+    fn secret_fingerprints(&self) -> HashMap<String, String> {
+        if !self.track_secrets {
+            return HashMap::new();
+        }
+        self.bots
+            .iter()
+            .filter(|bot| bot.platform == config::Platform::Telegram)
+            .map(|bot| (bot.name.clone(), state::fingerprint(&telegram::resolve_secret(bot, &self.secret))))
+            .collect()
+    }
+
+    /// The [`state::bind_key`] of binding `bot` to `url`, when `recent_bind`
+    /// is set and the bot takes a webhook.
+    fn bind_key(&self, bot: &BotBinding, url: &str) -> Option<String> {
+        self.recent_bind?;
+        (bot.mode == config::BotMode::Webhook).then(|| {
+            state::bind_key(bot, &telegram::webhook_url(bot, url), &telegram::resolve_secret(bot, &self.secret))
+        })
+    }
+
+    /// [`RebindConfig::bind_key`] of each bot in `urls`.
+    fn bind_keys(&self, urls: &HashMap<String, String>) -> HashMap<String, String> {
+        self.bots
+            .iter()
+            .filter_map(|bot| Some((bot.name.clone(), self.bind_key(bot, urls.get(&bot.name)?)?)))
+            .collect()
+    }
+
+    pub fn targets(&self) -> Targets<'_> {
+        Targets {
+            client: &self.client,
+            retry: self.retry,
+            telegram_api_base: &self.api_base,
+            discord_api_base: &self.discord_api_base,
+            slack_api_base: &self.slack_api_base,
+            secret: &self.secret,
+            tokens: self.tokens.as_ref(),
+        }
+    }
+}
+
+async fn bind_and_verify(config: &RebindConfig, bot: &BotBinding, url: &str, rotated: bool) -> Result<Outcome, BindError> {
+    let target = config.targets().for_bot(bot);
+    let token = config.tokens.token(bot)?;
+    let webhook_url = telegram::webhook_url(bot, url);
+    if let Some(check) = &config.healthcheck {
+        if let Some(problem) = check.problem(&config.client, bot, url).await {
+            return Ok(Outcome::Unhealthy(problem));
+        }
+    }
+    if !config.force && !rotated {
+        // If the lookup fails we just bind; the bind call will surface any real problem.
+        if let Ok(info) = target.live_webhook(bot, &token).await {
+            if telegram::already_bound(&info, bot, &webhook_url) {
+                return Ok(Outcome::Unchanged { webhook_url });
+            }
+        }
+    }
+    target.bind(bot, &token, url).await?;
+    let verified = match target.live_webhook(bot, &token).await {
+        Ok(info) => match telegram::verification_problem(&info, &webhook_url) {
+            Some(problem) => Err(problem),
+            None => Ok(info),
+        },
+        Err(err) => Err(err.to_string()),
+    };
+    Ok(Outcome::Bound { webhook_url, verified })
+}
+
+/// Leaves a `mode = "poll"` bot without a webhook. `deleteWebhook` is only
+/// sent when one is set, or the lookup fails, or `config.force` is on.
+async fn ensure_polling(config: &RebindConfig, bot: &BotBinding) -> Result<Outcome, BindError> {
+    let target = config.targets().for_bot(bot);
+    let token = config.tokens.token(bot)?;
+    let removed = match target.live_webhook(bot, &token).await {
+        Ok(info) if info.url.is_empty() && !config.force => return Ok(Outcome::Polling { removed: None }),
+        Ok(info) => Some(info.url).filter(|url| !url.is_empty()),
+        Err(_) => None,
+    };
+    target.unbind(bot, &token).await?;
+    Ok(Outcome::Polling { removed })
+}
+
+/// Removes each of `bots`' webhook (`deleteWebhook` on Telegram), returning
+/// per-bot results in the same order.
+pub async fn unbind(targets: Targets<'_>, bots: &[BotBinding]) -> Vec<(String, Result<(), BindError>)> {
+    let mut results = Vec::with_capacity(bots.len());
+    for bot in bots {
+        let result = match targets.tokens.token(bot) {
+            Ok(token) => targets.for_bot(bot).unbind(bot, &token).await,
+            Err(err) => Err(err),
+        };
+        results.push((bot.name.clone(), result));
+    }
+    results
+}
+
+/// Fetches each of `bots`' live webhook concurrently without changing
+/// anything, returning per-bot results in the same order.
+pub async fn audit(targets: Targets<'_>, bots: &[BotBinding]) -> Vec<(String, Result<WebhookInfo, BindError>)> {
+    let lookups = bots.iter().map(|bot| async move {
+        let result = match targets.tokens.token(bot) {
+            Ok(token) => targets.for_bot(bot).live_webhook(bot, &token).await,
+            Err(err) => Err(err),
+        };
+        (bot.name.clone(), result)
+    });
+    futures_util::future::join_all(lookups).await
+}
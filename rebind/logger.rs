@@ -0,0 +1,129 @@
+//! A small stderr logger for the `log` facade, filtered by `RUST_LOG`.
+//!
+//! `RUST_LOG` is a comma-separated list of directives, each either a level
+//! (`warn`) or `target=level` (`rebind::tunnel=debug`). The most specific
+//! matching target wins; without a match the bare level applies, defaulting
+//! to `info`.
+
+use std::env;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+struct Logger {
+    default: LevelFilter,
+    /// `(target prefix, level)`, longest prefix first.
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Logger {
+    fn from_spec(spec: &str) -> Self {
+        let mut logger = Logger { default: LevelFilter::Info, targets: Vec::new() };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => match level.trim().parse() {
+                    Ok(level) => logger.targets.push((target.trim().to_string(), level)),
+                    Err(_) => eprintln!("Ignoring invalid RUST_LOG directive `{}`", directive),
+                },
+                None => match directive.parse() {
+                    Ok(level) => logger.default = level,
+                    // A bare target such as `rebind::tunnel` enables everything for it.
+                    Err(_) => logger.targets.push((directive.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        logger.targets.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        logger
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .find(|(prefix, _)| target == prefix || target.starts_with(&format!("{}::", prefix)))
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(std::io::stderr(), "{} {:<5} {}", timestamp(), record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Installs the logger as configured by `RUST_LOG`. Calling it twice is
+/// harmless; the second call is ignored.
+pub fn init() {
+    let logger = Logger::from_spec(&env::var("RUST_LOG").unwrap_or_default());
+    let max = logger.max_level();
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(max);
+    }
+}
+
+/// Lets only errors through from now on, whatever `RUST_LOG` says.
+pub fn errors_only() {
+    log::set_max_level(log::max_level().min(LevelFilter::Error));
+}
+
+/// Current UTC time as `YYYY-MM-DDTHH:MM:SSZ`.
+pub fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Civil-from-days (Howard Hinnant's algorithm), valid for any date after 1970.
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_specific_directive_wins() {
+        let logger = Logger::from_spec("warn, rebind=info ,rebind::tunnel=debug");
+        assert_eq!(logger.level_for("rebind"), LevelFilter::Info);
+        assert_eq!(logger.level_for("rebind::telegram"), LevelFilter::Info);
+        assert_eq!(logger.level_for("rebind::tunnel"), LevelFilter::Debug);
+        assert_eq!(logger.level_for("rebindx"), LevelFilter::Warn);
+        assert_eq!(logger.level_for("hyper::client"), LevelFilter::Warn);
+        assert_eq!(logger.max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn defaults_to_info() {
+        let logger = Logger::from_spec("");
+        assert_eq!(logger.level_for("rebind"), LevelFilter::Info);
+        assert_eq!(Logger::from_spec("off").max_level(), LevelFilter::Off);
+    }
+}
@@ -0,0 +1,145 @@
+//! Prometheus text-format metrics for watch mode, served on
+//! `REBIND_METRICS_ADDR` when it is set. A bare port listens on loopback;
+//! containers need an explicit `0.0.0.0:PORT`.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+
+use crate::{DiscoveryError, RebindReport};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    bound: AtomicU64,
+    failed: AtomicU64,
+    /// Unchanged bindings plus bots skipped for a missing or unhealthy tunnel.
+    skipped: AtomicU64,
+    discovery_failures: AtomicU64,
+    last_run: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts one poll or run.
+    pub fn record(&self, result: &Result<RebindReport, DiscoveryError>) {
+        match result {
+            Ok(report) => {
+                self.bound.fetch_add(report.bound() as u64, Ordering::Relaxed);
+                self.failed.fetch_add(report.failed() as u64, Ordering::Relaxed);
+                self.skipped.fetch_add((report.unchanged() + report.skipped()) as u64, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.discovery_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        self.last_run.store(now, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        format!(
+            "# HELP rebind_bindings_total Webhook bindings attempted, by result.\n\
+             # TYPE rebind_bindings_total counter\n\
+             rebind_bindings_total{{result=\"success\"}} {}\n\
+             rebind_bindings_total{{result=\"failure\"}} {}\n\
+             rebind_bindings_total{{result=\"skipped\"}} {}\n\
+             # HELP rebind_tunnel_discovery_failures_total Polls where no tunnel agent answered.\n\
+             # TYPE rebind_tunnel_discovery_failures_total counter\n\
+             rebind_tunnel_discovery_failures_total {}\n\
+             # HELP rebind_last_run_timestamp Unix time of the last poll.\n\
+             # TYPE rebind_last_run_timestamp gauge\n\
+             rebind_last_run_timestamp {}\n",
+            get(&self.bound),
+            get(&self.failed),
+            get(&self.skipped),
+            get(&self.discovery_failures),
+            get(&self.last_run),
+        )
+    }
+}
+
+/// `REBIND_METRICS_ADDR` (e.g. `127.0.0.1:9464`, or `9464` for loopback),
+/// if set.
+pub fn metrics_addr() -> Option<Result<SocketAddr, String>> {
+    let raw = std::env::var("REBIND_METRICS_ADDR").ok().filter(|a| !a.trim().is_empty())?;
+    Some(parse_addr(raw.trim()).map_err(|e| format!("invalid REBIND_METRICS_ADDR `{}`: {}", raw, e)))
+}
+
+fn parse_addr(raw: &str) -> Result<SocketAddr, String> {
+    if let Ok(port) = raw.parse::<u16>() {
+        return Ok(SocketAddr::from(([127, 0, 0, 1], port)));
+    }
+    raw.parse().map_err(|_| "expected IP:PORT (e.g. 0.0.0.0:9464) or a port".to_string())
+}
+
+/// Serves `GET /metrics` until the process exits. Binds before returning so
+/// a busy port is reported at startup.
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), hyper::Error> {
+    let make = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let response = if req.uri().path() == "/metrics" {
+                    Response::builder()
+                        .header("Content-Type", "text/plain; version=0.0.4")
+                        .body(Body::from(metrics.render()))
+                } else {
+                    Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty())
+                };
+                async move { Ok::<_, Infallible>(response.unwrap()) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make);
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            log::error!("[❌] metrics server stopped: {}", err);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::{BotOutcome, Outcome};
+
+    #[test]
+    fn counts_each_result() {
+        let metrics = Metrics::default();
+        let outcome = |bot: &str, outcome| BotOutcome { bot: bot.to_string(), outcome, duration: Duration::ZERO };
+        let report = RebindReport {
+            outcomes: vec![
+                outcome("gpt4o", Outcome::Unchanged { webhook_url: "https://a.ngrok.io/webhook".to_string() }),
+                outcome("mistral", Outcome::NoTunnel),
+                outcome("deepseek", Outcome::Failed(crate::BindError::Timeout)),
+            ],
+            ..RebindReport::default()
+        };
+        metrics.record(&Ok(report));
+        metrics.record(&Err(DiscoveryError::NoAgentReachable(vec![])));
+
+        let text = metrics.render();
+        assert!(text.contains("rebind_bindings_total{result=\"success\"} 0\n"), "{}", text);
+        assert!(text.contains("rebind_bindings_total{result=\"failure\"} 1\n"), "{}", text);
+        assert!(text.contains("rebind_bindings_total{result=\"skipped\"} 2\n"), "{}", text);
+        assert!(text.contains("rebind_tunnel_discovery_failures_total 1\n"), "{}", text);
+        assert!(!text.contains("rebind_last_run_timestamp 0\n"), "{}", text);
+    }
+
+    #[test]
+    fn a_bare_port_listens_on_loopback() {
+        assert_eq!(parse_addr("9464"), Ok(SocketAddr::from(([127, 0, 0, 1], 9464))));
+        assert_eq!(parse_addr("0.0.0.0:9464"), Ok(SocketAddr::from(([0, 0, 0, 0], 9464))));
+        assert_eq!(parse_addr("[::]:9464").map(|a| a.is_ipv6()), Ok(true));
+        assert!(parse_addr("localhost:9464").is_err());
+        assert!(parse_addr("0.0.0.0").is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! Completion callback: the JSON report of each run POSTed to
+//! `REBIND_NOTIFY_URL`, signed with `REBIND_NOTIFY_SECRET` so the receiver
+//! can tell it came from us. A failed callback is only logged.
+
+use std::time::Duration;
+
+use hyper::{Body, Method};
+
+use crate::resolve::{parsed, value_of, Setting};
+use crate::sha256::hmac_sha256_hex;
+use crate::telegram::{fetch, redact_tokens, request_builder};
+use crate::{HttpsClient, RebindReport};
+
+/// `sha256=<hex HMAC-SHA256 of the body>`, as GitHub signs its webhooks.
+pub const SIGNATURE_HEADER: &str = "X-Rebind-Signature";
+
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    client: HttpsClient,
+    pub url: String,
+    /// Empty sends the report unsigned.
+    secret: String,
+    timeout: Duration,
+}
+
+impl Notifier {
+    /// The resolved `notify_url`, with `notify_secret` to sign with and
+    /// `notify_timeout_secs` per callback (default 10). `None` when no URL
+    /// is set.
+    pub fn from_settings(settings: &[Setting], client: &HttpsClient) -> Option<Self> {
+        let url = value_of(settings, "notify_url")?.to_string();
+        let secret = value_of(settings, "notify_secret").unwrap_or_default().to_string();
+        if secret.is_empty() {
+            log::warn!("[⚠️] REBIND_NOTIFY_SECRET is not set; reports to {} go unsigned", redact_tokens(&url));
+        }
+        Some(Notifier {
+            client: client.clone(),
+            url,
+            secret,
+            timeout: Duration::from_secs(parsed(settings, "notify_timeout_secs").unwrap_or(10)),
+        })
+    }
+
+    /// The `X-Rebind-Signature` value for `body`, `None` without a secret.
+    pub fn signature(&self, body: &[u8]) -> Option<String> {
+        (!self.secret.is_empty()).then(|| format!("sha256={}", hmac_sha256_hex(self.secret.as_bytes(), body)))
+    }
+
+    /// POSTs `report` with its run id.
+    pub async fn send(&self, run_id: &str, report: &RebindReport) {
+        let mut body = report.to_json();
+        body["run_id"] = run_id.into();
+        let body = body.to_string();
+        let mut builder = request_builder(Method::POST).uri(&self.url).header("Content-Type", "application/json");
+        if let Some(signature) = self.signature(body.as_bytes()) {
+            builder = builder.header(SIGNATURE_HEADER, signature);
+        }
+        let url = redact_tokens(&self.url);
+        let req = match builder.body(Body::from(body)) {
+            Ok(req) => req,
+            Err(err) => {
+                log::warn!("[⚠️] Cannot send the report to {}: {}", url, err);
+                return;
+            }
+        };
+        match fetch(&self.client, req, self.timeout).await {
+            Ok((parts, _)) if parts.status.is_success() => log::debug!("Sent the report to {}", url),
+            Ok((parts, body)) => {
+                log::warn!("[⚠️] {} answered {} to the report: {}", url, parts.status, String::from_utf8_lossy(&body))
+            }
+            Err(err) => log::warn!("[⚠️] Cannot send the report to {}: {}", url, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Request, Response, Server};
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn reports_are_signed_over_the_exact_body() {
+        let (caught, mut received) = mpsc::unbounded_channel();
+        let make = make_service_fn(move |_| {
+            let caught = caught.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let caught = caught.clone();
+                    async move {
+                        let signature = req.headers().get(SIGNATURE_HEADER).map(|v| v.to_str().unwrap().to_string());
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let _ = caught.send((signature, body));
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make);
+        let url = format!("http://{}/rebind", server.local_addr());
+        tokio::spawn(server);
+
+        let notifier = Notifier {
+            client: crate::build_client().unwrap(),
+            url,
+            secret: "shared".to_string(),
+            timeout: Duration::from_secs(5),
+        };
+        notifier.send("run-1", &RebindReport::default()).await;
+        let (signature, body) = received.recv().await.unwrap();
+        assert_eq!(signature, Some(format!("sha256={}", hmac_sha256_hex(b"shared", &body))));
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["run_id"], "run-1");
+        assert_eq!(Notifier { secret: String::new(), ..notifier }.signature(&body), None);
+    }
+}
@@ -0,0 +1,304 @@
+//! OpenTelemetry traces (the `otel` feature). Each run becomes a trace: a
+//! `rebind.run` root span with a `rebind.discovery` child and one
+//! `rebind.bind` child per bot, built from the run's [`RebindEvent`]s and
+//! POSTed as OTLP/JSON to `OTEL_EXPORTER_OTLP_ENDPOINT` once the run is over.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::{Body, Method};
+use serde_json::{json, Value};
+
+use crate::config::env_or;
+use crate::events::{RebindEvent, RunSummary};
+use crate::ledger::random_bytes;
+use crate::telegram::{fetch, request_builder};
+use crate::HttpsClient;
+
+/// OTLP's `STATUS_CODE_OK` and `STATUS_CODE_ERROR`.
+const STATUS_OK: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+/// OTLP's `SPAN_KIND_INTERNAL`.
+const KIND_INTERNAL: u8 = 1;
+
+pub struct Tracer {
+    client: HttpsClient,
+    /// The collector's `/v1/traces` URL.
+    endpoint: String,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+    recorder: Recorder,
+}
+
+impl Tracer {
+    /// A tracer exporting to `OTEL_EXPORTER_OTLP_ENDPOINT`, with the
+    /// `key=value,...` pairs of `OTEL_EXPORTER_OTLP_HEADERS` sent along,
+    /// `OTEL_EXPORTER_OTLP_TIMEOUT` milliseconds per export (default 10000)
+    /// and `service.name` from `OTEL_SERVICE_NAME` (default `rebind`).
+    /// `None` when no endpoint is set.
+    pub fn from_env(client: &HttpsClient) -> Option<Tracer> {
+        let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|e| !e.trim().is_empty())?;
+        let headers = std::env::var("OTEL_EXPORTER_OTLP_HEADERS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .filter(|(key, _)| !key.is_empty())
+            .collect();
+        let service = std::env::var("OTEL_SERVICE_NAME").ok().filter(|s| !s.is_empty()).unwrap_or_else(|| "rebind".to_string());
+        Some(Tracer {
+            client: client.clone(),
+            endpoint: format!("{}/v1/traces", endpoint.trim().trim_end_matches('/')),
+            headers,
+            timeout: Duration::from_millis(env_or("OTEL_EXPORTER_OTLP_TIMEOUT", 10_000u64)),
+            recorder: Recorder::new(service),
+        })
+    }
+
+    /// Adds `event` to the current run's spans, exporting the run when the
+    /// event ends it.
+    pub async fn record(&mut self, event: &RebindEvent) {
+        if let Some(payload) = self.recorder.record(event, unix_nanos()) {
+            self.export(payload).await;
+        }
+    }
+
+    /// Exports a run that never completed, e.g. because discovery failed.
+    pub async fn finish(&mut self) {
+        if let Some(payload) = self.recorder.close(unix_nanos(), None) {
+            self.export(payload).await;
+        }
+    }
+
+    async fn export(&self, payload: Value) {
+        let mut builder = request_builder(Method::POST).uri(&self.endpoint).header("Content-Type", "application/json");
+        for (key, value) in &self.headers {
+            builder = builder.header(key, value);
+        }
+        let req = match builder.body(Body::from(payload.to_string())) {
+            Ok(req) => req,
+            Err(err) => {
+                log::warn!("[⚠️] Cannot export trace to {}: {}", self.endpoint, err);
+                return;
+            }
+        };
+        match fetch(&self.client, req, self.timeout).await {
+            Ok((parts, _)) if parts.status.is_success() => log::debug!("Exported trace to {}", self.endpoint),
+            Ok((parts, body)) => log::warn!(
+                "[⚠️] Trace collector {} answered {}: {}",
+                self.endpoint,
+                parts.status,
+                String::from_utf8_lossy(&body)
+            ),
+            Err(err) => log::warn!("[⚠️] Cannot export trace to {}: {}", self.endpoint, err),
+        }
+    }
+}
+
+/// Turns events into spans. Times are Unix nanoseconds as of when each
+/// event was received.
+struct Recorder {
+    service: String,
+    run: Option<Run>,
+}
+
+struct Run {
+    trace_id: String,
+    root_id: String,
+    start: u64,
+    /// The open discovery span's id and start.
+    discovery: Option<(String, u64)>,
+    /// Why discovery failed, which then fails the run.
+    error: Option<String>,
+    tunnels: HashMap<String, String>,
+    binding: HashMap<String, u64>,
+    spans: Vec<Value>,
+}
+
+impl Recorder {
+    fn new(service: String) -> Self {
+        Recorder { service, run: None }
+    }
+
+    /// The current run, started at `now` if there is none: `--retry-failed`
+    /// binds without discovering first.
+    fn run(&mut self, now: u64) -> &mut Run {
+        self.run.get_or_insert_with(|| Run {
+            trace_id: hex(&random_bytes()),
+            root_id: span_id(),
+            start: now,
+            discovery: None,
+            error: None,
+            tunnels: HashMap::new(),
+            binding: HashMap::new(),
+            spans: Vec::new(),
+        })
+    }
+
+    fn record(&mut self, event: &RebindEvent, now: u64) -> Option<Value> {
+        match event {
+            RebindEvent::DiscoveryStarted => {
+                // A run that never completed is exported before the next starts.
+                let unfinished = self.close(now, None);
+                self.run(now).discovery = Some((span_id(), now));
+                return unfinished;
+            }
+            RebindEvent::TunnelFound { name, url } => {
+                self.run(now).tunnels.insert(name.clone(), url.clone());
+            }
+            RebindEvent::DiscoveryFinished { tunnels, error } => {
+                let run = self.run(now);
+                run.error = error.clone();
+                run.end_discovery(now, vec![int_attr("rebind.tunnels", *tunnels as i64)]);
+            }
+            RebindEvent::BindStarted { name } => {
+                self.run(now).binding.insert(name.clone(), now);
+            }
+            RebindEvent::BindResult { name, outcome, detail, status, duration } => {
+                let run = self.run(now);
+                let start = run.binding.remove(name).unwrap_or_else(|| now.saturating_sub(duration.as_nanos() as u64));
+                let mut attributes = vec![str_attr("rebind.bot", name), str_attr("rebind.outcome", outcome)];
+                if let Some(url) = run.tunnels.get(name) {
+                    attributes.push(str_attr("url.full", url));
+                }
+                if let Some(status) = status {
+                    attributes.push(int_attr("http.response.status_code", i64::from(*status)));
+                }
+                let error = matches!(*outcome, "failed" | "unhealthy").then(|| detail.clone().unwrap_or_default());
+                let span = span(run, &span_id(), "rebind.bind", (start, now), attributes, error.as_deref());
+                run.spans.push(span);
+            }
+            RebindEvent::RunComplete { summary } => return self.close(now, Some(summary)),
+        }
+        None
+    }
+
+    /// Ends the current run at `now` and builds its export. Without a
+    /// `summary` the run didn't complete and is marked as failed.
+    fn close(&mut self, now: u64, summary: Option<&RunSummary>) -> Option<Value> {
+        let mut run = self.run.take()?;
+        run.end_discovery(now, Vec::new());
+        let (attributes, error) = match summary {
+            Some(summary) => (
+                vec![
+                    int_attr("rebind.bound", summary.bound as i64),
+                    int_attr("rebind.unchanged", summary.unchanged as i64),
+                    int_attr("rebind.failed", summary.failed as i64),
+                    int_attr("rebind.skipped", summary.skipped as i64),
+                ],
+                (summary.failed > 0).then(|| format!("{} bots failed", summary.failed)),
+            ),
+            None => (Vec::new(), Some(run.error.clone().unwrap_or_else(|| "the run did not complete".to_string()))),
+        };
+        let root = json!({
+            "traceId": run.trace_id,
+            "spanId": run.root_id,
+            "name": "rebind.run",
+            "kind": KIND_INTERNAL,
+            "startTimeUnixNano": run.start.to_string(),
+            "endTimeUnixNano": now.to_string(),
+            "attributes": attributes,
+            "status": status(error.as_deref()),
+        });
+        run.spans.insert(0, root);
+        Some(json!({
+            "resourceSpans": [{
+                "resource": { "attributes": [str_attr("service.name", &self.service)] },
+                "scopeSpans": [{
+                    "scope": { "name": "rebind", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": run.spans,
+                }],
+            }],
+        }))
+    }
+}
+
+impl Run {
+    fn end_discovery(&mut self, now: u64, attributes: Vec<Value>) {
+        if let Some((id, start)) = self.discovery.take() {
+            let span = span(self, &id, "rebind.discovery", (start, now), attributes, self.error.as_deref());
+            self.spans.push(span);
+        }
+    }
+}
+
+/// A child of `run`'s root span.
+fn span(run: &Run, id: &str, name: &str, (start, end): (u64, u64), attributes: Vec<Value>, error: Option<&str>) -> Value {
+    json!({
+        "traceId": run.trace_id,
+        "spanId": id,
+        "parentSpanId": run.root_id,
+        "name": name,
+        "kind": KIND_INTERNAL,
+        "startTimeUnixNano": start.to_string(),
+        "endTimeUnixNano": end.to_string(),
+        "attributes": attributes,
+        "status": status(error),
+    })
+}
+
+fn status(error: Option<&str>) -> Value {
+    match error {
+        Some(message) => json!({ "code": STATUS_ERROR, "message": message }),
+        None => json!({ "code": STATUS_OK }),
+    }
+}
+
+fn str_attr(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// OTLP/JSON writes 64-bit integers as strings.
+fn int_attr(key: &str, value: i64) -> Value {
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+fn span_id() -> String {
+    hex(&random_bytes()[..8])
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_run_becomes_one_trace() {
+        let mut recorder = Recorder::new("rebind".to_string());
+        let events = [
+            RebindEvent::DiscoveryStarted,
+            RebindEvent::TunnelFound { name: "gpt4o".to_string(), url: "https://a.ngrok.io".to_string() },
+            RebindEvent::DiscoveryFinished { tunnels: 1, error: None },
+            RebindEvent::BindStarted { name: "gpt4o".to_string() },
+            RebindEvent::BindResult {
+                name: "gpt4o".to_string(),
+                outcome: "failed",
+                detail: Some("401 Unauthorized: the token is wrong or revoked".to_string()),
+                status: Some(401),
+                duration: Duration::from_millis(5),
+            },
+        ];
+        for (at, event) in events.iter().enumerate() {
+            assert!(recorder.record(event, at as u64 * 10).is_none());
+        }
+        let summary = RunSummary { bound: 0, unchanged: 0, failed: 1, skipped: 0 };
+        let payload = recorder.record(&RebindEvent::RunComplete { summary }, 100).unwrap();
+        let spans = payload["resourceSpans"][0]["scopeSpans"][0]["spans"].as_array().unwrap();
+        let names: Vec<_> = spans.iter().map(|s| s["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["rebind.run", "rebind.discovery", "rebind.bind"]);
+        assert!(spans.iter().all(|s| s["traceId"] == spans[0]["traceId"]));
+        assert_eq!(spans[2]["parentSpanId"], spans[0]["spanId"]);
+        assert_eq!((&spans[2]["startTimeUnixNano"], &spans[2]["endTimeUnixNano"]), (&json!("30"), &json!("40")));
+        assert_eq!(spans[2]["status"]["code"], json!(STATUS_ERROR));
+        assert!(spans[2]["attributes"].as_array().unwrap().contains(&int_attr("http.response.status_code", 401)));
+        assert!(spans[2]["attributes"].as_array().unwrap().contains(&str_attr("url.full", "https://a.ngrok.io")));
+        assert!(recorder.run.is_none());
+    }
+}
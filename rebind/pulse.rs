@@ -0,0 +1,124 @@
+//! `rebind_complete` / `rebind_alert` pulses in the SentientOS pulse shape
+//! (`timestamp`, `source_daemon`, `event_type`, `priority`, `payload`),
+//! written as JSON lines for the monitoring daemon to pick up.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::ledger::append_jsonl;
+use crate::logger::timestamp;
+use crate::{DiscoveryError, Outcome, RebindReport};
+
+pub const DEFAULT_PULSE_PATH: &str = "/glow/rebind/pulse.jsonl";
+pub const SOURCE_DAEMON: &str = "rebind";
+
+#[derive(Debug, Clone)]
+pub struct PulseSink {
+    pub path: PathBuf,
+}
+
+impl PulseSink {
+    /// `REBIND_PULSE_PATH` (default [`DEFAULT_PULSE_PATH`]); empty disables
+    /// pulses.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("REBIND_PULSE_PATH").unwrap_or_else(|_| DEFAULT_PULSE_PATH.to_string());
+        if path.trim().is_empty() {
+            return None;
+        }
+        Some(PulseSink { path: PathBuf::from(path) })
+    }
+
+    /// Writes one pulse. `priority` is one of `info`, `warning`, `critical`.
+    pub fn emit(&self, event_type: &str, priority: &str, payload: Value) {
+        let pulse = json!({
+            "timestamp": timestamp(),
+            "source_daemon": SOURCE_DAEMON,
+            "event_type": event_type,
+            "priority": priority,
+            "payload": payload,
+        });
+        if let Err(err) = append_jsonl(&self.path, &[pulse]) {
+            log::warn!("[⚠️] Cannot write pulse to {}: {}", self.path.display(), err);
+        }
+    }
+
+    /// `rebind_complete` with the counts, plus, when `alert`, `rebind_alert`
+    /// if a bot failed or no tunnel was found for any bot.
+    pub fn report(&self, run_id: &str, report: &RebindReport, tunnels_found: usize, alert: bool) {
+        let counts = json!({
+            "run_id": run_id,
+            "bound": report.bound(),
+            "unchanged": report.unchanged(),
+            "failed": report.failed(),
+            "skipped": report.skipped(),
+        });
+        self.emit("rebind_complete", "info", counts);
+
+        let failed: Vec<Value> = report
+            .outcomes
+            .iter()
+            .filter_map(|o| match &o.outcome {
+                Outcome::Failed(err) => Some(json!({ "bot": o.bot, "error": err.to_string() })),
+                _ => None,
+            })
+            .collect();
+        if !alert {
+            return;
+        }
+        if tunnels_found == 0 {
+            self.emit("rebind_alert", "warning", json!({ "run_id": run_id, "reason": "no tunnels found", "failed": failed }));
+        } else if !failed.is_empty() {
+            self.emit("rebind_alert", "warning", json!({ "run_id": run_id, "reason": "bind failed", "failed": failed }));
+        }
+    }
+
+    /// `rebind_alert` naming each bot with more than `threshold` updates
+    /// queued, and how many.
+    pub fn backlog(&self, run_id: &str, backlogged: &[(&str, u64)], threshold: u64) {
+        let bots: Vec<Value> =
+            backlogged.iter().map(|(bot, pending)| json!({ "bot": bot, "pending_update_count": pending })).collect();
+        self.emit(
+            "rebind_alert",
+            "warning",
+            json!({ "run_id": run_id, "reason": "backlog", "threshold": threshold, "bots": bots }),
+        );
+    }
+
+    /// `rebind_alert` for a bind that took `factor` times its moving
+    /// average or more.
+    pub fn slow_bind(&self, run_id: &str, bot: &str, took: Duration, average_ms: f64, factor: f64) {
+        self.emit(
+            "rebind_alert",
+            "warning",
+            json!({
+                "run_id": run_id,
+                "reason": "slow bind",
+                "bot": bot,
+                "duration_ms": took.as_millis() as u64,
+                "average_ms": average_ms.round() as u64,
+                "factor": factor,
+            }),
+        );
+    }
+
+    /// `rebind_alert` naming each bot that has gone `after` without a
+    /// success, with how long it has been (`null` for never).
+    pub fn overdue(&self, run_id: &str, overdue: &[(String, Option<Duration>)], after: Duration) {
+        let bots: Vec<Value> = overdue
+            .iter()
+            .map(|(bot, age)| json!({ "bot": bot, "secs_since_success": age.map(|a| a.as_secs()) }))
+            .collect();
+        self.emit(
+            "rebind_alert",
+            "warning",
+            json!({ "run_id": run_id, "reason": "no recent success", "after_secs": after.as_secs(), "bots": bots }),
+        );
+    }
+
+    /// `rebind_alert` for a run that couldn't reach any tunnel agent.
+    pub fn discovery_failed(&self, run_id: &str, err: &DiscoveryError) {
+        self.emit("rebind_alert", "critical", json!({ "run_id": run_id, "reason": err.to_string() }));
+    }
+}
@@ -0,0 +1,237 @@
+//! Token bucket shared by every concurrent Telegram request, so binding many
+//! bots at once stays under the Bot API's global request rate, and the
+//! request slots that cap how many of a batch's requests are in flight.
+//! Requests waiting on either go in order of their bot's priority.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, Instant};
+
+use crate::config::{env_or, DEFAULT_PRIORITY};
+
+pub const DEFAULT_RATE_PER_SEC: f64 = 20.0;
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Tokens added per second; also the bucket size, so up to one second's
+    /// worth of requests may go out in a burst.
+    rate: f64,
+    bucket: Mutex<Bucket>,
+    turns: Turns,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    /// Set after a 429 so every task waits out Telegram's `retry_after`.
+    paused_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> Self {
+        RateLimiter {
+            rate,
+            bucket: Mutex::new(Bucket { tokens: rate, refilled: Instant::now(), paused_until: None }),
+            turns: Turns::default(),
+        }
+    }
+
+    /// Takes a token as of `now`, or says how long to wait before trying again.
+    fn try_take(&self, now: Instant) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        if let Some(until) = bucket.paused_until {
+            if now < until {
+                return Err(until - now);
+            }
+            bucket.paused_until = None;
+        }
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Waits until a request may be sent. Callers waiting for a token are
+    /// let through by priority, then in the order they arrived.
+    pub async fn acquire(&self) {
+        let _turn = self.turns.wait().await;
+        while let Err(wait) = self.try_take(Instant::now()) {
+            sleep(wait).await;
+        }
+    }
+
+    /// Holds every caller back for `wait`, e.g. after Telegram answered 429.
+    pub fn pause(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.paused_until.is_none_or(|current| current < until) {
+            bucket.paused_until = Some(until);
+        }
+    }
+}
+
+/// The process-wide Telegram limiter: `REBIND_RATE_PER_SEC` requests per
+/// second (default 20, Telegram allows about 30). `0` disables limiting.
+pub fn telegram_limiter() -> Option<&'static RateLimiter> {
+    static LIMITER: OnceLock<Option<RateLimiter>> = OnceLock::new();
+    LIMITER
+        .get_or_init(|| {
+            let rate = env_or("REBIND_RATE_PER_SEC", DEFAULT_RATE_PER_SEC);
+            (rate > 0.0).then(|| RateLimiter::new(rate))
+        })
+        .as_ref()
+}
+
+/// Hands out one turn at a time, to the waiter with the highest priority
+/// and, among equals, the one that asked first.
+#[derive(Debug, Default)]
+struct Turns {
+    state: Mutex<TurnQueue>,
+    changed: Notify,
+}
+
+#[derive(Debug, Default)]
+struct TurnQueue {
+    /// `(priority, arrival)` of every waiter.
+    waiting: Vec<(i64, u64)>,
+    arrivals: u64,
+    taken: bool,
+}
+
+/// A held turn; the next waiter's comes when it is dropped.
+struct Turn<'a>(&'a Turns);
+
+/// A waiter given up on before its turn came, e.g. by a deadline.
+struct Waiting<'a>(&'a Turns, u64);
+
+impl Turns {
+    async fn wait(&self) -> Turn<'_> {
+        let priority = current_priority();
+        let waiting = {
+            let mut queue = self.state.lock().unwrap();
+            let arrival = queue.arrivals;
+            queue.arrivals += 1;
+            queue.waiting.push((priority, arrival));
+            Waiting(self, arrival)
+        };
+        loop {
+            // Registered before checking, so a release in between isn't missed.
+            let changed = self.changed.notified();
+            {
+                let mut queue = self.state.lock().unwrap();
+                let first = queue.waiting.iter().max_by_key(|(priority, arrival)| (*priority, std::cmp::Reverse(*arrival)));
+                if !queue.taken && first.is_some_and(|(_, arrival)| *arrival == waiting.1) {
+                    queue.taken = true;
+                    queue.waiting.retain(|(_, arrival)| *arrival != waiting.1);
+                    return Turn(self);
+                }
+            }
+            changed.await;
+        }
+    }
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().taken = false;
+        self.0.changed.notify_waiters();
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().waiting.retain(|(_, arrival)| *arrival != self.1);
+        self.0.changed.notify_waiters();
+    }
+}
+
+tokio::task_local! {
+    static REQUEST_SLOTS: Arc<RequestSlots>;
+    static PRIORITY: i64;
+}
+
+struct RequestSlots {
+    permits: Arc<Semaphore>,
+    turns: Turns,
+}
+
+/// Runs `fut` with at most `slots` HTTP requests in flight at once. A slot
+/// is held for one request only, so a bot sleeping between retries doesn't
+/// keep the others waiting.
+pub async fn with_request_slots<F: Future>(slots: usize, fut: F) -> F::Output {
+    let slots = RequestSlots { permits: Arc::new(Semaphore::new(slots.max(1))), turns: Turns::default() };
+    REQUEST_SLOTS.scope(Arc::new(slots), fut).await
+}
+
+/// A slot for one request, when running under [`with_request_slots`].
+/// Requests waiting for one get it by priority.
+pub(crate) async fn request_slot() -> Option<OwnedSemaphorePermit> {
+    let slots = REQUEST_SLOTS.try_with(Arc::clone).ok()?;
+    let _turn = slots.turns.wait().await;
+    slots.permits.clone().acquire_owned().await.ok()
+}
+
+/// Runs `fut` with its requests waiting their turn at `priority`; higher
+/// goes first. Requests outside it wait at [`DEFAULT_PRIORITY`].
+pub async fn with_priority<F: Future>(priority: i64, fut: F) -> F::Output {
+    PRIORITY.scope(priority, fut).await
+}
+
+fn current_priority() -> i64 {
+    PRIORITY.try_with(|p| *p).unwrap_or(DEFAULT_PRIORITY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refills_at_rate() {
+        let limiter = RateLimiter::new(2.0);
+        let start = limiter.bucket.lock().unwrap().refilled;
+        assert!(limiter.try_take(start).is_ok());
+        assert!(limiter.try_take(start).is_ok());
+        assert_eq!(limiter.try_take(start), Err(Duration::from_millis(500)));
+        assert!(limiter.try_take(start + Duration::from_millis(500)).is_ok());
+
+        limiter.bucket.lock().unwrap().paused_until = Some(start + Duration::from_secs(3));
+        assert_eq!(limiter.try_take(start + Duration::from_secs(1)), Err(Duration::from_secs(2)));
+        assert!(limiter.try_take(start + Duration::from_secs(3)).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_holds_every_caller_back() {
+        let limiter = RateLimiter::new(100.0);
+        let start = Instant::now();
+        limiter.pause(Duration::from_secs(7));
+        limiter.pause(Duration::from_secs(2));
+        limiter.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(7));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiters_go_by_priority() {
+        let limiter = RateLimiter::new(1.0);
+        limiter.pause(Duration::from_secs(1));
+        let order = Mutex::new(Vec::new());
+        let waiter = |name, priority| {
+            let (limiter, order) = (&limiter, &order);
+            with_priority(priority, async move {
+                limiter.acquire().await;
+                order.lock().unwrap().push(name);
+            })
+        };
+        // The first waiter is alone when it arrives, so it goes first anyway.
+        futures_util::future::join4(waiter("a", 50), waiter("low", 10), waiter("high", 90), waiter("mid", 50)).await;
+        assert_eq!(*order.lock().unwrap(), ["a", "high", "mid", "low"]);
+    }
+}
@@ -0,0 +1,410 @@
+//! Just enough regex for URL rewrites: literals, `.`, `[...]` classes,
+//! `\d \w \s` and their negations, groups (`(?:...)` for non-capturing),
+//! `|`, `^`, `$`, and the quantifiers `* + ? {n} {n,} {n,m}`, lazy with a
+//! trailing `?`. Matching backtracks, which is fine for URL-sized input.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Char(char),
+    Any,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+    Start,
+    End,
+    /// Alternatives, and the capture slot when the group captures.
+    Group(Option<usize>, Vec<Vec<Node>>),
+    Repeat { node: Box<Node>, min: u32, max: Option<u32>, greedy: bool },
+}
+
+impl Node {
+    fn accepts(&self, c: char) -> bool {
+        match self {
+            Node::Char(want) => c == *want,
+            Node::Any => c != '\n',
+            Node::Class { ranges, negated } => ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Regex {
+    source: String,
+    alts: Vec<Vec<Node>>,
+    /// Capture groups, not counting the whole match.
+    groups: usize,
+}
+
+impl fmt::Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Start and end (in chars) of each group's last match; slot 0 is the
+/// whole match.
+type Caps = Vec<Option<(usize, usize)>>;
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0, groups: 0 };
+        let alts = parser.alternatives()?;
+        if parser.pos < parser.chars.len() {
+            return Err(format!("unmatched `)` at offset {}", parser.pos));
+        }
+        Ok(Regex { source: pattern.to_string(), alts, groups: parser.groups })
+    }
+
+    pub fn groups(&self) -> usize {
+        self.groups
+    }
+
+    /// The leftmost match in `text`: byte ranges of the whole match and of
+    /// every group, `None` for groups that didn't take part.
+    pub fn captures(&self, text: &str) -> Option<Vec<Option<(usize, usize)>>> {
+        let chars: Vec<char> = text.chars().collect();
+        let matcher = Matcher { text: &chars };
+        let whole = Node::Group(Some(0), self.alts.clone());
+        for start in 0..=chars.len() {
+            let mut caps: Caps = vec![None; self.groups + 1];
+            if matcher.node(&whole, start, &mut caps, &mut |_, _| true) {
+                let offsets: Vec<usize> = text.char_indices().map(|(i, _)| i).chain([text.len()]).collect();
+                return Some(caps.into_iter().map(|cap| cap.map(|(s, e)| (offsets[s], offsets[e]))).collect());
+            }
+        }
+        None
+    }
+
+    /// `text` with its first match replaced by `replacement`, in which `$1`
+    /// or `${1}` stands for a group and `$$` for a dollar sign. `None` when
+    /// nothing matches.
+    pub fn replace(&self, text: &str, replacement: &str) -> Option<String> {
+        let caps = self.captures(text)?;
+        let (start, end) = caps[0].unwrap_or_default();
+        let mut out = text[..start].to_string();
+        for piece in expand(replacement) {
+            match piece {
+                Piece::Literal(s) => out.push_str(s),
+                Piece::Group(n) => {
+                    if let Some(Some((s, e))) = caps.get(n) {
+                        out.push_str(&text[*s..*e]);
+                    }
+                }
+            }
+        }
+        out.push_str(&text[end..]);
+        Some(out)
+    }
+
+    /// Checks that `replacement` only refers to groups this pattern has.
+    pub fn check_replacement(&self, replacement: &str) -> Result<(), String> {
+        match expand(replacement).into_iter().find_map(|p| match p {
+            Piece::Group(n) if n > self.groups => Some(n),
+            _ => None,
+        }) {
+            Some(n) => Err(format!("replacement refers to group {} but the pattern has {}", n, self.groups)),
+            None => Ok(()),
+        }
+    }
+}
+
+enum Piece<'a> {
+    Literal(&'a str),
+    Group(usize),
+}
+
+fn expand(replacement: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = replacement;
+    while let Some(dollar) = rest.find('$') {
+        pieces.push(Piece::Literal(&rest[..dollar]));
+        let after = &rest[dollar + 1..];
+        let braced = after.strip_prefix('{').and_then(|a| a.split_once('}')).filter(|(n, _)| n.parse::<usize>().is_ok());
+        let digits = after.find(|c: char| !c.is_ascii_digit()).unwrap_or(after.len());
+        rest = if let Some((n, tail)) = braced {
+            pieces.push(Piece::Group(n.parse().unwrap()));
+            tail
+        } else if digits > 0 {
+            pieces.push(Piece::Group(after[..digits].parse().unwrap_or(usize::MAX)));
+            &after[digits..]
+        } else if let Some(tail) = after.strip_prefix('$') {
+            pieces.push(Piece::Literal("$"));
+            tail
+        } else {
+            pieces.push(Piece::Literal("$"));
+            after
+        };
+    }
+    pieces.push(Piece::Literal(rest));
+    pieces
+}
+
+/// What a `\` escape stands for.
+enum Escaped {
+    Char(char),
+    Class(Vec<(char, char)>, bool),
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// `a|b|c` up to an unmatched `)` or the end.
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alts = vec![self.sequence()?];
+        while self.eat('|') {
+            alts.push(self.sequence()?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek().filter(|c| *c != '|' && *c != ')') {
+            let at = self.pos;
+            self.pos += 1;
+            let atom = match c {
+                '(' => {
+                    let index = if self.eat('?') {
+                        if !self.eat(':') {
+                            return Err(format!("unsupported group syntax at offset {}", at));
+                        }
+                        None
+                    } else {
+                        self.groups += 1;
+                        Some(self.groups)
+                    };
+                    let alts = self.alternatives()?;
+                    if !self.eat(')') {
+                        return Err(format!("unclosed `(` at offset {}", at));
+                    }
+                    Node::Group(index, alts)
+                }
+                '[' => self.class(at)?,
+                '.' => Node::Any,
+                '^' => Node::Start,
+                '$' => Node::End,
+                '\\' => self.escape()?,
+                '*' | '+' | '?' | '{' => return Err(format!("`{}` at offset {} has nothing to repeat", c, at)),
+                c => Node::Char(c),
+            };
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, String> {
+        let at = self.pos;
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                let close = self.chars[self.pos..].iter().position(|c| *c == '}').map(|i| self.pos + i);
+                let body: String = close.map(|close| self.chars[self.pos + 1..close].iter().collect()).unwrap_or_default();
+                let number = |s: &str| s.trim().parse::<u32>().ok();
+                let bounds = match body.split_once(',') {
+                    None => number(&body).map(|n| (n, Some(n))),
+                    Some((lo, "")) => number(lo).map(|n| (n, None)),
+                    Some((lo, hi)) => number(lo).zip(number(hi)).map(|(lo, hi)| (lo, Some(hi))),
+                };
+                match bounds {
+                    Some((lo, Some(hi))) if hi < lo => return Err(format!("`{{{}}}` at offset {} is backwards", body, at)),
+                    Some(bounds) => {
+                        self.pos = close.unwrap();
+                        bounds
+                    }
+                    None => return Err(format!("invalid repetition at offset {}", at)),
+                }
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        let greedy = !self.eat('?');
+        Ok(Node::Repeat { node: Box::new(atom), min, max, greedy })
+    }
+
+    /// The character after a `\`, outside or inside a class.
+    fn escaped(&mut self) -> Result<Escaped, String> {
+        let Some(c) = self.peek() else { return Err("pattern ends with `\\`".to_string()) };
+        self.pos += 1;
+        let class = |ranges: &[(char, char)], negated| Ok(Escaped::Class(ranges.to_vec(), negated));
+        const DIGIT: &[(char, char)] = &[('0', '9')];
+        const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+        const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+        match c {
+            'd' => class(DIGIT, false),
+            'D' => class(DIGIT, true),
+            'w' => class(WORD, false),
+            'W' => class(WORD, true),
+            's' => class(SPACE, false),
+            'S' => class(SPACE, true),
+            'n' => Ok(Escaped::Char('\n')),
+            't' => Ok(Escaped::Char('\t')),
+            c if c.is_ascii_alphanumeric() => Err(format!("unsupported escape `\\{}`", c)),
+            c => Ok(Escaped::Char(c)),
+        }
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        Ok(match self.escaped()? {
+            Escaped::Char(c) => Node::Char(c),
+            Escaped::Class(ranges, negated) => Node::Class { ranges, negated },
+        })
+    }
+
+    fn class(&mut self, at: usize) -> Result<Node, String> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let Some(c) = self.peek() else { return Err(format!("unclosed `[` at offset {}", at)) };
+            self.pos += 1;
+            let lo = match c {
+                ']' if !first => break,
+                '\\' => match self.escaped()? {
+                    Escaped::Char(c) => c,
+                    Escaped::Class(_, true) => return Err(format!("negated escapes inside `[` at offset {} are not supported", at)),
+                    Escaped::Class(more, false) => {
+                        ranges.extend(more);
+                        first = false;
+                        continue;
+                    }
+                },
+                c => c,
+            };
+            first = false;
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|c| *c != ']') {
+                self.pos += 1;
+                let hi = match self.peek() {
+                    Some('\\') => {
+                        self.pos += 1;
+                        let Escaped::Char(c) = self.escaped()? else {
+                            return Err(format!("invalid range in `[` at offset {}", at));
+                        };
+                        c
+                    }
+                    Some(c) => {
+                        self.pos += 1;
+                        c
+                    }
+                    None => return Err(format!("unclosed `[` at offset {}", at)),
+                };
+                if hi < lo {
+                    return Err(format!("range `{}-{}` at offset {} is backwards", lo, hi, at));
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        Ok(Node::Class { ranges, negated })
+    }
+}
+
+struct Matcher<'t> {
+    text: &'t [char],
+}
+
+/// Called with the position after a match and the captures so far; says
+/// whether the rest of the pattern matched from there.
+type Next<'k> = &'k mut dyn FnMut(usize, &mut Caps) -> bool;
+
+impl Matcher<'_> {
+    fn sequence(&self, nodes: &[Node], at: usize, caps: &mut Caps, next: Next<'_>) -> bool {
+        let Some((node, rest)) = nodes.split_first() else { return next(at, caps) };
+        self.node(node, at, caps, &mut |after, caps| self.sequence(rest, after, caps, next))
+    }
+
+    fn node(&self, node: &Node, at: usize, caps: &mut Caps, next: Next<'_>) -> bool {
+        match node {
+            Node::Start => at == 0 && next(at, caps),
+            Node::End => at == self.text.len() && next(at, caps),
+            Node::Char(_) | Node::Any | Node::Class { .. } => {
+                self.text.get(at).is_some_and(|c| node.accepts(*c)) && next(at + 1, caps)
+            }
+            Node::Group(index, alts) => {
+                for alt in alts {
+                    let matched = self.sequence(alt, at, caps, &mut |end, caps| {
+                        let Some(index) = *index else { return next(end, caps) };
+                        let saved = caps[index];
+                        caps[index] = Some((at, end));
+                        next(end, caps) || {
+                            caps[index] = saved;
+                            false
+                        }
+                    });
+                    if matched {
+                        return true;
+                    }
+                }
+                false
+            }
+            Node::Repeat { node, min, max, greedy } => self.repeat(node, (*min, *max, *greedy), 0, at, caps, next),
+        }
+    }
+
+    fn repeat(&self, node: &Node, bounds: (u32, Option<u32>, bool), count: u32, at: usize, caps: &mut Caps, next: Next<'_>) -> bool {
+        let (min, max, greedy) = bounds;
+        let more = |caps: &mut Caps, next: Next<'_>| {
+            max.is_none_or(|max| count < max)
+                && self.node(node, at, caps, &mut |after, caps| {
+                    // An empty match can't make progress past the minimum.
+                    (after != at || count < min) && self.repeat(node, bounds, count + 1, after, caps, next)
+                })
+        };
+        // Lazy repetition stops as soon as the rest matches.
+        if !greedy && count >= min && next(at, caps) {
+            return true;
+        }
+        more(caps, next) || (greedy && count >= min && next(at, caps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_with_groups() {
+        let re = Regex::new(r"^https://([a-z0-9-]+)\.ngrok(-free)?\.(io|app)$").unwrap();
+        assert_eq!(re.groups(), 3);
+        assert_eq!(re.replace("https://abc123.ngrok.io", "https://bots.example.com").as_deref(), Some("https://bots.example.com"));
+        assert_eq!(re.replace("https://ab-1.ngrok-free.app", "https://$1.example.com").as_deref(), Some("https://ab-1.example.com"));
+        assert_eq!(re.replace("https://abc.example.com", "x"), None);
+        assert!(re.check_replacement("${4}").is_err());
+
+        let lazy = Regex::new(r"a(.+?)b").unwrap();
+        assert_eq!(lazy.replace("xaXbYb", "[$1]").as_deref(), Some("x[X]Yb"));
+        let counted = Regex::new(r"\d{2,3}").unwrap();
+        assert_eq!(counted.captures("a1234").unwrap()[0], Some((1, 4)));
+        assert_eq!(Regex::new(r"[^/]+$").unwrap().replace("https://a/b", "c").as_deref(), Some("https://a/c"));
+        assert_eq!(Regex::new(r"(a|ab)c").unwrap().replace("abc", "$$").as_deref(), Some("$"));
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_parse() {
+        assert!(Regex::new("(abc").is_err());
+        assert!(Regex::new("abc)").is_err());
+        assert!(Regex::new("*a").is_err());
+        assert!(Regex::new("[a-").is_err());
+        assert!(Regex::new(r"\p{L}").is_err());
+        assert!(Regex::new("a{3,1}").is_err());
+    }
+}
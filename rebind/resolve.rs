@@ -0,0 +1,527 @@
+//! Where each run-wide setting comes from. Every setting in [`SPECS`],
+//! and the flags below, is looked up in the same order, and the first
+//! source that sets it wins:
+//!
+//! 1. a command-line flag (`--config`, `--profile`, `--dry-run`, ...);
+//! 2. its environment variable;
+//! 3. the selected `[profiles.NAME]` of the bot table;
+//! 4. the bot table's top level;
+//! 5. the built-in default.
+//!
+//! Not every setting exists at every level: the bot table can't name
+//! itself or its own profile, and most settings have no flag. A key on a
+//! single `[[bot]]`, like its own `drop_pending_updates`, is more specific
+//! than all of these and wins for that bot. `--list` and `--explain` show
+//! which source each setting was taken from.
+//!
+//! Variables that tune one subsystem rather than the run (tunnel
+//! discovery, TLS and proxies, where tokens come from, `--self-test` and
+//! the watch circuit breaker) are read from the environment only;
+//! `docs/ENVIRONMENT.md` lists every variable and the levels it has.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::config::{build_table, file_layers, read_table, source_name, BotTable, ConfigError, DropPending, FileLayer, SuccessPolicy, DEFAULT_CONFIG_PATH};
+use crate::ledger::DEFAULT_LEDGER_PATH;
+use crate::target::{DEFAULT_DISCORD_API_BASE, DEFAULT_SLACK_API_BASE};
+use crate::telegram::{
+    DEFAULT_API_BASE, DEFAULT_HTTP_TIMEOUT_SECS, DEFAULT_MAX_DELAY_MS, DEFAULT_MAX_FLOOD_WAIT_SECS, DEFAULT_RETRY_BUDGET_SECS,
+};
+
+/// The levels a setting can come from, highest precedence first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    Flag,
+    Env,
+    Profile,
+    File,
+    Default,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Flag => "flag",
+            Source::Env => "env",
+            Source::Profile => "profile",
+            Source::File => "file",
+            Source::Default => "default",
+        }
+    }
+}
+
+/// A setting's value and the source that won.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub name: &'static str,
+    /// `None` when no source sets it and there is no default.
+    pub value: Option<String>,
+    pub source: Source,
+    /// The flag, variable or key that set it, e.g. `REBIND_PROFILE` or
+    /// `profiles.staging.api_base`; empty for a default.
+    pub key: String,
+    /// Never shown: `--list` and `--explain` only say whether it is set.
+    pub secret: bool,
+}
+
+impl Setting {
+    /// Where the value came from, for a person: `env TELEGRAM_API_BASE`.
+    pub fn origin(&self) -> String {
+        match self.source {
+            Source::Default => "built-in default".to_string(),
+            source => format!("{} {}", source.as_str(), self.key),
+        }
+    }
+
+    /// Whether an on/off setting is on.
+    pub fn is_on(&self) -> bool {
+        self.value.as_deref() == Some("true")
+    }
+
+    /// The value for a person: `none` when unset, `set` for a secret.
+    pub fn shown(&self) -> &str {
+        match &self.value {
+            None => "none",
+            Some(_) if self.secret => "set",
+            Some(value) => value,
+        }
+    }
+}
+
+/// How a setting's value is checked, and written once it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Any text, trimmed; empty is the same as unset.
+    Text,
+    /// Like `Text`, but empty is a value of its own: an empty
+    /// `REBIND_LEDGER_PATH` turns the ledger off.
+    Path,
+    /// Taken as written and never shown.
+    Secret,
+    /// An `http://` or `https://` URL, without its trailing `/`.
+    Url,
+    /// A whole number.
+    Number,
+    /// On or off: `1`, `true` or `yes`, and `0`, `false` or `no`.
+    Switch,
+    DropPending,
+    SuccessPolicy,
+}
+
+impl Kind {
+    /// `raw` as the setting's value, or why it can't be one.
+    fn check(self, raw: &str) -> Result<String, String> {
+        match self {
+            Kind::Text | Kind::Path | Kind::Secret => Ok(raw.to_string()),
+            Kind::Url if raw.starts_with("http://") || raw.starts_with("https://") => Ok(raw.trim_end_matches('/').to_string()),
+            Kind::Url => Err(format!("`{}` must be an http:// or https:// URL", raw)),
+            Kind::Number => raw.parse::<u64>().map(|n| n.to_string()).map_err(|_| format!("`{}` is not a whole number", raw)),
+            Kind::Switch => match raw.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" => Ok("true".to_string()),
+                "0" | "false" | "no" => Ok("false".to_string()),
+                _ => Err(format!("`{}` must be true or false", raw)),
+            },
+            Kind::DropPending => raw.parse::<DropPending>().map(|drop| drop.as_str().to_string()),
+            Kind::SuccessPolicy => raw.parse::<SuccessPolicy>().map(|policy| policy.to_string()),
+        }
+    }
+
+    /// Whether the bot table may write the value as `value`: text kinds
+    /// only as strings, numbers and switches also bare.
+    fn accepts(self, value: &Value) -> bool {
+        match self {
+            Kind::Text | Kind::Path | Kind::Secret | Kind::Url | Kind::SuccessPolicy => value.is_string(),
+            Kind::Number => value.is_string() || value.is_u64(),
+            Kind::Switch | Kind::DropPending => value.is_string() || value.is_boolean(),
+        }
+    }
+}
+
+/// A run-wide setting the bot table can carry.
+pub struct Spec {
+    /// What `--list` calls it.
+    pub name: &'static str,
+    pub env: &'static str,
+    /// Its key at the top of the bot table and in a profile.
+    pub key: &'static str,
+    default: Option<fn() -> String>,
+    kind: Kind,
+}
+
+/// Every run-wide setting the bot table can carry, in the order `--list`
+/// shows them.
+pub static SPECS: &[Spec] = &[
+    Spec { name: "api_base", env: "TELEGRAM_API_BASE", key: "api_base", default: Some(|| DEFAULT_API_BASE.to_string()), kind: Kind::Url },
+    Spec {
+        name: "discord_api_base",
+        env: "DISCORD_API_BASE",
+        key: "discord_api_base",
+        default: Some(|| DEFAULT_DISCORD_API_BASE.to_string()),
+        kind: Kind::Url,
+    },
+    Spec { name: "slack_api_base", env: "SLACK_API_BASE", key: "slack_api_base", default: Some(|| DEFAULT_SLACK_API_BASE.to_string()), kind: Kind::Url },
+    Spec { name: "webhook_base_path", env: "REBIND_WEBHOOK_BASE_PATH", key: "webhook_base_path", default: None, kind: Kind::Text },
+    Spec {
+        name: "drop_pending",
+        env: "REBIND_DROP_PENDING",
+        key: "drop_pending_updates",
+        default: Some(|| DropPending::Never.as_str().to_string()),
+        kind: Kind::DropPending,
+    },
+    Spec { name: "tg_secret", env: "TG_SECRET", key: "tg_secret", default: None, kind: Kind::Secret },
+    Spec { name: "success_policy", env: "REBIND_SUCCESS_POLICY", key: "success_policy", default: Some(|| SuccessPolicy::All.to_string()), kind: Kind::SuccessPolicy },
+    Spec { name: "concurrency", env: "REBIND_CONCURRENCY", key: "concurrency", default: Some(|| "8".to_string()), kind: Kind::Number },
+    Spec { name: "max_retries", env: "REBIND_MAX_RETRIES", key: "max_retries", default: Some(|| "4".to_string()), kind: Kind::Number },
+    Spec { name: "base_delay_ms", env: "REBIND_BASE_DELAY_MS", key: "base_delay_ms", default: Some(|| "500".to_string()), kind: Kind::Number },
+    Spec { name: "max_delay_ms", env: "REBIND_MAX_DELAY_MS", key: "max_delay_ms", default: Some(|| DEFAULT_MAX_DELAY_MS.to_string()), kind: Kind::Number },
+    Spec {
+        name: "http_timeout_secs",
+        env: "REBIND_HTTP_TIMEOUT_SECS",
+        key: "http_timeout_secs",
+        default: Some(|| DEFAULT_HTTP_TIMEOUT_SECS.to_string()),
+        kind: Kind::Number,
+    },
+    Spec {
+        name: "retry_budget_secs",
+        env: "REBIND_RETRY_BUDGET_SECS",
+        key: "retry_budget_secs",
+        default: Some(|| DEFAULT_RETRY_BUDGET_SECS.to_string()),
+        kind: Kind::Number,
+    },
+    Spec {
+        name: "max_flood_wait_secs",
+        env: "REBIND_MAX_FLOOD_WAIT_SECS",
+        key: "max_flood_wait_secs",
+        default: Some(|| DEFAULT_MAX_FLOOD_WAIT_SECS.to_string()),
+        kind: Kind::Number,
+    },
+    Spec { name: "deadline_secs", env: "REBIND_DEADLINE_SECS", key: "deadline_secs", default: Some(|| "0".to_string()), kind: Kind::Number },
+    Spec { name: "startup_delay_ms", env: "REBIND_STARTUP_DELAY_MS", key: "startup_delay_ms", default: Some(|| "2000".to_string()), kind: Kind::Number },
+    Spec { name: "startup_jitter_ms", env: "REBIND_STARTUP_JITTER_MS", key: "startup_jitter_ms", default: Some(|| "0".to_string()), kind: Kind::Number },
+    Spec { name: "track_secrets", env: "REBIND_TRACK_SECRETS", key: "track_secrets", default: Some(|| "false".to_string()), kind: Kind::Switch },
+    Spec { name: "recent_bind_secs", env: "REBIND_RECENT_BIND_SECS", key: "recent_bind_secs", default: Some(|| "0".to_string()), kind: Kind::Number },
+    Spec { name: "healthcheck", env: "REBIND_HEALTHCHECK", key: "healthcheck", default: Some(|| "false".to_string()), kind: Kind::Switch },
+    Spec { name: "healthcheck_path", env: "REBIND_HEALTHCHECK_PATH", key: "healthcheck_path", default: None, kind: Kind::Text },
+    Spec {
+        name: "healthcheck_timeout_secs",
+        env: "REBIND_HEALTHCHECK_TIMEOUT_SECS",
+        key: "healthcheck_timeout_secs",
+        default: Some(|| "3".to_string()),
+        kind: Kind::Number,
+    },
+    Spec { name: "watch_interval", env: "REBIND_WATCH_INTERVAL", key: "watch_interval", default: Some(|| "30".to_string()), kind: Kind::Number },
+    Spec {
+        name: "watch_heartbeat_secs",
+        env: "REBIND_WATCH_HEARTBEAT_SECS",
+        key: "watch_heartbeat_secs",
+        default: Some(|| "300".to_string()),
+        kind: Kind::Number,
+    },
+    Spec { name: "ledger_path", env: "REBIND_LEDGER_PATH", key: "ledger_path", default: Some(|| DEFAULT_LEDGER_PATH.to_string()), kind: Kind::Path },
+    Spec { name: "signing_key", env: "REBIND_SIGNING_KEY", key: "signing_key", default: None, kind: Kind::Secret },
+    Spec { name: "notify_url", env: "REBIND_NOTIFY_URL", key: "notify_url", default: None, kind: Kind::Url },
+    Spec { name: "notify_secret", env: "REBIND_NOTIFY_SECRET", key: "notify_secret", default: None, kind: Kind::Secret },
+    Spec { name: "notify_timeout_secs", env: "REBIND_NOTIFY_TIMEOUT_SECS", key: "notify_timeout_secs", default: Some(|| "10".to_string()), kind: Kind::Number },
+    Spec { name: "post_bind_command", env: "REBIND_POST_BIND_COMMAND", key: "post_bind_command", default: None, kind: Kind::Text },
+    Spec {
+        name: "post_bind_timeout_secs",
+        env: "REBIND_POST_BIND_TIMEOUT_SECS",
+        key: "post_bind_timeout_secs",
+        default: Some(|| "10".to_string()),
+        kind: Kind::Number,
+    },
+];
+
+/// The spec whose bot table key is `key`, if any.
+pub(crate) fn spec_for_key(key: &str) -> Option<&'static Spec> {
+    SPECS.iter().find(|spec| spec.key == key)
+}
+
+/// Why the bot table's `value` can't be the setting under `key`, if it
+/// can't; `path` is where the table holds it.
+pub(crate) fn file_value_problem(spec: &Spec, path: &str, value: &Value) -> Option<String> {
+    let expected = match spec.kind {
+        Kind::Number => Some("a whole number"),
+        Kind::Switch => Some("true or false"),
+        Kind::DropPending => Some("true, false or \"first-only\""),
+        _ => None,
+    };
+    if !spec.kind.accepts(value) {
+        return Some(format!("`{}` must be {}, not {}", path, expected.unwrap_or("a string"), value));
+    }
+    let text = file_text(value)?;
+    match (spec.kind.check(&text), expected) {
+        (Ok(_), _) => None,
+        (Err(_), _) if text.is_empty() && spec.kind != Kind::Path => None,
+        (Err(_), Some(expected)) => Some(format!("`{}` must be {}, not {}", path, expected, value)),
+        (Err(err), None) => Some(format!("`{}`: {}", path, err)),
+    }
+}
+
+/// A bot table value as its setting sees it: strings trimmed, numbers and
+/// booleans written out.
+pub(crate) fn file_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.trim().to_string()),
+        Value::Bool(_) | Value::Number(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// What the command line set.
+#[derive(Debug, Clone, Default)]
+pub struct Flags {
+    /// `--config`.
+    pub config: Option<String>,
+    /// `--profile`.
+    pub profile: Option<String>,
+    /// `--dry-run`.
+    pub dry_run: bool,
+    /// `--strict-unmapped`.
+    pub strict_unmapped: bool,
+    /// `--wait-lock` or `--wait-lock=SECS`.
+    pub wait_lock: Option<Duration>,
+}
+
+impl Flags {
+    /// The bot table to read: `--config`, then `REBIND_CONFIG`, then
+    /// [`DEFAULT_CONFIG_PATH`].
+    pub fn config(&self) -> Setting {
+        first(
+            "config",
+            vec![
+                (Source::Flag, "--config".to_string(), self.config.clone()),
+                (Source::Env, "REBIND_CONFIG".to_string(), env_value("REBIND_CONFIG")),
+                (Source::Default, String::new(), Some(DEFAULT_CONFIG_PATH.to_string())),
+            ],
+        )
+    }
+
+    /// The profile to lay over the table: `--profile`, then
+    /// `REBIND_PROFILE`; none by default.
+    pub fn profile(&self) -> Setting {
+        first(
+            "profile",
+            vec![
+                (Source::Flag, "--profile".to_string(), self.profile.clone()),
+                (Source::Env, "REBIND_PROFILE".to_string(), env_value("REBIND_PROFILE")),
+            ],
+        )
+    }
+
+    /// `--dry-run`, then `REBIND_DRY_RUN`; off by default.
+    pub fn dry_run(&self) -> Setting {
+        switch("dry_run", "--dry-run", self.dry_run, "REBIND_DRY_RUN")
+    }
+
+    /// `--strict-unmapped`, then `REBIND_STRICT_UNMAPPED`; off by default.
+    pub fn strict_unmapped(&self) -> Setting {
+        switch("strict_unmapped", "--strict-unmapped", self.strict_unmapped, "REBIND_STRICT_UNMAPPED")
+    }
+
+    /// How many seconds to wait for the state file's lock: `--wait-lock`
+    /// only; by default a run gives up at once.
+    pub fn wait_lock(&self) -> Setting {
+        let secs = self.wait_lock.map(|wait| wait.as_secs().to_string());
+        first("wait_lock_secs", vec![(Source::Flag, "--wait-lock".to_string(), secs)])
+    }
+}
+
+/// The bot table and every run-wide setting, resolved.
+#[derive(Debug, Clone)]
+pub struct ResolvedConfig {
+    pub table: BotTable,
+    pub settings: Vec<Setting>,
+}
+
+impl ResolvedConfig {
+    /// The winning value of the setting called `name`.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        value_of(&self.settings, name)
+    }
+}
+
+/// The winning value of the setting called `name` in `settings`.
+pub fn value_of<'a>(settings: &'a [Setting], name: &str) -> Option<&'a str> {
+    settings.iter().find(|s| s.name == name)?.value.as_deref()
+}
+
+/// Resolves the bot table's path and profile, reads it, merges every
+/// other setting with its flag, environment variable and default in the
+/// order the module describes, and builds the table with the winners.
+pub fn resolve_config(flags: &Flags) -> Result<ResolvedConfig, ConfigError> {
+    let config = flags.config();
+    let profile = flags.profile();
+    let path = config.value.clone().unwrap_or_default();
+    let value = read_table(&path)?;
+    let selected = profile.value.clone();
+    let layers = value.as_ref().map(|value| file_layers(value, selected.as_deref())).unwrap_or_default();
+    let mut settings = vec![config, profile];
+    settings.extend(table_settings(source_name(&path), &layers, selected.as_deref(), env_raw, DropPending::Never));
+    settings.extend([flags.dry_run(), flags.strict_unmapped(), flags.wait_lock()]);
+    let table = build_table(source_name(&path), value, selected.as_deref(), &settings)?;
+    Ok(ResolvedConfig { table, settings })
+}
+
+/// Every setting in [`SPECS`], each from `env` (the environment, or
+/// nothing for [`crate::config::parse_table`]), then the selected
+/// `profile`'s entry in `layers`, then the top level's, then its default;
+/// `drop_pending` is the default of `drop_pending`. A value that doesn't
+/// check out is warned about and the next source is tried; the bot table's
+/// own values were already checked by its shape check.
+pub(crate) fn table_settings(
+    path: &str,
+    layers: &HashMap<&'static str, FileLayer>,
+    profile: Option<&str>,
+    env: fn(&str) -> Option<String>,
+    drop_pending: DropPending,
+) -> Vec<Setting> {
+    let mut settings = Vec::new();
+    for spec in SPECS {
+        let layer = layers.get(spec.key).cloned().unwrap_or_default();
+        let from_env = env(spec.env).map(|raw| if spec.kind == Kind::Secret { raw } else { raw.trim().to_string() });
+        let mut candidates = vec![(Source::Env, spec.env.to_string(), from_env)];
+        if let Some(selected) = profile {
+            candidates.push((Source::Profile, format!("profiles.{}.{}", selected, spec.key), layer.profile));
+        }
+        candidates.push((Source::File, format!("{} in {}", spec.key, path), layer.top));
+        let default = match spec.kind {
+            Kind::DropPending => Some(drop_pending.as_str().to_string()),
+            _ => spec.default.map(|default| default()),
+        };
+        candidates.extend(default.map(|value| (Source::Default, String::new(), Some(value))));
+        let candidates = candidates.into_iter().map(|(source, key, value)| {
+            let value = value.filter(|value| !value.is_empty() || spec.kind == Kind::Path).and_then(|value| match spec.kind.check(&value) {
+                Ok(value) => Some(value),
+                Err(err) => {
+                    log::warn!("[⚠️] Ignoring invalid {}: {}", key, err);
+                    None
+                }
+            });
+            (source, key, value)
+        });
+        let mut setting = first(spec.name, candidates.collect());
+        setting.secret = spec.kind == Kind::Secret;
+        settings.push(setting);
+    }
+    settings
+}
+
+/// The value of the setting called `name` in `settings`, parsed; `None`
+/// when nothing sets it.
+pub fn parsed<T: FromStr>(settings: &[Setting], name: &str) -> Option<T> {
+    value_of(settings, name)?.parse().ok()
+}
+
+/// Whether the on/off setting called `name` is on in `settings`.
+pub fn is_on(settings: &[Setting], name: &str) -> bool {
+    value_of(settings, name) == Some("true")
+}
+
+/// `settings` as an indented block, one per line with its source.
+pub fn describe(settings: &[Setting]) -> String {
+    let mut out = String::new();
+    for setting in settings {
+        let _ = writeln!(out, "  {:<24} {} ({})", setting.name, setting.shown(), setting.origin());
+    }
+    out
+}
+
+/// An on/off setting: `flag` when given, then `var`, then off. A `var`
+/// that isn't a [`Kind::Switch`] value is ignored with a warning.
+fn switch(name: &'static str, flag: &str, set: bool, var: &str) -> Setting {
+    let from_env = env_value(var).and_then(|value| match Kind::Switch.check(&value) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            log::warn!("[⚠️] Ignoring invalid {}: {}", var, err);
+            None
+        }
+    });
+    first(
+        name,
+        vec![
+            (Source::Flag, flag.to_string(), set.then(|| "true".to_string())),
+            (Source::Env, var.to_string(), from_env),
+            (Source::Default, String::new(), Some("false".to_string())),
+        ],
+    )
+}
+
+/// The first of `candidates`, in precedence order, that sets a value; a
+/// setting without a value when none does.
+fn first(name: &'static str, candidates: Vec<(Source, String, Option<String>)>) -> Setting {
+    debug_assert!(candidates.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    candidates
+        .into_iter()
+        .find_map(|(source, key, value)| value.map(|value| Setting { name, value: Some(value), source, key, secret: false }))
+        .unwrap_or(Setting { name, value: None, source: Source::Default, key: String::new(), secret: false })
+}
+
+/// `key` from the environment as it is set, empty or not.
+fn env_raw(key: &str) -> Option<String> {
+    env::var(key).ok()
+}
+
+/// `key` from the environment, trimmed; `None` when unset or empty.
+fn env_value(key: &str) -> Option<String> {
+    env::var(key).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn the_first_source_that_sets_a_value_wins() {
+        let path = env::temp_dir().join(format!("rebind-resolve-{}.toml", std::process::id()));
+        let src = "webhook_base_path = \"/tg\"\ndrop_pending_updates = true\nconcurrency = 3\ntg_secret = \"s3cret\"\n\n[[bot]]\nport = 9977\nname = \"gpt4o\"\n\n\
+                   [profiles.staging]\nwebhook_base_path = \"/staging\"\ndrop_pending_updates = \"first-only\"\nhttp_timeout_secs = 5\n";
+        fs::write(&path, src).unwrap();
+        let config = path.to_str().unwrap().to_string();
+        let flags = |profile: Option<&str>| Flags { config: Some(config.clone()), profile: profile.map(str::to_string), ..Flags::default() };
+        let won = |resolved: &ResolvedConfig, name: &str| resolved.settings.iter().find(|s| s.name == name).unwrap().clone();
+
+        env::set_var("REBIND_WEBHOOK_BASE_PATH", "/env");
+        let from_env = resolve_config(&flags(Some("staging")));
+        env::remove_var("REBIND_WEBHOOK_BASE_PATH");
+        let from_env = from_env.unwrap();
+        let setting = won(&from_env, "webhook_base_path");
+        assert_eq!((setting.value.as_deref(), setting.origin()), (Some("/env"), "env REBIND_WEBHOOK_BASE_PATH".to_string()));
+        assert_eq!(from_env.table.bots[0].webhook_path, "/env/webhook");
+
+        let staging = resolve_config(&flags(Some("staging"))).unwrap();
+        assert_eq!(won(&staging, "webhook_base_path").origin(), "profile profiles.staging.webhook_base_path");
+        assert_eq!(staging.table.bots[0].webhook_path, "/staging/webhook");
+        assert_eq!(won(&staging, "drop_pending").value.as_deref(), Some("first-only"));
+        assert_eq!(staging.table.bots[0].drop_pending_updates, DropPending::FirstOnly);
+        assert_eq!(parsed::<u64>(&staging.settings, "http_timeout_secs"), Some(5));
+
+        let shared = resolve_config(&flags(None)).unwrap();
+        fs::remove_file(&path).unwrap();
+        let setting = won(&shared, "webhook_base_path");
+        assert_eq!((setting.source, setting.key), (Source::File, format!("webhook_base_path in {}", config)));
+        assert_eq!(shared.table.bots[0].webhook_path, "/tg/webhook");
+        assert_eq!(shared.table.bots[0].drop_pending_updates, DropPending::Always);
+        assert_eq!(won(&shared, "api_base").value.as_deref(), Some(DEFAULT_API_BASE));
+        assert_eq!(parsed::<usize>(&shared.settings, "concurrency"), Some(3));
+        assert_eq!(parsed::<u64>(&shared.settings, "http_timeout_secs"), Some(DEFAULT_HTTP_TIMEOUT_SECS));
+        assert_eq!(value_of(&shared.settings, "tg_secret"), Some("s3cret"));
+        assert_eq!(won(&shared, "tg_secret").shown(), "set");
+        assert_eq!(describe(&[won(&shared, "wait_lock_secs")]), "  wait_lock_secs           none (built-in default)\n");
+    }
+
+    #[test]
+    fn bot_table_settings_are_checked_like_their_variables() {
+        let problem = |src: &str| crate::config::parse_bots("bots.toml", src, false).unwrap_err().to_string();
+        assert!(problem("concurrency = \"lots\"\n").contains("line 1: `concurrency` must be a whole number, not \"lots\""));
+        assert!(problem("[profiles.ci]\ntrack_secrets = 2\n").contains("line 2: `profiles.ci.track_secrets` must be true or false, not 2"));
+        assert!(problem("notify_url = \"ftp://x\"\n").contains("line 1: `notify_url`:"));
+    }
+}
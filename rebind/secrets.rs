@@ -0,0 +1,151 @@
+//! Per-bot webhook secrets kept in `REBIND_SECRETS_FILE`, a JSON object
+//! mapping bot names to secrets, and `--rotate-secret`, which replaces them.
+//! An entry there wins over the bot's environment variable, so a rotated
+//! secret sticks even while the old one is still exported.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::{env, fs, io};
+
+use crate::config::{env_or, BotBinding, DropPending};
+use crate::ledger::random_bytes;
+use crate::target::{Targets, Telegram, WebhookTarget};
+use crate::telegram;
+
+/// Length of a generated secret by default (`REBIND_SECRET_LENGTH`).
+pub const DEFAULT_SECRET_LENGTH: usize = 64;
+
+/// The characters Telegram accepts in `secret_token`; exactly 64, so a
+/// random byte masked to six bits picks one uniformly.
+const SECRET_CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789_-";
+
+/// `REBIND_SECRETS_FILE`, if set.
+pub fn secrets_path() -> Option<PathBuf> {
+    env::var("REBIND_SECRETS_FILE").ok().filter(|p| !p.trim().is_empty()).map(|p| PathBuf::from(p.trim()))
+}
+
+/// Reads the secrets file; a file that doesn't exist yet is empty.
+pub fn load(path: &Path) -> Result<HashMap<String, String>, String> {
+    let src = match fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(format!("cannot read {}: {}", path.display(), err)),
+    };
+    serde_json::from_str(&src)
+        .map_err(|e| format!("{} must be a JSON object mapping bot names to secrets: {}", path.display(), e))
+}
+
+/// Replaces the file in one rename, readable by its owner only.
+pub fn save(path: &Path, secrets: &HashMap<String, String>) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    let mut sorted: Vec<_> = secrets.iter().collect();
+    sorted.sort();
+    let body: serde_json::Map<String, serde_json::Value> =
+        sorted.into_iter().map(|(bot, secret)| (bot.clone(), secret.clone().into())).collect();
+    fs::write(&tmp, serde_json::to_vec_pretty(&body).unwrap())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+    }
+    fs::rename(&tmp, path)
+}
+
+/// `bot`'s entry in the secrets file, read once per process. A file that
+/// can't be read counts as empty here; the binary refuses to start on one.
+pub fn file_secret(bot: &str) -> Option<(String, String)> {
+    static SECRETS: OnceLock<Option<(PathBuf, HashMap<String, String>)>> = OnceLock::new();
+    let (path, secrets) = SECRETS
+        .get_or_init(|| {
+            let path = secrets_path()?;
+            let secrets = load(&path).unwrap_or_default();
+            Some((path, secrets))
+        })
+        .as_ref()?;
+    let secret = secrets.get(bot).filter(|s| !s.is_empty())?;
+    Some((format!("`{}` in {}", bot, path.display()), secret.clone()))
+}
+
+/// A random secret of `len` characters (clamped to Telegram's 1–256).
+pub fn generate(len: usize) -> String {
+    let len = len.clamp(1, 256);
+    let mut out = String::with_capacity(len);
+    while out.len() < len {
+        for byte in random_bytes() {
+            if out.len() < len {
+                out.push(SECRET_CHARSET[usize::from(byte & 0x3f)] as char);
+            }
+        }
+    }
+    out
+}
+
+/// `REBIND_SECRET_LENGTH`, default [`DEFAULT_SECRET_LENGTH`].
+pub fn secret_length() -> usize {
+    env_or("REBIND_SECRET_LENGTH", DEFAULT_SECRET_LENGTH)
+}
+
+/// Re-sends `bot`'s live webhook URL with a fresh secret and checks that
+/// the URL survived. Returns the new secret, which only takes effect once
+/// the caller saves it.
+pub async fn rotate(targets: Targets<'_>, bot: &BotBinding, len: usize) -> Result<String, String> {
+    if bot.secret.is_some() {
+        return Err("secret is set inline in the bot table; rotate it there".to_string());
+    }
+    let token = targets.tokens.token(bot).map_err(|e| e.to_string())?;
+    let telegram = |secret: String| Telegram {
+        client: targets.client,
+        api_base: targets.telegram_api_base,
+        retry: targets.retry,
+        secret,
+    };
+    let current = telegram(telegram::resolve_secret(bot, targets.secret));
+    let live = current.live_webhook(bot, &token).await.map_err(|e| format!("getWebhookInfo failed: {}", e))?;
+    if live.url.is_empty() {
+        return Err("no webhook is set; bind it before rotating its secret".to_string());
+    }
+    let Some(public_url) = live.url.strip_suffix(&bot.webhook_path) else {
+        return Err(format!("live webhook {} doesn't end with webhook_path {}", live.url, bot.webhook_path));
+    };
+
+    // A rotation must not discard updates that are already queued.
+    let bot = &BotBinding { drop_pending_updates: DropPending::Never, ..bot.clone() };
+    let secret = generate(len);
+    let rotated = telegram(secret.clone());
+    rotated.bind(bot, &token, public_url).await.map_err(|e| format!("setWebhook failed: {}", e))?;
+    let after = rotated.live_webhook(bot, &token).await.map_err(|e| format!("getWebhookInfo failed: {}", e))?;
+    if after.url != live.url {
+        return Err(format!("webhook changed from {} to {} during rotation", live.url, after.url));
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_secrets_are_valid_and_distinct() {
+        let secret = generate(DEFAULT_SECRET_LENGTH);
+        assert_eq!(secret.len(), DEFAULT_SECRET_LENGTH);
+        assert!(telegram::validate_secret("test", &secret).is_ok());
+        assert_ne!(secret, generate(DEFAULT_SECRET_LENGTH));
+        assert_eq!(generate(1000).len(), 256);
+        assert_eq!(generate(0).len(), 1);
+    }
+
+    #[test]
+    fn secrets_file_round_trips() {
+        let path = env::temp_dir().join(format!("rebind-secrets-{}.json", std::process::id()));
+        assert!(load(&path).unwrap().is_empty());
+        let secrets = HashMap::from([("gpt4o".to_string(), "s3cret".to_string())]);
+        save(&path, &secrets).unwrap();
+        let loaded = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), secrets);
+    }
+}
@@ -0,0 +1,252 @@
+//! `--self-test`: binds each bot, then proves the webhook is reachable end
+//! to end. A throwaway receiver takes over the bot's local port when it is
+//! free, a synthetic update is posted to the public URL through the tunnel,
+//! and `getWebhookInfo` is checked for delivery errors raised since the bind.
+//!
+//! `--verify-secret` goes one step further and checks the secret Telegram
+//! itself sends along with a real delivery.
+//!
+//! Receivers listen on loopback unless `REBIND_SELF_TEST_ADDR` names another
+//! address, e.g. `0.0.0.0` when the tunnel agent runs in another container.
+
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep;
+
+use crate::config::{env_or, BotBinding, Platform};
+use crate::ledger::new_uuid;
+use crate::telegram::{self, encode_query_value, request_builder, SECRET_HEADER};
+use crate::{discover, DiscoveryError, RebindConfig};
+
+/// Header marking our synthetic update, so the receiver can tell it apart
+/// from real traffic arriving during the test.
+const NONCE_HEADER: &str = "X-Rebind-Self-Test";
+
+/// The address receivers listen on: `REBIND_SELF_TEST_ADDR`, loopback by
+/// default.
+pub fn receiver_ip() -> Result<IpAddr, String> {
+    match std::env::var("REBIND_SELF_TEST_ADDR").ok().filter(|a| !a.trim().is_empty()) {
+        None => Ok(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        Some(raw) => raw
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid REBIND_SELF_TEST_ADDR `{}`: expected an IP address such as 0.0.0.0", raw)),
+    }
+}
+
+/// Stands in for the bot on its local port. Only our own probe gets a 200;
+/// anything else is answered 503 so Telegram keeps real updates queued
+/// instead of handing them to us.
+struct Receiver {
+    received: Arc<AtomicBool>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl Receiver {
+    /// `None` when the port is taken, usually by the bot itself.
+    fn start(addr: SocketAddr, nonce: String, secret: Option<String>) -> Option<Self> {
+        let received = Arc::new(AtomicBool::new(false));
+        let seen = received.clone();
+        let make = make_service_fn(move |_| {
+            let (seen, nonce, secret) = (seen.clone(), nonce.clone(), secret.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+                    let ours = header(NONCE_HEADER).as_deref() == Some(nonce.as_str());
+                    let authorized = secret.is_none() || header(SECRET_HEADER) == secret;
+                    let status = match (ours, authorized) {
+                        (true, true) => {
+                            seen.store(true, Ordering::SeqCst);
+                            StatusCode::OK
+                        }
+                        (true, false) => StatusCode::FORBIDDEN,
+                        (false, _) => StatusCode::SERVICE_UNAVAILABLE,
+                    };
+                    async move { Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap()) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&addr).ok()?.serve(make);
+        let (stop, stopped) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = stopped.await;
+        }));
+        Some(Receiver { received, stop: Some(stop) })
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// Self-tests every Telegram bot in `config.bots`, in table order, with
+/// receivers on `ip`. Discord bots are left out; their endpoint is validated
+/// by Discord on update.
+pub async fn self_test(config: &RebindConfig, ip: IpAddr) -> Result<Vec<(String, Result<(), String>)>, DiscoveryError> {
+    let urls = discover(config).await?;
+    let mut results = Vec::new();
+    for bot in config.bots.iter().filter(|b| b.platform == Platform::Telegram) {
+        let result = match urls.get(&bot.name) {
+            Some(url) => test_bot(config, bot, url, ip).await,
+            None => Err(format!("no tunnel discovered for port {}", bot.port)),
+        };
+        results.push((bot.name.clone(), result));
+    }
+    Ok(results)
+}
+
+async fn test_bot(config: &RebindConfig, bot: &BotBinding, public_url: &str, ip: IpAddr) -> Result<(), String> {
+    let token = config.tokens.token(bot).map_err(|e| e.to_string())?;
+    let secret = telegram::resolve_secret(bot, &config.secret);
+    let nonce = new_uuid();
+    let header_secret = (bot.secret_query.is_none() && !secret.is_empty()).then(|| secret.clone());
+    let receiver = Receiver::start(SocketAddr::new(ip, bot.port), nonce.clone(), header_secret.clone());
+    if receiver.is_none() {
+        log::info!("[🩺] {}: port {} is in use; probing the running server instead", bot.name, bot.port);
+    }
+
+    let bound_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+    config.targets().for_bot(bot).bind(bot, &token, public_url).await.map_err(|e| format!("bind failed: {}", e))?;
+
+    // The payload already carries the secret the way Telegram will send it:
+    // as a header, or folded into the URL in query mode.
+    let payload = telegram::set_webhook_payload(bot, &telegram::webhook_url(bot, public_url), &secret);
+    let target = payload["url"].as_str().unwrap_or_default().to_string();
+    let mut req = request_builder(Method::POST)
+        .uri(&target)
+        .header("Content-Type", "application/json")
+        .header(NONCE_HEADER, &nonce);
+    if let Some(secret) = &header_secret {
+        req = req.header(SECRET_HEADER, secret);
+    }
+    let body = serde_json::json!({ "update_id": 0, "rebind_self_test": nonce }).to_string();
+    let req = req.body(Body::from(body)).map_err(|e| format!("invalid webhook URL: {}", e))?;
+    let (parts, _) = telegram::fetch(&config.client, req, config.retry.for_bot(bot).timeout)
+        .await
+        .map_err(|e| format!("webhook unreachable through the tunnel: {}", e))?;
+    match &receiver {
+        Some(receiver) if !receiver.received.load(Ordering::SeqCst) => {
+            return Err(format!("probe answered {} but never reached port {}", parts.status, bot.port));
+        }
+        None if !parts.status.is_success() => {
+            return Err(format!("webhook answered {} through the tunnel", parts.status));
+        }
+        _ => {}
+    }
+
+    sleep(Duration::from_secs(env_or("REBIND_SELF_TEST_WAIT_SECS", 5u64))).await;
+    let info = telegram::verify_webhook(&config.client, &config.api_base, &token, config.retry.for_bot(bot).timeout)
+        .await
+        .map_err(|e| format!("getWebhookInfo failed: {}", e))?;
+    match (info.last_error_message.as_deref().filter(|m| !m.is_empty()), info.last_error_date) {
+        (Some(message), date) if date.is_none_or(|d| d >= bound_at) => {
+            Err(format!("Telegram reports a delivery error: {}", message))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// How long `--verify-secret` waits for Telegram to deliver an update, by
+/// default (`REBIND_VERIFY_SECRET_WAIT_SECS`).
+pub const DEFAULT_VERIFY_SECRET_WAIT_SECS: u64 = 60;
+
+/// Binds every Telegram bot in `config.bots` with a receiver on its port,
+/// waits for Telegram to deliver an update through the tunnel and checks
+/// that it carries the bot's secret. The Bot API can't be asked for a test
+/// update, so this needs one already queued or a message sent to the bot
+/// while it waits. The update is answered 503, which leaves it queued for
+/// the bot once it is back on its port. Receivers listen on `ip`.
+pub async fn verify_secrets(
+    config: &RebindConfig,
+    ip: IpAddr,
+) -> Result<Vec<(String, Result<(), String>)>, DiscoveryError> {
+    let urls = discover(config).await?;
+    let wait = Duration::from_secs(env_or("REBIND_VERIFY_SECRET_WAIT_SECS", DEFAULT_VERIFY_SECRET_WAIT_SECS));
+    let mut results = Vec::new();
+    for bot in config.bots.iter().filter(|b| b.platform == Platform::Telegram) {
+        let result = match urls.get(&bot.name) {
+            Some(url) => verify_secret(config, bot, url, ip, wait).await,
+            None => Err(format!("no tunnel discovered for port {}", bot.port)),
+        };
+        results.push((bot.name.clone(), result));
+    }
+    Ok(results)
+}
+
+/// The secret each delivery to `addr` came with: the header, or in query
+/// mode the `param` query parameter. Every delivery is answered 503.
+fn catch_deliveries(addr: SocketAddr, param: Option<String>) -> Option<(mpsc::UnboundedReceiver<Option<String>>, oneshot::Sender<()>)> {
+    let (deliveries, caught) = mpsc::unbounded_channel();
+    let make = make_service_fn(move |_| {
+        let (deliveries, param) = (deliveries.clone(), param.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let secret = match &param {
+                    Some(param) => {
+                        let prefix = format!("{}=", encode_query_value(param));
+                        let query = req.uri().query().unwrap_or_default();
+                        query.split('&').find_map(|pair| pair.strip_prefix(&prefix)).map(str::to_string)
+                    }
+                    None => req.headers().get(SECRET_HEADER).and_then(|v| v.to_str().ok()).map(str::to_string),
+                };
+                if req.method() == Method::POST {
+                    let _ = deliveries.send(secret);
+                }
+                async move {
+                    Ok::<_, Infallible>(Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::empty()).unwrap())
+                }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr).ok()?.serve(make);
+    let (stop, stopped) = oneshot::channel::<()>();
+    tokio::spawn(server.with_graceful_shutdown(async {
+        let _ = stopped.await;
+    }));
+    Some((caught, stop))
+}
+
+async fn verify_secret(
+    config: &RebindConfig,
+    bot: &BotBinding,
+    public_url: &str,
+    ip: IpAddr,
+    wait: Duration,
+) -> Result<(), String> {
+    let token = config.tokens.token(bot).map_err(|e| e.to_string())?;
+    let secret = telegram::resolve_secret(bot, &config.secret);
+    let Some((mut caught, _stop)) = catch_deliveries(SocketAddr::new(ip, bot.port), bot.secret_query.clone()) else {
+        return Err(format!("port {} is in use; stop the bot so Telegram's delivery can be received here", bot.port));
+    };
+    config.targets().for_bot(bot).bind(bot, &token, public_url).await.map_err(|e| format!("bind failed: {}", e))?;
+    let pending = telegram::verify_webhook(&config.client, &config.api_base, &token, config.retry.for_bot(bot).timeout)
+        .await
+        .map_or(0, |info| info.pending_update_count);
+    if pending == 0 {
+        log::info!("[🩺] {}: no updates queued; send the bot a message within {}s", bot.name, wait.as_secs());
+    }
+
+    let sent = match &bot.secret_query {
+        Some(param) => format!("the `{}` query parameter", param),
+        None => SECRET_HEADER.to_string(),
+    };
+    match tokio::time::timeout(wait, caught.recv()).await {
+        Err(_) | Ok(None) => Err(format!("Telegram delivered no update within {}s", wait.as_secs())),
+        Ok(Some(None)) if secret.is_empty() => Ok(()),
+        Ok(Some(Some(_))) if secret.is_empty() => Err(format!("Telegram sent {} though this bot has no secret", sent)),
+        Ok(Some(Some(received))) if received == encode_query_value(&secret) || received == secret => Ok(()),
+        Ok(Some(Some(_))) => Err(format!("Telegram sent {} with a different secret than ours", sent)),
+        Ok(Some(None)) => Err(format!("Telegram delivered an update without {}", sent)),
+    }
+}
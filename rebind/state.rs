@@ -0,0 +1,586 @@
+//! Last-known-good bindings, persisted between runs as a JSON object of
+//! bot name to public URL. With `REBIND_TRACK_SECRETS` the object is
+//! wrapped as `{"bindings": {...}, "secrets": {...}}` so each bot's secret
+//! fingerprint is kept alongside its URL. Bots whose last bind failed are
+//! kept under `failed` with the URL they failed on, for `--retry-failed`,
+//! and the tunnel each bot was last discovered on under `tunnels`, with
+//! when it was first seen, so a report can tell how old each tunnel is.
+//! `--watch` also keeps a moving average of each bot's bind latency under
+//! `latency`, and every run notes under `succeeded` when each bot was last
+//! bound, or found still bound, to its tunnel. With
+//! `REBIND_RECENT_BIND_SECS`, `recent` keeps a [`bind_key`] of what each
+//! bot was last successfully bound with, so hosts sharing the state file
+//! skip a bind another one just made.
+//!
+//! Processes that bind take a [`StateLock`] on the state file first, so a
+//! cron job and a watch sharing one never bind at the same time.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, fmt, fs, io};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config::DropPending;
+use crate::sha256::sha256_hex;
+use crate::{BotBinding, Outcome, RebindReport};
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct State {
+    /// Bot name to the public URL it was last bound to.
+    pub bindings: HashMap<String, String>,
+    /// Bot name to the [`fingerprint`] of the secret it was bound with.
+    pub secrets: HashMap<String, String>,
+    /// Bot name to the public URL its last bind attempt failed on.
+    pub failed: HashMap<String, String>,
+    /// Bot name to the tunnel it was last discovered on.
+    pub tunnels: HashMap<String, SeenTunnel>,
+    /// Bot name to the exponential moving average of its bind latency, in
+    /// milliseconds.
+    pub latency: HashMap<String, f64>,
+    /// Bot name to the Unix time it was last seen bound to its current
+    /// tunnel, by a bind or by a poll that found nothing to change.
+    pub succeeded: HashMap<String, i64>,
+    /// Bot name to the [`bind_key`] of its last successful bind.
+    pub recent: HashMap<String, RecentBind>,
+}
+
+/// What a bot was last bound with, as a [`bind_key`], and the Unix time
+/// the bind was verified.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecentBind {
+    pub key: String,
+    pub at: i64,
+}
+
+/// A public URL and the Unix time it was first discovered. Tunnel agents
+/// don't reliably say when a tunnel started, so the first sighting stands
+/// in for it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeenTunnel {
+    pub url: String,
+    pub since: i64,
+}
+
+/// Only ever built to be written or read straight back into [`State`], so
+/// the size of `Tracked` doesn't matter.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
+enum StateFile {
+    Tracked {
+        bindings: HashMap<String, String>,
+        #[serde(default)]
+        secrets: HashMap<String, String>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        failed: HashMap<String, String>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        tunnels: HashMap<String, SeenTunnel>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        latency: HashMap<String, f64>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        succeeded: HashMap<String, i64>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        recent: HashMap<String, RecentBind>,
+    },
+    Plain(HashMap<String, String>),
+}
+
+/// Identifies a secret in the state file without storing it.
+pub fn fingerprint(secret: &str) -> String {
+    sha256_hex(format!("rebind-secret:{}", secret).as_bytes())[..16].to_string()
+}
+
+/// Identifies binding `bot` to `webhook_url` with `secret` and the bot's
+/// `setWebhook` options, without storing the secret. Any change to one of
+/// them changes the key.
+pub fn bind_key(bot: &BotBinding, webhook_url: &str, secret: &str) -> String {
+    let bound = json!([
+        webhook_url,
+        fingerprint(secret),
+        bot.allowed_updates,
+        bot.max_connections,
+        bot.ip_address,
+        match bot.drop_pending_updates {
+            DropPending::FirstOnly => json!("first-only"),
+            drop => json!(drop == DropPending::Always),
+        },
+    ]);
+    sha256_hex(format!("rebind-bind:{}", bound).as_bytes())[..16].to_string()
+}
+
+/// `REBIND_STATE_FILE`, or `~/.cache/rebind/state.json`. An empty
+/// `REBIND_STATE_FILE` turns persistence off.
+pub fn state_path() -> Option<PathBuf> {
+    match env::var("REBIND_STATE_FILE") {
+        Ok(path) if path.trim().is_empty() => None,
+        Ok(path) => Some(PathBuf::from(path)),
+        Err(_) => {
+            let home = env::var_os("HOME")?;
+            Some(Path::new(&home).join(".cache").join("rebind").join("state.json"))
+        }
+    }
+}
+
+/// Reads the saved bindings. A missing file is an empty state; an
+/// unreadable one is reported and treated the same way.
+pub fn load(path: &Path) -> State {
+    match fs::read(path) {
+        Ok(raw) => match serde_json::from_slice(&raw) {
+            Ok(StateFile::Tracked { bindings, secrets, failed, tunnels, latency, succeeded, recent }) => {
+                State { bindings, secrets, failed, tunnels, latency, succeeded, recent }
+            }
+            Ok(StateFile::Plain(bindings)) => State { bindings, ..State::default() },
+            Err(err) => {
+                log::warn!("[⚠️] Ignoring unreadable state file {}: {}", path.display(), err);
+                State::default()
+            }
+        },
+        Err(err) if err.kind() == io::ErrorKind::NotFound => State::default(),
+        Err(err) => {
+            log::warn!("[⚠️] Cannot read state file {}: {}", path.display(), err);
+            State::default()
+        }
+    }
+}
+
+/// Writes `state` atomically, creating the parent directory if needed. The
+/// plain layout is kept while no secrets are tracked, nothing failed or
+/// succeeded, no tunnels were seen, no latency was averaged and no recent
+/// bind was noted.
+pub fn save(path: &Path, state: &State) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let file = if state.secrets.is_empty()
+        && state.failed.is_empty()
+        && state.tunnels.is_empty()
+        && state.latency.is_empty()
+        && state.succeeded.is_empty()
+        && state.recent.is_empty()
+    {
+        StateFile::Plain(state.bindings.clone())
+    } else {
+        StateFile::Tracked {
+            bindings: state.bindings.clone(),
+            secrets: state.secrets.clone(),
+            failed: state.failed.clone(),
+            tunnels: state.tunnels.clone(),
+            latency: state.latency.clone(),
+            succeeded: state.succeeded.clone(),
+            recent: state.recent.clone(),
+        }
+    };
+    let tmp = with_suffix(path, ".tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(&file).unwrap())?;
+    fs::rename(&tmp, path)
+}
+
+/// An advisory lock on `<state file>.lock`, held until dropped. The OS
+/// releases it when the process dies, so a crash never leaves it stuck.
+#[derive(Debug)]
+pub struct StateLock {
+    pub path: PathBuf,
+    _file: File,
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    /// Another process still held the lock when we gave up.
+    Held(PathBuf),
+    Io(PathBuf, io::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Held(path) => write!(f, "another rebind is already running (it holds {})", path.display()),
+            LockError::Io(path, err) => write!(f, "cannot lock {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+/// `path` with `suffix` added to its whole file name, so `state.dat` and
+/// `state.bak` don't share one `.lock`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// How often [`lock`] retries while it waits.
+const LOCK_POLL: Duration = Duration::from_millis(250);
+
+/// Locks the state file at `path`, waiting up to `wait` for another process
+/// to let go; without `wait` a held lock fails at once.
+pub async fn lock(path: &Path, wait: Option<Duration>) -> Result<StateLock, LockError> {
+    let lock_path = with_suffix(path, ".lock");
+    let io_error = |err| LockError::Io(lock_path.clone(), err);
+    if let Some(dir) = lock_path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(io_error)?;
+    }
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path).map_err(io_error)?;
+    let deadline = wait.map(|wait| Instant::now() + wait);
+    let mut announced = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(StateLock { path: lock_path, _file: file }),
+            Err(TryLockError::Error(err)) => return Err(io_error(err)),
+            Err(TryLockError::WouldBlock) => {}
+        }
+        match deadline {
+            Some(deadline) if Instant::now() < deadline => {
+                if !announced {
+                    log::info!("[⏳] Another rebind holds {}; waiting for it to finish", lock_path.display());
+                    announced = true;
+                }
+                tokio::time::sleep(LOCK_POLL).await;
+            }
+            _ => return Err(LockError::Held(lock_path)),
+        }
+    }
+}
+
+impl State {
+    /// Copies every bot that is now bound (or already was) from `urls`, and
+    /// its entry in `secrets` (fingerprints of the secrets just sent), into
+    /// the state, and notes which bots failed on which URL. Returns whether
+    /// anything changed.
+    pub fn record(
+        &mut self,
+        report: &RebindReport,
+        urls: &HashMap<String, String>,
+        secrets: &HashMap<String, String>,
+    ) -> bool {
+        let mut changed = false;
+        for outcome in &report.outcomes {
+            match outcome.outcome {
+                Outcome::Bound { .. } | Outcome::Unchanged { .. } => {
+                    if let Some(url) = urls.get(&outcome.bot) {
+                        changed |= self.bindings.insert(outcome.bot.clone(), url.clone()).as_ref() != Some(url);
+                    }
+                    if let Some(secret) = secrets.get(&outcome.bot) {
+                        changed |= self.secrets.insert(outcome.bot.clone(), secret.clone()).as_ref() != Some(secret);
+                    }
+                    changed |= self.failed.remove(&outcome.bot).is_some();
+                }
+                Outcome::Failed(_) => {
+                    if let Some(url) = urls.get(&outcome.bot) {
+                        changed |= self.failed.insert(outcome.bot.clone(), url.clone()).as_ref() != Some(url);
+                    }
+                }
+                Outcome::Polling { .. } => {
+                    changed |= self.bindings.remove(&outcome.bot).is_some();
+                    changed |= self.failed.remove(&outcome.bot).is_some();
+                }
+                Outcome::NoTunnel | Outcome::Unhealthy(_) => {}
+            }
+        }
+        changed
+    }
+
+    /// Notes the tunnels just discovered at Unix time `now`. A bot whose
+    /// public URL changed got a new tunnel, which is logged with how long the
+    /// old one had been up. Returns whether anything changed.
+    pub fn observe(&mut self, urls: &HashMap<String, String>, now: i64) -> bool {
+        let mut changed = false;
+        for (bot, url) in urls {
+            match self.tunnels.get(bot) {
+                Some(seen) if &seen.url == url => continue,
+                Some(seen) => log::info!(
+                    "[🔄] {}: tunnel restarted after {} up; now {}",
+                    bot,
+                    format_age(Duration::from_secs(now.saturating_sub(seen.since).max(0) as u64)),
+                    url
+                ),
+                None => {}
+            }
+            self.tunnels.insert(bot.clone(), SeenTunnel { url: url.clone(), since: now });
+            changed = true;
+        }
+        changed
+    }
+
+    /// How long each bot in `urls` has had its tunnel as of `now`, for bots
+    /// whose tunnel was seen before on the same URL.
+    pub fn tunnel_ages(&self, urls: &HashMap<String, String>, now: i64) -> HashMap<String, Duration> {
+        urls.iter()
+            .filter_map(|(bot, url)| {
+                let seen = self.tunnels.get(bot).filter(|seen| &seen.url == url)?;
+                Some((bot.clone(), Duration::from_secs(now.saturating_sub(seen.since).max(0) as u64)))
+            })
+            .collect()
+    }
+
+    /// Folds `latency` into `bot`'s moving average with weight `alpha`
+    /// (0 to 1, higher follows recent binds more closely) and returns the
+    /// average before it, `None` for the bot's first bind.
+    pub fn track_latency(&mut self, bot: &str, latency: Duration, alpha: f64) -> Option<f64> {
+        let ms = latency.as_secs_f64() * 1000.0;
+        let previous = self.latency.get(bot).copied();
+        let average = previous.map_or(ms, |average| alpha * ms + (1.0 - alpha) * average);
+        self.latency.insert(bot.to_string(), average);
+        previous
+    }
+
+    /// Notes Unix time `now` as the last success of every bot whose saved
+    /// binding is its URL in `urls` and whose last bind didn't fail. Call it
+    /// after [`State::record`]. Returns whether any bot was noted.
+    pub fn note_successes(&mut self, urls: &HashMap<String, String>, now: i64) -> bool {
+        let mut noted = false;
+        for (bot, url) in urls {
+            if self.bindings.get(bot) == Some(url) && !self.failed.contains_key(bot) {
+                self.succeeded.insert(bot.clone(), now);
+                noted = true;
+            }
+        }
+        noted
+    }
+
+    /// Notes Unix time `now` against `keys` (each bot's [`bind_key`]) for
+    /// every bot of `report` just bound and verified, and drops the entry of
+    /// every bot that failed. Returns whether anything changed.
+    pub fn note_recent(&mut self, report: &RebindReport, keys: &HashMap<String, String>, now: i64) -> bool {
+        let mut changed = false;
+        for outcome in &report.outcomes {
+            match (&outcome.outcome, keys.get(&outcome.bot)) {
+                (Outcome::Bound { verified: Ok(_), .. }, Some(key)) => {
+                    self.recent.insert(outcome.bot.clone(), RecentBind { key: key.clone(), at: now });
+                    changed = true;
+                }
+                (Outcome::Failed(_), _) => changed |= self.recent.remove(&outcome.bot).is_some(),
+                _ => {}
+            }
+        }
+        changed
+    }
+
+    /// Whether `bot` has been bound, or seen bound, by any run that kept
+    /// this state.
+    pub fn ever_bound(&self, bot: &str) -> bool {
+        self.bindings.contains_key(bot) || self.succeeded.contains_key(bot) || self.recent.contains_key(bot)
+    }
+
+    /// How long ago `bot` was bound with `key`, when that was within
+    /// `window` of `now` and no bind of it has failed since.
+    pub fn recently_bound(&self, bot: &str, key: &str, window: Duration, now: i64) -> Option<Duration> {
+        let recent = self.recent.get(bot).filter(|recent| recent.key == key && !self.failed.contains_key(bot))?;
+        let age = Duration::from_secs(now.saturating_sub(recent.at).max(0) as u64);
+        (age <= window).then_some(age)
+    }
+
+    /// The bots of `bots` without a success in the `after` before `now`,
+    /// each with how long ago its last one was; `None` for a bot that never
+    /// succeeded.
+    pub fn overdue<'a>(
+        &self,
+        bots: impl IntoIterator<Item = &'a str>,
+        after: Duration,
+        now: i64,
+    ) -> Vec<(String, Option<Duration>)> {
+        bots.into_iter()
+            .filter_map(|bot| {
+                let age = self.succeeded.get(bot).map(|at| Duration::from_secs(now.saturating_sub(*at).max(0) as u64));
+                age.is_none_or(|age| age > after).then(|| (bot.to_string(), age))
+            })
+            .collect()
+    }
+
+    /// Drops everything known about `bot`.
+    pub fn forget(&mut self, bot: &str) -> bool {
+        let url = self.bindings.remove(bot);
+        let secret = self.secrets.remove(bot);
+        let failed = self.failed.remove(bot);
+        let tunnel = self.tunnels.remove(bot);
+        let latency = self.latency.remove(bot);
+        let succeeded = self.succeeded.remove(bot);
+        let recent = self.recent.remove(bot);
+        url.is_some()
+            || secret.is_some()
+            || failed.is_some()
+            || tunnel.is_some()
+            || latency.is_some()
+            || succeeded.is_some()
+            || recent.is_some()
+    }
+}
+
+/// The current Unix time in seconds, as [`State::observe`] expects it.
+pub fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+/// `age` as `45s`, `12m`, `3h12m` or `2d4h`.
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86_399 => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{}h", secs / 86_400, secs % 86_400 / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BindError, BotOutcome};
+    use std::time::Duration;
+
+    #[test]
+    fn records_only_successful_bots_and_round_trips() {
+        let urls: HashMap<String, String> =
+            [("gpt4o", "https://a.ngrok.io"), ("mistral", "https://b.ngrok.io")]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+        let report = RebindReport {
+            outcomes: vec![
+                BotOutcome {
+                    bot: "gpt4o".to_string(),
+                    outcome: Outcome::Unchanged { webhook_url: "https://a.ngrok.io/webhook".to_string() },
+                    duration: Duration::ZERO,
+                },
+                BotOutcome {
+                    bot: "mistral".to_string(),
+                    outcome: Outcome::Failed(BindError::Timeout),
+                    duration: Duration::ZERO,
+                },
+            ],
+            ..RebindReport::default()
+        };
+        let mut state = State::default();
+        assert!(state.record(&report, &urls, &HashMap::new()));
+        assert!(!state.record(&report, &urls, &HashMap::new()));
+        assert_eq!(state.bindings.len(), 1);
+        assert_eq!(state.failed["mistral"], "https://b.ngrok.io");
+
+        let path = env::temp_dir().join(format!("rebind-state-{}", std::process::id())).join("state.json");
+        save(&path, &state).unwrap();
+        assert_eq!(load(&path), state);
+        state.failed.clear();
+        save(&path, &state).unwrap();
+        assert!(fs::read_to_string(&path).unwrap().starts_with("{\n  \"gpt4o\""));
+        assert_eq!(load(&path), state);
+
+        let secrets = [("gpt4o".to_string(), fingerprint("s3cret"))].into_iter().collect();
+        assert!(state.record(&report, &urls, &secrets));
+        save(&path, &state).unwrap();
+        assert_eq!(load(&path), state);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(load(&path), State::default());
+    }
+
+    #[test]
+    fn a_new_url_resets_the_tunnel_age() {
+        let urls = |url: &str| HashMap::from([("gpt4o".to_string(), url.to_string())]);
+        let mut state = State::default();
+        assert!(state.observe(&urls("https://a.ngrok.io"), 1_000));
+        assert!(!state.observe(&urls("https://a.ngrok.io"), 1_600));
+        assert_eq!(state.tunnel_ages(&urls("https://a.ngrok.io"), 1_600)["gpt4o"], Duration::from_secs(600));
+        assert!(state.tunnel_ages(&urls("https://b.ngrok.io"), 1_600).is_empty());
+
+        assert!(state.observe(&urls("https://b.ngrok.io"), 2_000));
+        assert_eq!(state.tunnel_ages(&urls("https://b.ngrok.io"), 2_030)["gpt4o"], Duration::from_secs(30));
+        assert_eq!(
+            [45, 720, 11_520, 187_200].map(|secs| format_age(Duration::from_secs(secs))),
+            ["45s", "12m", "3h12m", "2d4h"]
+        );
+    }
+
+    #[test]
+    fn latency_average_leans_towards_recent_binds() {
+        let mut state = State::default();
+        assert_eq!(state.track_latency("gpt4o", Duration::from_millis(100), 0.25), None);
+        assert_eq!(state.track_latency("gpt4o", Duration::from_millis(500), 0.25), Some(100.0));
+        assert_eq!(state.latency["gpt4o"], 200.0);
+
+        let path = env::temp_dir().join(format!("rebind-latency-{}", std::process::id())).join("state.json");
+        save(&path, &state).unwrap();
+        assert_eq!(load(&path), state);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert!(state.forget("gpt4o"));
+        assert!(state.latency.is_empty());
+    }
+
+    #[test]
+    fn only_bots_without_a_recent_success_are_overdue() {
+        let urls: HashMap<String, String> = [("gpt4o", "https://a.ngrok.io"), ("mistral", "https://b.ngrok.io")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut state = State { bindings: urls.clone(), ..State::default() };
+        state.failed.insert("mistral".to_string(), "https://b.ngrok.io".to_string());
+        assert!(state.note_successes(&urls, 1_000));
+        assert_eq!(state.succeeded, HashMap::from([("gpt4o".to_string(), 1_000)]));
+
+        let bots = ["gpt4o", "mistral"];
+        let after = Duration::from_secs(300);
+        assert_eq!(state.overdue(bots, after, 1_200), [("mistral".to_string(), None)]);
+        state.failed.clear();
+        state.note_successes(&urls, 1_100);
+        assert!(state.overdue(bots, after, 1_400).is_empty());
+        assert_eq!(state.overdue(["gpt4o"], after, 1_401), [("gpt4o".to_string(), Some(Duration::from_secs(301)))]);
+    }
+
+    #[test]
+    fn only_a_fresh_bind_with_the_same_key_counts_as_recent() {
+        let bot = crate::config::default_bots(false).remove(0);
+        let key = bind_key(&bot, "https://a.ngrok.io/webhook", "s3cret");
+        assert_ne!(key, bind_key(&bot, "https://a.ngrok.io/webhook", "other"));
+        assert_ne!(key, bind_key(&BotBinding { max_connections: Some(10), ..bot.clone() }, "https://a.ngrok.io/webhook", "s3cret"));
+
+        let bound = |outcome| RebindReport {
+            outcomes: vec![BotOutcome { bot: bot.name.clone(), outcome, duration: Duration::ZERO }],
+            ..RebindReport::default()
+        };
+        let verified = Ok(crate::WebhookInfo {
+            url: "https://a.ngrok.io/webhook".to_string(),
+            pending_update_count: 0,
+            last_error_message: None,
+            last_error_date: None,
+            allowed_updates: None,
+        });
+        let keys = HashMap::from([(bot.name.clone(), key.clone())]);
+        let mut state = State::default();
+        assert!(!state.note_recent(&bound(Outcome::Bound { webhook_url: String::new(), verified: Err("wrong url".to_string()) }), &keys, 1_000));
+        assert!(state.note_recent(&bound(Outcome::Bound { webhook_url: String::new(), verified }), &keys, 1_000));
+
+        let window = Duration::from_secs(60);
+        assert_eq!(state.recently_bound(&bot.name, &key, window, 1_030), Some(Duration::from_secs(30)));
+        assert_eq!(state.recently_bound(&bot.name, &key, window, 1_061), None);
+        assert_eq!(state.recently_bound(&bot.name, "other", window, 1_030), None);
+
+        let path = env::temp_dir().join(format!("rebind-recent-{}", std::process::id())).join("state.json");
+        save(&path, &state).unwrap();
+        assert_eq!(load(&path), state);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert!(state.note_recent(&bound(Outcome::Failed(BindError::Timeout)), &keys, 1_040));
+        assert_eq!(state.recently_bound(&bot.name, &key, window, 1_040), None);
+    }
+
+    #[tokio::test]
+    async fn a_second_lock_on_the_same_state_file_waits_or_fails() {
+        let path = env::temp_dir().join(format!("rebind-lock-{}", std::process::id())).join("state.json");
+        let held = lock(&path, None).await.unwrap();
+        assert_eq!(held.path, path.with_file_name("state.json.lock"));
+        assert!(matches!(lock(&path, None).await, Err(LockError::Held(_))));
+        let (dat, bak) = (lock(&path.with_file_name("state.dat"), None).await, lock(&path.with_file_name("state.bak"), None).await);
+        assert!(dat.is_ok() && bak.is_ok(), "unrelated state files share a lock");
+        assert!(matches!(lock(&path, Some(Duration::from_millis(300))).await, Err(LockError::Held(_))));
+
+        let waiting = tokio::spawn({
+            let path = path.clone();
+            async move { lock(&path, Some(Duration::from_secs(5))).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(held);
+        assert!(waiting.await.unwrap().is_ok());
+        fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+}
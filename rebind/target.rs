@@ -0,0 +1,339 @@
+//! Platforms a bot's webhook can be registered with. Each target turns a
+//! resolved public URL and token into its own API calls; the rest of the
+//! rebinder only sees [`WebhookTarget`].
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use futures_util::future::BoxFuture;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+
+use crate::config::{BotBinding, Platform};
+use crate::selftest::receiver_ip;
+use crate::telegram::{self, request_builder, send_with_retry, BindError, RetryPolicy, WebhookInfo};
+use crate::tokens::TokenProvider;
+use crate::HttpsClient;
+
+pub const DEFAULT_DISCORD_API_BASE: &str = "https://discord.com/api/v10";
+pub const DEFAULT_SLACK_API_BASE: &str = "https://slack.com/api";
+
+pub trait WebhookTarget: Send + Sync {
+    /// Points the platform at `public_url` plus the bot's webhook path.
+    fn bind<'a>(&'a self, bot: &'a BotBinding, token: &'a str, public_url: &'a str) -> BoxFuture<'a, Result<(), BindError>>;
+
+    /// What the platform currently has registered.
+    fn live_webhook<'a>(&'a self, bot: &'a BotBinding, token: &'a str) -> BoxFuture<'a, Result<WebhookInfo, BindError>>;
+
+    /// Removes the registration.
+    fn unbind<'a>(&'a self, bot: &'a BotBinding, token: &'a str) -> BoxFuture<'a, Result<(), BindError>>;
+}
+
+/// API roots and shared settings every target is built from.
+#[derive(Clone, Copy)]
+pub struct Targets<'a> {
+    pub client: &'a HttpsClient,
+    pub retry: RetryPolicy,
+    pub telegram_api_base: &'a str,
+    pub discord_api_base: &'a str,
+    pub slack_api_base: &'a str,
+    /// `TG_SECRET`, for Telegram bots without their own secret; Discord and
+    /// Slack sign requests themselves.
+    pub secret: &'a str,
+    pub tokens: &'a dyn TokenProvider,
+}
+
+impl<'a> Targets<'a> {
+    /// The target for `bot`, with its own timeout applied.
+    pub fn for_bot(&self, bot: &BotBinding) -> Box<dyn WebhookTarget + 'a> {
+        let retry = self.retry.for_bot(bot);
+        match bot.platform {
+            Platform::Telegram => Box::new(Telegram {
+                client: self.client,
+                api_base: self.telegram_api_base,
+                retry,
+                secret: telegram::resolve_secret(bot, self.secret),
+            }),
+            Platform::Discord => {
+                Box::new(Discord { client: self.client, api_base: self.discord_api_base, retry })
+            }
+            Platform::Slack => Box::new(Slack { client: self.client, api_base: self.slack_api_base, retry }),
+        }
+    }
+}
+
+pub struct Telegram<'a> {
+    pub client: &'a HttpsClient,
+    pub api_base: &'a str,
+    pub retry: RetryPolicy,
+    /// This bot's resolved `secret_token`.
+    pub secret: String,
+}
+
+impl WebhookTarget for Telegram<'_> {
+    fn bind<'a>(&'a self, bot: &'a BotBinding, token: &'a str, public_url: &'a str) -> BoxFuture<'a, Result<(), BindError>> {
+        Box::pin(telegram::bind_webhook(self.client, self.api_base, self.retry, bot, token, public_url, &self.secret))
+    }
+
+    fn live_webhook<'a>(&'a self, bot: &'a BotBinding, token: &'a str) -> BoxFuture<'a, Result<WebhookInfo, BindError>> {
+        Box::pin(async move {
+            let mut info = telegram::verify_webhook(self.client, self.api_base, token, self.retry.timeout).await?;
+            info.url = telegram::without_secret_param(bot, &info.url, &self.secret);
+            Ok(info)
+        })
+    }
+
+    fn unbind<'a>(&'a self, bot: &'a BotBinding, token: &'a str) -> BoxFuture<'a, Result<(), BindError>> {
+        Box::pin(telegram::delete_webhook(self.client, self.api_base, self.retry, bot, token))
+    }
+}
+
+/// Sets the application's `interactions_endpoint_url` through
+/// `PATCH /applications/{id}`.
+pub struct Discord<'a> {
+    pub client: &'a HttpsClient,
+    pub api_base: &'a str,
+    pub retry: RetryPolicy,
+}
+
+impl Discord<'_> {
+    /// `application_id` is required for Discord bots when the table is loaded.
+    fn application_url(&self, bot: &BotBinding) -> String {
+        format!("{}/applications/{}", self.api_base, bot.application_id.as_deref().unwrap_or_default())
+    }
+
+    async fn patch(&self, bot: &BotBinding, token: &str, endpoint_url: Value) -> Result<(), BindError> {
+        let uri = self.application_url(bot);
+        let body = serde_json::to_vec(&json!({ "interactions_endpoint_url": endpoint_url })).unwrap();
+        send_with_retry(self.client, self.retry, None, &bot.name, || {
+            request_builder(Method::PATCH)
+                .uri(&uri)
+                .header("Authorization", format!("Bot {}", token))
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.clone()))
+                .unwrap()
+        })
+        .await
+        .map_err(discord_error)?;
+        Ok(())
+    }
+}
+
+/// Discord reports failures as `{"message": ..., "code": ...}`, which
+/// [`BindError::from_response`] keeps as the raw description.
+fn discord_error(err: BindError) -> BindError {
+    let message = |description: String| {
+        serde_json::from_str::<Value>(&description)
+            .ok()
+            .and_then(|v| v.get("message").and_then(|m| m.as_str()).map(str::to_string))
+            .unwrap_or(description)
+    };
+    match err {
+        BindError::TelegramError(api) => BindError::DiscordError { status: api.error_code, message: message(api.description) },
+        BindError::Unauthorized(description) => BindError::Unauthorized(message(description)),
+        other => other,
+    }
+}
+
+impl WebhookTarget for Discord<'_> {
+    fn bind<'a>(&'a self, bot: &'a BotBinding, token: &'a str, public_url: &'a str) -> BoxFuture<'a, Result<(), BindError>> {
+        Box::pin(self.patch(bot, token, json!(telegram::webhook_url(bot, public_url))))
+    }
+
+    fn live_webhook<'a>(&'a self, bot: &'a BotBinding, token: &'a str) -> BoxFuture<'a, Result<WebhookInfo, BindError>> {
+        Box::pin(async move {
+            let uri = self.application_url(bot);
+            let req = request_builder(Method::GET)
+                .uri(uri)
+                .header("Authorization", format!("Bot {}", token))
+                .body(Body::empty())
+                .unwrap();
+            let (parts, body) = telegram::fetch(self.client, req, self.retry.timeout).await?;
+            if !parts.status.is_success() {
+                return Err(discord_error(BindError::from_response(parts.status, &body)));
+            }
+            let app: Value = serde_json::from_slice(&body).map_err(|_| BindError::DiscordError {
+                status: i64::from(parts.status.as_u16()),
+                message: format!("unexpected Discord response: {}", String::from_utf8_lossy(&body)),
+            })?;
+            Ok(WebhookInfo {
+                url: app["interactions_endpoint_url"].as_str().unwrap_or_default().to_string(),
+                pending_update_count: 0,
+                last_error_message: None,
+                last_error_date: None,
+                allowed_updates: None,
+            })
+        })
+    }
+
+    fn unbind<'a>(&'a self, bot: &'a BotBinding, token: &'a str) -> BoxFuture<'a, Result<(), BindError>> {
+        Box::pin(self.patch(bot, token, Value::Null))
+    }
+}
+
+/// Sets the app's Event Subscriptions request URL by exporting its manifest
+/// and writing it back with `settings.event_subscriptions.request_url`
+/// changed. The token is an app configuration token; those expire after 12
+/// hours, so long-running setups need to refresh it.
+///
+/// Slack only accepts a request URL that answers its `url_verification`
+/// challenge. When the bot's port is free, a stand-in answers it for the
+/// length of the update (listening where the self-test receivers do);
+/// otherwise the bot's own server has to.
+pub struct Slack<'a> {
+    pub client: &'a HttpsClient,
+    pub api_base: &'a str,
+    pub retry: RetryPolicy,
+}
+
+impl Slack<'_> {
+    /// Calls the Web API `method` with a JSON body, turning `"ok": false`
+    /// into [`BindError::SlackError`].
+    async fn call(&self, bot: &BotBinding, token: &str, method: &str, args: Value) -> Result<Value, BindError> {
+        let uri = format!("{}/{}", self.api_base, method);
+        let body = serde_json::to_vec(&args).unwrap();
+        let reply = send_with_retry(self.client, self.retry, None, &bot.name, || {
+            request_builder(Method::POST)
+                .uri(&uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", "application/json; charset=utf-8")
+                .body(Body::from(body.clone()))
+                .unwrap()
+        })
+        .await
+        .map_err(slack_error)?;
+        let reply: Value = serde_json::from_slice(&reply)
+            .map_err(|_| BindError::SlackError(format!("unexpected response: {}", String::from_utf8_lossy(&reply))))?;
+        if reply["ok"].as_bool() == Some(true) {
+            return Ok(reply);
+        }
+        let mut message = reply["error"].as_str().unwrap_or("unknown error").to_string();
+        let details: Vec<&str> = reply["errors"].as_array().into_iter().flatten().filter_map(|e| e["message"].as_str()).collect();
+        if !details.is_empty() {
+            message = format!("{} ({})", message, details.join("; "));
+        }
+        Err(match message.as_str() {
+            "invalid_auth" | "not_authed" | "token_expired" | "token_revoked" => BindError::Unauthorized(message),
+            _ => BindError::SlackError(message),
+        })
+    }
+
+    async fn manifest(&self, bot: &BotBinding, token: &str) -> Result<Value, BindError> {
+        let app_id = bot.application_id.as_deref().unwrap_or_default();
+        let reply = self.call(bot, token, "apps.manifest.export", json!({ "app_id": app_id })).await?;
+        match reply.get("manifest") {
+            Some(manifest) if manifest.is_object() => Ok(manifest.clone()),
+            _ => Err(BindError::SlackError("apps.manifest.export returned no manifest".to_string())),
+        }
+    }
+
+    /// Rewrites the request URL; `None` removes it.
+    async fn set_request_url(&self, bot: &BotBinding, token: &str, url: Option<String>) -> Result<(), BindError> {
+        let mut manifest = self.manifest(bot, token).await?;
+        let settings = &mut manifest["settings"];
+        if !settings.is_object() {
+            *settings = json!({});
+        }
+        let events = &mut settings["event_subscriptions"];
+        if !events.is_object() {
+            *events = json!({});
+        }
+        let events = events.as_object_mut().unwrap();
+        match url {
+            Some(url) => {
+                events.insert("request_url".to_string(), json!(url));
+            }
+            None => {
+                events.remove("request_url");
+            }
+        }
+        let app_id = bot.application_id.as_deref().unwrap_or_default();
+        let args = json!({ "app_id": app_id, "manifest": manifest.to_string() });
+        self.call(bot, token, "apps.manifest.update", args).await.map(|_| ())
+    }
+}
+
+/// Slack reports failures as `{"ok": false, "error": ...}`, which
+/// [`BindError::from_response`] keeps as the raw description.
+fn slack_error(err: BindError) -> BindError {
+    let message = |description: String| {
+        serde_json::from_str::<Value>(&description)
+            .ok()
+            .and_then(|v| v.get("error").and_then(|m| m.as_str()).map(str::to_string))
+            .unwrap_or(description)
+    };
+    match err {
+        BindError::TelegramError(api) => BindError::SlackError(format!("HTTP {}: {}", api.error_code, message(api.description))),
+        BindError::Unauthorized(description) => BindError::Unauthorized(message(description)),
+        other => other,
+    }
+}
+
+impl WebhookTarget for Slack<'_> {
+    fn bind<'a>(&'a self, bot: &'a BotBinding, token: &'a str, public_url: &'a str) -> BoxFuture<'a, Result<(), BindError>> {
+        Box::pin(async move {
+            let responder = receiver_ip().ok().and_then(|ip| ChallengeResponder::start(SocketAddr::new(ip, bot.port)));
+            if responder.is_none() {
+                log::debug!("{}: port {} is in use; leaving Slack's URL check to the app", bot.name, bot.port);
+            }
+            self.set_request_url(bot, token, Some(telegram::webhook_url(bot, public_url))).await
+        })
+    }
+
+    fn live_webhook<'a>(&'a self, bot: &'a BotBinding, token: &'a str) -> BoxFuture<'a, Result<WebhookInfo, BindError>> {
+        Box::pin(async move {
+            let manifest = self.manifest(bot, token).await?;
+            Ok(WebhookInfo {
+                url: manifest["settings"]["event_subscriptions"]["request_url"].as_str().unwrap_or_default().to_string(),
+                pending_update_count: 0,
+                last_error_message: None,
+                last_error_date: None,
+                allowed_updates: None,
+            })
+        })
+    }
+
+    fn unbind<'a>(&'a self, bot: &'a BotBinding, token: &'a str) -> BoxFuture<'a, Result<(), BindError>> {
+        Box::pin(self.set_request_url(bot, token, None))
+    }
+}
+
+/// Answers Slack's `url_verification` challenge on a bot's local port while
+/// the request URL is updated; anything else gets a 503. Stops when dropped.
+struct ChallengeResponder {
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl ChallengeResponder {
+    /// `None` when the port is taken, usually by the app itself.
+    fn start(addr: SocketAddr) -> Option<Self> {
+        let make = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+                let response = match serde_json::from_slice::<Value>(&body) {
+                    Ok(v) if v["type"] == "url_verification" && v["challenge"].is_string() => Response::builder()
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(json!({ "challenge": v["challenge"] }).to_string())),
+                    _ => Response::builder().status(hyper::StatusCode::SERVICE_UNAVAILABLE).body(Body::empty()),
+                };
+                Ok::<_, Infallible>(response.unwrap())
+            }))
+        });
+        let server = Server::try_bind(&addr).ok()?.serve(make);
+        let (stop, stopped) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = stopped.await;
+        }));
+        Some(ChallengeResponder { stop: Some(stop) })
+    }
+}
+
+impl Drop for ChallengeResponder {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
@@ -0,0 +1,1016 @@
+//! Telegram Bot API calls: `setWebhook` with retries and `getWebhookInfo`.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, fmt, fs, io};
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::{request, response::Parts};
+use hyper::header::{LOCATION, RETRY_AFTER, USER_AGENT};
+use hyper::{Body, Method, Request, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::sleep;
+
+use crate::config::{env_or, BotBinding, DropPending};
+use crate::ledger::new_uuid;
+use crate::ratelimit::{request_slot, telegram_limiter, RateLimiter};
+use crate::resolve::{parsed, Setting};
+use crate::{secrets, trace};
+use crate::HttpsClient;
+
+pub const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_API_BASE: &str = "https://api.telegram.org";
+pub const USER_AGENT_VALUE: &str = concat!("sentientos-rebind/", env!("CARGO_PKG_VERSION"));
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Carries `secret_token` on every update Telegram delivers.
+pub const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// `{api_base}/bot{token}/{method}`.
+pub fn method_url(api_base: &str, token: &str, method: &str) -> String {
+    format!("{}/bot{}/{}", api_base, token, method)
+}
+
+pub const DEFAULT_MAX_DELAY_MS: u64 = 30_000;
+pub const DEFAULT_RETRY_BUDGET_SECS: u64 = 60;
+pub const DEFAULT_MAX_FLOOD_WAIT_SECS: u64 = 60;
+
+/// How a request is retried. Up to `max_attempts` are made, each bounded by
+/// `timeout`; between them the wait starts at `base_delay` and doubles, but
+/// never beyond `max_delay` (a 429's own `retry_after` is honoured as is).
+/// Whichever runs out first ends the retries: the attempts, or the
+/// `budget` of time since the first attempt. An attempt gets no more than
+/// the budget has left, and a wait that would outlast it isn't started, so
+/// a request never takes longer than the budget however the rest is set.
+/// A 429 asking for a longer wait than `max_flood_wait` isn't waited out
+/// at all: the request fails with [`BindError::FloodWait`].
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    /// Upper bound on the backoff between two attempts.
+    pub max_delay: Duration,
+    /// Bound on each individual Telegram request, body included.
+    pub timeout: Duration,
+    /// Bound on all attempts of one request and the waits between them;
+    /// `None` leaves only `max_attempts`.
+    pub budget: Option<Duration>,
+    /// Longest `retry_after` a 429 is waited out for; Telegram asks for far
+    /// longer after a burst of webhook changes.
+    pub max_flood_wait: Duration,
+}
+
+impl RetryPolicy {
+    /// The resolved `max_retries` (default 4 attempts), `base_delay_ms`
+    /// (default 500ms, doubled after every failed attempt up to
+    /// `max_delay_ms`, default 30s), `http_timeout_secs` (default 10s per
+    /// request), `retry_budget_secs` (default 60s per request, retries
+    /// included; 0 for none) and `max_flood_wait_secs` (default 60).
+    pub fn from_settings(settings: &[Setting]) -> Self {
+        let budget = parsed(settings, "retry_budget_secs").unwrap_or(DEFAULT_RETRY_BUDGET_SECS);
+        RetryPolicy {
+            max_attempts: parsed(settings, "max_retries").unwrap_or(4u32).max(1),
+            base_delay: Duration::from_millis(parsed(settings, "base_delay_ms").unwrap_or(500)),
+            max_delay: Duration::from_millis(parsed(settings, "max_delay_ms").unwrap_or(DEFAULT_MAX_DELAY_MS)),
+            timeout: Duration::from_secs(parsed(settings, "http_timeout_secs").unwrap_or(DEFAULT_HTTP_TIMEOUT_SECS)),
+            budget: (budget > 0).then(|| Duration::from_secs(budget)),
+            max_flood_wait: Duration::from_secs(parsed(settings, "max_flood_wait_secs").unwrap_or(DEFAULT_MAX_FLOOD_WAIT_SECS)),
+        }
+    }
+
+    /// This policy with `bot`'s own `timeout_secs`, if it has one.
+    pub fn for_bot(self, bot: &BotBinding) -> Self {
+        RetryPolicy { timeout: bot.timeout.unwrap_or(self.timeout), ..self }
+    }
+
+    pub fn backoff(&self, failed_attempts: u32) -> Duration {
+        self.base_delay.saturating_mul(2u32.saturating_pow(failed_attempts.saturating_sub(1))).min(self.max_delay)
+    }
+}
+
+/// The `{"ok":false,"error_code":...,"description":...}` body Telegram sends
+/// with a failed call. `parameters` carries extras such as `retry_after`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TelegramApiError {
+    pub error_code: i64,
+    pub description: String,
+    #[serde(default)]
+    pub parameters: Option<Value>,
+}
+
+impl TelegramApiError {
+    /// `parameters.retry_after`, which Telegram sets on 429s.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.parameters.as_ref()?.get("retry_after")?.as_u64().map(Duration::from_secs)
+    }
+
+    /// `parameters.migrate_to_chat_id`: the group became a supergroup with
+    /// this id, and calls naming the old one fail until they use it.
+    pub fn migrate_to_chat_id(&self) -> Option<i64> {
+        self.parameters.as_ref()?.get("migrate_to_chat_id")?.as_i64()
+    }
+}
+
+/// What a request that got no HTTP answer ran into, as far as the error
+/// chain tells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionError {
+    /// The host name didn't resolve.
+    Dns,
+    /// Nothing listens on the port, or a firewall rejects it.
+    ConnectionRefused,
+    /// The TLS handshake failed, e.g. over an untrusted certificate.
+    Tls,
+    Other,
+}
+
+impl ConnectionError {
+    /// Sorts `err` by the first cause in its chain that gives it away.
+    pub fn classify(err: &hyper::Error) -> Self {
+        let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(err);
+        while let Some(err) = cause {
+            #[cfg(feature = "tls-native")]
+            if err.is::<native_tls::Error>() {
+                return ConnectionError::Tls;
+            }
+            if err.downcast_ref::<io::Error>().is_some_and(|io| io.kind() == io::ErrorKind::ConnectionRefused) {
+                return ConnectionError::ConnectionRefused;
+            }
+            let message = err.to_string().to_ascii_lowercase();
+            if message.starts_with("dns error") || message.contains("failed to lookup address") {
+                return ConnectionError::Dns;
+            }
+            if ["certificate", "handshake", "tls", "ssl"].iter().any(|word| message.contains(word)) {
+                return ConnectionError::Tls;
+            }
+            cause = err.source();
+        }
+        ConnectionError::Other
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConnectionError::Dns => "dns",
+            ConnectionError::ConnectionRefused => "connection_refused",
+            ConnectionError::Tls => "tls",
+            ConnectionError::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BindError {
+    MissingToken(String),
+    HttpError(ConnectionError, hyper::Error),
+    TelegramError(TelegramApiError),
+    Timeout,
+    /// `REBIND_CERT_PATH` is set but the certificate can't be read.
+    CertError(String, io::Error),
+    DiscordError { status: i64, message: String },
+    /// Slack answered `{"ok": false, "error": ...}`, with any details it gave.
+    SlackError(String),
+    /// A GET was redirected too often or to another host.
+    Redirect(String),
+    /// The API rejected the token (HTTP 401). Never retried: the token is
+    /// wrong or revoked, and waiting won't fix it.
+    Unauthorized(String),
+    /// The run's deadline (`REBIND_DEADLINE_SECS`) passed first.
+    DeadlineExceeded,
+    /// The run was cancelled first. A `setWebhook` already sent may or may
+    /// not have taken effect.
+    Cancelled,
+    /// The response started but its body could not be read to the end,
+    /// e.g. because the server closed the connection mid-response.
+    BodyRead(hyper::Error),
+    /// The response body was longer than the caller allows, in bytes.
+    BodyTooLarge(usize),
+    /// A 429 asked to wait this long, more than
+    /// [`RetryPolicy::max_flood_wait`], so the request wasn't retried.
+    FloodWait(Duration),
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindError::MissingToken(var) => write!(f, "no token (expected {})", var),
+            BindError::HttpError(kind, err) => {
+                let err = redact_tokens(&err.to_string());
+                match kind {
+                    ConnectionError::Dns => write!(f, "DNS lookup failed: {}", err),
+                    ConnectionError::ConnectionRefused => write!(f, "connection refused: {}", err),
+                    ConnectionError::Tls => write!(f, "TLS handshake failed: {}", err),
+                    ConnectionError::Other => write!(f, "{}", err),
+                }
+            }
+            BindError::TelegramError(err) => {
+                write!(f, "Telegram error {}: {}", err.error_code, redact_tokens(&err.description))?;
+                match err.migrate_to_chat_id() {
+                    Some(chat) => write!(f, " (migrated to chat {})", chat),
+                    None => Ok(()),
+                }
+            }
+            BindError::Timeout => write!(f, "request timed out"),
+            BindError::CertError(path, err) => write!(f, "cannot read certificate {}: {}", path, err),
+            BindError::DiscordError { status, message } => {
+                write!(f, "Discord error {}: {}", status, redact_tokens(message))
+            }
+            BindError::SlackError(message) => write!(f, "Slack error: {}", redact_tokens(message)),
+            BindError::Redirect(problem) => write!(f, "{}", redact_tokens(problem)),
+            BindError::Unauthorized(description) => {
+                write!(f, "401 {}: the token is wrong or revoked", redact_tokens(description))
+            }
+            BindError::DeadlineExceeded => write!(f, "deadline exceeded"),
+            BindError::Cancelled => write!(f, "cancelled before the bind finished; the webhook may or may not have changed"),
+            BindError::BodyRead(err) => write!(f, "response body cut short: {}", redact_tokens(&err.to_string())),
+            BindError::BodyTooLarge(max) => write!(f, "response body larger than {} bytes", max),
+            BindError::FloodWait(wait) => write!(f, "flood wait: Telegram asked to wait {}s before retrying", wait.as_secs()),
+        }
+    }
+}
+
+impl std::error::Error for BindError {}
+
+impl From<hyper::Error> for BindError {
+    fn from(err: hyper::Error) -> Self {
+        if err.is_timeout() {
+            BindError::Timeout
+        } else {
+            BindError::HttpError(ConnectionError::classify(&err), err)
+        }
+    }
+}
+
+impl BindError {
+    /// Builds a `TelegramError` from a failed response, preferring the
+    /// structured body Telegram sends and falling back to the HTTP status and
+    /// raw body text. A 401 becomes [`BindError::Unauthorized`].
+    pub fn from_response(status: StatusCode, body: &[u8]) -> Self {
+        let err = serde_json::from_slice::<TelegramApiError>(body).unwrap_or_else(|_| TelegramApiError {
+            error_code: i64::from(status.as_u16()),
+            description: String::from_utf8_lossy(body).into_owned(),
+            parameters: None,
+        });
+        if status == StatusCode::UNAUTHORIZED || err.error_code == 401 {
+            return BindError::Unauthorized(err.description);
+        }
+        BindError::TelegramError(err)
+    }
+
+    /// The HTTP status the API answered with, for errors that have one.
+    pub fn http_status(&self) -> Option<u16> {
+        match self {
+            BindError::TelegramError(api) => u16::try_from(api.error_code).ok().filter(|s| (100..600).contains(s)),
+            BindError::DiscordError { status, .. } => u16::try_from(*status).ok(),
+            BindError::Unauthorized(_) => Some(401),
+            BindError::FloodWait(_) => Some(429),
+            _ => None,
+        }
+    }
+
+    /// How long Telegram asked us to wait before retrying, if it said.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            BindError::TelegramError(err) => err.retry_after(),
+            BindError::FloodWait(wait) => Some(*wait),
+            _ => None,
+        }
+    }
+
+    /// Whether [`send_with_retry`] retries this error: no answer in time,
+    /// or a 429 or 5xx. One that still comes back from it used up every
+    /// attempt, or the retry budget.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            BindError::Timeout | BindError::HttpError(..) | BindError::BodyRead(_) => true,
+            _ => self.http_status().and_then(|s| StatusCode::from_u16(s).ok()).is_some_and(is_transient),
+        }
+    }
+
+    /// Why the request got no answer, for errors below HTTP.
+    pub fn connection_error(&self) -> Option<ConnectionError> {
+        match self {
+            BindError::HttpError(kind, _) => Some(*kind),
+            _ => None,
+        }
+    }
+}
+
+/// Masks all but the last 4 characters of `token`.
+pub fn redact_token(token: &str) -> String {
+    let keep = token.len().saturating_sub(4);
+    match token.get(keep..) {
+        Some(tail) => format!("{}{}", "*".repeat(token[..keep].chars().count()), tail),
+        None => "*".repeat(token.chars().count()),
+    }
+}
+
+/// Runs [`redact_token`] over everything in `text` shaped like a bot token
+/// (`<digits>:<30+ of [A-Za-z0-9_-]>`), so URLs and error messages can be
+/// logged safely.
+pub fn redact_tokens(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let is_secret = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'-';
+    let mut out = String::with_capacity(text.len());
+    let (mut i, mut copied) = (0, 0);
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_digit() || (i > 0 && bytes[i - 1].is_ascii_digit()) {
+            i += 1;
+            continue;
+        }
+        let mut colon = i;
+        while colon < bytes.len() && bytes[colon].is_ascii_digit() {
+            colon += 1;
+        }
+        let mut end = colon + 1;
+        while end < bytes.len() && is_secret(bytes[end]) {
+            end += 1;
+        }
+        if colon < bytes.len() && bytes[colon] == b':' && end - colon > 30 {
+            out.push_str(&text[copied..i]);
+            out.push_str(&redact_token(&text[i..end]));
+            copied = end;
+            i = end;
+        } else {
+            i = colon;
+        }
+    }
+    out.push_str(&text[copied..]);
+    out
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn retry_after_header(parts: &Parts) -> Option<Duration> {
+    parts
+        .headers
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Telegram's own hint on a 429 answered with `parts`: the longer of
+/// `parameters.retry_after` and the `Retry-After` header if it sent both.
+fn hinted_wait(parts: &Parts, err: &BindError) -> Option<Duration> {
+    err.retry_after().max(retry_after_header(parts)).filter(|_| parts.status == StatusCode::TOO_MANY_REQUESTS)
+}
+
+/// How long to wait after `attempt` failed with the response in `parts`:
+/// the [`hinted_wait`] if there is one, the policy's backoff otherwise.
+fn retry_wait(policy: &RetryPolicy, attempt: u32, parts: &Parts, err: &BindError) -> Duration {
+    hinted_wait(parts, err).unwrap_or_else(|| policy.backoff(attempt))
+}
+
+/// Starts every outgoing request, so ngrok, Telegram and Discord logs can
+/// be matched against ours: `User-Agent: sentientos-rebind/<version>` and a
+/// fresh `X-Request-Id`.
+pub fn request_builder(method: Method) -> request::Builder {
+    Request::builder().method(method).header(USER_AGENT, USER_AGENT_VALUE).header(REQUEST_ID_HEADER, new_uuid())
+}
+
+/// The `X-Request-Id` set by [`request_builder`], or `-`.
+pub fn request_id<T>(req: &Request<T>) -> String {
+    req.headers().get(REQUEST_ID_HEADER).and_then(|v| v.to_str().ok()).unwrap_or("-").to_string()
+}
+
+/// Sends `req` and reads the whole response body, giving up with
+/// [`BindError::Timeout`] once `timeout` has elapsed. Each exchange is
+/// logged at debug level with its request id. Under
+/// [`with_request_slots`](crate::ratelimit::with_request_slots) the
+/// exchange first waits for a free slot; the timeout starts once it has one.
+pub async fn fetch(client: &HttpsClient, req: Request<Body>, timeout: Duration) -> Result<(Parts, Bytes), BindError> {
+    fetch_capped(client, req, timeout, usize::MAX).await
+}
+
+/// [`fetch`], failing with [`BindError::BodyTooLarge`] as soon as the body
+/// is known to be longer than `max_body` bytes rather than buffering it.
+pub async fn fetch_capped(
+    client: &HttpsClient,
+    req: Request<Body>,
+    timeout: Duration,
+    max_body: usize,
+) -> Result<(Parts, Bytes), BindError> {
+    let _slot = request_slot().await;
+    let (id, method) = (request_id(&req), req.method().clone());
+    let target = redact_tokens(&req.uri().to_string());
+    // Request bodies are always buffered, so keeping a copy for the trace
+    // costs nothing but memory.
+    let (req, traced) = if trace::enabled() {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+        let traced = (parts.uri.clone(), parts.headers.clone(), body.clone());
+        (Request::from_parts(parts, Body::from(body)), Some(traced))
+    } else {
+        (req, None)
+    };
+    let started = Instant::now();
+    let exchange = async {
+        let (parts, body) = client.request(req).await?.into_parts();
+        let body = read_body(body, max_body).await?;
+        Ok((parts, body))
+    };
+    let result = tokio::time::timeout(timeout, exchange).await.unwrap_or(Err(BindError::Timeout));
+    match &result {
+        Ok((parts, _)) => log::debug!("[{}] {} {} -> {}", id, method, target, parts.status),
+        Err(err) => log::debug!("[{}] {} {} -> {}", id, method, target, err),
+    }
+    if let Some((uri, headers, body)) = traced {
+        trace::record(&id, (&method, &uri, &headers), &body, &result, started.elapsed());
+    }
+    result
+}
+
+/// The whole of `body`, at most `max` bytes of it. A read that fails part
+/// way is a [`BindError::BodyRead`], never a shorter body.
+async fn read_body(mut body: Body, max: usize) -> Result<Bytes, BindError> {
+    if body.size_hint().lower() > max as u64 {
+        return Err(BindError::BodyTooLarge(max));
+    }
+    let mut read = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| if err.is_timeout() { BindError::Timeout } else { BindError::BodyRead(err) })?;
+        if read.len() + chunk.len() > max {
+            return Err(BindError::BodyTooLarge(max));
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(read.into())
+}
+
+/// Redirect hops a GET follows by default (`REBIND_MAX_REDIRECTS`).
+pub const DEFAULT_MAX_REDIRECTS: usize = 3;
+
+/// Where a redirect from `current` to `location` leads, refusing anything
+/// that leaves `current`'s host and port.
+fn next_hop(current: &Uri, location: &str) -> Result<Uri, String> {
+    let scheme = current.scheme_str().unwrap_or("http");
+    let authority = current.authority().map_or("", |a| a.as_str());
+    let absolute = if location.contains("://") {
+        location.to_string()
+    } else if let Some(rest) = location.strip_prefix("//") {
+        format!("{}://{}", scheme, rest)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, location)
+    } else {
+        let dir = current.path().rsplit_once('/').map_or("", |(dir, _)| dir);
+        format!("{}://{}{}/{}", scheme, authority, dir, location)
+    };
+    let next: Uri = absolute.parse().map_err(|e| format!("invalid redirect target {}: {}", location, e))?;
+    let port = |uri: &Uri| uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+    let same_host = next.host().zip(current.host()).is_some_and(|(a, b)| a.eq_ignore_ascii_case(b));
+    if !same_host || port(&next) != port(current) {
+        return Err(format!("{} redirected to another host ({})", current, next));
+    }
+    Ok(next)
+}
+
+/// [`fetch_capped`] for a GET of `uri`, following redirects that stay on the
+/// same host, at most `REBIND_MAX_REDIRECTS` (default 3) of them. `build`
+/// starts each hop's request. Only GETs come through here: a redirected
+/// `setWebhook` would hand the secret to wherever the redirect points.
+pub async fn fetch_get<F>(
+    client: &HttpsClient,
+    uri: Uri,
+    timeout: Duration,
+    max_body: usize,
+    build: F,
+) -> Result<(Parts, Bytes), BindError>
+where
+    F: Fn() -> request::Builder,
+{
+    let max = env_or("REBIND_MAX_REDIRECTS", DEFAULT_MAX_REDIRECTS);
+    let mut uri = uri;
+    let mut hops = 0;
+    loop {
+        let req = build().method(Method::GET).uri(uri.clone()).body(Body::empty()).unwrap();
+        let (parts, body) = fetch_capped(client, req, timeout, max_body).await?;
+        let location = parts.headers.get(LOCATION).and_then(|v| v.to_str().ok());
+        let Some(location) = location.filter(|_| parts.status.is_redirection() && parts.status != StatusCode::NOT_MODIFIED)
+        else {
+            return Ok((parts, body));
+        };
+        if hops == max {
+            return Err(BindError::Redirect(format!("{} redirected more than {} times", uri, max)));
+        }
+        let next = next_hop(&uri, location).map_err(BindError::Redirect)?;
+        log::debug!("{} redirected ({}) to {}", redact_tokens(&uri.to_string()), parts.status, redact_tokens(&next.to_string()));
+        uri = next;
+        hops += 1;
+    }
+}
+
+/// Sends the request built by `build` until it succeeds, fails with a
+/// non-transient status, or `policy.max_attempts` is exhausted. 429s wait
+/// for `parameters.retry_after` or the `Retry-After` header, whichever is
+/// longer, when Telegram provides one, unless that is more than
+/// `policy.max_flood_wait`, which fails with [`BindError::FloodWait`]
+/// without pausing anything; each attempt is bounded by
+/// `policy.timeout`, and all of them together by `policy.budget`. Every
+/// attempt first takes a token from `limiter`, and a 429 pauses the limiter
+/// so concurrent requests back off too. Returns the body of the successful
+/// response, or the last attempt's error.
+pub async fn send_with_retry<F>(
+    client: &HttpsClient,
+    policy: RetryPolicy,
+    limiter: Option<&RateLimiter>,
+    label: &str,
+    mut build: F,
+) -> Result<Bytes, BindError>
+where
+    F: FnMut() -> Request<Body>,
+{
+    let started = tokio::time::Instant::now();
+    let left = || policy.budget.map(|budget| budget.saturating_sub(started.elapsed()));
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        if let Some(limiter) = limiter {
+            limiter.acquire().await;
+        }
+        let req = build();
+        let id = request_id(&req);
+        let timeout = left().map_or(policy.timeout, |left| left.min(policy.timeout));
+        let (err, wait) = match fetch(client, req, timeout).await {
+            Ok((parts, body)) if parts.status.is_success() => return Ok(body),
+            Ok((parts, body)) => {
+                let status = parts.status;
+                let err = BindError::from_response(status, &body);
+                if let Some(flood) = hinted_wait(&parts, &err).filter(|wait| *wait > policy.max_flood_wait) {
+                    log::warn!(
+                        "[🌊] {} told to wait {}s, more than REBIND_MAX_FLOOD_WAIT_SECS allows; not retrying (request {})",
+                        label, flood.as_secs(), id
+                    );
+                    return Err(BindError::FloodWait(flood));
+                }
+                let wait = retry_wait(&policy, attempt, &parts, &err);
+                if let Some(limiter) = limiter.filter(|_| status == StatusCode::TOO_MANY_REQUESTS) {
+                    limiter.pause(wait);
+                }
+                if !is_transient(status) {
+                    return Err(err);
+                }
+                (err, wait)
+            }
+            Err(err) => (err, policy.backoff(attempt)),
+        };
+        if attempt >= policy.max_attempts {
+            if attempt > 1 {
+                log::error!("[⏳] {} giving up after {} attempts (request {})", label, attempt, id);
+            }
+            return Err(err);
+        }
+        if let Some(left) = left().filter(|left| *left <= wait) {
+            log::error!(
+                "[⏳] {} giving up after {} attempts: retry budget spent ({:?} left, next retry in {:?}, request {})",
+                label, attempt, left, wait, id
+            );
+            return Err(err);
+        }
+        // `err` is already redacted by its Display impl.
+        log::warn!(
+            "[⏳] {} attempt {}/{} failed ({}, request {}), retrying in {:?}",
+            label, attempt, policy.max_attempts, err, id, wait
+        );
+        sleep(wait).await;
+    }
+}
+
+pub fn resolve_token(bot: &BotBinding) -> Result<String, BindError> {
+    let token_var = bot.token_var();
+    match env::var(&token_var) {
+        Ok(t) if !t.is_empty() => Ok(t),
+        _ => Err(BindError::MissingToken(token_var)),
+    }
+}
+
+/// Checks `secret` against what Telegram accepts for `secret_token`: 1–256
+/// characters from `A-Z a-z 0-9 _ -`. Anything else makes every
+/// `setWebhook` fail with a bare 400.
+/// `source` names where the secret came from in the error.
+pub fn validate_secret(source: &str, secret: &str) -> Result<(), String> {
+    let len = secret.chars().count();
+    if !(1..=256).contains(&len) {
+        return Err(format!("{} must be 1-256 characters long, got {}", source, len));
+    }
+    let mut bad: Vec<char> = secret.chars().filter(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-')).collect();
+    if bad.is_empty() {
+        return Ok(());
+    }
+    bad.sort_unstable();
+    bad.dedup();
+    let bad: Vec<String> = bad.iter().map(|c| format!("{:?}", c)).collect();
+    Err(format!("{} may only contain A-Z, a-z, 0-9, `_` and `-`; found {}", source, bad.join(", ")))
+}
+
+/// The bot's own secret and where it came from: `secret` in the bot table,
+/// else its entry in `REBIND_SECRETS_FILE`, else its
+/// [`BotBinding::secret_var`]. `None` means it uses `TG_SECRET`.
+pub fn own_secret(bot: &BotBinding) -> Option<(String, String)> {
+    if let Some(secret) = &bot.secret {
+        return Some((format!("secret of bot `{}`", bot.name), secret.clone()));
+    }
+    if let Some(found) = secrets::file_secret(&bot.name) {
+        return Some(found);
+    }
+    let var = bot.secret_var();
+    env::var(&var).ok().filter(|s| !s.is_empty()).map(|secret| (var, secret))
+}
+
+/// The secret sent for `bot`: [`own_secret`], or the global `tg_secret`.
+/// Empty when neither is set, and the bot is bound without one.
+pub fn resolve_secret(bot: &BotBinding, tg_secret: &str) -> String {
+    own_secret(bot).map_or_else(|| tg_secret.to_string(), |(_, secret)| secret)
+}
+
+/// The public URL plus the bot's `webhook_path`.
+pub fn webhook_url(bot: &BotBinding, public_url: &str) -> String {
+    format!("{}{}", public_url.trim_end_matches('/'), bot.webhook_path)
+}
+
+/// Percent-encodes everything outside `A-Z a-z 0-9 - . _ ~`.
+pub(crate) fn encode_query_value(value: &str) -> String {
+    let mut out = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(byte as char),
+            other => out.push_str(&format!("%{:02X}", other)),
+        }
+    }
+    out
+}
+
+/// `webhook_url` with the secret appended as `param=value`.
+fn with_secret_param(webhook_url: &str, param: &str, tg_secret: &str) -> String {
+    let separator = if webhook_url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}={}", webhook_url, separator, encode_query_value(param), encode_query_value(tg_secret))
+}
+
+/// Undoes [`with_secret_param`] on a URL reported by `getWebhookInfo`, so it
+/// can be compared with [`webhook_url`] and logged. A value other than
+/// `tg_secret` is masked rather than removed, which keeps the URLs unequal.
+pub fn without_secret_param(bot: &BotBinding, url: &str, tg_secret: &str) -> String {
+    let Some(param) = &bot.secret_query else { return url.to_string() };
+    let Some((base, query)) = url.split_once('?') else { return url.to_string() };
+    let prefix = format!("{}=", encode_query_value(param));
+    let mut kept = Vec::new();
+    for pair in query.split('&') {
+        match pair.strip_prefix(&prefix) {
+            Some(value) if value == encode_query_value(tg_secret) => {}
+            Some(_) => kept.push(format!("{}***", prefix)),
+            None => kept.push(pair.to_string()),
+        }
+    }
+    if kept.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}?{}", base, kept.join("&"))
+    }
+}
+
+/// In the bot's query-secret mode the secret goes into `url` instead of
+/// `secret_token`. An empty `tg_secret` is left out, so Telegram sends
+/// updates without one.
+pub fn set_webhook_payload(bot: &BotBinding, webhook_url: &str, tg_secret: &str) -> Value {
+    let mut payload = match &bot.secret_query {
+        _ if tg_secret.is_empty() => serde_json::json!({ "url": webhook_url }),
+        None => serde_json::json!({ "url": webhook_url, "secret_token": tg_secret }),
+        Some(param) => serde_json::json!({ "url": with_secret_param(webhook_url, param, tg_secret) }),
+    };
+    if bot.drop_pending_updates == DropPending::Always {
+        payload["drop_pending_updates"] = Value::Bool(true);
+    }
+    if let Some(allowed) = &bot.allowed_updates {
+        payload["allowed_updates"] = serde_json::json!(allowed);
+    }
+    if let Some(max) = bot.max_connections {
+        payload["max_connections"] = serde_json::json!(max);
+    }
+    if let Some(ip) = &bot.ip_address {
+        payload["ip_address"] = serde_json::json!(ip);
+    }
+    payload
+}
+
+/// The PEM file named by `REBIND_CERT_PATH`, if set, for servers with a
+/// self-signed certificate.
+pub fn load_certificate() -> Result<Option<(String, Vec<u8>)>, BindError> {
+    match env::var("REBIND_CERT_PATH") {
+        Ok(path) if !path.is_empty() => match fs::read(&path) {
+            Ok(pem) => Ok(Some((path, pem))),
+            Err(err) => Err(BindError::CertError(path, err)),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Encodes `payload` as `multipart/form-data` with `pem` attached as the
+/// `certificate` file. Non-string values are sent JSON-encoded, as the Bot
+/// API expects for fields like `allowed_updates`. Returns the body and
+/// boundary.
+pub fn multipart_payload(payload: &Value, pem: &[u8]) -> (Vec<u8>, String) {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    let boundary = format!("rebind-{:x}", nanos);
+    let mut body = Vec::new();
+    for (name, value) in payload.as_object().into_iter().flatten() {
+        let text = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        body.extend_from_slice(
+            format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", boundary, name, text).as_bytes(),
+        );
+    }
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"certificate\"; filename=\"cert.pem\"\r\nContent-Type: application/x-pem-file\r\n\r\n",
+            boundary
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(pem);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    (body, boundary)
+}
+
+/// POSTs `setWebhook` to `api_base` (normally [`DEFAULT_API_BASE`]). When
+/// `REBIND_CERT_PATH` is set the certificate is uploaded alongside, which
+/// switches the request to `multipart/form-data`.
+pub async fn bind_webhook(
+    client: &HttpsClient,
+    api_base: &str,
+    policy: RetryPolicy,
+    bot: &BotBinding,
+    token: &str,
+    url: &str,
+    tg_secret: &str,
+) -> Result<(), BindError> {
+    let webhook_url = webhook_url(bot, url);
+    let endpoint = method_url(api_base, token, "setWebhook");
+    let payload = set_webhook_payload(bot, &webhook_url, tg_secret);
+    let (body, content_type) = match load_certificate()? {
+        Some((_, pem)) => {
+            let (body, boundary) = multipart_payload(&payload, &pem);
+            (body, format!("multipart/form-data; boundary={}", boundary))
+        }
+        None => (serde_json::to_vec(&payload).unwrap(), "application/json".to_string()),
+    };
+
+    send_with_retry(client, policy, telegram_limiter(), &bot.name, || {
+        request_builder(Method::POST)
+            .uri(&endpoint)
+            .header("Content-Type", &content_type)
+            .body(Body::from(body.clone()))
+            .unwrap()
+    })
+    .await?;
+    Ok(())
+}
+
+/// Removes the webhook so the bot can be driven by `getUpdates` again,
+/// dropping queued updates when the bot sets `drop_pending_updates`.
+pub async fn delete_webhook(
+    client: &HttpsClient,
+    api_base: &str,
+    policy: RetryPolicy,
+    bot: &BotBinding,
+    token: &str,
+) -> Result<(), BindError> {
+    let endpoint = method_url(api_base, token, "deleteWebhook");
+    let body = serde_json::to_vec(&serde_json::json!({ "drop_pending_updates": bot.drop_pending_updates == DropPending::Always })).unwrap();
+
+    send_with_retry(client, policy, telegram_limiter(), &bot.name, || {
+        request_builder(Method::POST)
+            .uri(&endpoint)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.clone()))
+            .unwrap()
+    })
+    .await?;
+    Ok(())
+}
+
+/// Describes why `info` doesn't match a webhook we just set to `expected`.
+pub fn verification_problem(info: &WebhookInfo, expected: &str) -> Option<String> {
+    if info.url != expected {
+        return Some(format!("live webhook is {:?}, expected {:?}", info.url, expected));
+    }
+    match &info.last_error_message {
+        Some(msg) if !msg.is_empty() => Some(format!("last error {:?}", msg)),
+        _ => None,
+    }
+}
+
+/// Whether the live webhook already matches what we would set, so the
+/// `setWebhook` call can be skipped. Telegram never echoes the secret token
+/// back, so a rotated `TG_SECRET` needs `--force`.
+pub fn already_bound(info: &WebhookInfo, bot: &BotBinding, expected: &str) -> bool {
+    info.url == expected && (bot.allowed_updates.is_none() || info.allowed_updates == bot.allowed_updates)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebhookInfo {
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub pending_update_count: u64,
+    #[serde(default)]
+    pub last_error_message: Option<String>,
+    /// Unix time of `last_error_message`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error_date: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_updates: Option<Vec<String>>,
+}
+
+/// Asks Telegram which webhook is currently live for `token`.
+pub async fn verify_webhook(
+    client: &HttpsClient,
+    api_base: &str,
+    token: &str,
+    timeout: Duration,
+) -> Result<WebhookInfo, BindError> {
+    let endpoint = method_url(api_base, token, "getWebhookInfo");
+    let uri = endpoint.parse::<Uri>().map_err(|e| BindError::Redirect(format!("invalid API URL {}: {}", endpoint, e)))?;
+    if let Some(limiter) = telegram_limiter() {
+        limiter.acquire().await;
+    }
+    let (parts, body) = fetch_get(client, uri, timeout, usize::MAX, || request_builder(Method::GET)).await?;
+    let status = parts.status;
+    if !status.is_success() {
+        return Err(BindError::from_response(status, &body));
+    }
+    serde_json::from_slice::<Value>(&body)
+        .ok()
+        .and_then(|v| v.get("result").cloned())
+        .and_then(|r| serde_json::from_value(r).ok())
+        .ok_or_else(|| {
+            BindError::TelegramError(TelegramApiError {
+                error_code: i64::from(status.as_u16()),
+                description: format!("unexpected getWebhookInfo response: {}", String::from_utf8_lossy(&body)),
+                parameters: None,
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn connection_errors_say_what_failed() {
+        let client = hyper::Client::new();
+        // Bound and dropped, so nothing listens there any more.
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let refused = client.get(format!("http://127.0.0.1:{}/", port).parse().unwrap()).await.unwrap_err();
+        let refused = BindError::from(refused);
+        assert_eq!(refused.connection_error(), Some(ConnectionError::ConnectionRefused));
+        assert!(refused.to_string().starts_with("connection refused: "), "{}", refused);
+        let unresolved = client.get("http://api.example.invalid/".parse().unwrap()).await.unwrap_err();
+        assert_eq!(BindError::from(unresolved).connection_error(), Some(ConnectionError::Dns));
+        assert_eq!(BindError::Timeout.connection_error(), None);
+    }
+
+    #[test]
+    fn parses_structured_error_body() {
+        let body = br#"{"ok":false,"error_code":429,"description":"Too Many Requests: retry after 7","parameters":{"retry_after":7}}"#;
+        let err = BindError::from_response(StatusCode::TOO_MANY_REQUESTS, body);
+        let BindError::TelegramError(api) = &err else { panic!("expected TelegramError, got {:?}", err) };
+        assert_eq!(api.error_code, 429);
+        assert_eq!(api.description, "Too Many Requests: retry after 7");
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(7)));
+
+        let body = br#"{"ok":false,"error_code":400,"description":"Bad Request: group chat was upgraded to a supergroup chat","parameters":{"migrate_to_chat_id":-1001234}}"#;
+        let err = BindError::from_response(StatusCode::BAD_REQUEST, body);
+        assert!(err.to_string().ends_with("supergroup chat (migrated to chat -1001234)"), "{}", err);
+    }
+
+    #[test]
+    fn falls_back_to_status_and_raw_body() {
+        let err = BindError::from_response(StatusCode::BAD_GATEWAY, b"<html>bad gateway</html>");
+        let BindError::TelegramError(api) = &err else { panic!("expected TelegramError, got {:?}", err) };
+        assert_eq!(api.error_code, 502);
+        assert_eq!(api.description, "<html>bad gateway</html>");
+        assert_eq!(err.retry_after(), None);
+    }
+
+    #[test]
+    fn retries_back_off_unless_telegram_says_when() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(3),
+            timeout: Duration::ZERO,
+            budget: None,
+            max_flood_wait: Duration::from_secs(60),
+        };
+        let parts = |status: u16, header: Option<&str>| {
+            let mut res = hyper::Response::builder().status(status);
+            if let Some(secs) = header {
+                res = res.header(RETRY_AFTER, secs);
+            }
+            res.body(()).unwrap().into_parts().0
+        };
+        let waits: Vec<_> = (1..=4).map(|n| retry_wait(&policy, n, &parts(502, None), &BindError::Timeout)).collect();
+        assert_eq!(waits, [500, 1000, 2000, 3000].map(Duration::from_millis));
+
+        let throttled = BindError::from_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            br#"{"ok":false,"error_code":429,"description":"Too Many Requests","parameters":{"retry_after":7}}"#,
+        );
+        assert_eq!(retry_wait(&policy, 1, &parts(429, Some("3")), &throttled), Duration::from_secs(7));
+        assert_eq!(retry_wait(&policy, 1, &parts(429, Some("9")), &throttled), Duration::from_secs(9));
+        assert_eq!(retry_wait(&policy, 1, &parts(429, None), &throttled), Duration::from_secs(7));
+        assert_eq!(retry_wait(&policy, 1, &parts(429, Some("3")), &BindError::Timeout), Duration::from_secs(3));
+        // Only a 429 gets to pick its delay.
+        assert_eq!(retry_wait(&policy, 2, &parts(503, Some("30")), &BindError::Timeout), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn redirects_stay_on_the_original_host() {
+        let here: Uri = "http://127.0.0.1:4040/api/tunnels".parse().unwrap();
+        assert_eq!(next_hop(&here, "/api/tunnels/").unwrap(), "http://127.0.0.1:4040/api/tunnels/");
+        assert_eq!(next_hop(&here, "list").unwrap(), "http://127.0.0.1:4040/api/list");
+        assert_eq!(next_hop(&here, "http://127.0.0.1:4040/v2").unwrap(), "http://127.0.0.1:4040/v2");
+        assert!(next_hop(&here, "http://127.0.0.1:4041/api/tunnels").is_err());
+        assert!(next_hop(&here, "//evil.example/api").unwrap_err().contains("another host"));
+        let https: Uri = "https://api.telegram.org/bot1:x/getWebhookInfo".parse().unwrap();
+        assert!(next_hop(&https, "https://API.telegram.org:443/getWebhookInfo").is_ok());
+    }
+
+    #[test]
+    fn multipart_carries_fields_and_certificate() {
+        let payload = serde_json::json!({ "url": "https://a.ngrok.io/webhook", "allowed_updates": ["message"] });
+        let (body, boundary) = multipart_payload(&payload, b"PEM");
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("name=\"url\"\r\n\r\nhttps://a.ngrok.io/webhook\r\n"));
+        assert!(body.contains("name=\"allowed_updates\"\r\n\r\n[\"message\"]\r\n"));
+        assert!(body.contains("filename=\"cert.pem\"\r\nContent-Type: application/x-pem-file\r\n\r\nPEM\r\n"));
+        assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+    }
+
+    #[test]
+    fn secret_charset_and_length() {
+        assert!(validate_secret("TG_SECRET", "abc_DEF-123").is_ok());
+        assert!(validate_secret("TG_SECRET", &"a".repeat(256)).is_ok());
+        assert!(validate_secret("TG_SECRET", "").is_err());
+        assert!(validate_secret("TG_SECRET", &"a".repeat(257)).is_err());
+        let err = validate_secret("TG_SECRET", "bad:secret:é").unwrap_err();
+        assert!(err.contains("':'") && err.contains("'é'"), "{}", err);
+    }
+
+    #[test]
+    fn bot_secret_wins_over_tg_secret() {
+        let mut bot = crate::config::default_bots(false).remove(0);
+        bot.secret_env = Some("REBIND_TEST_UNSET_SECRET".to_string());
+        assert_eq!(resolve_secret(&bot, "global"), "global");
+        bot.secret = Some("own".to_string());
+        assert_eq!(resolve_secret(&bot, "global"), "own");
+        assert_eq!(own_secret(&bot).unwrap().0, "secret of bot `gpt4o`");
+    }
+
+    #[test]
+    fn query_mode_moves_secret_into_url() {
+        let mut bot = crate::config::default_bots(false).remove(0);
+        bot.secret_query = Some("token".to_string());
+        let payload = set_webhook_payload(&bot, "https://a.ngrok.io/webhook", "s3cret");
+        assert_eq!(payload, serde_json::json!({ "url": "https://a.ngrok.io/webhook?token=s3cret" }));
+
+        let live = payload["url"].as_str().unwrap();
+        assert_eq!(without_secret_param(&bot, live, "s3cret"), "https://a.ngrok.io/webhook");
+        assert_eq!(without_secret_param(&bot, live, "rotated"), "https://a.ngrok.io/webhook?token=***");
+        assert_eq!(with_secret_param("https://a.ngrok.io/hook?v=1", "token", "a b"), "https://a.ngrok.io/hook?v=1&token=a%20b");
+
+        // Without a secret there is nothing to send, in either mode.
+        assert_eq!(set_webhook_payload(&bot, "https://a.ngrok.io/webhook", ""), serde_json::json!({ "url": "https://a.ngrok.io/webhook" }));
+        bot.secret_query = None;
+        assert_eq!(set_webhook_payload(&bot, "https://a.ngrok.io/webhook", ""), serde_json::json!({ "url": "https://a.ngrok.io/webhook" }));
+    }
+
+    const TOKEN: &str = "123456789:AAHfiqksKZ8WmR2zSjiQ7_v4TMAKdiHm9T0";
+
+    #[test]
+    fn redact_token_keeps_last_four() {
+        assert_eq!(redact_token("abcdefgh"), "****efgh");
+        assert_eq!(redact_token("abc"), "abc");
+        assert!(redact_token(TOKEN).ends_with("m9T0"));
+        assert!(!redact_token(TOKEN).contains("123456789"));
+    }
+
+    #[test]
+    fn tokens_never_appear_in_formatted_errors() {
+        let endpoint = method_url(DEFAULT_API_BASE, TOKEN, "setWebhook");
+        let err = BindError::TelegramError(TelegramApiError {
+            error_code: 404,
+            description: format!("Not Found: {}", endpoint),
+            parameters: None,
+        });
+        let logged = format!("[❌] Failed gpt4o: {}", err);
+        assert!(!logged.contains(TOKEN), "{}", logged);
+        assert!(logged.contains("/setWebhook"));
+
+        let scrubbed = redact_tokens(&format!("{} and again {}", endpoint, TOKEN));
+        assert!(!scrubbed.contains(TOKEN), "{}", scrubbed);
+        assert_eq!(redact_tokens("port 9977: no tunnel"), "port 9977: no tunnel");
+    }
+}
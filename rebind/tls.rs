@@ -0,0 +1,60 @@
+//! TLS settings for the shared client: extra root certificates from
+//! `REBIND_CA_BUNDLE` for a Bot API server behind a private CA, and
+//! `REBIND_INSECURE_SKIP_VERIFY` for local testing only.
+
+use std::{env, fs};
+
+use native_tls::{Certificate, TlsConnector};
+
+use crate::config::env_flag;
+
+/// Splits a PEM bundle into its certificates; native-tls only parses one
+/// at a time.
+fn pem_certificates(pem: &str) -> Vec<String> {
+    const END: &str = "-----END CERTIFICATE-----";
+    pem.split_inclusive(END)
+        .filter_map(|chunk| chunk.find("-----BEGIN CERTIFICATE-----").map(|start| chunk[start..].to_string()))
+        .filter(|cert| cert.ends_with(END))
+        .collect()
+}
+
+/// The connector [`crate::build_client`] wraps: the system trust store plus
+/// every certificate in `REBIND_CA_BUNDLE`, with verification switched off
+/// entirely when `REBIND_INSECURE_SKIP_VERIFY` is set.
+pub fn connector_from_env() -> Result<TlsConnector, String> {
+    let mut builder = TlsConnector::builder();
+    if let Some(path) = env::var("REBIND_CA_BUNDLE").ok().filter(|p| !p.trim().is_empty()) {
+        let pem = fs::read_to_string(&path).map_err(|e| format!("cannot read REBIND_CA_BUNDLE {}: {}", path, e))?;
+        let certs = pem_certificates(&pem);
+        if certs.is_empty() {
+            return Err(format!("REBIND_CA_BUNDLE {} holds no PEM certificates", path));
+        }
+        for (idx, cert) in certs.iter().enumerate() {
+            let cert = Certificate::from_pem(cert.as_bytes())
+                .map_err(|e| format!("certificate {} in REBIND_CA_BUNDLE {} is invalid: {}", idx + 1, path, e))?;
+            builder.add_root_certificate(cert);
+        }
+        log::debug!("Trusting {} extra root certificates from {}", certs.len(), path);
+    }
+    if env_flag("REBIND_INSECURE_SKIP_VERIFY") {
+        log::warn!(
+            "[⚠️] REBIND_INSECURE_SKIP_VERIFY is set: TLS certificates and hostnames are NOT verified, so anyone \
+             on the network path can impersonate Telegram and read every bot token. Never use this outside local testing"
+        );
+        builder.danger_accept_invalid_certs(true).danger_accept_invalid_hostnames(true);
+    }
+    builder.build().map_err(|e| format!("cannot set up TLS: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_split_into_certificates() {
+        let cert = |body: &str| format!("-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----", body);
+        let bundle = format!("# corporate roots\n{}\nsubject=intermediate\n{}\n", cert("AAAA"), cert("BBBB"));
+        assert_eq!(pem_certificates(&bundle), [cert("AAAA"), cert("BBBB")]);
+        assert!(pem_certificates("-----BEGIN CERTIFICATE-----\ntruncated").is_empty());
+    }
+}
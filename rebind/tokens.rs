@@ -0,0 +1,258 @@
+//! Where bot tokens come from: `BOT_TOKEN_<NAME>` variables, or a secrets
+//! file named by `REBIND_TOKENS_FILE` with the variables as fallback.
+
+use std::collections::HashMap;
+use std::{env, fs};
+
+use serde_json::Value;
+
+use crate::config::{env_or, BotBinding, Platform};
+use crate::telegram::{self, BindError};
+use crate::toml;
+
+pub trait TokenProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// The bot's token, or [`BindError::MissingToken`] naming where it was
+    /// looked for.
+    fn token(&self, bot: &BotBinding) -> Result<String, BindError>;
+    /// Where [`TokenProvider::token`] finds (or would look for) the bot's
+    /// token, for `--list`.
+    fn source(&self, bot: &BotBinding) -> String {
+        bot.token_var()
+    }
+}
+
+/// Each bot's `token_env`, or `BOT_TOKEN_<NAME>`.
+pub struct EnvTokens;
+
+impl TokenProvider for EnvTokens {
+    fn name(&self) -> &'static str {
+        "env"
+    }
+
+    fn token(&self, bot: &BotBinding) -> Result<String, BindError> {
+        telegram::resolve_token(bot)
+    }
+}
+
+/// A `{bot_name: token}` map read once at startup, as JSON or as top-level
+/// TOML keys. Bots missing from it fall back to [`EnvTokens`].
+pub struct FileTokens {
+    pub path: String,
+    tokens: HashMap<String, String>,
+}
+
+impl FileTokens {
+    /// Warns when the file can be read by anyone but its owner.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let src = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        warn_if_exposed(path);
+        let value = match serde_json::from_str::<Value>(&src) {
+            Ok(value) => value,
+            Err(_) => toml::parse(&src).map_err(|e| format!("cannot parse {}: {}", path, e))?,
+        };
+        let Value::Object(map) = value else {
+            return Err(format!("{} must map bot names to tokens", path));
+        };
+        let mut tokens = HashMap::new();
+        for (name, token) in map {
+            match token {
+                Value::String(token) if !token.is_empty() => {
+                    tokens.insert(name, token);
+                }
+                _ => return Err(format!("{}: token for `{}` must be a non-empty string", path, name)),
+            }
+        }
+        Ok(FileTokens { path: path.to_string(), tokens })
+    }
+}
+
+impl TokenProvider for FileTokens {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn token(&self, bot: &BotBinding) -> Result<String, BindError> {
+        if let Some(token) = self.tokens.get(&bot.name) {
+            return Ok(token.clone());
+        }
+        EnvTokens.token(bot).map_err(|_| {
+            BindError::MissingToken(format!("{} or `{}` in {}", bot.token_var(), bot.name, self.path))
+        })
+    }
+
+    fn source(&self, bot: &BotBinding) -> String {
+        if self.tokens.contains_key(&bot.name) {
+            format!("`{}` in {}", bot.name, self.path)
+        } else {
+            bot.token_var()
+        }
+    }
+}
+
+#[cfg(unix)]
+fn warn_if_exposed(path: &str) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(meta) = fs::metadata(path) {
+        let mode = meta.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            log::warn!("[⚠️] {} has mode {:o}; tokens files should be 600", path, mode);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_exposed(_path: &str) {}
+
+/// [`FileTokens`] when `REBIND_TOKENS_FILE` is set, otherwise [`EnvTokens`].
+pub fn token_provider() -> Result<Box<dyn TokenProvider>, String> {
+    match env::var("REBIND_TOKENS_FILE") {
+        Ok(path) if !path.trim().is_empty() => Ok(Box::new(FileTokens::load(path.trim())?)),
+        _ => Ok(Box::new(EnvTokens)),
+    }
+}
+
+/// How a Telegram token compares with the `<bot id>:<35 characters>` shape
+/// BotFather hands out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenShape {
+    Ok,
+    /// An id and a secret, but not the usual length; only warned about, in
+    /// case Telegram changes the format.
+    Unusual(String),
+    /// Can't be a bot token, so Telegram would only answer 401.
+    Malformed(String),
+}
+
+/// Checks `token` without sending it anywhere. Never includes the token in
+/// the reason.
+pub fn telegram_token_shape(token: &str) -> TokenShape {
+    if token.chars().any(char::is_whitespace) {
+        return TokenShape::Malformed("contains whitespace".to_string());
+    }
+    let Some((id, secret)) = token.split_once(':') else {
+        return TokenShape::Malformed("has no `:` between the bot id and the secret".to_string());
+    };
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return TokenShape::Malformed("doesn't start with the numeric bot id".to_string());
+    }
+    if secret.is_empty() {
+        return TokenShape::Malformed("has nothing after the `:`".to_string());
+    }
+    if !secret.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+        return TokenShape::Malformed("has characters other than letters, digits, `-` and `_` after the `:`".to_string());
+    }
+    match secret.len() {
+        35 => TokenShape::Ok,
+        n => TokenShape::Unusual(format!("has {} characters after the `:`, not the usual 35", n)),
+    }
+}
+
+/// [`telegram_token_shape`] for Telegram bots; other platforms' tokens
+/// aren't checked.
+pub fn token_shape(bot: &BotBinding, token: &str) -> TokenShape {
+    match bot.platform {
+        Platform::Telegram => telegram_token_shape(token),
+        Platform::Discord | Platform::Slack => TokenShape::Ok,
+    }
+}
+
+/// What to do with a bot whose token is [`TokenShape::Malformed`], from
+/// `REBIND_MALFORMED_TOKENS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MalformedTokens {
+    /// Stop before sending anything (the default).
+    #[default]
+    Fail,
+    /// Leave the bot out of the run.
+    Skip,
+    /// Warn and send the token anyway.
+    Warn,
+}
+
+impl std::str::FromStr for MalformedTokens {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(MalformedTokens::Fail),
+            "skip" => Ok(MalformedTokens::Skip),
+            "warn" => Ok(MalformedTokens::Warn),
+            other => Err(format!("unknown policy `{}` (expected fail, skip or warn)", other)),
+        }
+    }
+}
+
+impl MalformedTokens {
+    pub fn from_env() -> Self {
+        env_or("REBIND_MALFORMED_TOKENS", MalformedTokens::Fail)
+    }
+}
+
+/// What to do with a bot that has no token at all, from
+/// `REBIND_MISSING_TOKEN`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingTokens {
+    /// Leave the bot out of the run, saying so only in the log.
+    Skip,
+    /// Leave the bot out, and name it in the run's summary.
+    Warn,
+    /// Stop before sending anything (the default).
+    #[default]
+    Error,
+}
+
+impl std::str::FromStr for MissingTokens {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(MissingTokens::Skip),
+            "warn" => Ok(MissingTokens::Warn),
+            "error" => Ok(MissingTokens::Error),
+            other => Err(format!("unknown policy `{}` (expected skip, warn or error)", other)),
+        }
+    }
+}
+
+impl MissingTokens {
+    pub fn from_env() -> Self {
+        env_or("REBIND_MISSING_TOKEN", MissingTokens::Error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::default_bots;
+
+    #[test]
+    fn file_tokens_fall_back_to_env() {
+        let path = env::temp_dir().join(format!("rebind-tokens-{}.toml", std::process::id()));
+        fs::write(&path, "gpt4o = \"123:abc\"\n").unwrap();
+        let tokens = FileTokens::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let bots = default_bots(false);
+        assert_eq!(tokens.token(&bots[0]).unwrap(), "123:abc");
+        let err = tokens.token(&bots[1]).unwrap_err();
+        assert!(err.to_string().contains("BOT_TOKEN_MISTRAL or `mistral` in"), "{}", err);
+    }
+
+    #[test]
+    fn malformed_tokens_are_told_from_unusual_ones() {
+        let secret = "AAHdqTcvCH1vGWJxfSeofSAs0K5PALDsaw_";
+        assert_eq!(telegram_token_shape(&format!("110201543:{}", secret)), TokenShape::Ok);
+        assert!(matches!(telegram_token_shape("110201543:abc"), TokenShape::Unusual(_)));
+        for token in [secret.to_string(), format!("{}:110201543", secret), "110201543:".to_string(), format!("110201543:{} ", secret), "1:a+b".to_string()] {
+            match telegram_token_shape(&token) {
+                TokenShape::Malformed(reason) => assert!(!reason.contains(secret), "{}", reason),
+                other => panic!("{:?} for {:?}", other, token),
+            }
+        }
+        let mut bot = default_bots(false).remove(0);
+        bot.platform = Platform::Discord;
+        assert_eq!(token_shape(&bot, "anything"), TokenShape::Ok);
+    }
+}
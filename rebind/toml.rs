@@ -0,0 +1,382 @@
+//! Just enough TOML for the bot table: tables, arrays of tables, strings,
+//! integers, floats, booleans, arrays and inline tables.
+
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+pub fn parse(src: &str) -> Result<Value, ParseError> {
+    parse_with_lines(src).map(|(value, _)| value)
+}
+
+/// Like [`parse`], also returning the line each key and table header was
+/// found on, keyed by path such as `bot[1].allowed_updates` or `bot[1]`.
+pub fn parse_with_lines(src: &str) -> Result<(Value, HashMap<String, usize>), ParseError> {
+    let mut parser = Parser { chars: src.chars().collect(), pos: 0, line: 1 };
+    let mut root = Map::new();
+    let mut lines = HashMap::new();
+    let mut current: Vec<String> = Vec::new();
+
+    loop {
+        parser.skip_blank_lines();
+        let Some(c) = parser.peek() else { break };
+        let line = parser.line;
+        if c == '[' {
+            parser.bump();
+            let array = parser.eat('[');
+            let path = parser.key_path()?;
+            parser.expect(']')?;
+            if array {
+                parser.expect(']')?;
+            }
+            parser.end_of_line()?;
+            if array {
+                let (last, parents) = path.split_last().unwrap();
+                let table = parser.navigate(&mut root, parents)?;
+                let entry = table.entry(last.clone()).or_insert_with(|| Value::Array(Vec::new()));
+                match entry {
+                    Value::Array(items) => items.push(Value::Object(Map::new())),
+                    _ => return Err(parser.error(format!("`{}` is not an array of tables", path.join(".")))),
+                }
+            } else {
+                parser.navigate(&mut root, &path)?;
+            }
+            lines.insert(label(&root, &path), line);
+            current = path;
+        } else {
+            let path = parser.key_path()?;
+            parser.skip_spaces();
+            parser.expect('=')?;
+            parser.skip_spaces();
+            let value = parser.value()?;
+            parser.end_of_line()?;
+            let (last, parents) = path.split_last().unwrap();
+            let mut full = current.clone();
+            full.extend_from_slice(parents);
+            let table = parser.navigate(&mut root, &full)?;
+            if table.insert(last.clone(), value).is_some() {
+                return Err(ParseError { line, message: format!("duplicate key `{}`", last) });
+            }
+            full.push(last.clone());
+            lines.insert(label(&root, &full), line);
+        }
+    }
+    Ok((Value::Object(root), lines))
+}
+
+/// `path` with the index of the table it currently points into after each
+/// array of tables, e.g. `bot[1].name`.
+fn label(root: &Map<String, Value>, path: &[String]) -> String {
+    let mut out = String::new();
+    let mut table = Some(root);
+    for key in path {
+        if !out.is_empty() {
+            out.push('.');
+        }
+        out.push_str(key);
+        let next = table.and_then(|t| t.get(key));
+        if let Some(Value::Array(items)) = next {
+            if items.last().is_some_and(Value::is_object) {
+                out.push_str(&format!("[{}]", items.len() - 1));
+            }
+        }
+        table = match next {
+            Some(Value::Array(items)) => items.last().and_then(Value::as_object),
+            Some(Value::Object(map)) => Some(map),
+            _ => None,
+        };
+    }
+    out
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError { line: self.line, message: message.into() }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, want: char) -> bool {
+        if self.peek() == Some(want) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, want: char) -> Result<(), ParseError> {
+        self.skip_spaces();
+        if self.eat(want) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}`", want)))
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') | Some('\r') => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip_spaces();
+        self.skip_comment();
+        self.eat('\r');
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("unexpected `{}` after value", c))),
+        }
+    }
+
+    fn key_path(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut path = Vec::new();
+        loop {
+            self.skip_spaces();
+            let key = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let mut key = String::new();
+                    while let Some(c) = self.peek() {
+                        if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                            key.push(c);
+                            self.bump();
+                        } else {
+                            break;
+                        }
+                    }
+                    if key.is_empty() {
+                        return Err(self.error("expected a key"));
+                    }
+                    key
+                }
+            };
+            path.push(key);
+            self.skip_spaces();
+            if !self.eat('.') {
+                return Ok(path);
+            }
+        }
+    }
+
+    fn navigate<'m>(
+        &self,
+        mut table: &'m mut Map<String, Value>,
+        path: &[String],
+    ) -> Result<&'m mut Map<String, Value>, ParseError> {
+        for key in path {
+            let entry = table.entry(key.clone()).or_insert_with(|| Value::Object(Map::new()));
+            let next = match entry {
+                Value::Array(items) => items.last_mut(),
+                other => Some(other),
+            };
+            table = match next {
+                Some(Value::Object(map)) => map,
+                _ => return Err(self.error(format!("`{}` is not a table", key))),
+            };
+        }
+        Ok(table)
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.basic_string()?)),
+            Some('\'') => Ok(Value::String(self.literal_string()?)),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some(_) => self.scalar(),
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, ParseError> {
+        self.bump();
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('"') => return Ok(out),
+                Some('\\') => {
+                    let c = match self.bump() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('u') => self.unicode_escape(4)?,
+                        Some('U') => self.unicode_escape(8)?,
+                        _ => return Err(self.error("invalid escape sequence")),
+                    };
+                    out.push(c);
+                }
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self, len: usize) -> Result<char, ParseError> {
+        let mut hex = String::new();
+        for _ in 0..len {
+            hex.extend(self.bump());
+        }
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error(format!("invalid unicode escape `{}`", hex)))
+    }
+
+    fn literal_string(&mut self) -> Result<String, ParseError> {
+        self.bump();
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('\'') => return Ok(out),
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn skip_array_space(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') | Some('\r') => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.bump();
+        let mut items = Vec::new();
+        loop {
+            self.skip_array_space();
+            if self.eat(']') {
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_array_space();
+            if self.eat(']') {
+                return Ok(Value::Array(items));
+            }
+            if !self.eat(',') {
+                return Err(self.error("expected `,` or `]` in array"));
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, ParseError> {
+        self.bump();
+        let mut map = Map::new();
+        self.skip_spaces();
+        if self.eat('}') {
+            return Ok(Value::Object(map));
+        }
+        loop {
+            let path = self.key_path()?;
+            self.expect('=')?;
+            self.skip_spaces();
+            let value = self.value()?;
+            let (last, parents) = path.split_last().unwrap();
+            let table = self.navigate(&mut map, parents)?;
+            if table.insert(last.clone(), value).is_some() {
+                return Err(self.error(format!("duplicate key `{}`", last)));
+            }
+            self.skip_spaces();
+            if self.eat('}') {
+                return Ok(Value::Object(map));
+            }
+            if !self.eat(',') {
+                return Err(self.error("expected `,` or `}` in inline table"));
+            }
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Value, ParseError> {
+        let mut raw = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '_' | '.') {
+                raw.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        match raw.as_str() {
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            "" => return Err(self.error("expected a value")),
+            _ => {}
+        }
+        let digits = raw.replace('_', "");
+        if let Ok(n) = digits.parse::<i64>() {
+            return Ok(Value::Number(n.into()));
+        }
+        digits
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number)
+            .ok_or_else(|| self.error(format!("unsupported value `{}`", raw)))
+    }
+}
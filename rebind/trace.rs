@@ -0,0 +1,212 @@
+//! `--trace-file`: every HTTP request of the run and its response, one JSON
+//! line per exchange, for debugging a bind that fails for no clear reason.
+//! Far more verbose than the ledger, but redacted the same way: tokens are
+//! masked wherever they appear, credential headers and secret-looking JSON
+//! fields and form parts are replaced, uploaded files are reduced to their
+//! size, and query strings keep their names only.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use hyper::body::Bytes;
+use hyper::http::response::Parts;
+use hyper::{HeaderMap, Method, Uri};
+use serde_json::{json, Value};
+
+use crate::logger;
+use crate::telegram::{redact_tokens, BindError};
+use crate::tunnel::shown_header;
+
+static TRACE: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// JSON fields and form parts whose value is never written, by any part
+/// of their name.
+const SECRET_FIELDS: &[&str] = &["secret", "token", "password", "key", "auth"];
+
+/// Starts tracing to `path`, truncating it. The file is only readable by
+/// its owner, since bodies carry webhook URLs and chat metadata.
+pub fn install(path: &Path) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path)?;
+    let _ = TRACE.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Whether [`install`] was called, so requests know to keep their body.
+pub(crate) fn enabled() -> bool {
+    TRACE.get().is_some()
+}
+
+/// Appends one exchange; a failed write is only logged.
+pub(crate) fn record(
+    id: &str,
+    (method, uri, headers): (&Method, &Uri, &HeaderMap),
+    body: &[u8],
+    result: &Result<(Parts, Bytes), BindError>,
+    elapsed: Duration,
+) {
+    let Some(file) = TRACE.get() else { return };
+    let headers: serde_json::Map<String, Value> = headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            (name.to_string(), json!(redact_tokens(shown_header(name.as_str(), &value))))
+        })
+        .collect();
+    let mut line = json!({
+        "ts": logger::timestamp(),
+        "request_id": id,
+        "elapsed_ms": elapsed.as_millis() as u64,
+        "request": {
+            "method": method.as_str(),
+            "url": redact_url(&uri.to_string()),
+            "headers": headers,
+            "body": redact_body(body),
+        },
+    });
+    match result {
+        Ok((parts, body)) => line["response"] = json!({ "status": parts.status.as_u16(), "body": redact_body(body) }),
+        Err(err) => line["error"] = json!(err.to_string()),
+    }
+    let mut file = file.lock().unwrap();
+    if let Err(err) = writeln!(file, "{}", line) {
+        log::warn!("[⚠️] Cannot write to the trace file: {}", err);
+    }
+}
+
+/// `body` as JSON when it parses, as an object of its parts when it is
+/// `multipart/form-data`, otherwise as text; `null` when empty.
+fn redact_body(body: &[u8]) -> Value {
+    if body.is_empty() {
+        return Value::Null;
+    }
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) => {
+            redact_json(&mut value);
+            value
+        }
+        Err(_) => match redact_multipart(body) {
+            Some(parts) => parts,
+            None => json!(redact_tokens(&String::from_utf8_lossy(body))),
+        },
+    }
+}
+
+/// A `multipart/form-data` body as `{name: value}`, with secret-looking
+/// parts blanked like [`redact_json`] does and each file (the
+/// `setWebhook` certificate) given as its size. `None` when `body` doesn't
+/// start with a boundary line.
+fn redact_multipart(body: &[u8]) -> Option<Value> {
+    let text = String::from_utf8_lossy(body);
+    let boundary = text.strip_prefix("--")?.split("\r\n").next()?;
+    if boundary.is_empty() {
+        return None;
+    }
+    let mut parts = serde_json::Map::new();
+    for part in text.split(&format!("--{}", boundary)).skip(1) {
+        let Some((headers, content)) = part.trim_start_matches("\r\n").split_once("\r\n\r\n") else { continue };
+        let Some(name) = form_param(headers, "name") else { continue };
+        let content = content.strip_suffix("\r\n").unwrap_or(content);
+        let lowered = name.to_ascii_lowercase();
+        let value = if form_param(headers, "filename").is_some() {
+            json!(format!("<{} bytes>", content.len()))
+        } else if SECRET_FIELDS.iter().any(|word| lowered.contains(word)) {
+            json!("<redacted>")
+        } else {
+            json!(redact_url(content))
+        };
+        parts.insert(name.to_string(), value);
+    }
+    Some(Value::Object(parts))
+}
+
+/// The quoted `param="..."` of a part's `Content-Disposition` header.
+fn form_param<'a>(headers: &'a str, param: &str) -> Option<&'a str> {
+    let disposition = headers.lines().find(|line| line.to_ascii_lowercase().starts_with("content-disposition:"))?;
+    disposition.split(';').find_map(|field| field.trim().strip_prefix(param)?.strip_prefix("=\"")?.strip_suffix('"'))
+}
+
+/// Blanks secret-looking fields and redacts every other string in place.
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                if value.is_string() && SECRET_FIELDS.iter().any(|word| key.contains(word)) {
+                    *value = json!("<redacted>");
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json),
+        Value::String(text) => *text = redact_url(text),
+        _ => {}
+    }
+}
+
+/// [`redact_tokens`], with every query value of a URL replaced, since a
+/// `secret_mode = "query"` webhook carries its secret there.
+fn redact_url(url: &str) -> String {
+    let url = redact_tokens(url);
+    let Some((base, query)) = url.split_once('?').filter(|(base, _)| base.contains("://")) else {
+        return url;
+    };
+    let (query, fragment) = query.split_once('#').map_or((query, None), |(query, fragment)| (query, Some(fragment)));
+    let params: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) => format!("{}=<redacted>", name),
+            None => pair.to_string(),
+        })
+        .collect();
+    match fragment {
+        Some(fragment) => format!("{}?{}#{}", base, params.join("&"), fragment),
+        None => format!("{}?{}", base, params.join("&")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telegram::{multipart_payload, set_webhook_payload};
+
+    #[test]
+    fn secrets_never_reach_the_trace() {
+        let token = "123456789:AAHdqTcvCH1vGWJxfSeofSAs0K5PALDsaw_";
+        let body = json!({
+            "url": "https://a.ngrok.io/webhook?token=s3cret",
+            "secret_token": "s3cret",
+            "description": format!("bad token {}", token),
+            "allowed_updates": ["message"],
+        });
+        let traced = redact_body(body.to_string().as_bytes()).to_string();
+        assert!(!traced.contains("s3cret") && !traced.contains("AAHdqTcv"), "{}", traced);
+        assert!(traced.contains("https://a.ngrok.io/webhook?token=<redacted>"), "{}", traced);
+        assert!(traced.contains("\"allowed_updates\":[\"message\"]"), "{}", traced);
+        assert!(!redact_url(&format!("https://api.telegram.org/bot{}/setWebhook", token)).contains("AAHdqTcv"));
+        assert_eq!(redact_body(b"<html>"), json!("<html>"));
+    }
+
+    #[test]
+    fn multipart_set_webhook_bodies_are_redacted_too() {
+        let bot = crate::config::default_bots(false).remove(0);
+        let payload = set_webhook_payload(&bot, "https://a.ngrok.io/webhook", "s3cret");
+        let pem = b"-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n";
+        let (body, _) = multipart_payload(&payload, pem);
+        let traced = redact_body(&body);
+        assert!(!traced.to_string().contains("s3cret") && !traced.to_string().contains("BEGIN"), "{}", traced);
+        assert_eq!(traced["secret_token"], json!("<redacted>"));
+        assert_eq!(traced["url"], json!("https://a.ngrok.io/webhook"));
+        assert_eq!(traced["certificate"], json!(format!("<{} bytes>", pem.len())));
+    }
+}
@@ -0,0 +1,1381 @@
+//! Tunnel discovery: asks ngrok or cloudflared which public URLs exist and
+//! maps them onto bots by local port.
+
+use std::sync::Mutex;
+use std::{collections::HashMap, env, fmt, time::Duration};
+
+use futures_util::future::BoxFuture;
+use hyper::Method;
+use serde::de::{self, DeserializeOwned, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+use crate::config::{env_or, AgentPorts, BotBinding};
+use crate::telegram::{fetch_get, request_builder, BindError};
+use crate::HttpsClient;
+
+pub static NGROK_APIS: &[(&str, &str)] = &[
+    ("main", "http://localhost:4040/api/tunnels"),
+    ("alt", "http://localhost:4041/api/tunnels"),
+];
+pub const DEFAULT_CLOUDFLARED_METRICS: &str = "http://localhost:2000";
+/// ngrok's hosted API, queried by the `ngrok-api` provider.
+pub const NGROK_CLOUD_API: &str = "https://api.ngrok.com/tunnels";
+/// Agents are local, so an unresponsive one is given up on quickly.
+pub const DEFAULT_DISCOVERY_TIMEOUT_SECS: u64 = 3;
+/// Largest agent response read (`REBIND_DISCOVERY_MAX_BYTES`); a page of
+/// tunnels is a few kilobytes.
+pub const DEFAULT_DISCOVERY_MAX_BYTES: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tunnel {
+    pub public_url: String,
+    pub addr: String,
+    /// Label of the agent or metrics server that reported it.
+    pub agent: String,
+    /// ngrok's `proto` (`https`, `http`, ...), lowercased; `None` when the
+    /// agent didn't say, and the `public_url` scheme is used instead.
+    pub proto: Option<String>,
+}
+
+impl Tunnel {
+    /// Whether a webhook could be served on it.
+    pub fn is_https(&self) -> bool {
+        match &self.proto {
+            Some(proto) => proto == "https",
+            None => self.public_url.get(..8).is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://")),
+        }
+    }
+}
+
+/// Which tunnel a bot gets when several forward to its port, by the order
+/// agents are queried in (`REBIND_TUNNEL_PRECEDENCE`, default `first`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Precedence {
+    #[default]
+    First,
+    Last,
+}
+
+impl std::str::FromStr for Precedence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(Precedence::First),
+            "last" => Ok(Precedence::Last),
+            other => Err(format!("unknown precedence `{}` (expected first or last)", other)),
+        }
+    }
+}
+
+/// The local port a tunnel forwards to. Accepts a bare port (`9977`),
+/// `host:port`, `[ipv6]:port`, and any of those behind a scheme or followed
+/// by a path. An unbracketed IPv6 address has no readable port.
+pub fn tunnel_port(addr: &str) -> Option<u16> {
+    let addr = addr.trim();
+    let rest = addr.split_once("://").map_or(addr, |(_, rest)| rest);
+    let authority = rest.split('/').next()?;
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    if let Ok(port) = authority.parse() {
+        return Some(port);
+    }
+    if let Some(bracketed) = authority.strip_prefix('[') {
+        let (_, after) = bracketed.split_once(']')?;
+        return after.strip_prefix(':')?.parse().ok();
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => port.parse().ok(),
+        _ => None,
+    }
+}
+
+/// `url` with its scheme and host lowercased and any trailing `/` dropped,
+/// so webhook paths join onto it cleanly; an error when what's left doesn't
+/// parse as an absolute URL.
+pub fn normalize_public_url(url: &str) -> Result<String, String> {
+    let url = url.trim();
+    let (scheme, rest) = url.split_once("://").ok_or_else(|| format!("`{}` has no scheme", url))?;
+    let split = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (host, tail) = rest.split_at(split);
+    let tail = if tail.contains(['?', '#']) { tail } else { tail.trim_end_matches('/') };
+    let normalized = format!("{}://{}{}", scheme.to_ascii_lowercase(), host.to_ascii_lowercase(), tail);
+    match normalized.parse::<hyper::Uri>() {
+        Ok(uri) if uri.host().is_some_and(|h| !h.is_empty()) => Ok(normalized),
+        Ok(_) => Err(format!("`{}` has no host", url)),
+        Err(err) => Err(format!("`{}` is not a valid URL: {}", url, err)),
+    }
+}
+
+/// Maps tunnels onto bot names by the port they forward to. Shared by every
+/// provider so they agree on what counts as a match. Bots sharing a port
+/// (one server routing by path) all get that port's URL. When several tunnels
+/// forward to the same port, `precedence` picks the earliest or latest one
+/// in query order and the others are named in a warning.
+/// Webhooks must be HTTPS, so only [`Tunnel::is_https`] tunnels match; a
+/// bot whose port only has a plain `http` one is left out with a warning.
+/// Public URLs go through [`normalize_public_url`]; a tunnel whose URL
+/// doesn't survive it is skipped with an error for each bot on its port.
+pub fn match_tunnels(tunnels: &[Tunnel], bots: &[BotBinding], precedence: Precedence) -> HashMap<String, String> {
+    let tunnels: Vec<Tunnel> = tunnels
+        .iter()
+        .filter_map(|tunnel| match normalize_public_url(&tunnel.public_url) {
+            Ok(public_url) => Some(Tunnel { public_url, ..tunnel.clone() }),
+            Err(err) => {
+                let port = tunnel_port(&tunnel.addr);
+                for bot in bots.iter().filter(|b| Some(b.port) == port) {
+                    log::error!("[❌] {}: skipping the tunnel from {} ({}): {}", bot.name, tunnel.agent, tunnel.addr, err);
+                }
+                None
+            }
+        })
+        .collect();
+    let ordered: Box<dyn Iterator<Item = &Tunnel>> = match precedence {
+        Precedence::First => Box::new(tunnels.iter()),
+        Precedence::Last => Box::new(tunnels.iter().rev()),
+    };
+    let mut kept: HashMap<String, &Tunnel> = HashMap::new();
+    let mut plain: HashMap<String, &Tunnel> = HashMap::new();
+    for tunnel in ordered {
+        let Some(port) = tunnel_port(&tunnel.addr) else { continue };
+        if !tunnel.is_https() {
+            for bot in bots.iter().filter(|b| b.port == port) {
+                plain.entry(bot.name.clone()).or_insert(tunnel);
+            }
+            continue;
+        }
+        for bot in bots.iter().filter(|b| b.port == port) {
+            match kept.get(&bot.name) {
+                None => {
+                    kept.insert(bot.name.clone(), tunnel);
+                }
+                Some(winner) if winner.public_url != tunnel.public_url => {
+                    log::warn!(
+                        "[⚠️] {}: port {} is exposed as {} ({}) and {} ({}); using {}",
+                        bot.name,
+                        port,
+                        winner.public_url,
+                        winner.agent,
+                        tunnel.public_url,
+                        tunnel.agent,
+                        winner.public_url
+                    );
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    let mut skipped: Vec<(&String, &&Tunnel)> = plain.iter().filter(|(name, _)| !kept.contains_key(*name)).collect();
+    skipped.sort_by_key(|(name, _)| *name);
+    for (name, tunnel) in skipped {
+        log::warn!(
+            "[⚠️] {}: port {} is only exposed over plain HTTP ({}); webhooks need an https tunnel, skipping",
+            name,
+            tunnel_port(&tunnel.addr).unwrap_or_default(),
+            tunnel.public_url
+        );
+    }
+    kept.into_iter().map(|(name, tunnel)| (name, tunnel.public_url.clone())).collect()
+}
+
+/// ngrok agent APIs to query, in precedence order. `NGROK_API_URLS` is a
+/// comma-separated list whose entries are either a URL (labelled `agent-N`)
+/// or `label=URL`; without it the built-in [`NGROK_APIS`] are used. A
+/// `file://PATH` URL reads a captured `/api/tunnels` answer from disk
+/// instead, for reproducing someone's tunnel layout offline. A `localhost`
+/// agent is looked for on `127.0.0.1` and then `[::1]`.
+pub fn ngrok_apis() -> Vec<(String, String)> {
+    let Ok(list) = env::var("NGROK_API_URLS") else {
+        return NGROK_APIS.iter().map(|(label, api)| (label.to_string(), api.to_string())).collect();
+    };
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .enumerate()
+        .map(|(i, entry)| match entry.split_once('=') {
+            Some((label, api)) if !label.contains('/') => (label.trim().to_string(), api.trim().to_string()),
+            _ => (format!("agent-{}", i), entry.to_string()),
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    /// None of these agent endpoints answered at all.
+    NoAgentReachable(Vec<String>),
+    /// Tunnels whose port no bot uses, as `(port, public_url)`; only raised
+    /// with `--strict-unmapped`.
+    Unmapped(Vec<(u16, String)>),
+    /// The run's deadline passed before any agent answered.
+    DeadlineExceeded,
+    /// [`crate::RebindConfig::cancel`] was cancelled before any agent answered.
+    Cancelled,
+    /// Every provider of a [`FallbackProvider`] failed or found no tunnel,
+    /// as `(provider, what happened)`.
+    Exhausted(Vec<(String, String)>),
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::NoAgentReachable(endpoints) => {
+                write!(f, "no tunnel agent reachable on any of [{}]", endpoints.join(", "))
+            }
+            DiscoveryError::Unmapped(tunnels) => {
+                let list: Vec<String> = tunnels.iter().map(|(port, url)| format!("{} ({})", url, port)).collect();
+                write!(f, "tunnels forward to ports no bot uses: {}", list.join(", "))
+            }
+            DiscoveryError::DeadlineExceeded => write!(f, "deadline exceeded during tunnel discovery"),
+            DiscoveryError::Cancelled => write!(f, "cancelled during tunnel discovery"),
+            DiscoveryError::Exhausted(tried) => {
+                let tried: Vec<String> = tried.iter().map(|(name, outcome)| format!("{}: {}", name, outcome)).collect();
+                write!(f, "no tunnel provider found a tunnel ({})", tried.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+/// How one agent, or cloudflared metrics server, answered a discovery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AgentState {
+    Ok,
+    /// No answer, or none in time.
+    Unreachable,
+    /// An answer that couldn't be used: an error status, JSON that doesn't
+    /// parse, or a body cut short.
+    ParseError,
+}
+
+impl AgentState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AgentState::Ok => "ok",
+            AgentState::Unreachable => "unreachable",
+            AgentState::ParseError => "parse-error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentStatus {
+    /// The agent's label, or the metrics server's URL.
+    pub agent: String,
+    pub state: AgentState,
+    /// Every tunnel it reported, whether or not a bot got it.
+    pub tunnels: Vec<Tunnel>,
+}
+
+impl<T> From<&Fetched<T>> for AgentState {
+    fn from(fetched: &Fetched<T>) -> Self {
+        match fetched {
+            Fetched::Json(_) => AgentState::Ok,
+            Fetched::Unusable | Fetched::Incomplete => AgentState::ParseError,
+            Fetched::Unreachable => AgentState::Unreachable,
+        }
+    }
+}
+
+pub trait TunnelProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Bot name to public URL. Fails only when no agent could be reached;
+    /// an agent with no matching tunnels yields an empty map.
+    fn public_urls(&self) -> BoxFuture<'_, Result<HashMap<String, String>, DiscoveryError>>;
+    /// The provider the last successful [`TunnelProvider::public_urls`] came
+    /// from; only a [`FallbackProvider`] differs from its own name.
+    fn used(&self) -> &'static str {
+        self.name()
+    }
+    /// How each agent answered the last [`TunnelProvider::public_urls`], in
+    /// query order; empty for a provider that doesn't say, or when that
+    /// discovery was cut short.
+    fn agents(&self) -> Vec<AgentStatus> {
+        Vec::new()
+    }
+    /// Tunnels the last [`TunnelProvider::public_urls`] found on ports no
+    /// bot uses, as `(port, public URL)`; empty for a provider that doesn't
+    /// say.
+    fn unmapped(&self) -> Vec<(u16, String)> {
+        Vec::new()
+    }
+}
+
+/// Several providers in preference order: each is asked in turn until one
+/// finds a tunnel for some bot, so a backup such as cloudflared only
+/// matters when ngrok comes up empty or can't be reached.
+pub struct FallbackProvider {
+    pub providers: Vec<Box<dyn TunnelProvider>>,
+    used: Mutex<Option<&'static str>>,
+    agents: Mutex<Vec<AgentStatus>>,
+    unmapped: Mutex<Vec<(u16, String)>>,
+}
+
+impl TunnelProvider for FallbackProvider {
+    fn name(&self) -> &'static str {
+        "fallback"
+    }
+
+    /// Fails with [`DiscoveryError::Exhausted`] when every provider failed
+    /// or found nothing.
+    fn public_urls(&self) -> BoxFuture<'_, Result<HashMap<String, String>, DiscoveryError>> {
+        Box::pin(async move {
+            let mut tried = Vec::new();
+            self.agents.lock().unwrap().clear();
+            self.unmapped.lock().unwrap().clear();
+            for (i, provider) in self.providers.iter().enumerate() {
+                let urls = provider.public_urls().await;
+                self.agents.lock().unwrap().extend(provider.agents());
+                let outcome = match urls {
+                    Ok(urls) if !urls.is_empty() => {
+                        if i > 0 {
+                            log::info!("[🔀] Using {}, the first provider with tunnels", provider.name());
+                        }
+                        *self.used.lock().unwrap() = Some(provider.used());
+                        *self.unmapped.lock().unwrap() = provider.unmapped();
+                        return Ok(urls);
+                    }
+                    Ok(_) => "no tunnel for any bot".to_string(),
+                    Err(err) => err.to_string(),
+                };
+                if let Some(next) = self.providers.get(i + 1) {
+                    log::warn!("[⚠️] {}: {}; trying {}", provider.name(), outcome, next.name());
+                }
+                tried.push((provider.name().to_string(), outcome));
+            }
+            Err(DiscoveryError::Exhausted(tried))
+        })
+    }
+
+    fn used(&self) -> &'static str {
+        self.used.lock().unwrap().unwrap_or_else(|| self.name())
+    }
+
+    /// The agents of every provider asked, up to the one that was used.
+    fn agents(&self) -> Vec<AgentStatus> {
+        self.agents.lock().unwrap().clone()
+    }
+
+    /// Those of the provider that was used.
+    fn unmapped(&self) -> Vec<(u16, String)> {
+        self.unmapped.lock().unwrap().clone()
+    }
+}
+
+/// Selects the provider named by `REBIND_TUNNEL_PROVIDER` (default `ngrok`,
+/// querying [`ngrok_apis`], or `ngrok-api` when `NGROK_API_KEY` is set),
+/// resolving port conflicts as
+/// `REBIND_TUNNEL_PRECEDENCE` says. A comma-separated list, such as
+/// `ngrok,cloudflared`, is a [`FallbackProvider`] trying each in turn.
+/// `bots` should be the whole table, so a
+/// tunnel is only called unmapped when no configured bot uses its port;
+/// `strict_unmapped` makes such tunnels fail discovery instead of warning.
+/// `agents` are the `[[agent]]` entries of the bot table; a label that
+/// names no ngrok agent is warned about.
+/// Each agent request times out after `REBIND_DISCOVERY_TIMEOUT_SECS`
+/// (default 3s, independent of the Telegram timeout); `NGROK_API_TIMEOUT_SECS`
+/// overrides it for ngrok. An agent that times out is skipped, as is one
+/// whose answer is cut short or longer than `REBIND_DISCOVERY_MAX_BYTES`.
+/// `headers` (the bot table's `[tunnel.headers]`) go with every request
+/// to an agent.
+pub fn tunnel_provider(
+    client: &HttpsClient,
+    bots: &[BotBinding],
+    agents: &[AgentPorts],
+    headers: &[(String, String)],
+    strict_unmapped: bool,
+) -> Result<Box<dyn TunnelProvider>, String> {
+    let default = if env::var("NGROK_API_KEY").is_ok_and(|k| !k.trim().is_empty()) { "ngrok-api" } else { "ngrok" };
+    let names = env::var("REBIND_TUNNEL_PROVIDER").unwrap_or_else(|_| default.to_string());
+    let mut names: Vec<&str> = names.split(',').map(str::trim).filter(|n| !n.is_empty()).collect();
+    if names.is_empty() {
+        names.push("ngrok");
+    }
+    if !agents.is_empty() && !names.iter().any(|n| n.starts_with("ngrok")) {
+        log::warn!("[⚠️] [[agent]] entries only apply to ngrok agents; ignoring them for {}", names.join(", "));
+    }
+    if !headers.is_empty() {
+        let shown: Vec<String> = headers.iter().map(|(name, value)| format!("{}: {}", name, shown_header(name, value))).collect();
+        log::debug!("Tunnel requests carry {}", shown.join(", "));
+    }
+    let mut providers = names
+        .iter()
+        .map(|name| named_provider(name, client, bots, agents, headers, strict_unmapped))
+        .collect::<Result<Vec<_>, _>>()?;
+    if providers.len() == 1 {
+        return Ok(providers.remove(0));
+    }
+    Ok(Box::new(FallbackProvider { providers, used: Mutex::new(None), agents: Mutex::default(), unmapped: Mutex::default() }))
+}
+
+/// Queries only the ngrok agent labelled `label` in [`ngrok_apis`], for
+/// moving bots onto its tunnels; an error names the labels there are.
+pub fn agent_provider(
+    client: &HttpsClient,
+    bots: &[BotBinding],
+    agents: &[AgentPorts],
+    headers: &[(String, String)],
+    label: &str,
+) -> Result<Box<dyn TunnelProvider>, String> {
+    let apis = ngrok_apis();
+    let labels: Vec<&str> = apis.iter().map(|(l, _)| l.as_str()).collect();
+    if !labels.contains(&label) {
+        return Err(format!("no ngrok agent labelled `{}` in NGROK_API_URLS (known: {})", label, labels.join(", ")));
+    }
+    let apis = apis.iter().filter(|(l, _)| l == label).cloned().collect();
+    let agents: Vec<AgentPorts> = agents.iter().filter(|a| a.label == label).cloned().collect();
+    Ok(Box::new(ngrok_agents(client, bots, &agents, headers, false, apis)))
+}
+
+/// The `ngrok` provider over the agents `apis`.
+fn ngrok_agents(
+    client: &HttpsClient,
+    bots: &[BotBinding],
+    agents: &[AgentPorts],
+    headers: &[(String, String)],
+    strict_unmapped: bool,
+    apis: Vec<(String, String)>,
+) -> NgrokProvider {
+    let timeout = env_or("REBIND_DISCOVERY_TIMEOUT_SECS", DEFAULT_DISCOVERY_TIMEOUT_SECS);
+    NgrokProvider {
+        client: client.clone(),
+        expected_ports: expected_ports(agents, &apis),
+        apis,
+        bots: bots.to_vec(),
+        timeout: Duration::from_secs(env_or("NGROK_API_TIMEOUT_SECS", timeout)),
+        precedence: env_or("REBIND_TUNNEL_PRECEDENCE", Precedence::First),
+        strict_unmapped,
+        api_key: None,
+        region: None,
+        max_body: env_or("REBIND_DISCOVERY_MAX_BYTES", DEFAULT_DISCOVERY_MAX_BYTES),
+        headers: headers.to_vec(),
+        statuses: Mutex::default(),
+        unmapped: Mutex::default(),
+    }
+}
+
+/// One provider of a `REBIND_TUNNEL_PROVIDER` list.
+fn named_provider(
+    name: &str,
+    client: &HttpsClient,
+    bots: &[BotBinding],
+    agents: &[AgentPorts],
+    headers: &[(String, String)],
+    strict_unmapped: bool,
+) -> Result<Box<dyn TunnelProvider>, String> {
+    let api_key = env::var("NGROK_API_KEY").ok().map(|k| k.trim().to_string()).filter(|k| !k.is_empty());
+    let timeout = Duration::from_secs(env_or("REBIND_DISCOVERY_TIMEOUT_SECS", DEFAULT_DISCOVERY_TIMEOUT_SECS));
+    let precedence = env_or("REBIND_TUNNEL_PRECEDENCE", Precedence::First);
+    let max_body = env_or("REBIND_DISCOVERY_MAX_BYTES", DEFAULT_DISCOVERY_MAX_BYTES);
+    match name {
+        "ngrok" => Ok(Box::new(ngrok_agents(client, bots, agents, headers, strict_unmapped, ngrok_apis()))),
+        "ngrok-api" => {
+            let Some(api_key) = api_key else {
+                return Err("REBIND_TUNNEL_PROVIDER=ngrok-api needs NGROK_API_KEY".to_string());
+            };
+            let api = env::var("NGROK_CLOUD_API_URL").unwrap_or_else(|_| NGROK_CLOUD_API.to_string());
+            let apis = vec![("ngrok-api".to_string(), api.trim().to_string())];
+            Ok(Box::new(NgrokProvider {
+                client: client.clone(),
+                expected_ports: expected_ports(agents, &apis),
+                apis,
+                bots: bots.to_vec(),
+                timeout: Duration::from_secs(env_or("NGROK_API_TIMEOUT_SECS", timeout.as_secs())),
+                precedence,
+                strict_unmapped,
+                api_key: Some(api_key),
+                region: env::var("NGROK_REGION").ok().map(|r| r.trim().to_string()).filter(|r| !r.is_empty()),
+                max_body,
+                headers: headers.to_vec(),
+                statuses: Mutex::default(),
+                unmapped: Mutex::default(),
+            }))
+        }
+        "cloudflared" => {
+            Ok(Box::new(CloudflaredProvider {
+                client: client.clone(),
+                timeout,
+                metrics_urls: env::var("CLOUDFLARED_METRICS_URLS")
+                    .unwrap_or_else(|_| DEFAULT_CLOUDFLARED_METRICS.to_string())
+                    .split(',')
+                    .map(|u| u.trim().trim_end_matches('/').to_string())
+                    .filter(|u| !u.is_empty())
+                    .collect(),
+                bots: bots.to_vec(),
+                precedence,
+                strict_unmapped,
+                max_body,
+                headers: headers.to_vec(),
+                statuses: Mutex::default(),
+                unmapped: Mutex::default(),
+            }))
+        }
+        other => {
+            Err(format!("unknown REBIND_TUNNEL_PROVIDER `{}` (expected ngrok, ngrok-api or cloudflared)", other))
+        }
+    }
+}
+
+/// What to log for a `[tunnel.headers]` value: `<redacted>` when the
+/// header's name suggests a credential.
+pub fn shown_header<'a>(name: &str, value: &'a str) -> &'a str {
+    const SECRET: &[&str] = &["auth", "key", "token", "secret", "password", "cookie", "session", "signature"];
+    let name = name.to_ascii_lowercase();
+    if SECRET.iter().any(|word| name.contains(word)) {
+        "<redacted>"
+    } else {
+        value
+    }
+}
+
+/// Each agent label in `apis` with the ports its `[[agent]]` entry expects.
+fn expected_ports(agents: &[AgentPorts], apis: &[(String, String)]) -> HashMap<String, Vec<u16>> {
+    let mut expected = HashMap::new();
+    for agent in agents {
+        if apis.iter().any(|(label, _)| label == &agent.label) {
+            expected.insert(agent.label.clone(), agent.expected_ports.clone());
+        } else {
+            let labels: Vec<&str> = apis.iter().map(|(label, _)| label.as_str()).collect();
+            log::warn!("[⚠️] [[agent]] `{}` names no ngrok agent (agents: {})", agent.label, labels.join(", "));
+        }
+    }
+    expected
+}
+
+/// Ports of `expected` that none of `tunnels` forwards to.
+pub fn missing_ports(tunnels: &[Tunnel], expected: &[u16]) -> Vec<u16> {
+    expected.iter().copied().filter(|port| !tunnels.iter().any(|t| tunnel_port(&t.addr) == Some(*port))).collect()
+}
+
+enum Fetched<T = Value> {
+    Json(T),
+    /// The agent answered, but not with usable JSON.
+    Unusable,
+    /// The agent's answer was cut short or too long to read whole, so
+    /// what it lists is unknown: unlike [`Fetched::Unusable`], not an
+    /// answer of no tunnels.
+    Incomplete,
+    Unreachable,
+}
+
+/// `headers` go with the request, and `api_key` authenticates against
+/// ngrok's hosted API; answers longer than `max_body` bytes are
+/// [`Fetched::Incomplete`]. A `file://` `api` is read from disk and
+/// parsed just the same; a file that can't be read is
+/// [`Fetched::Unreachable`]. The answer is read straight into `T`, so
+/// fields `T` doesn't name are skipped rather than kept.
+async fn fetch_json<T: DeserializeOwned>(
+    client: &HttpsClient,
+    label: &str,
+    api: &str,
+    timeout: Duration,
+    max_body: usize,
+    headers: &[(String, String)],
+    api_key: Option<&str>,
+) -> Fetched<T> {
+    if let Some(path) = api.strip_prefix("file://") {
+        return match tokio::fs::read(path).await {
+            Ok(body) if body.len() > max_body => {
+                log::warn!("[{}] \u{1f4a5} {} is larger than {} bytes; ignoring it", label, path, max_body);
+                Fetched::Incomplete
+            }
+            Ok(body) => parse_answer(label, &body),
+            Err(err) => {
+                log::warn!("[{}] \u{1f4a5} cannot read {}: {}", label, path, err);
+                Fetched::Unreachable
+            }
+        };
+    }
+    let uri = match api.parse::<hyper::Uri>() {
+        Ok(uri) => uri,
+        Err(err) => {
+            log::error!("Invalid URI {}: {}", api, err);
+            return Fetched::Unreachable;
+        }
+    };
+    let build = || {
+        let req = headers.iter().fold(request_builder(Method::GET), |req, (name, value)| req.header(name, value));
+        match api_key {
+            Some(key) => req.header("Authorization", format!("Bearer {}", key)).header("Ngrok-Version", "2"),
+            None => req,
+        }
+    };
+    match fetch_get(client, uri, timeout, max_body, build).await {
+        Ok((parts, body)) if parts.status.is_success() => parse_answer(label, &body),
+        Ok((parts, _)) => {
+            log::warn!("[{}] request failed: {}", label, parts.status);
+            Fetched::Unusable
+        }
+        Err(BindError::Timeout) => {
+            log::warn!("[{}] \u{1f4a5} no response within {:?}", label, timeout);
+            Fetched::Unreachable
+        }
+        Err(err @ (BindError::BodyRead(_) | BindError::BodyTooLarge(_))) => {
+            log::warn!("[{}] \u{1f4a5} {}; ignoring its partial answer", label, err);
+            Fetched::Incomplete
+        }
+        Err(err) => {
+            log::warn!("[{}] \u{1f4a5} {}", label, err);
+            Fetched::Unreachable
+        }
+    }
+}
+
+/// A successful answer from an agent, or a captured one read from a file.
+fn parse_answer<T: DeserializeOwned>(label: &str, body: &[u8]) -> Fetched<T> {
+    match serde_json::from_slice::<T>(body) {
+        Ok(v) => Fetched::Json(v),
+        Err(err) if err.is_eof() && !body.is_empty() => {
+            log::warn!("[{}] \u{1f4a5} response ends mid-JSON after {} bytes; ignoring it", label, body.len());
+            Fetched::Incomplete
+        }
+        Err(err) => {
+            log::warn!("[{}] unreadable response: {}", label, err);
+            Fetched::Unusable
+        }
+    }
+}
+
+/// Tunnels forwarding to a port no bot uses, as `(port, public_url)`, each
+/// listed once.
+pub fn unmapped_tunnels(tunnels: &[Tunnel], bots: &[BotBinding]) -> Vec<(u16, String)> {
+    let mut unmapped = Vec::new();
+    for tunnel in tunnels {
+        let Some(port) = tunnel_port(&tunnel.addr) else { continue };
+        let entry = (port, tunnel.public_url.clone());
+        if !bots.iter().any(|b| b.port == port) && !unmapped.contains(&entry) {
+            unmapped.push(entry);
+        }
+    }
+    unmapped
+}
+
+/// Shared tail of every provider: fails if no endpoint answered, otherwise
+/// matches `tunnels` and warns when none of them belongs to a bot. Tunnels
+/// on unknown ports are warned about, or fail discovery when
+/// `strict_unmapped`.
+fn discovered(
+    tunnels: &[Tunnel],
+    bots: &[BotBinding],
+    reached: usize,
+    endpoints: &[&str],
+    precedence: Precedence,
+    strict_unmapped: bool,
+) -> Result<HashMap<String, String>, DiscoveryError> {
+    if reached == 0 && !endpoints.is_empty() {
+        return Err(DiscoveryError::NoAgentReachable(endpoints.iter().map(|e| e.to_string()).collect()));
+    }
+    let unmapped = unmapped_tunnels(tunnels, bots);
+    if strict_unmapped && !unmapped.is_empty() {
+        return Err(DiscoveryError::Unmapped(unmapped));
+    }
+    for (port, url) in &unmapped {
+        log::warn!(
+            "[⚠️] Tunnel {} forwards to port {}, which no bot uses; add a [[bot]] entry with port = {} to bind it",
+            url,
+            port,
+            port
+        );
+    }
+    let urls = match_tunnels(tunnels, bots, precedence);
+    if urls.is_empty() {
+        log::warn!("[⚠️] Tunnel agent reachable, but no tunnel forwards to a bot port ({} tunnels seen)", tunnels.len());
+    }
+    Ok(urls)
+}
+
+pub struct NgrokProvider {
+    pub client: HttpsClient,
+    /// `(label, api_url)` pairs in precedence order, queried concurrently.
+    pub apis: Vec<(String, String)>,
+    pub bots: Vec<BotBinding>,
+    pub timeout: Duration,
+    pub precedence: Precedence,
+    pub strict_unmapped: bool,
+    /// Set for ngrok's hosted API (`ngrok-api`), sent as a bearer token.
+    pub api_key: Option<String>,
+    /// Only tunnels the hosted API reports in this region (`NGROK_REGION`).
+    pub region: Option<String>,
+    /// Agent label to the ports it should have tunnels for, from the
+    /// `[[agent]]` entries of the bot table.
+    pub expected_ports: HashMap<String, Vec<u16>>,
+    /// Longest answer read from an agent, in bytes.
+    pub max_body: usize,
+    /// Sent with every request to an agent, as `(name, value)`.
+    pub headers: Vec<(String, String)>,
+    /// How each agent answered the last discovery; start it empty.
+    pub statuses: Mutex<Vec<AgentStatus>>,
+    /// The last discovery's [`TunnelProvider::unmapped`]; start it empty.
+    pub unmapped: Mutex<Vec<(u16, String)>>,
+}
+
+impl TunnelProvider for NgrokProvider {
+    fn name(&self) -> &'static str {
+        if self.api_key.is_some() {
+            "ngrok-api"
+        } else {
+            "ngrok"
+        }
+    }
+
+    fn public_urls(&self) -> BoxFuture<'_, Result<HashMap<String, String>, DiscoveryError>> {
+        Box::pin(self.get_public_urls())
+    }
+
+    fn agents(&self) -> Vec<AgentStatus> {
+        self.statuses.lock().unwrap().clone()
+    }
+
+    fn unmapped(&self) -> Vec<(u16, String)> {
+        self.unmapped.lock().unwrap().clone()
+    }
+}
+
+impl NgrokProvider {
+    /// Queries every agent at once, so a slow or unreachable one only costs
+    /// its own timeout. Results are merged in `apis` order, whichever agent
+    /// answers first, so precedence stays deterministic. An agent that
+    /// answered without a tunnel for one of its expected ports is warned
+    /// about.
+    async fn get_public_urls(&self) -> Result<HashMap<String, String>, DiscoveryError> {
+        self.statuses.lock().unwrap().clear();
+        self.unmapped.lock().unwrap().clear();
+        let queries = self.apis.iter().map(|(label, api)| self.query_agent(label, api));
+        let (mut tunnels, mut reached, mut statuses) = (Vec::new(), 0, Vec::new());
+        let results = futures_util::future::join_all(queries).await;
+        for ((label, _), (found, answered, state)) in self.apis.iter().zip(results) {
+            if let Some(expected) = self.expected_ports.get(label).filter(|_| answered) {
+                for port in missing_ports(&found, expected) {
+                    let bots: Vec<&str> = self.bots.iter().filter(|b| b.port == port).map(|b| b.name.as_str()).collect();
+                    let serving = if bots.is_empty() { String::new() } else { format!(" ({})", bots.join(", ")) };
+                    log::warn!("[⚠️] [{}] no tunnel for port {}{}, which this agent is expected to serve", label, port, serving);
+                }
+            }
+            tunnels.extend(found.iter().cloned());
+            reached += usize::from(answered);
+            statuses.push(AgentStatus { agent: label.clone(), state, tunnels: found });
+        }
+        *self.statuses.lock().unwrap() = statuses;
+        *self.unmapped.lock().unwrap() = unmapped_tunnels(&tunnels, &self.bots);
+        let endpoints: Vec<&str> = self.apis.iter().map(|(_, api)| api.as_str()).collect();
+        discovered(&tunnels, &self.bots, reached, &endpoints, self.precedence, self.strict_unmapped)
+    }
+
+    /// One agent's tunnels across all its pages, whether it answered, and
+    /// how its last page went.
+    async fn query_agent(&self, label: &str, api: &str) -> (Vec<Tunnel>, bool, AgentState) {
+        let mut tunnels = Vec::new();
+        let api = self.reach(label, api).await;
+        let mut page = api.clone();
+        let mut seen = Vec::new();
+        loop {
+            let fetched: Fetched<NgrokPage> =
+                fetch_json(&self.client, label, &page, self.timeout, self.max_body, &self.headers, self.api_key.as_deref()).await;
+            let state = AgentState::from(&fetched);
+            match fetched {
+                Fetched::Json(answer) => {
+                    let next = next_page(&api, &answer);
+                    let found = answer.tunnels(label, self.region.as_deref());
+                    log::debug!("[{}] {} tunnels: {:?}", label, found.len(), found);
+                    tunnels.extend(found);
+                    seen.push(page.clone());
+                    match next {
+                        Some(next) if seen.len() < MAX_PAGES && !seen.contains(&next) => page = next,
+                        _ => return (tunnels, true, state),
+                    }
+                }
+                Fetched::Unusable => return (tunnels, true, state),
+                Fetched::Incomplete | Fetched::Unreachable => return (tunnels, !seen.is_empty(), state),
+            }
+        }
+    }
+
+    /// The first of `api`'s [`loopback_variants`] something listens on,
+    /// or `api` itself when there is only one or none of them does, so the
+    /// usual error is reported for it.
+    async fn reach(&self, label: &str, api: &str) -> String {
+        let variants = loopback_variants(api);
+        if variants.len() > 1 {
+            for variant in variants {
+                if listening(&variant, self.timeout).await {
+                    log::debug!("[{}] using {} for {}", label, variant, api);
+                    return variant;
+                }
+            }
+        }
+        api.to_string()
+    }
+}
+
+/// Whether a TCP connection to `url`'s host and port succeeds in time.
+async fn listening(url: &str, timeout: Duration) -> bool {
+    let Ok(uri) = url.parse::<hyper::Uri>() else { return false };
+    let Some(host) = uri.host() else { return false };
+    let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    matches!(tokio::time::timeout(timeout, tokio::net::TcpStream::connect((host, port))).await, Ok(Ok(_)))
+}
+
+/// `api` with a `localhost` host replaced by `127.0.0.1` and then `[::1]`,
+/// since an agent listening on one of them refuses connections on the
+/// other, and `localhost` may resolve to either first. Any other URL is
+/// returned as is.
+pub fn loopback_variants(api: &str) -> Vec<String> {
+    let Some((scheme, rest)) = api.split_once("://") else { return vec![api.to_string()] };
+    let host_end = rest.find([':', '/', '?', '#']).unwrap_or(rest.len());
+    if !rest[..host_end].eq_ignore_ascii_case("localhost") {
+        return vec![api.to_string()];
+    }
+    ["127.0.0.1", "[::1]"].iter().map(|ip| format!("{}://{}{}", scheme, ip, &rest[host_end..])).collect()
+}
+
+/// Upper bound on `next_page_uri` hops per agent, in case an API keeps
+/// handing out new pages.
+const MAX_PAGES: usize = 50;
+
+/// The page after `answer`, resolving a relative `next_page_uri` against
+/// the scheme and host of `api`.
+fn next_page(api: &str, answer: &NgrokPage) -> Option<String> {
+    let next = answer.next_page_uri.0.as_deref().filter(|n| !n.is_empty())?;
+    if next.starts_with("http://") || next.starts_with("https://") {
+        return Some(next.to_string());
+    }
+    let uri = api.parse::<hyper::Uri>().ok()?;
+    Some(format!("{}://{}/{}", uri.scheme_str()?, uri.authority()?, next.trim_start_matches('/')))
+}
+
+/// The parts of an ngrok tunnels answer that discovery reads. It is
+/// parsed straight from the body, skipping every other field instead of
+/// building a [`Value`] tree of the whole answer, which on a host with
+/// hundreds of tunnels is mostly request metrics. Fields of an unexpected
+/// type read as missing, as does an entry that isn't an object.
+#[derive(Debug, Default, Deserialize)]
+pub struct NgrokPage {
+    #[serde(default)]
+    tunnels: Lenient<Vec<Lenient<NgrokEntry>>>,
+    #[serde(default)]
+    next_page_uri: Text,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct NgrokEntry {
+    #[serde(default)]
+    public_url: Text,
+    #[serde(default)]
+    proto: Text,
+    #[serde(default)]
+    config: Lenient<NgrokEntryConfig>,
+    #[serde(default)]
+    forwards_to: Text,
+    #[serde(default)]
+    addr: Text,
+    #[serde(default)]
+    region: Text,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct NgrokEntryConfig {
+    #[serde(default)]
+    addr: Text,
+}
+
+/// A string, or `None` for any other JSON value, which is skipped unread.
+#[derive(Debug, Default)]
+struct Text(Option<String>);
+
+impl<'de> Deserialize<'de> for Text {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(Skipping(|s: &str| Text(Some(s.to_string())), || Text(None)))
+    }
+}
+
+/// `T` read from a JSON object or array, or `T::default()` for anything
+/// else.
+#[derive(Debug, Default)]
+struct Lenient<T>(T);
+
+impl<'de, T: Deserialize<'de> + Default> Deserialize<'de> for Lenient<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Nested<T>(std::marker::PhantomData<T>);
+        impl<'de, T: Deserialize<'de> + Default> Visitor<'de> for Nested<T> {
+            type Value = Lenient<T>;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("any JSON value")
+            }
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                T::deserialize(de::value::MapAccessDeserializer::new(map)).map(Lenient)
+            }
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                T::deserialize(de::value::SeqAccessDeserializer::new(seq)).map(Lenient)
+            }
+            fn visit_str<E>(self, _: &str) -> Result<Self::Value, E> {
+                Ok(Lenient(T::default()))
+            }
+            fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> {
+                Ok(Lenient(T::default()))
+            }
+            fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> {
+                Ok(Lenient(T::default()))
+            }
+            fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> {
+                Ok(Lenient(T::default()))
+            }
+            fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> {
+                Ok(Lenient(T::default()))
+            }
+            fn visit_unit<E>(self) -> Result<Self::Value, E> {
+                Ok(Lenient(T::default()))
+            }
+        }
+        deserializer.deserialize_any(Nested(std::marker::PhantomData))
+    }
+}
+
+/// Visits any JSON value, keeping a string through `keep` and skipping
+/// everything else, nested objects and arrays included, as `skip()`.
+struct Skipping<K, S>(K, S);
+
+impl<'de, V, K: FnOnce(&str) -> V, S: FnOnce() -> V> Visitor<'de> for Skipping<K, S> {
+    type Value = V;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any JSON value")
+    }
+    fn visit_str<E>(self, s: &str) -> Result<V, E> {
+        Ok((self.0)(s))
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<V, A::Error> {
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+        Ok((self.1)())
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<V, A::Error> {
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok((self.1)())
+    }
+    fn visit_bool<E>(self, _: bool) -> Result<V, E> {
+        Ok((self.1)())
+    }
+    fn visit_i64<E>(self, _: i64) -> Result<V, E> {
+        Ok((self.1)())
+    }
+    fn visit_u64<E>(self, _: u64) -> Result<V, E> {
+        Ok((self.1)())
+    }
+    fn visit_f64<E>(self, _: f64) -> Result<V, E> {
+        Ok((self.1)())
+    }
+    fn visit_unit<E>(self) -> Result<V, E> {
+        Ok((self.1)())
+    }
+}
+
+impl NgrokPage {
+    /// Every entry that has both a `public_url` and a local address,
+    /// attributed to `agent`, and with `region` only those the hosted API
+    /// places there. The agent API puts the address in `config.addr`; the
+    /// ngrok cloud API and some newer agents use `forwards_to` or a
+    /// top-level `addr`. `tcp` tunnels can't serve a webhook and are left
+    /// out, so they never count as unmapped either.
+    pub fn tunnels(self, agent: &str, region: Option<&str>) -> Vec<Tunnel> {
+        let mut tunnels = Vec::new();
+        for Lenient(t) in self.tunnels.0 {
+            let proto = t.proto.0.map(|p| p.to_ascii_lowercase());
+            if proto.as_deref() == Some("tcp") || region.is_some_and(|region| t.region.0.as_deref() != Some(region)) {
+                continue;
+            }
+            let addr = t.config.0.addr.0.or(t.forwards_to.0).or(t.addr.0);
+            if let (Some(public_url), Some(addr)) = (t.public_url.0, addr) {
+                tunnels.push(Tunnel { public_url, addr, agent: agent.to_string(), proto });
+            }
+        }
+        tunnels
+    }
+}
+
+/// [`NgrokPage::tunnels`] of an answer already parsed into a [`Value`].
+pub fn ngrok_tunnels(agent: &str, v: &Value) -> Vec<Tunnel> {
+    NgrokPage::deserialize(v).unwrap_or_default().tunnels(agent, None)
+}
+
+/// Maps a raw ngrok `/api/tunnels` body onto bot names. Bodies that aren't
+/// JSON yield no URLs.
+pub fn parse_tunnels(body: &[u8], bots: &[BotBinding]) -> HashMap<String, String> {
+    match serde_json::from_slice::<NgrokPage>(body) {
+        Ok(page) => match_tunnels(&page.tunnels("ngrok", None), bots, Precedence::First),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Reads ingress rules from each cloudflared metrics server's `/config`
+/// endpoint; every rule with a hostname becomes an `https://` tunnel.
+pub struct CloudflaredProvider {
+    pub client: HttpsClient,
+    pub timeout: Duration,
+    pub metrics_urls: Vec<String>,
+    pub bots: Vec<BotBinding>,
+    pub precedence: Precedence,
+    pub strict_unmapped: bool,
+    /// Longest answer read from a metrics server, in bytes.
+    pub max_body: usize,
+    /// Sent with every request to a metrics server, as `(name, value)`.
+    pub headers: Vec<(String, String)>,
+    /// How each metrics server answered the last discovery; start it empty.
+    pub statuses: Mutex<Vec<AgentStatus>>,
+    /// The last discovery's [`TunnelProvider::unmapped`]; start it empty.
+    pub unmapped: Mutex<Vec<(u16, String)>>,
+}
+
+/// The tunnels in the ingress rules of cloudflared's `/config` answer
+/// from `metrics`; rules without a hostname serve no public URL.
+fn ingress_tunnels(metrics: &str, answer: &Value) -> Vec<Tunnel> {
+    let ingress = answer.get("config").and_then(|c| c.get("ingress")).and_then(|i| i.as_array());
+    ingress
+        .into_iter()
+        .flatten()
+        .filter_map(|rule| {
+            let (hostname, service) = (rule.get("hostname")?.as_str()?, rule.get("service")?.as_str()?);
+            Some(Tunnel {
+                public_url: format!("https://{}", hostname),
+                addr: service.to_string(),
+                agent: metrics.to_string(),
+                proto: Some("https".to_string()),
+            })
+        })
+        .collect()
+}
+
+impl TunnelProvider for CloudflaredProvider {
+    fn name(&self) -> &'static str {
+        "cloudflared"
+    }
+
+    fn public_urls(&self) -> BoxFuture<'_, Result<HashMap<String, String>, DiscoveryError>> {
+        Box::pin(async move {
+            self.statuses.lock().unwrap().clear();
+            self.unmapped.lock().unwrap().clear();
+            let (mut tunnels, mut reached, mut statuses) = (Vec::new(), 0, Vec::new());
+            for metrics in &self.metrics_urls {
+                let api = format!("{}/config", metrics);
+                let fetched: Fetched = fetch_json(&self.client, metrics, &api, self.timeout, self.max_body, &self.headers, None).await;
+                let state = AgentState::from(&fetched);
+                let found = match fetched {
+                    Fetched::Json(v) => ingress_tunnels(metrics, &v),
+                    Fetched::Unusable => Vec::new(),
+                    Fetched::Incomplete | Fetched::Unreachable => {
+                        statuses.push(AgentStatus { agent: metrics.clone(), state, tunnels: Vec::new() });
+                        continue;
+                    }
+                };
+                reached += 1;
+                tunnels.extend(found.iter().cloned());
+                statuses.push(AgentStatus { agent: metrics.clone(), state, tunnels: found });
+            }
+            log::debug!("cloudflared ingress: {:?}", tunnels);
+            *self.statuses.lock().unwrap() = statuses;
+            *self.unmapped.lock().unwrap() = unmapped_tunnels(&tunnels, &self.bots);
+            let endpoints: Vec<&str> = self.metrics_urls.iter().map(String::as_str).collect();
+            discovered(&tunnels, &self.bots, reached, &endpoints, self.precedence, self.strict_unmapped)
+        })
+    }
+
+    fn agents(&self) -> Vec<AgentStatus> {
+        self.statuses.lock().unwrap().clone()
+    }
+
+    fn unmapped(&self) -> Vec<(u16, String)> {
+        self.unmapped.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::default_bots;
+
+    fn ngrok_body(tunnels: &[(&str, &str)]) -> Vec<u8> {
+        let tunnels: Vec<Value> = tunnels
+            .iter()
+            .map(|(public_url, addr)| {
+                serde_json::json!({
+                    "name": "command_line",
+                    "uri": "/api/tunnels/command_line",
+                    "public_url": public_url,
+                    "proto": public_url.split_once("://").map_or("https", |(scheme, _)| scheme),
+                    "config": { "addr": addr, "inspect": true },
+                })
+            })
+            .collect();
+        serde_json::to_vec(&serde_json::json!({ "tunnels": tunnels, "uri": "/api/tunnels" })).unwrap()
+    }
+
+    #[test]
+    fn matches_every_known_port() {
+        let body = ngrok_body(&[
+            ("https://a.ngrok.io", "http://localhost:9977"),
+            ("https://b.ngrok.io", "http://localhost:9988"),
+            ("https://c.ngrok.io", "localhost:9966"),
+        ]);
+        let urls = parse_tunnels(&body, &default_bots(false));
+        assert_eq!(urls.len(), 3);
+        assert_eq!(urls["gpt4o"], "https://a.ngrok.io");
+        assert_eq!(urls["mistral"], "https://b.ngrok.io");
+        assert_eq!(urls["deepseek"], "https://c.ngrok.io");
+    }
+
+    #[test]
+    fn ignores_unknown_ports() {
+        let body = ngrok_body(&[
+            ("https://a.ngrok.io", "http://localhost:9977"),
+            ("https://x.ngrok.io", "http://localhost:1234"),
+        ]);
+        let urls = parse_tunnels(&body, &default_bots(false));
+        assert_eq!(urls.len(), 1);
+        assert_eq!(urls["gpt4o"], "https://a.ngrok.io");
+
+        let tunnels = ngrok_tunnels("main", &serde_json::from_slice(&body).unwrap());
+        assert_eq!(unmapped_tunnels(&tunnels, &default_bots(false)), vec![(1234, "https://x.ngrok.io".to_string())]);
+    }
+
+    #[test]
+    fn bots_on_a_shared_port_get_the_same_url() {
+        let mut bots = default_bots(false);
+        bots[1].port = bots[0].port;
+        let urls = parse_tunnels(&ngrok_body(&[("https://a.ngrok.io", "http://localhost:9977")]), &bots);
+        assert_eq!(urls["gpt4o"], "https://a.ngrok.io");
+        assert_eq!(urls["mistral"], "https://a.ngrok.io");
+    }
+
+    #[test]
+    fn reads_the_port_from_every_addr_form() {
+        assert_eq!(tunnel_port("9977"), Some(9977));
+        assert_eq!(tunnel_port("localhost:9977"), Some(9977));
+        assert_eq!(tunnel_port("127.0.0.1:9977"), Some(9977));
+        assert_eq!(tunnel_port("http://localhost:9977/"), Some(9977));
+        assert_eq!(tunnel_port("https://user@localhost:9977/api"), Some(9977));
+        assert_eq!(tunnel_port("[::1]:9977"), Some(9977));
+        assert_eq!(tunnel_port("http://[fe80::1%25eth0]:9977"), Some(9977));
+        assert_eq!(tunnel_port("::1"), None);
+        assert_eq!(tunnel_port("[::1]"), None);
+        assert_eq!(tunnel_port("http://localhost"), None);
+        assert_eq!(tunnel_port("localhost:99999"), None);
+    }
+
+    #[test]
+    fn skips_addr_without_port() {
+        let body = ngrok_body(&[("https://a.ngrok.io", "http://localhost")]);
+        assert!(parse_tunnels(&body, &default_bots(false)).is_empty());
+    }
+
+    #[test]
+    fn empty_tunnel_list() {
+        let body = ngrok_body(&[]);
+        assert!(parse_tunnels(&body, &default_bots(false)).is_empty());
+        assert!(parse_tunnels(b"not json", &default_bots(false)).is_empty());
+    }
+
+    #[test]
+    fn public_urls_are_normalized_before_matching() {
+        assert_eq!(normalize_public_url("HTTPS://Abc.NGROK.io/").unwrap(), "https://abc.ngrok.io");
+        assert_eq!(normalize_public_url("https://a.ngrok.io/Base//").unwrap(), "https://a.ngrok.io/Base");
+        assert_eq!(normalize_public_url("https://a.ngrok.io/?x=1").unwrap(), "https://a.ngrok.io/?x=1");
+        assert!(normalize_public_url("a.ngrok.io").is_err());
+        assert!(normalize_public_url("https://").is_err());
+        assert!(normalize_public_url("https://bad host.io").is_err());
+
+        let body = ngrok_body(&[
+            ("HTTPS://Trailing.ngrok.io///", "http://localhost:9977"),
+            ("https://bad host.ngrok.io", "http://localhost:9988"),
+        ]);
+        let urls = parse_tunnels(&body, &default_bots(false));
+        assert_eq!(urls["gpt4o"], "https://trailing.ngrok.io");
+        assert!(!urls.contains_key("mistral"));
+    }
+
+    #[test]
+    fn localhost_agents_are_tried_on_both_loopbacks() {
+        assert_eq!(
+            loopback_variants("http://localhost:4040/api/tunnels"),
+            ["http://127.0.0.1:4040/api/tunnels", "http://[::1]:4040/api/tunnels"]
+        );
+        assert_eq!(loopback_variants("http://LOCALHOST/api"), ["http://127.0.0.1/api", "http://[::1]/api"]);
+        assert_eq!(loopback_variants("http://localhost.example:4040/"), ["http://localhost.example:4040/"]);
+        assert_eq!(loopback_variants("file:///tmp/tunnels.json"), ["file:///tmp/tunnels.json"]);
+    }
+
+    #[test]
+    fn first_tunnel_for_a_port_wins() {
+        let body = ngrok_body(&[
+            ("https://first.ngrok.io", "http://localhost:9977"),
+            ("https://second.ngrok.io", "http://localhost:9977/"),
+        ]);
+        assert_eq!(parse_tunnels(&body, &default_bots(false))["gpt4o"], "https://first.ngrok.io");
+    }
+
+    #[test]
+    fn only_https_tunnels_are_bound() {
+        // ngrok's `bind_tls = "both"` lists the http tunnel first.
+        let body = ngrok_body(&[
+            ("http://a.ngrok.io", "http://localhost:9977"),
+            ("https://a.ngrok.io", "http://localhost:9977"),
+            ("http://b.ngrok.io", "http://localhost:9988"),
+        ]);
+        let urls = parse_tunnels(&body, &default_bots(false));
+        assert_eq!(urls["gpt4o"], "https://a.ngrok.io");
+        assert!(!urls.contains_key("mistral"));
+    }
+
+    #[test]
+    fn proto_decides_and_tcp_tunnels_are_left_out() {
+        let v = serde_json::json!({ "tunnels": [
+            { "public_url": "tcp://0.tcp.ngrok.io:12345", "proto": "tcp", "config": { "addr": "localhost:9977" } },
+            { "public_url": "http://a.ngrok.io", "proto": "http", "config": { "addr": "localhost:9977" } },
+            { "public_url": "https://a.ngrok.io", "proto": "https", "config": { "addr": "localhost:9977" } },
+            { "public_url": "tcp://0.tcp.ngrok.io:23456", "proto": "TCP", "config": { "addr": "localhost:9988" } },
+            { "public_url": "tcp://0.tcp.ngrok.io:34567", "proto": "tcp", "config": { "addr": "localhost:1234" } },
+        ]});
+        let tunnels = ngrok_tunnels("main", &v);
+        assert_eq!(tunnels.len(), 2);
+        let bots = default_bots(false);
+        let urls = match_tunnels(&tunnels, &bots, Precedence::First);
+        assert_eq!(urls["gpt4o"], "https://a.ngrok.io");
+        assert!(!urls.contains_key("mistral"));
+        assert!(unmapped_tunnels(&tunnels, &bots).is_empty());
+        assert!(discovered(&tunnels, &bots, 1, &["main"], Precedence::First, true).is_ok());
+    }
+
+    #[test]
+    fn expected_ports_without_a_tunnel_are_missing() {
+        let body = ngrok_body(&[("https://a.ngrok.io", "http://localhost:9977"), ("http://b.ngrok.io", "localhost:9966")]);
+        let tunnels = ngrok_tunnels("main", &serde_json::from_slice(&body).unwrap());
+        assert_eq!(missing_ports(&tunnels, &[9977, 9988, 9966]), [9988]);
+
+        let agents = [
+            AgentPorts { label: "main".to_string(), expected_ports: vec![9977] },
+            AgentPorts { label: "spare".to_string(), expected_ports: vec![9988] },
+        ];
+        let apis = vec![("main".to_string(), "http://localhost:4040/api/tunnels".to_string())];
+        assert_eq!(expected_ports(&agents, &apis), HashMap::from([("main".to_string(), vec![9977])]));
+    }
+
+    #[test]
+    fn accepts_forwards_to_and_top_level_addr() {
+        let body = serde_json::json!({
+            "tunnels": [
+                { "public_url": "https://a.ngrok.app", "forwards_to": "http://localhost:9977" },
+                { "public_url": "https://b.ngrok.app", "addr": "localhost:9988" },
+                { "public_url": "https://c.ngrok.app", "config": { "addr": "localhost:9966" }, "forwards_to": "localhost:1" },
+            ],
+        });
+        let urls = parse_tunnels(&serde_json::to_vec(&body).unwrap(), &default_bots(false));
+        assert_eq!(urls["gpt4o"], "https://a.ngrok.app");
+        assert_eq!(urls["mistral"], "https://b.ngrok.app");
+        assert_eq!(urls["deepseek"], "https://c.ngrok.app");
+    }
+
+    #[test]
+    fn odd_entries_read_as_missing_without_spoiling_the_answer() {
+        let body = serde_json::json!({
+            "tunnels": [
+                "not an entry",
+                { "public_url": 42, "config": { "addr": "localhost:9988" } },
+                { "public_url": "https://c.ngrok.io", "config": "localhost:1", "addr": "localhost:9966" },
+                { "public_url": "https://a.ngrok.io", "proto": ["https"], "config": { "addr": "localhost:9977" },
+                  "metrics": { "http": { "count": 3, "p99": 1.5e6 } } },
+            ],
+            "next_page_uri": false,
+        });
+        let page: NgrokPage = serde_json::from_slice(&serde_json::to_vec(&body).unwrap()).unwrap();
+        assert_eq!(next_page("http://localhost:4040/api/tunnels", &page), None);
+        let urls = match_tunnels(&page.tunnels("main", None), &default_bots(false), Precedence::First);
+        assert_eq!(urls.len(), 2);
+        assert_eq!(urls["deepseek"], "https://c.ngrok.io");
+        assert_eq!(urls["gpt4o"], "https://a.ngrok.io");
+        assert!(ngrok_tunnels("main", &serde_json::json!({ "tunnels": "none" })).is_empty());
+    }
+
+    #[test]
+    fn hosted_api_tunnels_filter_by_region() {
+        let body = serde_json::json!({
+            "tunnels": [
+                { "public_url": "https://eu.ngrok.app", "region": "eu", "forwards_to": "http://localhost:9977" },
+                { "public_url": "https://us.ngrok.app", "region": "us", "forwards_to": "localhost:9988" },
+            ],
+            "next_page_uri": null,
+        });
+        let tunnels = NgrokPage::deserialize(&body).unwrap().tunnels("ngrok-api", Some("eu"));
+        assert_eq!(tunnels.len(), 1);
+        assert_eq!(tunnels[0].public_url, "https://eu.ngrok.app");
+    }
+
+    #[test]
+    fn credential_headers_are_redacted_in_logs() {
+        assert_eq!(shown_header("Authorization", "Bearer abc"), "<redacted>");
+        assert_eq!(shown_header("X-Api-Key", "k"), "<redacted>");
+        assert_eq!(shown_header("Proxy-Authorization", "Basic xyz"), "<redacted>");
+        assert_eq!(shown_header("X-Forwarded-For", "10.0.0.1"), "10.0.0.1");
+    }
+
+    #[test]
+    fn next_page_resolves_against_the_agent() {
+        let api = "http://localhost:4040/api/tunnels";
+        let page = |v: Value| NgrokPage::deserialize(&v).unwrap();
+        let next = |next: &str| page(serde_json::json!({ "tunnels": [], "next_page_uri": next }));
+        assert_eq!(next_page(api, &next("/api/tunnels?before_id=t2")).unwrap(), "http://localhost:4040/api/tunnels?before_id=t2");
+        assert_eq!(next_page(api, &next("https://api.ngrok.com/tunnels?p=2")).unwrap(), "https://api.ngrok.com/tunnels?p=2");
+        assert_eq!(next_page(api, &next("")), None);
+        assert_eq!(next_page(api, &page(serde_json::json!({ "tunnels": [], "next_page_uri": null }))), None);
+    }
+
+    #[test]
+    fn precedence_picks_between_agents() {
+        let tunnel = |agent: &str, public_url: &str| Tunnel {
+            public_url: public_url.to_string(),
+            addr: "http://localhost:9977".to_string(),
+            agent: agent.to_string(),
+            proto: None,
+        };
+        let tunnels = [tunnel("main", "https://main.ngrok.io"), tunnel("alt", "https://alt.ngrok.io")];
+        let bots = default_bots(false);
+        assert_eq!(match_tunnels(&tunnels, &bots, Precedence::First)["gpt4o"], "https://main.ngrok.io");
+        assert_eq!(match_tunnels(&tunnels, &bots, Precedence::Last)["gpt4o"], "https://alt.ngrok.io");
+    }
+
+    /// Answers with `urls`, or fails with no agent reachable when `None`.
+    struct Canned(&'static str, Option<&'static [(&'static str, &'static str)]>);
+
+    impl TunnelProvider for Canned {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn public_urls(&self) -> BoxFuture<'_, Result<HashMap<String, String>, DiscoveryError>> {
+            Box::pin(async move {
+                let urls = self.1.ok_or_else(|| DiscoveryError::NoAgentReachable(vec![self.0.to_string()]))?;
+                Ok(urls.iter().map(|(bot, url)| (bot.to_string(), url.to_string())).collect())
+            })
+        }
+    }
+
+    fn fallback(providers: Vec<Canned>) -> FallbackProvider {
+        let providers = providers.into_iter().map(|p| Box::new(p) as Box<dyn TunnelProvider>).collect();
+        FallbackProvider { providers, used: Mutex::new(None), agents: Mutex::default(), unmapped: Mutex::default() }
+    }
+
+    #[tokio::test]
+    async fn fallback_uses_the_first_provider_with_tunnels() {
+        let chain = fallback(vec![
+            Canned("ngrok", None),
+            Canned("ngrok-api", Some(&[])),
+            Canned("cloudflared", Some(&[("gpt4o", "https://a.trycloudflare.com")])),
+        ]);
+        assert_eq!(chain.used(), "fallback");
+        assert_eq!(chain.public_urls().await.unwrap()["gpt4o"], "https://a.trycloudflare.com");
+        assert_eq!(chain.used(), "cloudflared");
+
+        let empty = fallback(vec![Canned("ngrok", None), Canned("cloudflared", Some(&[]))]);
+        let err = empty.public_urls().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "no tunnel provider found a tunnel (ngrok: no tunnel agent reachable on any of [ngrok]; cloudflared: no tunnel for any bot)"
+        );
+    }
+}
@@ -0,0 +1,259 @@
+//! One consolidated `getWebhookInfo` pass over every bot, correlating the
+//! webhook we set with the one the platform reports. Used after a one-shot
+//! rebind and by `--verify-only`.
+
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::config::BotBinding;
+use crate::{audit, BindError, Outcome, RebindReport, Targets, WebhookInfo};
+
+/// What each bot's webhook should look like.
+#[derive(Debug, Clone, Default)]
+pub struct Expected {
+    /// Webhook URL each bot was set to, empty for a polling bot; bots
+    /// missing here are only checked for delivery problems.
+    pub set: HashMap<String, String>,
+    /// Webhook URL each bot had before, which Telegram may keep reporting
+    /// for a moment after the change.
+    pub previous: HashMap<String, String>,
+    /// Unix time of the binds. Telegram keeps the last delivery error
+    /// forever, so errors dated earlier are ignored.
+    pub since: Option<i64>,
+    /// Public URLs of the tunnels discovered this run; a live webhook under
+    /// none of them is orphaned. `None` skips the check.
+    pub tunnels: Option<Vec<String>>,
+}
+
+impl Expected {
+    /// Expects every bot bound, left unchanged or polling in `report`, with `previous`
+    /// taken from the bindings saved before the run and the tunnels it
+    /// discovered.
+    pub fn from_report(report: &RebindReport, previous: HashMap<String, String>, since: i64) -> Self {
+        let set = report
+            .outcomes
+            .iter()
+            .filter_map(|o| match &o.outcome {
+                Outcome::Bound { webhook_url, .. } | Outcome::Unchanged { webhook_url } => {
+                    Some((o.bot.clone(), webhook_url.clone()))
+                }
+                Outcome::Polling { .. } => Some((o.bot.clone(), String::new())),
+                _ => None,
+            })
+            .collect();
+        let tunnels = Some(report.tunnels.values().cloned().collect());
+        Expected { set, previous, since: Some(since), tunnels }
+    }
+}
+
+/// Whether the live webhook `url` lies under none of `tunnels`: a binding
+/// left behind by a tunnel that no longer exists.
+pub fn orphaned(url: &str, tunnels: &[String]) -> bool {
+    !url.is_empty()
+        && !tunnels.iter().any(|tunnel| {
+            let tunnel = tunnel.trim_end_matches('/');
+            url.strip_prefix(tunnel).is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?']))
+        })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The live webhook is the one we set, or nothing was expected.
+    Ok,
+    /// Telegram still reports the replaced webhook (or none); usually
+    /// transient.
+    NotYetUpdated,
+    /// Telegram reports a webhook we didn't set.
+    Mismatch,
+    /// `getWebhookInfo` itself failed.
+    Failed,
+}
+
+impl Verdict {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Ok => "ok",
+            Verdict::NotYetUpdated => "pending",
+            Verdict::Mismatch => "mismatch",
+            Verdict::Failed => "error",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BotVerification {
+    pub bot: String,
+    pub expected: Option<String>,
+    pub live: Result<WebhookInfo, BindError>,
+    pub verdict: Verdict,
+    /// Delivery errors since [`Expected::since`], a backlog and orphaned
+    /// webhooks; a bot with problems is flagged even when its URL matches.
+    pub problems: Vec<String>,
+    /// Updates queued for the bot, when more than the threshold passed to
+    /// [`verify_all`]: its server isn't keeping up, or was down.
+    pub backlog: Option<u64>,
+}
+
+impl BotVerification {
+    pub fn flagged(&self) -> bool {
+        self.verdict != Verdict::Ok || !self.problems.is_empty()
+    }
+}
+
+/// Per-bot verification, in the order the bots were given.
+#[derive(Debug, Default)]
+pub struct VerificationReport {
+    pub bots: Vec<BotVerification>,
+}
+
+impl VerificationReport {
+    pub fn ok(&self) -> bool {
+        self.bots.iter().all(|b| !b.flagged())
+    }
+
+    pub fn count(&self, verdict: Verdict) -> usize {
+        self.bots.iter().filter(|b| b.verdict == verdict).count()
+    }
+
+    /// Bots with a backlog and how many updates each has queued.
+    pub fn backlogged(&self) -> Vec<(&str, u64)> {
+        self.bots.iter().filter_map(|b| Some((b.bot.as_str(), b.backlog?))).collect()
+    }
+
+    /// One entry per bot: its verdict, what was set and what is live.
+    pub fn to_json(&self) -> Value {
+        let rows: Vec<_> = self
+            .bots
+            .iter()
+            .map(|b| {
+                let mut entry = json!({
+                    "bot": b.bot,
+                    "verdict": b.verdict.as_str(),
+                    "expected": b.expected,
+                    "flagged": b.flagged(),
+                    "backlog": b.backlog.is_some(),
+                    "problems": b.problems,
+                });
+                match &b.live {
+                    Ok(info) => {
+                        entry["url"] = json!(info.url);
+                        entry["pending_update_count"] = json!(info.pending_update_count);
+                        entry["last_error_message"] = json!(info.last_error_message);
+                        entry["last_error_date"] = json!(info.last_error_date);
+                    }
+                    Err(err) => entry["error"] = json!(err.to_string()),
+                }
+                entry
+            })
+            .collect();
+        Value::Array(rows)
+    }
+}
+
+/// Fetches every bot's live webhook concurrently and compares it with
+/// `expected`. More than `pending_warn` queued updates is a backlog.
+pub async fn verify_all(
+    targets: Targets<'_>,
+    bots: &[BotBinding],
+    expected: &Expected,
+    pending_warn: u64,
+) -> VerificationReport {
+    let bots = audit(targets, bots)
+        .await
+        .into_iter()
+        .map(|(bot, live)| {
+            let set = expected.set.get(&bot).cloned();
+            let (verdict, problems) = match &live {
+                Ok(info) => judge(info, set.as_deref(), expected, expected.previous.get(&bot), pending_warn),
+                Err(_) => (Verdict::Failed, Vec::new()),
+            };
+            let backlog = live.as_ref().ok().map(|info| info.pending_update_count).filter(|n| *n > pending_warn);
+            BotVerification { bot, expected: set, live, verdict, problems, backlog }
+        })
+        .collect();
+    VerificationReport { bots }
+}
+
+fn judge(
+    info: &WebhookInfo,
+    set: Option<&str>,
+    expected: &Expected,
+    previous: Option<&String>,
+    pending_warn: u64,
+) -> (Verdict, Vec<String>) {
+    let mut problems = Vec::new();
+    let verdict = match set {
+        Some(set) if info.url == set => Verdict::Ok,
+        Some(_) if info.url.is_empty() || previous == Some(&info.url) => Verdict::NotYetUpdated,
+        Some(_) => Verdict::Mismatch,
+        None => {
+            if info.url.is_empty() {
+                problems.push("no webhook set".to_string());
+            }
+            Verdict::Ok
+        }
+    };
+    if expected.tunnels.as_deref().is_some_and(|tunnels| orphaned(&info.url, tunnels)) {
+        problems.push(format!("webhook points at {}, which no current tunnel serves", info.url));
+    }
+    let recent = info.last_error_date.zip(expected.since).is_none_or(|(date, since)| date >= since);
+    if let Some(message) = info.last_error_message.as_deref().filter(|m| !m.is_empty() && recent) {
+        problems.push(format!("last error: {}", message));
+    }
+    if info.pending_update_count > pending_warn {
+        problems.push(format!("backlog of {} pending updates", info.pending_update_count));
+    }
+    (verdict, problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(url: &str, last_error: Option<(&str, i64)>, pending: u64) -> WebhookInfo {
+        WebhookInfo {
+            url: url.to_string(),
+            pending_update_count: pending,
+            last_error_message: last_error.map(|(m, _)| m.to_string()),
+            last_error_date: last_error.map(|(_, d)| d),
+            allowed_updates: None,
+        }
+    }
+
+    #[test]
+    fn tells_a_lagging_webhook_from_a_foreign_one() {
+        let (new, old) = ("https://b.ngrok.io/webhook", "https://a.ngrok.io/webhook".to_string());
+        let none = Expected::default();
+        assert_eq!(judge(&info(new, None, 0), Some(new), &none, Some(&old), 100), (Verdict::Ok, vec![]));
+        assert_eq!(judge(&info(&old, None, 0), Some(new), &none, Some(&old), 100).0, Verdict::NotYetUpdated);
+        assert_eq!(judge(&info("", None, 0), Some(new), &none, None, 100).0, Verdict::NotYetUpdated);
+        assert_eq!(judge(&info("https://x.example/hook", None, 0), Some(new), &none, Some(&old), 100).0, Verdict::Mismatch);
+    }
+
+    #[test]
+    fn only_recent_errors_and_long_queues_are_problems() {
+        let url = "https://b.ngrok.io/webhook";
+        let stale = info(url, Some(("Connection refused", 1_000)), 0);
+        let since = Expected { since: Some(2_000), ..Expected::default() };
+        let none = Expected::default();
+        assert!(judge(&stale, Some(url), &since, None, 100).1.is_empty());
+        assert_eq!(judge(&stale, Some(url), &none, None, 100).1, ["last error: Connection refused"]);
+        assert_eq!(judge(&info(url, None, 101), Some(url), &none, None, 100).1, ["backlog of 101 pending updates"]);
+        assert_eq!(judge(&info("", None, 0), None, &none, None, 100), (Verdict::Ok, vec!["no webhook set".to_string()]));
+    }
+
+    #[test]
+    fn webhooks_outside_every_tunnel_are_orphaned() {
+        let tunnels = vec!["https://b.ngrok.io".to_string()];
+        assert!(!orphaned("https://b.ngrok.io/webhook", &tunnels));
+        assert!(!orphaned("https://b.ngrok.io", &tunnels));
+        assert!(orphaned("https://b.ngrok.io.evil.example/webhook", &tunnels));
+        assert!(orphaned("https://a.ngrok.io/webhook", &tunnels));
+        assert!(!orphaned("", &tunnels));
+
+        let expected = Expected { tunnels: Some(tunnels), ..Expected::default() };
+        let (_, problems) = judge(&info("https://a.ngrok.io/webhook", None, 0), None, &expected, None, 100);
+        assert_eq!(problems, ["webhook points at https://a.ngrok.io/webhook, which no current tunnel serves"]);
+    }
+}
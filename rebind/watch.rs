@@ -0,0 +1,530 @@
+//! `--watch` support: poll the tunnel provider and rebind only the bots
+//! whose public URL moved since the last poll.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::{env_or, BotMode};
+use crate::ledger::random_bytes;
+use crate::verify::orphaned;
+use crate::{
+    audit, bind_all, discover, emit_pulses, note_kept_bindings, notify, provider_used, record_ledger, save_state,
+    secret_rotated, state, BindError, BotBinding, DiscoveryError, Outcome, RebindConfig, RebindEvent, RebindReport, RunSummary, State,
+};
+
+/// Binds per bot that [`Watcher::average_durations`] averages over.
+pub const DURATION_WINDOW: usize = 10;
+
+/// Binds quicker than this are never called slow, however quick the bot
+/// usually is.
+pub const SLOW_BIND_FLOOR: Duration = Duration::from_millis(250);
+
+/// How each bot's bind latency is averaged across polls, and when a bind
+/// is slow enough to warn about.
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyPolicy {
+    /// Weight of the newest bind in the moving average.
+    pub alpha: f64,
+    /// A bind taking this many times the average is slow; 0 never warns.
+    pub slow_factor: f64,
+}
+
+impl LatencyPolicy {
+    /// `REBIND_LATENCY_ALPHA` (default 0.2, between 0 and 1) and
+    /// `REBIND_SLOW_BIND_FACTOR` (default 3).
+    pub fn from_env() -> Self {
+        LatencyPolicy {
+            alpha: env_or("REBIND_LATENCY_ALPHA", 0.2f64).clamp(0.01, 1.0),
+            slow_factor: env_or("REBIND_SLOW_BIND_FACTOR", 3.0f64),
+        }
+    }
+
+    /// Whether a bind that `took` this long is slow next to `average_ms`.
+    fn slow(&self, took: Duration, average_ms: f64) -> bool {
+        self.slow_factor > 0.0
+            && took >= SLOW_BIND_FLOOR
+            && took.as_secs_f64() * 1000.0 >= self.slow_factor * average_ms
+    }
+}
+
+/// When a bot that keeps failing is given a rest.
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerPolicy {
+    /// Consecutive failures that open the circuit; 0 disables the breaker.
+    pub threshold: u32,
+    /// First cooldown, doubled (with jitter) each time a trial bind fails.
+    pub cooldown: Duration,
+    pub max_cooldown: Duration,
+}
+
+impl BreakerPolicy {
+    /// `REBIND_BREAKER_THRESHOLD` (default 3), `REBIND_BREAKER_COOLDOWN_SECS`
+    /// (default 60) and `REBIND_BREAKER_MAX_COOLDOWN_SECS` (default 1800).
+    pub fn from_env() -> Self {
+        BreakerPolicy {
+            threshold: env_or("REBIND_BREAKER_THRESHOLD", 3u32),
+            cooldown: Duration::from_secs(env_or("REBIND_BREAKER_COOLDOWN_SECS", 60u64)),
+            max_cooldown: Duration::from_secs(env_or("REBIND_BREAKER_MAX_COOLDOWN_SECS", 1800u64)),
+        }
+    }
+
+    /// The cooldown after the circuit opened for the `trips`-th time in a
+    /// row, jittered by up to ±20% so bots that broke together don't retry
+    /// in lockstep.
+    fn cooldown(&self, trips: u32) -> Duration {
+        let base = self.cooldown.saturating_mul(2u32.saturating_pow(trips.saturating_sub(1))).min(self.max_cooldown);
+        let jitter = f64::from(random_bytes()[0]) / 255.0 * 0.4 + 0.8;
+        base.mul_f64(jitter).min(self.max_cooldown)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    /// How many times the circuit has opened without a success since.
+    trips: u32,
+    open_until: Option<Instant>,
+}
+
+/// Every bot's [`Breaker`]. Times come from tokio's clock, which tests can
+/// pause and advance.
+#[derive(Debug)]
+struct Breakers {
+    policy: BreakerPolicy,
+    /// Whether binds are retried at all; when they are, a bind that failed
+    /// after its last retry opens the circuit without waiting for the
+    /// threshold, as retrying it again next poll would only repeat them.
+    retries: bool,
+    bots: HashMap<String, Breaker>,
+}
+
+pub struct Watcher {
+    config: RebindConfig,
+    /// Last URL (and secret, when tracked) each bot was successfully bound
+    /// to, seeded from the state file so a restart doesn't rebind everything.
+    last_seen: State,
+    /// Set once the first poll has run; until then every bot is bound.
+    started: bool,
+    breakers: Breakers,
+    latency: LatencyPolicy,
+    /// The last [`DURATION_WINDOW`] bind durations of each bot.
+    durations: HashMap<String, VecDeque<Duration>>,
+    /// Every how many polls the live webhooks are checked for ones left on
+    /// a vanished tunnel (`REBIND_WATCH_AUDIT_POLLS`, default 10; 0 never).
+    audit_every: u64,
+    polls: u64,
+    /// Bots already alerted about as overdue, so each is alerted once per
+    /// outage.
+    alerted: HashSet<String>,
+    /// Bots Telegram told to wait longer than `REBIND_MAX_FLOOD_WAIT_SECS`,
+    /// and when they may be tried again.
+    flood_waits: HashMap<String, Instant>,
+}
+
+impl Watcher {
+    pub fn new(config: RebindConfig) -> Self {
+        let last_seen = config.state_file.as_deref().map(state::load).unwrap_or_default();
+        let retries = config.retry.max_attempts > 1;
+        Watcher {
+            config,
+            last_seen,
+            started: false,
+            breakers: Breakers {
+                policy: BreakerPolicy::from_env(),
+                retries,
+                bots: HashMap::new(),
+            },
+            latency: LatencyPolicy::from_env(),
+            durations: HashMap::new(),
+            audit_every: env_or("REBIND_WATCH_AUDIT_POLLS", 10u64),
+            polls: 0,
+            alerted: HashSet::new(),
+            flood_waits: HashMap::new(),
+        }
+    }
+
+    /// Each bot's mean bind duration over its recent polls, for spotting a
+    /// bot whose API calls are slowing down.
+    pub fn average_durations(&self) -> HashMap<String, Duration> {
+        self.durations
+            .iter()
+            .filter(|(_, recent)| !recent.is_empty())
+            .map(|(bot, recent)| (bot.clone(), recent.iter().sum::<Duration>() / recent.len() as u32))
+            .collect()
+    }
+
+    /// Discovers tunnels once and rebinds the bots whose URL (or tracked
+    /// secret) changed. The first poll binds every bot with a tunnel instead,
+    /// since the saved state can't tell whether Telegram still agrees; bots
+    /// whose live webhook already matches are left alone as in a one-shot
+    /// run. The report only covers those bots, so an empty report means no
+    /// change.
+    /// Failed bots are retried on the next poll, as is everything when no
+    /// tunnel agent answers, until their circuit opens; they then wait out
+    /// a cooldown and get a single trial bind. A bind that timed out or got
+    /// 429s and 5xxs through every retry opens the circuit at once. A bot
+    /// told to wait longer than `REBIND_MAX_FLOOD_WAIT_SECS` is left out of
+    /// the polls until that wait is over instead, without counting towards
+    /// its circuit. Bots
+    /// that succeeded are only bound again once their URL or secret moves.
+    /// Every `REBIND_WATCH_AUDIT_POLLS` polls the live webhooks are fetched
+    /// as well, and a bot whose webhook someone left on a tunnel that is
+    /// gone is rebound even though its own URL didn't move.
+    /// `mode = "poll"` bots are checked for a webhook on the first poll and
+    /// every audit poll.
+    /// A bot whose tunnel restarted under a new URL is logged with how long
+    /// the old one was up, which is usually why it is being rebound.
+    /// The report's `reasons` say why each bot was tried this poll.
+    /// Each successful bind also updates the bot's latency average in the
+    /// state file; one far slower than usual is warned about and raises a
+    /// `rebind_alert` pulse.
+    /// With [`RebindConfig::alert_after`], a bot is only alerted about once
+    /// it is [`Watcher::overdue`], even when discovery fails, and the
+    /// report lists the bots that are.
+    pub async fn poll(&mut self) -> Result<RebindReport, DiscoveryError> {
+        let started = Instant::now();
+        let urls = match discover(&self.config).await {
+            Ok(urls) => urls,
+            Err(err) => {
+                self.alert_overdue();
+                return Err(err);
+            }
+        };
+        let discovery = started.elapsed();
+        note_kept_bindings(&urls, &self.last_seen.bindings);
+        let unix_now = state::unix_now();
+        let observed = self.last_seen.observe(&urls, unix_now);
+        let secrets = self.config.secret_fingerprints();
+        let now = Instant::now();
+        let first = !std::mem::replace(&mut self.started, true);
+        self.polls += 1;
+        let audit = self.polls.is_multiple_of(self.audit_every);
+        let orphans = if !first && audit {
+            self.orphans(&urls, now).await
+        } else {
+            Vec::new()
+        };
+        let changed: Vec<(&BotBinding, &'static str)> = self
+            .config
+            .bots
+            .iter()
+            .filter(|bot| !self.breakers.resting(&bot.name, now))
+            .filter(|bot| self.flood_waits.get(&bot.name).is_none_or(|until| now >= *until))
+            .filter_map(|bot| {
+                let moved = |url| self.last_seen.bindings.get(&bot.name) != Some(url);
+                let rotated = || secret_rotated(bot, &secrets, &self.last_seen);
+                let reason = match urls.get(&bot.name) {
+                    _ if bot.mode == BotMode::Poll => (first || audit).then_some("polling check"),
+                    None => None,
+                    Some(_) if first && self.config.force => Some("forced"),
+                    Some(_) if first => Some("startup"),
+                    Some(_) if orphans.contains(&bot.name) => Some("orphaned webhook"),
+                    Some(url) if !moved(url) && !rotated() => None,
+                    Some(_) if self.last_seen.failed.contains_key(&bot.name) => Some("previously failed"),
+                    Some(url) if moved(url) => Some("new tunnel"),
+                    Some(_) => Some("secret rotated"),
+                };
+                reason.map(|reason| (bot, reason))
+            })
+            .collect();
+        let mut report = bind_all(&self.config, changed.iter().map(|(bot, _)| *bot), &urls, &secrets, &self.last_seen).await;
+        report.reasons = changed.iter().map(|(bot, reason)| (bot.name.clone(), reason.to_string())).collect();
+        report.tunnel_ages = self.last_seen.tunnel_ages(&report.tunnels, unix_now);
+        (report.discovery, report.elapsed) = (discovery, started.elapsed());
+        report.provider = provider_used(&self.config);
+        if report.provider.is_some() {
+            report.agents = self.config.provider.agents();
+        }
+        self.breakers.update(&report, Instant::now());
+        self.note_flood_waits(&report, Instant::now());
+        for outcome in report.outcomes.iter().filter(|o| !matches!(o.outcome, Outcome::NoTunnel)) {
+            let recent = self.durations.entry(outcome.bot.clone()).or_default();
+            if recent.len() == DURATION_WINDOW {
+                recent.pop_front();
+            }
+            recent.push_back(outcome.duration);
+        }
+        let tracked = self.track_latency(&report);
+        record_ledger(&self.config, &report, &self.last_seen.bindings, &urls);
+        if !report.outcomes.is_empty() || urls.is_empty() {
+            emit_pulses(&self.config, &report, &urls);
+            if !report.cancelled {
+                notify(&self.config, &report).await;
+            }
+        }
+        let noted = self.last_seen.record(&report, &urls, &secrets)
+            | self.last_seen.note_successes(&urls, unix_now)
+            | self.last_seen.note_recent(&report, &self.config.bind_keys(&urls), unix_now);
+        if noted | observed | tracked {
+            save_state(&self.config, &self.last_seen);
+        }
+        report.overdue = self.alert_overdue();
+        self.config.emit(RebindEvent::RunComplete { summary: RunSummary::from(&report) });
+        Ok(report)
+    }
+
+    /// The bots that have gone [`RebindConfig::alert_after`] without being
+    /// bound, or found still bound, to their tunnel, with how long since
+    /// their last success (`None`: not since the state file began). Always
+    /// empty without `alert_after`.
+    pub fn overdue(&self) -> Vec<(String, Option<Duration>)> {
+        let Some(after) = self.config.alert_after else { return Vec::new() };
+        self.last_seen.overdue(self.config.bots.iter().map(|b| b.name.as_str()), after, state::unix_now())
+    }
+
+    /// Logs and raises a `rebind_alert` for every bot that just became
+    /// [`Watcher::overdue`], and notes the ones that recovered. Returns the
+    /// names of all overdue bots.
+    fn alert_overdue(&mut self) -> Vec<String> {
+        let Some(after) = self.config.alert_after else { return Vec::new() };
+        let overdue = self.overdue();
+        let fresh: Vec<(String, Option<Duration>)> =
+            overdue.iter().filter(|(bot, _)| !self.alerted.contains(bot)).cloned().collect();
+        for (bot, age) in &fresh {
+            match age {
+                Some(age) => log::error!(
+                    "[🚨] {}: no successful bind for {} (alerting after {})",
+                    bot,
+                    state::format_age(*age),
+                    state::format_age(after)
+                ),
+                None => log::error!("[🚨] {}: never bound successfully (alerting after {})", bot, state::format_age(after)),
+            }
+        }
+        if let (false, Some(pulses)) = (fresh.is_empty(), &self.config.pulses) {
+            pulses.overdue(&self.config.run_id, &fresh, after);
+        }
+        let names: Vec<String> = overdue.into_iter().map(|(bot, _)| bot).collect();
+        for bot in self.alerted.iter().filter(|bot| !names.contains(bot)) {
+            log::info!("[✅] {}: bound again; no longer overdue", bot);
+        }
+        self.alerted = names.iter().cloned().collect();
+        names
+    }
+
+    /// Folds the latency of each bot bound or left unchanged into its
+    /// average, warning about binds that took [`LatencyPolicy::slow_factor`]
+    /// times it. Failed binds are left out, as timeouts would skew it.
+    /// Returns whether any average changed.
+    fn track_latency(&mut self, report: &RebindReport) -> bool {
+        let mut tracked = false;
+        for outcome in &report.outcomes {
+            if !matches!(outcome.outcome, Outcome::Bound { .. } | Outcome::Unchanged { .. }) {
+                continue;
+            }
+            tracked = true;
+            let Some(average) = self.last_seen.track_latency(&outcome.bot, outcome.duration, self.latency.alpha) else {
+                continue;
+            };
+            if self.latency.slow(outcome.duration, average) {
+                log::warn!(
+                    "[🐢] {}: bind took {}ms, {:.1}x its average of {:.0}ms",
+                    outcome.bot,
+                    outcome.duration.as_millis(),
+                    outcome.duration.as_secs_f64() * 1000.0 / average,
+                    average
+                );
+                if let Some(pulses) = &self.config.pulses {
+                    pulses.slow_bind(&self.config.run_id, &outcome.bot, outcome.duration, average, self.latency.slow_factor);
+                }
+            }
+        }
+        tracked
+    }
+
+    /// Notes when each bot of `report` that got a [`BindError::FloodWait`]
+    /// may be tried again, and forgets the wait of every other bot tried.
+    fn note_flood_waits(&mut self, report: &RebindReport, now: Instant) {
+        for outcome in &report.outcomes {
+            match &outcome.outcome {
+                Outcome::Failed(BindError::FloodWait(wait)) => {
+                    log::warn!("[🌊] {}: next attempt after Telegram's flood wait, in {}s", outcome.bot, wait.as_secs());
+                    self.flood_waits.insert(outcome.bot.clone(), now + *wait);
+                }
+                _ => {
+                    self.flood_waits.remove(&outcome.bot);
+                }
+            }
+        }
+    }
+
+    /// Bots with a tunnel whose live webhook lies under none of the tunnels
+    /// just discovered; a bot whose webhook can't be fetched is left to the
+    /// next audit.
+    async fn orphans(&self, urls: &HashMap<String, String>, now: Instant) -> Vec<String> {
+        let bots: Vec<_> = self
+            .config
+            .bots
+            .iter()
+            .filter(|bot| urls.contains_key(&bot.name) && !self.breakers.resting(&bot.name, now))
+            .cloned()
+            .collect();
+        let tunnels: Vec<String> = urls.values().cloned().collect();
+        let mut orphans = Vec::new();
+        for (bot, live) in audit(self.config.targets(), &bots).await {
+            let Ok(info) = live else { continue };
+            if orphaned(&info.url, &tunnels) {
+                log::warn!(
+                    "[🔍] {}: webhook points at {}, which no current tunnel serves; rebinding to {}",
+                    bot,
+                    info.url,
+                    urls[&bot]
+                );
+                orphans.push(bot);
+            }
+        }
+        orphans
+    }
+}
+
+impl Breakers {
+    fn resting(&self, bot: &str, now: Instant) -> bool {
+        self.bots.get(bot).and_then(|b| b.open_until).is_some_and(|until| now < until)
+    }
+
+    fn update(&mut self, report: &RebindReport, now: Instant) {
+        if self.policy.threshold == 0 {
+            return;
+        }
+        for outcome in &report.outcomes {
+            match &outcome.outcome {
+                // The watcher waits these out on its own; they say nothing
+                // about the bot.
+                Outcome::Failed(BindError::FloodWait(_)) => {}
+                Outcome::Failed(_) | Outcome::Unhealthy(_) => {
+                    let exhausted = matches!(&outcome.outcome, Outcome::Failed(err) if self.retries && err.is_transient());
+                    let breaker = self.bots.entry(outcome.bot.clone()).or_default();
+                    breaker.failures += 1;
+                    if breaker.failures < self.policy.threshold && breaker.trips == 0 && !exhausted {
+                        continue;
+                    }
+                    breaker.trips += 1;
+                    let cooldown = self.policy.cooldown(breaker.trips);
+                    breaker.open_until = Some(now + cooldown);
+                    if exhausted && breaker.failures < self.policy.threshold {
+                        log::warn!(
+                            "[🔌] {}: circuit open after using up its retries; next attempt in {}s",
+                            outcome.bot,
+                            cooldown.as_secs()
+                        );
+                    } else {
+                        log::warn!(
+                            "[🔌] {}: circuit open after {} consecutive failures; next attempt in {}s",
+                            outcome.bot,
+                            breaker.failures,
+                            cooldown.as_secs()
+                        );
+                    }
+                }
+                Outcome::Bound { .. } | Outcome::Unchanged { .. } | Outcome::Polling { .. } => {
+                    if self.bots.remove(&outcome.bot).is_some_and(|b| b.trips > 0) {
+                        log::info!("[🔌] {}: circuit closed", outcome.bot);
+                    }
+                }
+                Outcome::NoTunnel => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cooldown_doubles_up_to_the_cap() {
+        let policy = BreakerPolicy {
+            threshold: 3,
+            cooldown: Duration::from_secs(60),
+            max_cooldown: Duration::from_secs(300),
+        };
+        let within = |trips, secs: f64| {
+            let got = policy.cooldown(trips).as_secs_f64();
+            assert!(got >= secs * 0.8 - 0.01 && got <= (secs * 1.2).min(300.0) + 0.01, "trip {}: {}", trips, got);
+        };
+        within(1, 60.0);
+        within(2, 120.0);
+        within(3, 240.0);
+        within(10, 300.0);
+    }
+
+    #[test]
+    fn only_binds_well_past_the_average_are_slow() {
+        let policy = LatencyPolicy { alpha: 0.2, slow_factor: 3.0 };
+        assert!(policy.slow(Duration::from_millis(900), 300.0));
+        assert!(!policy.slow(Duration::from_millis(899), 300.0));
+        assert!(!policy.slow(Duration::from_millis(200), 20.0));
+        assert!(!LatencyPolicy { slow_factor: 0.0, ..policy }.slow(Duration::from_secs(60), 300.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn open_circuit_rests_until_the_cooldown_elapses() {
+        let policy = BreakerPolicy {
+            threshold: 2,
+            cooldown: Duration::from_secs(60),
+            max_cooldown: Duration::from_secs(60),
+        };
+        let mut breakers = Breakers { policy, retries: false, bots: HashMap::new() };
+        let report = |outcome| RebindReport {
+            outcomes: vec![crate::BotOutcome { bot: "gpt4o".to_string(), outcome, duration: Duration::ZERO }],
+            ..RebindReport::default()
+        };
+        breakers.update(&report(Outcome::Failed(crate::BindError::Timeout)), Instant::now());
+        assert!(!breakers.resting("gpt4o", Instant::now()));
+        breakers.update(&report(Outcome::Failed(crate::BindError::Timeout)), Instant::now());
+        assert!(breakers.resting("gpt4o", Instant::now()));
+
+        // Jitter may shorten the 60s cooldown to 48s, never lengthen it past the cap.
+        tokio::time::advance(Duration::from_secs(47)).await;
+        assert!(breakers.resting("gpt4o", Instant::now()));
+        tokio::time::advance(Duration::from_secs(14)).await;
+        assert!(!breakers.resting("gpt4o", Instant::now()));
+
+        let webhook_url = "https://a.ngrok.io/webhook".to_string();
+        breakers.update(&report(Outcome::Unchanged { webhook_url }), Instant::now());
+        assert!(breakers.bots.is_empty());
+    }
+
+    #[test]
+    fn changes_pair_outcomes_with_why_they_were_tried() {
+        let outcome = |bot: &str, outcome| crate::BotOutcome { bot: bot.to_string(), outcome, duration: Duration::ZERO };
+        let report = RebindReport {
+            outcomes: vec![
+                outcome("gpt4o", Outcome::Unchanged { webhook_url: "https://a.ngrok.io/webhook".to_string() }),
+                outcome("mistral", Outcome::Failed(crate::BindError::Timeout)),
+            ],
+            reasons: [("gpt4o", "new tunnel"), ("mistral", "previously failed")]
+                .map(|(bot, reason)| (bot.to_string(), reason.to_string()))
+                .into(),
+            ..RebindReport::default()
+        };
+        assert_eq!(report.changes(), [("gpt4o", "unchanged", "new tunnel"), ("mistral", "failed", "previously failed")]);
+        assert_eq!(report.to_json()["changes"][1], serde_json::json!({ "bot": "mistral", "outcome": "failed", "reason": "previously failed" }));
+        assert!(RebindReport::default().to_json().get("changes").is_none());
+    }
+
+    #[test]
+    fn a_bind_that_used_up_its_retries_rests_at_once() {
+        let policy = BreakerPolicy {
+            threshold: 3,
+            cooldown: Duration::from_secs(60),
+            max_cooldown: Duration::from_secs(60),
+        };
+        let mut breakers = Breakers { policy, retries: true, bots: HashMap::new() };
+        let report = |bot: &str, err| RebindReport {
+            outcomes: vec![crate::BotOutcome { bot: bot.to_string(), outcome: Outcome::Failed(err), duration: Duration::ZERO }],
+            ..RebindReport::default()
+        };
+        breakers.update(&report("gpt4o", crate::BindError::Timeout), Instant::now());
+        assert!(breakers.resting("gpt4o", Instant::now()));
+
+        // Nothing was retried, so the threshold still applies.
+        breakers.update(&report("mistral", crate::BindError::Unauthorized("Unauthorized".to_string())), Instant::now());
+        assert!(!breakers.resting("mistral", Instant::now()));
+        // A flood wait is waited out by the watcher, not the breaker.
+        breakers.update(&report("deepseek", crate::BindError::FloodWait(Duration::from_secs(300))), Instant::now());
+        assert!(!breakers.bots.contains_key("deepseek"));
+    }
+}
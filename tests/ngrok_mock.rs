@@ -0,0 +1,268 @@
+//! ngrok discovery against local hyper servers standing in for tunnel
+//! agents.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use rebind::config::default_bots;
+use rebind::tunnel::{AgentState, DiscoveryError, NgrokProvider, Precedence};
+use rebind::{HttpsClient, TunnelProvider};
+use serde_json::json;
+
+/// An agent that answers every request after `delay` with one tunnel from
+/// `public_url` to `port`.
+fn mock_agent(delay: Duration, public_url: &'static str, port: u16) -> SocketAddr {
+    let make = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |_: Request<Body>| async move {
+            tokio::time::sleep(delay).await;
+            let body = json!({
+                "tunnels": [{ "public_url": public_url, "config": { "addr": format!("http://localhost:{}", port) } }],
+            });
+            Ok::<_, Infallible>(Response::new(Body::from(body.to_string())))
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+/// An agent that promises a longer body than it sends, then hangs up.
+async fn truncating_agent() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let head = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 500\r\n\r\n";
+            let _ = stream.write_all(format!("{}{{\"tunnels\": [", head).as_bytes()).await;
+        }
+    });
+    addr
+}
+
+fn client() -> HttpsClient {
+    rebind::direct_client()
+}
+
+#[tokio::test]
+async fn agents_are_queried_concurrently_and_merged_in_order() {
+    let slow = mock_agent(Duration::from_millis(600), "https://slow.ngrok.io", 9977);
+    let fast = mock_agent(Duration::from_millis(400), "https://fast.ngrok.io", 9977);
+    let other = mock_agent(Duration::ZERO, "https://other.ngrok.io", 9988);
+    let provider = NgrokProvider {
+        client: client(),
+        apis: [("slow", slow), ("fast", fast), ("other", other)]
+            .iter()
+            .map(|(label, addr)| (label.to_string(), format!("http://{}/api/tunnels", addr)))
+            .collect(),
+        bots: default_bots(false),
+        timeout: Duration::from_secs(5),
+        precedence: Precedence::First,
+        strict_unmapped: false,
+        api_key: None,
+        region: None,
+        expected_ports: HashMap::new(),
+        max_body: 1 << 20,
+        headers: Vec::new(),
+        statuses: Default::default(),
+        unmapped: Default::default(),
+    };
+
+    let started = Instant::now();
+    let urls = provider.public_urls().await.unwrap();
+    let took = started.elapsed();
+
+    // One after the other would take at least a second.
+    assert!(took < Duration::from_millis(900), "discovery took {:?}", took);
+    // The first agent listed wins the shared port even though it answered last.
+    assert_eq!(urls["gpt4o"], "https://slow.ngrok.io");
+    assert_eq!(urls["mistral"], "https://other.ngrok.io");
+}
+
+#[tokio::test]
+async fn cut_short_and_oversized_answers_are_not_empty_ones() {
+    let truncated = truncating_agent().await;
+    let huge = mock_agent(Duration::ZERO, "https://huuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuuge.ngrok.io", 9977);
+    let ok = mock_agent(Duration::ZERO, "https://ok.ngrok.io", 9988);
+    let provider = |agents: &[SocketAddr]| NgrokProvider {
+        client: client(),
+        apis: agents.iter().enumerate().map(|(i, addr)| (i.to_string(), format!("http://{}/api/tunnels", addr))).collect(),
+        bots: default_bots(false),
+        timeout: Duration::from_secs(5),
+        precedence: Precedence::First,
+        strict_unmapped: false,
+        api_key: None,
+        region: None,
+        expected_ports: HashMap::new(),
+        // Enough for ok's tunnel list, not for huge's.
+        max_body: 128,
+        headers: Vec::new(),
+        statuses: Default::default(),
+        unmapped: Default::default(),
+    };
+
+    let urls = provider(&[truncated, huge, ok]).public_urls().await.unwrap();
+    assert_eq!(urls.len(), 1);
+    assert_eq!(urls["mistral"], "https://ok.ngrok.io");
+    // Neither answer counts as an agent reporting no tunnels.
+    let err = provider(&[truncated, huge]).public_urls().await.unwrap_err();
+    assert!(matches!(err, DiscoveryError::NoAgentReachable(_)), "{}", err);
+}
+
+#[tokio::test]
+async fn tunnel_headers_reach_the_agent() {
+    // An auth proxy in front of the inspector: no tunnels without the header.
+    let make = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let authorized = req.headers().get("authorization").is_some_and(|v| v == "Bearer inspector");
+            let body = if authorized {
+                json!({ "tunnels": [
+                    { "public_url": "https://a.ngrok.io", "config": { "addr": "http://localhost:9977" } },
+                    { "public_url": "https://spare.ngrok.io", "config": { "addr": "http://localhost:7000" } },
+                ] })
+            } else {
+                json!({ "tunnels": [] })
+            };
+            Ok::<_, Infallible>(Response::new(Body::from(body.to_string())))
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+    let api = format!("http://{}/api/tunnels", server.local_addr());
+    tokio::spawn(server);
+    let provider = |headers: Vec<(String, String)>| NgrokProvider {
+        client: client(),
+        apis: vec![("main".to_string(), api.clone())],
+        bots: default_bots(false),
+        timeout: Duration::from_secs(5),
+        precedence: Precedence::First,
+        strict_unmapped: false,
+        api_key: None,
+        region: None,
+        expected_ports: HashMap::new(),
+        max_body: 1 << 20,
+        headers,
+        statuses: Default::default(),
+        unmapped: Default::default(),
+    };
+
+    assert!(provider(Vec::new()).public_urls().await.unwrap().is_empty());
+    let headers = vec![("Authorization".to_string(), "Bearer inspector".to_string())];
+    let authorized = provider(headers);
+    assert_eq!(authorized.public_urls().await.unwrap()["gpt4o"], "https://a.ngrok.io");
+    assert_eq!(authorized.unmapped(), [(7000, "https://spare.ngrok.io".to_string())]);
+}
+
+#[tokio::test]
+async fn each_agents_answer_is_recorded() {
+    let main = mock_agent(Duration::ZERO, "https://main.ngrok.io", 9977);
+    let alt = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let make = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_: Request<Body>| async { Ok::<_, Infallible>(Response::new(Body::from("<html>"))) }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+    let garbled = server.local_addr();
+    tokio::spawn(server);
+    let provider = NgrokProvider {
+        client: client(),
+        apis: [("main", main), ("alt", alt), ("garbled", garbled)]
+            .iter()
+            .map(|(label, addr)| (label.to_string(), format!("http://{}/api/tunnels", addr)))
+            .collect(),
+        bots: default_bots(false),
+        timeout: Duration::from_secs(5),
+        precedence: Precedence::First,
+        strict_unmapped: false,
+        api_key: None,
+        region: None,
+        expected_ports: HashMap::new(),
+        max_body: 1 << 20,
+        headers: Vec::new(),
+        statuses: Default::default(),
+        unmapped: Default::default(),
+    };
+
+    // Discovery goes on with what main provided, but says who didn't answer.
+    let urls = provider.public_urls().await.unwrap();
+    assert_eq!(urls["gpt4o"], "https://main.ngrok.io");
+    let states: Vec<_> = provider.agents().into_iter().map(|a| (a.agent, a.state)).collect();
+    assert_eq!(
+        states,
+        [
+            ("main".to_string(), AgentState::Ok),
+            ("alt".to_string(), AgentState::Unreachable),
+            ("garbled".to_string(), AgentState::ParseError),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn captured_answers_are_read_from_files() {
+    let dir = std::env::temp_dir().join(format!("rebind-captured-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let captured = dir.join("tunnels.json");
+    let body = json!({ "tunnels": [{ "public_url": "https://captured.ngrok.io", "config": { "addr": "http://localhost:9988" } }] });
+    std::fs::write(&captured, body.to_string()).unwrap();
+    let provider = NgrokProvider {
+        client: client(),
+        apis: vec![
+            ("captured".to_string(), format!("file://{}", captured.display())),
+            ("gone".to_string(), format!("file://{}", dir.join("missing.json").display())),
+        ],
+        bots: default_bots(false),
+        timeout: Duration::from_secs(5),
+        precedence: Precedence::First,
+        strict_unmapped: false,
+        api_key: None,
+        region: None,
+        expected_ports: HashMap::new(),
+        max_body: 1 << 20,
+        headers: Vec::new(),
+        statuses: Default::default(),
+        unmapped: Default::default(),
+    };
+
+    let urls = provider.public_urls().await.unwrap();
+    assert_eq!(urls["mistral"], "https://captured.ngrok.io");
+    let states: Vec<_> = provider.agents().into_iter().map(|a| a.state).collect();
+    assert_eq!(states, [AgentState::Ok, AgentState::Unreachable]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_localhost_agent_is_found_on_either_loopback() {
+    let make = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+            let body = json!({ "tunnels": [{ "public_url": "https://v6.ngrok.io", "config": { "addr": "http://localhost:9977" } }] });
+            Ok::<_, Infallible>(Response::new(Body::from(body.to_string())))
+        }))
+    });
+    // Hosts without IPv6 loopback have nothing to test.
+    let Ok(builder) = Server::try_bind(&"[::1]:0".parse().unwrap()) else { return };
+    let server = builder.serve(make);
+    let port = server.local_addr().port();
+    tokio::spawn(server);
+    let provider = NgrokProvider {
+        client: client(),
+        apis: vec![("main".to_string(), format!("http://localhost:{}/api/tunnels", port))],
+        bots: default_bots(false),
+        timeout: Duration::from_secs(5),
+        precedence: Precedence::First,
+        strict_unmapped: false,
+        api_key: None,
+        region: None,
+        expected_ports: HashMap::new(),
+        max_body: 1 << 20,
+        headers: Vec::new(),
+        statuses: Default::default(),
+        unmapped: Default::default(),
+    };
+    assert_eq!(provider.public_urls().await.unwrap()["gpt4o"], "https://v6.ngrok.io");
+}
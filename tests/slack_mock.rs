@@ -0,0 +1,142 @@
+//! The Slack target against a fake Web API that, like Slack, only accepts
+//! a request URL answering its `url_verification` challenge.
+
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, Server};
+use rebind::config::{BotMode, DropPending, Platform};
+use rebind::{BindError, BotBinding, HttpsClient, RetryPolicy, Targets, TokenProvider};
+use serde_json::{json, Value};
+
+struct FixedToken;
+
+impl TokenProvider for FixedToken {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn token(&self, _: &BotBinding) -> Result<String, BindError> {
+        Ok("xoxe.xoxp-1-config".to_string())
+    }
+}
+
+/// `apps.manifest.export` and `apps.manifest.update` for one app, keeping
+/// the manifest between calls. An update is only accepted once its request
+/// URL echoes a challenge back.
+fn mock_slack() -> (String, Arc<Mutex<Value>>) {
+    let manifest = Arc::new(Mutex::new(json!({
+        "display_information": { "name": "helper" },
+        "settings": { "event_subscriptions": { "request_url": "https://old.ngrok.io/slack/events", "bot_events": ["app_mention"] } },
+    })));
+    let stored = manifest.clone();
+    let make = make_service_fn(move |_| {
+        let stored = stored.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let stored = stored.clone();
+                async move {
+                    let path = req.uri().path().to_string();
+                    let args: Value = serde_json::from_slice(&hyper::body::to_bytes(req.into_body()).await.unwrap()).unwrap();
+                    assert_eq!(args["app_id"], "A0123");
+                    let reply = match path.as_str() {
+                        "/apps.manifest.export" => json!({ "ok": true, "manifest": *stored.lock().unwrap() }),
+                        "/apps.manifest.update" => {
+                            let manifest: Value = serde_json::from_str(args["manifest"].as_str().unwrap()).unwrap();
+                            let url = manifest["settings"]["event_subscriptions"]["request_url"].as_str().unwrap().to_string();
+                            let check = Request::builder()
+                                .method(Method::POST)
+                                .uri(&url)
+                                .body(Body::from(json!({ "type": "url_verification", "challenge": "c-42" }).to_string()))
+                                .unwrap();
+                            let answer = match Client::new().request(check).await {
+                                Ok(resp) => hyper::body::to_bytes(resp.into_body()).await.unwrap_or_default(),
+                                Err(_) => Default::default(),
+                            };
+                            if serde_json::from_slice::<Value>(&answer).is_ok_and(|v| v["challenge"] == "c-42") {
+                                *stored.lock().unwrap() = manifest;
+                                json!({ "ok": true })
+                            } else {
+                                json!({
+                                    "ok": false,
+                                    "error": "invalid_manifest",
+                                    "errors": [{ "message": "URL didn't respond with the value of the challenge parameter" }],
+                                })
+                            }
+                        }
+                        _ => json!({ "ok": false, "error": "unknown_method" }),
+                    };
+                    Ok::<_, Infallible>(Response::new(Body::from(reply.to_string())))
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+    let base = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    (base, manifest)
+}
+
+fn client() -> HttpsClient {
+    rebind::direct_client()
+}
+
+#[tokio::test]
+async fn request_url_is_updated_after_answering_the_challenge() {
+    let (api_base, manifest) = mock_slack();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let bot = BotBinding {
+        port,
+        name: "helper".to_string(),
+        platform: Platform::Slack,
+        application_id: Some("A0123".to_string()),
+        token_env: None,
+        drop_pending_updates: DropPending::Never,
+        allowed_updates: None,
+        max_connections: None,
+        ip_address: None,
+        health_path: None,
+        webhook_path: "/slack/events".to_string(),
+        secret_query: None,
+        secret_env: None,
+        secret: None,
+        priority: 50,
+        stable_url: None,
+        timeout: None,
+        mode: BotMode::Webhook,
+    };
+    let client = client();
+    let targets = Targets {
+        client: &client,
+        retry: RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+            budget: None,
+            max_flood_wait: Duration::from_secs(60),
+        },
+        telegram_api_base: "",
+        discord_api_base: "",
+        slack_api_base: &api_base,
+        secret: "",
+        tokens: &FixedToken,
+    };
+    let target = targets.for_bot(&bot);
+
+    // The "tunnel" goes straight to the bot's port, where only our stand-in
+    // answers Slack's challenge.
+    let public_url = format!("http://127.0.0.1:{}", port);
+    target.bind(&bot, "xoxe.xoxp-1-config", &public_url).await.unwrap();
+    let live = target.live_webhook(&bot, "xoxe.xoxp-1-config").await.unwrap();
+    assert_eq!(live.url, format!("{}/slack/events", public_url));
+    assert_eq!(manifest.lock().unwrap()["settings"]["event_subscriptions"]["bot_events"], json!(["app_mention"]));
+
+    // With the port taken and nothing answering behind it, Slack's refusal
+    // comes through.
+    let _busy = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+    let err = target.bind(&bot, "xoxe.xoxp-1-config", "http://127.0.0.1:1").await.unwrap_err();
+    assert!(err.to_string().starts_with("Slack error: invalid_manifest (URL didn't respond"), "{}", err);
+}
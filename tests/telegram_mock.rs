@@ -0,0 +1,504 @@
+//! `bind_webhook` (and a whole `rebind` run) against a local hyper server
+//! standing in for api.telegram.org.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use rebind::config::{BotMode, DropPending, Platform};
+use rebind::ratelimit::{with_request_slots, RateLimiter};
+use rebind::telegram::send_with_retry;
+use rebind::{
+    bind_webhook, events, rebind, BindError, BotBinding, CancellationToken, DiscoveryError, HttpsClient, Outcome,
+    RebindConfig, RebindEvent, RetryPolicy, TokenProvider, TunnelProvider,
+};
+use serde_json::{json, Value};
+
+const TOKEN: &str = "123456:TEST";
+
+#[derive(Debug, Clone)]
+struct Seen {
+    method: Method,
+    path: String,
+    user_agent: Option<String>,
+    has_request_id: bool,
+    body: Value,
+}
+
+/// Serves `status`/`reply` to every request and records what it was sent.
+fn mock_telegram(status: StatusCode, reply: Value) -> (SocketAddr, Arc<Mutex<Vec<Seen>>>) {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    let make = make_service_fn(move |_| {
+        let (log, reply) = (log.clone(), reply.to_string());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let (log, reply) = (log.clone(), reply.clone());
+                async move {
+                    let (parts, body) = req.into_parts();
+                    let body = hyper::body::to_bytes(body).await.unwrap();
+                    log.lock().unwrap().push(Seen {
+                        method: parts.method,
+                        path: parts.uri.path().to_string(),
+                        user_agent: parts.headers.get("user-agent").and_then(|v| v.to_str().ok()).map(str::to_string),
+                        has_request_id: parts.headers.contains_key("x-request-id"),
+                        body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+                    });
+                    Ok::<_, Infallible>(Response::builder().status(status).body(Body::from(reply)).unwrap())
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (addr, seen)
+}
+
+fn client() -> HttpsClient {
+    rebind::direct_client()
+}
+
+fn policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 1,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_secs(30),
+        timeout: Duration::from_secs(5),
+        budget: None,
+        max_flood_wait: Duration::from_secs(60),
+    }
+}
+
+fn bot() -> BotBinding {
+    BotBinding {
+        port: 9977,
+        name: "gpt4o".to_string(),
+        platform: Platform::Telegram,
+        application_id: None,
+        token_env: None,
+        drop_pending_updates: DropPending::Never,
+        allowed_updates: None,
+        max_connections: None,
+        ip_address: None,
+        health_path: None,
+        webhook_path: "/webhook".to_string(),
+        secret_query: None,
+        secret_env: None,
+        secret: None,
+        priority: 50,
+        stable_url: None,
+        timeout: None,
+        mode: BotMode::Webhook,
+    }
+}
+
+#[tokio::test]
+async fn posts_set_webhook_with_url_and_secret() {
+    let (addr, seen) = mock_telegram(StatusCode::OK, json!({ "ok": true, "result": true }));
+    let base = format!("http://{}", addr);
+
+    bind_webhook(&client(), &base, policy(), &bot(), TOKEN, "https://a.ngrok.io", "s3cret").await.unwrap();
+
+    let seen = seen.lock().unwrap().clone();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].method, Method::POST);
+    assert_eq!(seen[0].path, format!("/bot{}/setWebhook", TOKEN));
+    assert_eq!(seen[0].body, json!({ "url": "https://a.ngrok.io/webhook", "secret_token": "s3cret" }));
+    assert_eq!(seen[0].user_agent.as_deref(), Some(concat!("sentientos-rebind/", env!("CARGO_PKG_VERSION"))));
+    assert!(seen[0].has_request_id);
+}
+
+#[tokio::test]
+async fn surfaces_telegram_error_response() {
+    let (addr, seen) = mock_telegram(
+        StatusCode::BAD_REQUEST,
+        json!({ "ok": false, "error_code": 400, "description": "Bad Request: bad webhook: HTTPS url must be provided" }),
+    );
+    let base = format!("http://{}", addr);
+
+    let err = bind_webhook(&client(), &base, policy(), &bot(), TOKEN, "http://insecure", "s3cret").await.unwrap_err();
+
+    match err {
+        BindError::TelegramError(api) => {
+            assert_eq!(api.error_code, 400);
+            assert!(api.description.contains("HTTPS url must be provided"), "{}", api.description);
+        }
+        other => panic!("expected TelegramError, got {:?}", other),
+    }
+    // 400 is not transient, so there is exactly one attempt.
+    assert_eq!(seen.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn unauthorized_is_not_retried() {
+    let (addr, seen) = mock_telegram(
+        StatusCode::UNAUTHORIZED,
+        json!({ "ok": false, "error_code": 401, "description": "Unauthorized" }),
+    );
+    let base = format!("http://{}", addr);
+    let policy = RetryPolicy { max_attempts: 4, ..policy() };
+
+    let err = bind_webhook(&client(), &base, policy, &bot(), TOKEN, "https://a.ngrok.io", "s3cret").await.unwrap_err();
+
+    assert!(matches!(err, BindError::Unauthorized(_)), "{:?}", err);
+    assert_eq!(seen.lock().unwrap().len(), 1);
+}
+
+/// Answers every bot but `stalled` at once; requests for `stalled` never get
+/// a response.
+fn mock_stalling(stalled: &'static str) -> SocketAddr {
+    let make = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |req: Request<Body>| async move {
+            if req.uri().path().contains(stalled) {
+                std::future::pending::<()>().await;
+            }
+            let reply = json!({ "ok": true, "result": true }).to_string();
+            Ok::<_, Infallible>(Response::new(Body::from(reply)))
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn a_bot_backing_off_frees_its_request_slot() {
+    let base = format!("http://{}", mock_stalling("111:SLOW"));
+    let (client, bot) = (client(), bot());
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(400),
+        timeout: Duration::from_millis(300),
+        ..policy()
+    };
+    let started = tokio::time::Instant::now();
+
+    let slow = async {
+        let result = bind_webhook(&client, &base, policy, &bot, "111:SLOW", "https://a.ngrok.io", "s3cret").await;
+        (result, started.elapsed())
+    };
+    let fast = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let result = bind_webhook(&client, &base, policy, &bot, TOKEN, "https://b.ngrok.io", "s3cret").await;
+        (result, started.elapsed())
+    };
+    let ((slow, slow_took), (fast, fast_took)) = with_request_slots(1, async { tokio::join!(slow, fast) }).await;
+
+    assert!(matches!(slow, Err(BindError::Timeout)), "{:?}", slow);
+    fast.unwrap();
+    // The fast bot only waits out the slow bot's first attempt, not its
+    // whole retry sequence (300 + 400 + 300 + 800 + 300 ms).
+    assert!(fast_took < Duration::from_millis(1000), "fast bot took {:?}", fast_took);
+    assert!(slow_took > Duration::from_millis(2000), "slow bot took {:?}", slow_took);
+}
+
+#[tokio::test]
+async fn retries_stop_once_the_budget_is_spent() {
+    let (addr, seen) = mock_telegram(
+        StatusCode::BAD_GATEWAY,
+        json!({ "ok": false, "error_code": 502, "description": "Bad Gateway" }),
+    );
+    let (base, client) = (format!("http://{}", addr), client());
+    // Twenty attempts would take over 3.5s of backoff alone.
+    let policy = RetryPolicy {
+        max_attempts: 20,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_millis(200),
+        budget: Some(Duration::from_millis(700)),
+        ..policy()
+    };
+    let started = tokio::time::Instant::now();
+    let err = bind_webhook(&client, &base, policy, &bot(), TOKEN, "https://a.ngrok.io", "s3cret").await.unwrap_err();
+    let took = started.elapsed();
+
+    assert!(matches!(&err, BindError::TelegramError(api) if api.error_code == 502), "{:?}", err);
+    assert!(took <= Duration::from_millis(800), "retrying took {:?}", took);
+    let attempts = seen.lock().unwrap().len();
+    assert!((3..20).contains(&attempts), "{} attempts", attempts);
+
+    // An attempt that hangs is cut short by what is left of the budget,
+    // not the much longer per-request timeout.
+    let base = format!("http://{}", mock_stalling(TOKEN));
+    let policy = RetryPolicy { timeout: Duration::from_secs(30), budget: Some(Duration::from_millis(500)), ..policy };
+    let started = tokio::time::Instant::now();
+    let err = bind_webhook(&client, &base, policy, &bot(), TOKEN, "https://a.ngrok.io", "s3cret").await.unwrap_err();
+    assert!(matches!(err, BindError::Timeout), "{:?}", err);
+    assert!(started.elapsed() <= Duration::from_millis(600), "stalled bind took {:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn a_429_waits_out_the_retry_after_in_its_body() {
+    let (addr, seen) = mock_telegram(
+        StatusCode::TOO_MANY_REQUESTS,
+        json!({ "ok": false, "error_code": 429, "description": "Too Many Requests: retry after 7", "parameters": { "retry_after": 7 } }),
+    );
+    let uri = format!("http://{}/bot{}/getWebhookInfo", addr, TOKEN);
+    let limiter = RateLimiter::new(20.0);
+
+    let err = send_with_retry(&client(), policy(), Some(&limiter), "gpt4o", || {
+        Request::get(&uri).body(Body::empty()).unwrap()
+    })
+    .await
+    .unwrap_err();
+
+    assert_eq!(err.retry_after(), Some(Duration::from_secs(7)));
+    assert_eq!(seen.lock().unwrap().len(), 1);
+    // The mock sends no `Retry-After` header, so the limiter holds the next
+    // request back for the body's 7 seconds (give or take the timer's
+    // millisecond rounding).
+    tokio::time::pause();
+    let paused = tokio::time::Instant::now();
+    limiter.acquire().await;
+    let waited = paused.elapsed();
+    assert!(waited > Duration::from_millis(6900) && waited <= Duration::from_millis(7010), "waited {:?}", waited);
+}
+
+#[tokio::test]
+async fn a_flood_wait_past_the_limit_fails_at_once_without_pausing_others() {
+    let (addr, seen) = mock_telegram(
+        StatusCode::TOO_MANY_REQUESTS,
+        json!({ "ok": false, "error_code": 429, "description": "Too Many Requests: retry after 300", "parameters": { "retry_after": 300 } }),
+    );
+    let uri = format!("http://{}/bot{}/setWebhook", addr, TOKEN);
+    let (client, limiter) = (client(), RateLimiter::new(20.0));
+    let send = |policy| send_with_retry(&client, policy, Some(&limiter), "gpt4o", || Request::post(&uri).body(Body::empty()).unwrap());
+
+    let err = send(RetryPolicy { max_attempts: 4, ..policy() }).await.unwrap_err();
+    assert!(matches!(err, BindError::FloodWait(wait) if wait == Duration::from_secs(300)), "{:?}", err);
+    assert_eq!(err.to_string(), "flood wait: Telegram asked to wait 300s before retrying");
+    assert_eq!(seen.lock().unwrap().len(), 1);
+    // Other bots' requests go out as usual.
+    tokio::time::timeout(Duration::from_millis(100), limiter.acquire()).await.unwrap();
+
+    // Within the limit it is an ordinary 429.
+    let err = send(RetryPolicy { max_flood_wait: Duration::from_secs(300), ..policy() }).await.unwrap_err();
+    assert!(matches!(&err, BindError::TelegramError(api) if api.error_code == 429), "{:?}", err);
+}
+
+struct FixedTunnels(HashMap<String, String>);
+
+impl TunnelProvider for FixedTunnels {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn public_urls(&self) -> BoxFuture<'_, Result<HashMap<String, String>, DiscoveryError>> {
+        Box::pin(async move { Ok(self.0.clone()) })
+    }
+}
+
+struct FixedToken;
+
+impl TokenProvider for FixedToken {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn token(&self, _: &BotBinding) -> Result<String, BindError> {
+        Ok(TOKEN.to_string())
+    }
+}
+
+#[tokio::test]
+async fn cancelling_a_run_reports_every_bot() {
+    // Telegram never answers, so the bind is still in flight when cancelled.
+    let make = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_: Request<Body>| std::future::pending::<Result<Response<Body>, Infallible>>()))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+    let api_base = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+
+    let untunnelled = BotBinding { name: "mistral".to_string(), port: 9988, ..bot() };
+    let (sender, mut received) = events::channel();
+    let cancel = CancellationToken::new();
+    let config = RebindConfig {
+        client: client(),
+        run_id: "run-1".to_string(),
+        api_base,
+        discord_api_base: String::new(),
+        slack_api_base: String::new(),
+        provider: Box::new(FixedTunnels(HashMap::from([("gpt4o".to_string(), "https://a.ngrok.io".to_string())]))),
+        tokens: Box::new(FixedToken),
+        secret: String::new(),
+        bots: vec![bot(), untunnelled],
+        rewrites: Vec::new(),
+        retry: RetryPolicy { timeout: Duration::from_secs(60), ..policy() },
+        concurrency: 2,
+        force: false,
+        state_file: None,
+        track_secrets: false,
+        healthcheck: None,
+        ledger: None,
+        pulses: None,
+        notify: None,
+        post_bind: None,
+        events: Some(sender),
+        deadline: None,
+        cancel: Some(cancel.clone()),
+        alert_after: None,
+        recent_bind: None,
+    };
+    tokio::spawn(async move {
+        while let Some(event) = received.recv().await {
+            if matches!(event, RebindEvent::BindStarted { .. }) {
+                cancel.cancel();
+            }
+        }
+    });
+
+    let report = tokio::time::timeout(Duration::from_secs(5), rebind(&config)).await.unwrap().unwrap();
+    assert!(report.cancelled);
+    assert_eq!(report.to_json()["cancelled"], json!(true));
+    let outcomes: Vec<_> = report.outcomes.iter().map(|o| (o.bot.as_str(), &o.outcome)).collect();
+    assert!(matches!(outcomes[..], [("gpt4o", Outcome::Failed(BindError::Cancelled)), ("mistral", Outcome::NoTunnel)]));
+}
+
+#[tokio::test]
+async fn reports_list_bots_in_table_order_not_completion_order() {
+    // Each bot's setWebhook is answered later than the next one's, so the
+    // binds finish in reverse table order.
+    let delays: HashMap<_, _> =
+        [("a", 300), ("b", 200), ("c", 100), ("d", 0)].map(|(host, ms)| (format!("https://{}.ngrok.io/webhook", host), ms)).into();
+    let make = make_service_fn(move |_| {
+        let delays = delays.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let delays = delays.clone();
+                async move {
+                    let body: Value = serde_json::from_slice(&hyper::body::to_bytes(req.into_body()).await.unwrap())
+                        .unwrap_or(Value::Null);
+                    let delay = body["url"].as_str().and_then(|url| delays.get(url)).copied().unwrap_or(0);
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    let reply = json!({ "ok": true, "result": true }).to_string();
+                    Ok::<_, Infallible>(Response::new(Body::from(reply)))
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+    let api_base = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+
+    let names = ["gpt4o", "mistral", "claude", "llama"];
+    let bots: Vec<_> =
+        names.iter().zip(9000..).map(|(name, port)| BotBinding { name: name.to_string(), port, ..bot() }).collect();
+    let tunnels = names.iter().zip(["a", "b", "c", "d"]).map(|(name, host)| (name.to_string(), format!("https://{}.ngrok.io", host)));
+    let (sender, mut received) = events::channel();
+    let config = RebindConfig {
+        client: client(),
+        run_id: "run-1".to_string(),
+        api_base,
+        discord_api_base: String::new(),
+        slack_api_base: String::new(),
+        provider: Box::new(FixedTunnels(tunnels.collect())),
+        tokens: Box::new(FixedToken),
+        secret: String::new(),
+        bots,
+        rewrites: Vec::new(),
+        retry: policy(),
+        concurrency: 4,
+        force: true,
+        state_file: None,
+        track_secrets: false,
+        healthcheck: None,
+        ledger: None,
+        pulses: None,
+        notify: None,
+        post_bind: None,
+        events: Some(sender),
+        deadline: None,
+        cancel: None,
+        alert_after: None,
+        recent_bind: None,
+    };
+
+    let report = rebind(&config).await.unwrap();
+    drop(config);
+    let mut finished = Vec::new();
+    while let Some(event) = received.recv().await {
+        if let RebindEvent::BindResult { name, .. } = event {
+            finished.push(name);
+        }
+    }
+    assert_eq!(finished, ["llama", "claude", "mistral", "gpt4o"]);
+    let outcomes: Vec<_> = report.outcomes.iter().map(|o| o.bot.as_str()).collect();
+    assert_eq!(outcomes, names);
+    let bound: Vec<_> = report.to_json()["bound"].as_array().unwrap().iter().map(|b| b["bot"].clone()).collect();
+    assert_eq!(bound, names.map(Value::from));
+}
+
+#[tokio::test]
+async fn polling_bots_lose_their_webhook_while_the_rest_are_bound() {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let log = calls.clone();
+    let make = make_service_fn(move |_| {
+        let log = log.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let method = req.uri().path().rsplit('/').next().unwrap_or_default().to_string();
+                let reply = match method.as_str() {
+                    "getWebhookInfo" => json!({ "ok": true, "result": { "url": "https://old.ngrok.io/webhook", "pending_update_count": 0 } }),
+                    _ => json!({ "ok": true, "result": true }),
+                };
+                log.lock().unwrap().push(method);
+                async move { Ok::<_, Infallible>(Response::new(Body::from(reply.to_string()))) }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+    let api_base = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+
+    // The polling bot has a tunnel on its port too; it must not be bound.
+    let poller = BotBinding { name: "mistral".to_string(), port: 9988, mode: BotMode::Poll, ..bot() };
+    let tunnels = [("gpt4o", "https://a.ngrok.io"), ("mistral", "https://b.ngrok.io")];
+    let config = RebindConfig {
+        client: client(),
+        run_id: "run-1".to_string(),
+        api_base,
+        discord_api_base: String::new(),
+        slack_api_base: String::new(),
+        provider: Box::new(FixedTunnels(tunnels.iter().map(|(name, url)| (name.to_string(), url.to_string())).collect())),
+        tokens: Box::new(FixedToken),
+        secret: String::new(),
+        bots: vec![bot(), poller],
+        rewrites: Vec::new(),
+        retry: policy(),
+        concurrency: 1,
+        force: false,
+        state_file: None,
+        track_secrets: false,
+        healthcheck: None,
+        ledger: None,
+        pulses: None,
+        notify: None,
+        post_bind: None,
+        events: None,
+        deadline: None,
+        cancel: None,
+        alert_after: None,
+        recent_bind: None,
+    };
+
+    let report = rebind(&config).await.unwrap();
+    let outcomes: Vec<_> = report.outcomes.iter().map(|o| (o.bot.as_str(), &o.outcome)).collect();
+    assert!(matches!(outcomes[0], ("gpt4o", Outcome::Bound { .. })), "{:?}", outcomes);
+    assert!(
+        matches!(outcomes[1], ("mistral", Outcome::Polling { removed: Some(url) }) if url == "https://old.ngrok.io/webhook"),
+        "{:?}",
+        outcomes
+    );
+    assert!(!report.tunnels.contains_key("mistral"));
+    assert_eq!(report.to_json()["polling"][0]["bot"], "mistral");
+    let calls = calls.lock().unwrap();
+    assert_eq!(calls.iter().filter(|c| *c == "setWebhook").count(), 1, "{:?}", calls);
+    assert_eq!(calls.iter().filter(|c| *c == "deleteWebhook").count(), 1, "{:?}", calls);
+}